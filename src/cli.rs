@@ -1,4 +1,18 @@
-use clap::Parser;
+use std::ffi::OsString;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+
+use crate::control::ControlCommand;
+use crate::logging::LogFormat;
+use crate::tui::Tui;
+use crate::watch::Watch;
+use crate::fs::config::{
+    parse_mode, parse_size, read_vocabulary, Config, DirAttrs, FileCache, Glob, GroupSpec, IdMap,
+    MediaType, RootEntry, RootListing, SortOrder, TagCase, TagOrder, TagRule, Vocabulary, Webhook,
+    DAY,
+};
 
 /// Filesystem for tagging files
 #[derive(Parser, Debug)]
@@ -6,12 +20,17 @@ use clap::Parser;
 pub(crate) struct Args {
     /// Act as a client, and mount FUSE at given path
     #[arg(short, long)]
-    pub mount_path: String,
+    pub mount_path: Option<String>,
 
     /// Source files from here, read only
     #[arg(short, long)]
     pub source_path: String,
 
+    /// Keep a tag set of its own over the source, e.g. `photos` next to `work`: the savefile,
+    /// logs, snapshots and content index of a profile are kept apart from those of the others
+    #[arg(long, value_name = "NAME")]
+    pub profile: Option<String>,
+
     /// Don't unmount on process exit
     #[arg(short = 'a', long)]
     pub no_unmount: bool,
@@ -19,4 +38,281 @@ pub(crate) struct Args {
     /// Disallow root to access the filesystem
     #[arg(short = 'r', long)]
     pub disallow_root: bool,
+
+    /// Let other users access the filesystem, within the modes of the tags
+    #[arg(long)]
+    pub allow_other: bool,
+
+    /// Show the number of files behind each tag directory, e.g. `music (1482)`
+    #[arg(short = 'c', long)]
+    pub show_counts: bool,
+
+    /// List files whose tags changed in this many days in /@recent
+    #[arg(long, default_value_t = 7)]
+    pub recent_days: u64,
+
+    /// List files discovered by this many of the last scans that found new files in /@new
+    #[arg(long, default_value_t = 1)]
+    pub new_scans: u64,
+
+    /// How files are ordered in tags that don't have their own sort order
+    #[arg(long, value_enum, default_value_t = SortOrder::Name)]
+    pub sort: SortOrder,
+
+    /// How tag directories are ordered in listings, e.g. `recent` to list the tags used last first
+    #[arg(long, value_enum, default_value_t = TagOrder::Any)]
+    pub tag_order: TagOrder,
+
+    /// Compare the contents of files with equal sizes in the background, listing identical files
+    /// in /@duplicates
+    #[arg(long)]
+    pub find_duplicates: bool,
+
+    /// Owner of the tag directories, defaults to the owner of the source path
+    #[arg(long, value_name = "UID")]
+    pub dir_uid: Option<u32>,
+
+    /// Group of the tag directories, defaults to the group of the source path
+    #[arg(long, value_name = "GID")]
+    pub dir_gid: Option<u32>,
+
+    /// Octal permissions of the tag directories, defaults to those of the source path
+    #[arg(long, value_name = "MODE", value_parser = parse_mode)]
+    pub dir_mode: Option<u16>,
+
+    /// Show uids of the source as other uids, e.g. `0:100000:65536` for a container. Can be repeated
+    #[arg(long, value_name = "SOURCE:MOUNT:COUNT")]
+    pub map_uid: Vec<IdMap>,
+
+    /// Show gids of the source as other gids, like --map-uid
+    #[arg(long, value_name = "SOURCE:MOUNT:COUNT")]
+    pub map_gid: Vec<IdMap>,
+
+    /// Fail reads and writes to the source that take longer than this, e.g. `10s`, instead of
+    /// hanging
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub backing_timeout: Option<Duration>,
+
+    /// How the kernel caches the data of files: dropped on every open, kept across opens, or not
+    /// cached at all
+    #[arg(long, value_enum, default_value_t = FileCache::Revalidate)]
+    pub file_cache: FileCache,
+
+    /// Whether tags differing only in case are the same tag: not at all, for ASCII letters, or for
+    /// letters of any script
+    #[arg(long, value_enum, default_value_t = TagCase::Sensitive)]
+    pub tag_case: TagCase,
+
+    /// Index the files in subdirectories of the source too, except hidden ones. They're shown by
+    /// their name, numbered like `a (2).flac` when several have the same one
+    #[arg(long)]
+    pub nested: bool,
+
+    /// Recognise files renamed where their identity in the source changes too, e.g. on another
+    /// machine before being synced over, by a size and mtime no other file has
+    #[arg(long)]
+    pub match_renames: bool,
+
+    /// Inode bits for tag directories, the rest numbers files, e.g. `16` for a 48/16 layout with
+    /// room for far more files. Stored in the savefile, and checked against the existing numbers
+    #[arg(long, value_name = "BITS", value_parser = clap::value_parser!(u32).range(16..=48))]
+    pub ino_split: Option<u32>,
+
+    /// Keep at most this many files from the source open, closing idle ones beyond it
+    #[arg(long, value_name = "COUNT")]
+    pub max_open_files: Option<usize>,
+
+    /// Retry reads from the source this many times when they fail for a transient reason
+    #[arg(long, default_value_t = 3)]
+    pub retries: u32,
+
+    /// Share the source with other mounts, e.g. on other machines: saves are locked and merged,
+    /// and changes of the others are picked up this often, e.g. `10s`
+    #[arg(long, value_name = "DURATION", value_parser = humantime::parse_duration)]
+    pub shared: Option<Duration>,
+
+    /// Index the words in the plain text files of the source, for `/@search/<words>` and the
+    /// search command. Only files that changed since they were last indexed are read
+    #[arg(long)]
+    pub index_content: bool,
+
+    /// Leave files matching this pattern out of the index, e.g. `*.part`. Can be repeated. Files
+    /// indexed before are treated as gone from the source
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<Glob>,
+
+    /// Leave files smaller than this out of the index, e.g. `1M`
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub min_size: Option<u64>,
+
+    /// Leave files larger than this out of the index, e.g. `4G`
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    pub max_size: Option<u64>,
+
+    /// Only index files of this type, told by their extension. Can be repeated
+    #[arg(long = "type", value_enum, value_name = "TYPE")]
+    pub types: Vec<MediaType>,
+
+    /// Tag every newly found file, `inbox` unless another tag is given, to sort them out later
+    #[arg(long, value_name = "TAG", num_args = 0..=1, default_missing_value = "inbox")]
+    pub inbox: Option<String>,
+
+    /// Run this shell command after files were tagged or untagged, with lines of `tagged` or
+    /// `untagged`, the tag and the file, separated by tabs, on stdin
+    #[arg(long, value_name = "COMMAND")]
+    pub hook: Option<String>,
+
+    /// Post files being tagged or untagged as JSON to this http:// URL, e.g. to start a rescan
+    #[arg(long, value_name = "URL")]
+    pub webhook: Option<Webhook>,
+
+    /// Only report changes of this tag to --hook and --webhook. Can be repeated
+    #[arg(long = "hook-tag", value_name = "TAG")]
+    pub hook_tags: Vec<String>,
+
+    /// How to log: text, JSON lines or straight to the systemd journal
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    /// Mount a snapshot taken with the snapshot command instead of the live tags, read-only
+    #[arg(long, value_name = "NAME")]
+    pub snapshot: Option<String>,
+
+    /// Declare an exclusive tag group, e.g. `status:{unread,reading,done}`. Missing tags are created
+    #[arg(short, long = "group", value_name = "SPEC")]
+    pub groups: Vec<GroupSpec>,
+
+    /// Tag the files matching any of the patterns, e.g. `music=*.flac,*.mp3`, creating the tag.
+    /// Applied to every file found at startup. Can be repeated
+    #[arg(long = "tag", value_name = "TAG=GLOB,...")]
+    pub tag_rules: Vec<TagRule>,
+
+    /// Tag newly found files with the tags other tools like Dolphin or GNOME stored in their
+    /// `user.xdg.tags` or `user.tags` extended attributes, creating missing tags
+    #[arg(long)]
+    pub import_xattr_tags: bool,
+
+    /// What the root of the mount lists besides the tags: no files, the untagged files, every
+    /// file, or only the tags and queries given with --root-entry instead of all tags
+    #[arg(long, value_enum, default_value_t = RootListing::All)]
+    pub root: RootListing,
+
+    /// A tag, or a query listed as a symlink, at the root of a `--root curated` mount, e.g.
+    /// `music` or `rock=music/rock`. Can be repeated
+    #[arg(long = "root-entry", value_name = "[NAME=]TAG[/TAG...]")]
+    pub root_entries: Vec<RootEntry>,
+
+    /// Only let the tags listed in this file be created and applied, one per line, so a shared
+    /// mount keeps one set of tags. Blank lines and lines starting with `#` are skipped
+    #[arg(long, value_name = "FILE", value_parser = read_vocabulary)]
+    pub vocabulary: Option<Vocabulary>,
+
+    /// Let this tag be created and applied when tags are limited by --vocabulary, or limit them to
+    /// the tags given this way. Can be repeated
+    #[arg(long = "allow-tag", value_name = "TAG")]
+    pub allowed_tags: Vec<String>,
+
+    /// Edit the savefile of the source path instead of mounting
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+impl Args {
+    pub fn config(&self) -> Config {
+        Config {
+            show_counts: self.show_counts,
+            recent: Duration::from_secs(self.recent_days * DAY),
+            new_scans: self.new_scans,
+            sort: self.sort,
+            tag_order: self.tag_order,
+            dirs: self.dir_attrs(),
+            uid_map: self.map_uid.clone(),
+            gid_map: self.map_gid.clone(),
+            read_only: self.snapshot.is_some(),
+            shared: self.shared,
+            exclude: self.exclude.clone(),
+            nested: self.nested,
+            min_size: self.min_size,
+            max_size: self.max_size,
+            types: self.types.clone(),
+            inbox: self.inbox.clone().map(Into::into),
+            hook: self.hook.clone(),
+            webhook: self.webhook.clone(),
+            hook_tags: self.hook_tags.iter().map(Into::into).collect(),
+            file_cache: self.file_cache,
+            tag_case: self.tag_case,
+            match_renames: self.match_renames,
+            tag_rules: self.tag_rules.clone(),
+            import_xattr_tags: self.import_xattr_tags,
+            root: self.root,
+            root_entries: self.root_entries.clone(),
+            vocabulary: self.vocabulary(),
+        }
+    }
+
+    /// The options of [Args::config] deciding which files are indexed, which tags they get or may
+    /// be given and how tags are named, for commands.
+    pub fn index_config(&self) -> Config {
+        Config {
+            exclude: self.exclude.clone(),
+            nested: self.nested,
+            min_size: self.min_size,
+            max_size: self.max_size,
+            types: self.types.clone(),
+            inbox: self.inbox.clone().map(Into::into),
+            tag_case: self.tag_case,
+            tag_rules: self.tag_rules.clone(),
+            import_xattr_tags: self.import_xattr_tags,
+            vocabulary: self.vocabulary(),
+            ..Default::default()
+        }
+    }
+
+    /// The tags of --vocabulary and --allow-tag, `None` unless either is given.
+    fn vocabulary(&self) -> Option<Vec<OsString>> {
+        if self.vocabulary.is_none() && self.allowed_tags.is_empty() {
+            return None;
+        }
+
+        let listed = self.vocabulary.iter().flat_map(|vocabulary| vocabulary.0.iter());
+        Some(listed.chain(self.allowed_tags.iter()).map(Into::into).collect())
+    }
+
+    /// Directory ownership from the source path, with any overrides applied.
+    fn dir_attrs(&self) -> DirAttrs {
+        let source = std::fs::metadata(&self.source_path)
+            .map(|md| DirAttrs {
+                uid: md.uid(),
+                gid: md.gid(),
+                perm: (md.permissions().mode() & 0o777) as u16,
+            })
+            .unwrap_or_default();
+
+        // Overrides are given as seen through the mount, the source's ids still need mapping
+        DirAttrs {
+            uid: self
+                .dir_uid
+                .unwrap_or_else(|| IdMap::apply(&self.map_uid, source.uid)),
+            gid: self
+                .dir_gid
+                .unwrap_or_else(|| IdMap::apply(&self.map_gid, source.gid)),
+            perm: self.dir_mode.unwrap_or(source.perm),
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum Command {
+    /// Browse the files and tags in the terminal, tagging and untagging them with a few keys
+    Tui(Tui),
+    /// Scan the source and report what changed in the index
+    Rescan,
+    /// Run quick checks of the source, the savefile and the system, as tab separated lines for
+    /// monitoring, failing if any check does. While mounted, read `/.tagfs/health` instead
+    Doctor,
+    /// Print the files entering and leaving the files carrying some tags as they change, e.g. to
+    /// convert everything tagged `to-convert`. Runs next to a mount
+    Watch(Watch),
+    #[command(flatten)]
+    Control(ControlCommand),
 }
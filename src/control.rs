@@ -0,0 +1,888 @@
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Args, Parser, Subcommand};
+use log::LevelFilter;
+
+use crate::fs::backing::BackingFS;
+use crate::fs::config::{parse_mode, Glob, SortOrder, TagRule};
+use crate::fs::error::TagError;
+use crate::fs::failures::ERROR_LOG;
+use crate::fs::profile::state_file;
+use crate::fs::meta::Position;
+use crate::fs::suggest::SUGGESTIONS;
+use crate::fs::tag::{TagFS, STARRED};
+
+/// Operations on the tag database, shared by the CLI and the control API.
+#[derive(Subcommand, Debug)]
+pub(crate) enum ControlCommand {
+    /// Create tags, skipping those that already exist
+    CreateTags {
+        /// The tags to create
+        #[arg(required = true)]
+        names: Vec<OsString>,
+
+        /// Never save the new tags, so they're gone with the mount, like `mkdir .tmp-<name>`
+        #[arg(long)]
+        ephemeral: bool,
+    },
+
+    /// Tag files in bulk, creating missing tags. Nothing is changed if any line is invalid
+    Apply {
+        /// Tab separated lines of a file name followed by its tags
+        #[arg(short, long)]
+        from_file: PathBuf,
+    },
+
+    /// Add tags to a file, creating missing tags
+    Tag {
+        /// The file to tag
+        file: OsString,
+
+        /// The tags to add
+        #[arg(required = true)]
+        tags: Vec<OsString>,
+    },
+
+    /// Remove tags from a file
+    Untag {
+        /// The file to untag
+        file: OsString,
+
+        /// The tags to remove
+        #[arg(required = true)]
+        tags: Vec<OsString>,
+    },
+
+    /// Tag files with the tags other tools like Dolphin or GNOME stored in their `user.xdg.tags`
+    /// or `user.tags` extended attributes, creating missing tags
+    ImportXattrTags {
+        /// The files to import the tags of, all files when left out
+        files: Vec<OsString>,
+    },
+
+    /// Add files to the @starred tag
+    Star {
+        /// The files to star
+        #[arg(required = true)]
+        files: Vec<OsString>,
+    },
+
+    /// Remove files from the @starred tag
+    Unstar {
+        /// The files to unstar
+        #[arg(required = true)]
+        files: Vec<OsString>,
+    },
+
+    /// Remove a tag
+    RmTag {
+        /// The tag to remove
+        name: OsString,
+
+        /// Move the tag's files to this tag before removing it
+        #[arg(short, long)]
+        move_to: Option<OsString>,
+
+        /// Remove the tag even if it still has files
+        #[arg(short, long)]
+        force: bool,
+    },
+
+    /// Leave a tag out of directory listings, it can still be entered by name
+    Hide {
+        /// The tag to hide
+        name: OsString,
+    },
+
+    /// Show a hidden tag in directory listings again
+    Unhide {
+        /// The tag to show
+        name: OsString,
+    },
+
+    /// List tags first at the root, after the tags pinned before, like favorites
+    Pin {
+        /// The tags to pin
+        #[arg(required = true)]
+        names: Vec<OsString>,
+    },
+
+    /// List pinned tags among the others again
+    Unpin {
+        /// The tags to unpin
+        #[arg(required = true)]
+        names: Vec<OsString>,
+    },
+
+    /// Protect a tag from changes through the mount, e.g. accidental drag and drop
+    Lock {
+        /// The tag to lock
+        name: OsString,
+    },
+
+    /// Allow changes to a locked tag again
+    Unlock {
+        /// The tag to unlock
+        name: OsString,
+    },
+
+    /// Make tags mutually exclusive: applying one removes the others from the file
+    Group {
+        /// The name of the group
+        group: String,
+
+        /// The tags in the group
+        #[arg(required = true)]
+        tags: Vec<OsString>,
+    },
+
+    /// Take tags out of their exclusive group
+    Ungroup {
+        /// The tags to take out
+        #[arg(required = true)]
+        tags: Vec<OsString>,
+    },
+
+    /// Pin a file at a position in the listing order of a tag
+    Order {
+        /// The tag to order
+        name: OsString,
+
+        /// The file to move, it's placed at the end unless --before or --after is given
+        #[arg(short, long = "move", required_unless_present = "clear")]
+        file: Option<OsString>,
+
+        /// Place the file before this one
+        #[arg(short, long, conflicts_with = "after")]
+        before: Option<OsString>,
+
+        /// Place the file after this one
+        #[arg(short, long)]
+        after: Option<OsString>,
+
+        /// Forget the manual order of the tag
+        #[arg(long, conflicts_with_all = ["file", "before", "after"])]
+        clear: bool,
+    },
+
+    /// Choose how the files of a tag are ordered, after any pinned files
+    Sort {
+        /// The tag to sort
+        name: OsString,
+
+        /// The order to use, leave out to fall back to the default of the mount
+        #[arg(value_enum)]
+        order: Option<SortOrder>,
+    },
+
+    /// Let files leave a tag some time after they were added to it, e.g. `30days` for
+    /// watch-later. Files already in the tag get the full time from now
+    Ttl {
+        /// The tag whose files expire
+        name: OsString,
+
+        /// How long files stay, leave out to keep them for good
+        #[arg(value_parser = humantime::parse_duration)]
+        ttl: Option<Duration>,
+    },
+
+    /// Check the contents of the files against their checksums of the last run, tagging files
+    /// that changed without being modified `corrupt`. The first run records the checksums
+    Verify {
+        /// Only check this many files, the next run continues where this one stopped
+        #[arg(short = 'n', long)]
+        limit: Option<usize>,
+
+        /// Only show how far the current pass got
+        #[arg(long, conflicts_with = "limit")]
+        status: bool,
+    },
+
+    /// List the indexed files that were absent from the source when last looked for, with their
+    /// tags
+    Fsck,
+
+    /// Restrict who can enter a tag when the mount is shared with other users
+    Chmod {
+        /// The tag to restrict
+        name: OsString,
+
+        /// Octal permission bits, e.g. 750 to only let the owner and the group of the mount's
+        /// directories in. Only read bits are checked
+        #[arg(value_parser = parse_mode, required_unless_present = "clear")]
+        mode: Option<u16>,
+
+        /// The uid the owner bits apply to
+        #[arg(short, long)]
+        owner: Option<u32>,
+
+        /// Lift all restrictions
+        #[arg(long, conflicts_with_all = ["mode", "owner"])]
+        clear: bool,
+    },
+
+    /// Show who changed which tags, and when
+    Audit {
+        /// Only show the last entries
+        #[arg(short = 'n', long)]
+        last: Option<usize>,
+    },
+
+    /// Show recent failures, like saves that didn't go through or an unreachable source
+    Errors {
+        /// Only show the last entries
+        #[arg(short = 'n', long)]
+        last: Option<usize>,
+    },
+
+    /// Show statistics about the index
+    Stats {
+        /// Report member counts, last changes and co-occurrences per tag
+        #[arg(short, long)]
+        tags: bool,
+    },
+
+    /// Show the size of the members of every tag, and how much of it no other tag carries, largest
+    /// first. Sizes are those of the last scan
+    Du {
+        /// Show sizes in bytes
+        #[arg(short, long)]
+        bytes: bool,
+    },
+
+    /// Write the tags as a Graphviz DOT graph: tags sized by their member count, linked by how
+    /// many files they share, e.g. `graph > tags.dot && dot -Tsvg tags.dot -o tags.svg`
+    Graph {
+        /// Leave out links between tags sharing fewer files
+        #[arg(long, default_value_t = 1)]
+        min_shared: usize,
+    },
+
+    /// Log every request of the mount with how long it took, and count them per kind. Run it again
+    /// for a report
+    Trace {
+        /// Stop tracing, with a last report
+        #[arg(long)]
+        off: bool,
+    },
+
+    /// List the files whose contents have all the given words, see --index-content
+    Search {
+        /// The words to look for
+        #[arg(required = true)]
+        terms: Vec<String>,
+    },
+
+    /// Recommend tags for a file, from tags that go along with its tags and from similarly
+    /// named files
+    Suggest {
+        /// The file to recommend tags for
+        file: OsString,
+
+        /// How many tags to recommend
+        #[arg(short = 'n', long, default_value_t = SUGGESTIONS)]
+        count: usize,
+    },
+
+    /// Show the metadata of a tag
+    Meta {
+        /// The tag to show
+        name: OsString,
+    },
+
+    /// Save the tags as they are now under a name, to restore or mount them later
+    Snapshot {
+        #[command(subcommand)]
+        action: SnapshotAction,
+    },
+
+    /// Merge the tags of another copy of the library, e.g. on a NAS, given its savefile
+    Sync {
+        /// The `.tagfs` savefile of the other copy
+        remote: PathBuf,
+    },
+
+    /// Bundle the savefile, audit log, snapshots and a manifest of the indexed files into a tar
+    /// archive, compressed with zstd when its name ends in `.zst`
+    Backup {
+        /// The archive to write
+        out: PathBuf,
+    },
+
+    /// Restore the tags of a backup onto the files indexed now
+    RestoreBackup {
+        /// The archive written by `backup`
+        archive: PathBuf,
+    },
+
+    /// Revert the last change to the tags, through the mount or a command
+    Undo,
+
+    /// Make the last undone change again
+    Redo,
+
+    /// Set (or clear) a metadata key of a tag, e.g. color, icon or description
+    SetMeta {
+        /// The tag to edit
+        name: OsString,
+
+        /// The key to set
+        key: String,
+
+        /// The new value, leave out to clear the key
+        value: Option<String>,
+    },
+
+    /// Change settings of a running mount without unmounting. Settings left out stay as they are,
+    /// and the next mount goes back to its own flags
+    Reload(Settings),
+}
+
+/// The settings of a mount that [ControlCommand::Reload] can change.
+#[derive(Args, Debug)]
+pub(crate) struct Settings {
+    /// Replace the rules of `--tag`, applying them to the indexed files. Can be repeated
+    #[arg(long = "tag", value_name = "TAG=GLOB,...")]
+    pub tag_rules: Vec<TagRule>,
+
+    /// Drop all rules of `--tag`, files keep the tags they got from them
+    #[arg(long, conflicts_with = "tag_rules")]
+    pub no_tag_rules: bool,
+
+    /// Replace the patterns of `--exclude` and scan the source again. Can be repeated
+    #[arg(long, value_name = "GLOB")]
+    pub exclude: Vec<Glob>,
+
+    /// Drop all patterns of `--exclude` and scan the source again
+    #[arg(long, conflicts_with = "exclude")]
+    pub no_exclude: bool,
+
+    /// List files whose tags changed in this many days in /@recent
+    #[arg(long)]
+    pub recent_days: Option<u64>,
+
+    /// Log up to this level: off, error, warn, info or debug
+    #[arg(long, value_name = "LEVEL")]
+    pub log_level: Option<LevelFilter>,
+}
+
+/// What to do with snapshots of the tags.
+#[derive(Subcommand, Debug)]
+pub(crate) enum SnapshotAction {
+    /// Save the tags as they are now, replacing any snapshot with the same name
+    Create {
+        /// The name of the snapshot
+        name: String,
+    },
+
+    /// List the snapshots and when they were taken
+    List,
+
+    /// Go back to the tags of a snapshot. This can be undone
+    Restore {
+        /// The snapshot to restore
+        name: String,
+    },
+}
+
+/// A control command written as a line of text, see `ControlCommand::parse_line`.
+#[derive(Parser, Debug)]
+#[command(no_binary_name = true, disable_help_subcommand = true)]
+struct ControlLine {
+    #[command(subcommand)]
+    command: ControlCommand,
+}
+
+impl ControlCommand {
+    /// Parse a command as it's written to `/.tagfs/control`, e.g. `tag "some file.pdf" work`.
+    /// Words are split like a shell would, with quotes and backslashes to keep spaces in names.
+    pub fn parse_line(line: &str) -> Result<Self, TagError> {
+        let words = split_words(line).map_err(TagError::Command)?;
+
+        ControlLine::try_parse_from(words)
+            .map(|line| line.command)
+            .map_err(|e| {
+                // Only keep the gist, the usage that follows it is meant for terminals
+                let message = e.to_string();
+                let gist = message.lines().next().unwrap_or_default();
+                TagError::Command(gist.trim_start_matches("error: ").to_string())
+            })
+    }
+
+    /// The names of the tags the command reads or changes.
+    pub fn tag_names(&self) -> Vec<&OsStr> {
+        match self {
+            ControlCommand::Tag { tags, .. }
+            | ControlCommand::Untag { tags, .. }
+            | ControlCommand::Group { tags, .. }
+            | ControlCommand::Ungroup { tags } => tags.iter().map(OsString::as_os_str).collect(),
+            ControlCommand::Pin { names } | ControlCommand::Unpin { names } => {
+                names.iter().map(OsString::as_os_str).collect()
+            }
+            ControlCommand::Star { .. } | ControlCommand::Unstar { .. } => vec![STARRED.as_ref()],
+            ControlCommand::RmTag { name, move_to, .. } => {
+                let names = [Some(name), move_to.as_ref()].into_iter().flatten();
+                names.map(OsString::as_os_str).collect()
+            }
+            ControlCommand::Hide { name }
+            | ControlCommand::Unhide { name }
+            | ControlCommand::Lock { name }
+            | ControlCommand::Unlock { name }
+            | ControlCommand::Order { name, .. }
+            | ControlCommand::Sort { name, .. }
+            | ControlCommand::Ttl { name, .. }
+            | ControlCommand::Chmod { name, .. }
+            | ControlCommand::Meta { name }
+            | ControlCommand::SetMeta { name, .. } => vec![name],
+            _ => vec![],
+        }
+    }
+
+    /// Whether the command leaves the tag database alone, and so isn't worth auditing.
+    /// Whether the command reads or writes files by a path given to it, like `apply --from-file`.
+    /// Those only run from the command line: through the control file they'd run with the
+    /// privileges and working directory of the mount, which often runs as root.
+    pub fn takes_path(&self) -> bool {
+        matches!(
+            self,
+            ControlCommand::Apply { .. }
+                | ControlCommand::Sync { .. }
+                | ControlCommand::Backup { .. }
+                | ControlCommand::RestoreBackup { .. }
+        )
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        matches!(
+            self,
+            ControlCommand::Fsck
+                | ControlCommand::Verify { status: true, .. }
+                | ControlCommand::Audit { .. }
+                | ControlCommand::Errors { .. }
+                | ControlCommand::Snapshot {
+                    action: SnapshotAction::List
+                }
+                | ControlCommand::Stats { .. }
+                | ControlCommand::Du { .. }
+                | ControlCommand::Graph { .. }
+                | ControlCommand::Search { .. }
+                | ControlCommand::Suggest { .. }
+                | ControlCommand::Meta { .. }
+                | ControlCommand::Trace { .. }
+        )
+    }
+}
+
+impl<B> TagFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    /// Run a control command, returning a human-readable report of what was done. Its changes
+    /// can be undone. Saving the resulting state is left to the caller.
+    pub(crate) fn execute(&mut self, command: ControlCommand) -> Result<String, TagError> {
+        let undoable = !command.is_read_only()
+            && !matches!(command, ControlCommand::Undo | ControlCommand::Redo);
+        if undoable {
+            self.begin_step();
+        }
+
+        let report = self.apply_command(command).inspect_err(|_| self.abandon_step())?;
+        if undoable {
+            self.record_step(report.trim_end().to_string());
+        }
+
+        Ok(report)
+    }
+
+    fn apply_command(&mut self, command: ControlCommand) -> Result<String, TagError> {
+        match command {
+            ControlCommand::CreateTags { names, ephemeral } => {
+                if !ephemeral {
+                    names.iter().try_for_each(|name| self.require_declared(name))?;
+                }
+                let before = self.tags().len();
+                for name in names {
+                    let name = name.into();
+                    if self.get_tnb_by_name(&name).is_some() {
+                        continue;
+                    }
+                    let tag = self.create_tag(name);
+                    if ephemeral {
+                        self.make_ephemeral(tag);
+                    }
+                }
+
+                let created = self.tags().len() - before;
+                if ephemeral {
+                    return Ok(format!("created {created} ephemeral tag(s), they aren't saved"));
+                }
+                Ok(format!("created {created} tag(s)"))
+            }
+            ControlCommand::Apply { from_file } => {
+                let mapping = std::fs::read_to_string(from_file)?;
+
+                // Validate everything before touching the state
+                let mut entries = Vec::new();
+                for (idx, line) in mapping.lines().enumerate() {
+                    if line.trim().is_empty() || line.starts_with('#') {
+                        continue;
+                    }
+
+                    let mut fields = line.split('\t');
+                    let file = fields.next().unwrap_or_default();
+                    let Some(fnb) = self.get_fnb_by_name(file) else {
+                        return Err(TagError::Mapping(idx + 1, "no file is indexed by that name"));
+                    };
+                    let tags = fields.filter(|tag| !tag.is_empty()).collect::<Vec<_>>();
+                    if tags.iter().any(|tag| self.require_declared(tag.as_ref()).is_err()) {
+                        return Err(TagError::Mapping(idx + 1, "a tag isn't in the vocabulary"));
+                    }
+
+                    entries.push((fnb, tags));
+                }
+
+                let before = self.tags().len();
+                let mut applied = 0;
+                for (fnb, tags) in entries.iter() {
+                    for &tag in tags {
+                        let tnb = self.get_or_create_tag(tag.into());
+                        self.add_file_to(*fnb, tnb);
+                        applied += 1;
+                    }
+                }
+
+                Ok(format!(
+                    "applied {applied} tag(s) to {} file(s), created {} tag(s)",
+                    entries.len(),
+                    self.tags().len() - before
+                ))
+            }
+            ControlCommand::Tag { file, tags } => {
+                let file_nb = self.require_file(&file)?;
+                tags.iter().try_for_each(|name| self.require_declared(name))?;
+                let before = self.tags().len();
+                for name in tags.iter() {
+                    let tag = self.get_or_create_tag(name.as_os_str().into());
+                    self.add_file_to(file_nb, tag);
+                }
+
+                Ok(format!(
+                    "tagged '{}' with {} tag(s), created {} tag(s)",
+                    file.to_string_lossy(),
+                    tags.len(),
+                    self.tags().len() - before
+                ))
+            }
+            ControlCommand::Untag { file, tags } => {
+                let file_nb = self.require_file(&file)?;
+                let tags = tags
+                    .iter()
+                    .map(|name| self.require_tag(name))
+                    .collect::<Result<Vec<_>, _>>()?;
+                for &tag in tags.iter() {
+                    self.remove_file_from(file_nb, tag);
+                }
+
+                Ok(format!(
+                    "removed {} tag(s) from '{}'",
+                    tags.len(),
+                    file.to_string_lossy()
+                ))
+            }
+            ControlCommand::ImportXattrTags { files } => self.import_xattr_tags_of(&files),
+            ControlCommand::Star { files } => {
+                let files = self.require_files(&files)?;
+                let starred = self.starred_tag();
+                for &file in files.iter() {
+                    self.add_file_to(file, starred);
+                }
+
+                Ok(format!("starred {} file(s)", files.len()))
+            }
+            ControlCommand::Unstar { files } => {
+                let files = self.require_files(&files)?;
+                let starred = self.starred_tag();
+                for &file in files.iter() {
+                    self.remove_file_from(file, starred);
+                }
+
+                Ok(format!("unstarred {} file(s)", files.len()))
+            }
+            ControlCommand::RmTag {
+                name,
+                move_to,
+                force,
+            } => {
+                let tag = self.require_tag(&name)?;
+                let move_to = move_to.map(|to| self.require_tag(&to)).transpose()?;
+                if let Some(to) = move_to.and_then(|to| self.tags().get_by_left(&to)) {
+                    self.require_declared(to)?;
+                }
+
+                self.remove_tag(tag, move_to, force)?;
+
+                Ok(format!("removed tag '{}'", name.to_string_lossy()))
+            }
+            ControlCommand::Hide { name } => {
+                let tag = self.require_tag(&name)?;
+                self.tag_meta_mut(tag).hidden = true;
+
+                Ok(format!("hid tag '{}'", name.to_string_lossy()))
+            }
+            ControlCommand::Unhide { name } => {
+                let tag = self.require_tag(&name)?;
+                self.tag_meta_mut(tag).hidden = false;
+
+                Ok(format!("unhid tag '{}'", name.to_string_lossy()))
+            }
+            ControlCommand::Pin { names } => {
+                let tags = names
+                    .iter()
+                    .map(|name| self.require_tag(name))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let pinned = tags.into_iter().filter(|&tag| self.pin_tag(tag)).count();
+
+                Ok(format!("pinned {pinned} tag(s)"))
+            }
+            ControlCommand::Unpin { names } => {
+                let tags = names
+                    .iter()
+                    .map(|name| self.require_tag(name))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let unpinned = tags.into_iter().filter(|&tag| self.unpin_tag(tag)).count();
+
+                Ok(format!("unpinned {unpinned} tag(s)"))
+            }
+            ControlCommand::Lock { name } => {
+                let tag = self.require_tag(&name)?;
+                self.tag_meta_mut(tag).locked = true;
+
+                Ok(format!("locked tag '{}'", name.to_string_lossy()))
+            }
+            ControlCommand::Unlock { name } => {
+                let tag = self.require_tag(&name)?;
+                self.tag_meta_mut(tag).locked = false;
+
+                Ok(format!("unlocked tag '{}'", name.to_string_lossy()))
+            }
+            ControlCommand::Group { group, tags } => {
+                let tags = tags
+                    .iter()
+                    .map(|name| self.require_tag(name))
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                self.declare_group(&group, &tags);
+
+                Ok(format!(
+                    "group '{group}' now has {} tag(s)",
+                    self.group_members(&group).len()
+                ))
+            }
+            ControlCommand::Ungroup { tags } => {
+                for name in tags.iter() {
+                    let tag = self.require_tag(name)?;
+                    self.tag_meta_mut(tag).group = None;
+                }
+
+                Ok(format!("ungrouped {} tag(s)", tags.len()))
+            }
+            ControlCommand::Order {
+                name,
+                file,
+                before,
+                after,
+                clear,
+            } => {
+                let tag = self.require_tag(&name)?;
+
+                if clear {
+                    self.tag_meta_mut(tag).order.clear();
+                    return Ok(format!("cleared order of tag '{}'", name.to_string_lossy()));
+                }
+
+                // Clap guarantees a file is given without --clear
+                let file = file.unwrap_or_default();
+                let position = match (before, after) {
+                    (Some(before), _) => Position::Before(self.require_file(before)?),
+                    (_, Some(after)) => Position::After(self.require_file(after)?),
+                    _ => Position::End,
+                };
+
+                self.place_file(tag, self.require_file(&file)?, position)?;
+
+                Ok(format!(
+                    "moved '{}' in tag '{}'",
+                    file.to_string_lossy(),
+                    name.to_string_lossy()
+                ))
+            }
+            ControlCommand::Sort { name, order } => {
+                let tag = self.require_tag(&name)?;
+                self.tag_meta_mut(tag).sort = order;
+
+                Ok(format!(
+                    "sorting tag '{}' by {}",
+                    name.to_string_lossy(),
+                    order
+                        .map(|order| order.name())
+                        .unwrap_or("the default order")
+                ))
+            }
+            ControlCommand::Ttl { name, ttl } => {
+                let tag = self.require_tag(&name)?;
+                self.set_ttl(tag, ttl);
+
+                Ok(match ttl {
+                    Some(ttl) => format!(
+                        "files leave tag '{}' {} after they were added",
+                        name.to_string_lossy(),
+                        humantime::format_duration(ttl)
+                    ),
+                    None => format!("files stay in tag '{}'", name.to_string_lossy()),
+                })
+            }
+            ControlCommand::Fsck => {
+                let missing = self.missing_files();
+                let mut report = format!("{} missing file(s)\n", missing.len());
+                for file in missing {
+                    let mut tags = self
+                        .tags_of(file)
+                        .into_iter()
+                        .filter_map(|tag| self.tags().get_by_left(&tag))
+                        .map(|name| name.to_string_lossy())
+                        .collect::<Vec<_>>();
+                    tags.sort();
+
+                    let name = self.get_fnm_by_number(file).cloned().unwrap_or_default();
+                    report.push_str(&format!(
+                        "{}\t{}\n",
+                        name.to_string_lossy(),
+                        tags.join("\t")
+                    ));
+                }
+
+                Ok(report)
+            }
+            ControlCommand::Verify { status: true, .. } => Ok(self.verify_progress()),
+            ControlCommand::Verify { limit, .. } => self.verify(limit),
+            ControlCommand::Chmod {
+                name,
+                mode,
+                owner,
+                clear,
+            } => {
+                let tag = self.require_tag(&name)?;
+                let meta = self.tag_meta_mut(tag);
+                meta.mode = mode;
+                meta.owner = owner.or(meta.owner).filter(|_| !clear);
+
+                Ok(match mode {
+                    Some(mode) => {
+                        format!("set mode of tag '{}' to {mode:o}", name.to_string_lossy())
+                    }
+                    None => format!("cleared mode of tag '{}'", name.to_string_lossy()),
+                })
+            }
+            ControlCommand::Audit { last } => Ok(self.audit_log(last).unwrap_or_default()),
+            ControlCommand::Errors { last } => {
+                Ok(self.read_log(&state_file(ERROR_LOG), last).unwrap_or_default())
+            }
+            ControlCommand::Stats { tags: false } => Ok(self.stats()),
+            ControlCommand::Stats { tags: true } => Ok(self.tag_stats()),
+            ControlCommand::Du { bytes } => Ok(self.disk_usage(bytes)),
+            ControlCommand::Graph { min_shared } => Ok(self.tag_graph(min_shared)),
+            ControlCommand::Trace { off } => Ok(self.set_tracing(off)),
+            ControlCommand::Search { terms } => Ok(self.search_report(&terms.join(" "))),
+            ControlCommand::Suggest { file, count } => {
+                let file = self.require_file(&file)?;
+
+                Ok(self.suggest_report(file, count))
+            }
+            ControlCommand::Meta { name } => {
+                let tag = self.require_tag(&name)?;
+
+                let mut entries = self.tag_meta(tag).map(|meta| meta.entries()).unwrap_or_default();
+                if self.is_ephemeral(tag) {
+                    entries.push(("ephemeral".to_string(), "yes, never saved".to_string()));
+                }
+                Ok(entries
+                    .into_iter()
+                    .map(|(key, value)| format!("{key}: {value}"))
+                    .collect::<Vec<_>>()
+                    .join("\n"))
+            }
+            ControlCommand::Snapshot { action } => match action {
+                SnapshotAction::Create { name } => self.create_snapshot(&name),
+                SnapshotAction::List => Ok(self.list_snapshots()),
+                SnapshotAction::Restore { name } => self.restore_snapshot(&name),
+            },
+            ControlCommand::Sync { remote } => self.sync(&remote),
+            ControlCommand::Backup { out } => self.backup(&out),
+            ControlCommand::RestoreBackup { archive } => self.restore_backup(&archive),
+            ControlCommand::Undo => self.undo(),
+            ControlCommand::Redo => self.redo(),
+            ControlCommand::SetMeta { name, key, value } => {
+                let tag = self.require_tag(&name)?;
+
+                if !self.tag_meta_mut(tag).set(&key, value) {
+                    return Err(TagError::ReadOnlyKey(key));
+                }
+
+                Ok(format!(
+                    "updated '{key}' of tag '{}'",
+                    name.to_string_lossy()
+                ))
+            }
+            ControlCommand::Reload(settings) => self.reload(settings),
+        }
+    }
+}
+
+/// Split a line into words on whitespace. Single quotes keep everything up to the closing quote,
+/// double quotes and bare words allow a backslash to escape the next character.
+fn split_words(line: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut chars = line.chars();
+
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated single quote".to_string()),
+                    }
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some('\\') => word.extend(chars.next()),
+                        Some(c) => word.push(c),
+                        None => return Err("unterminated double quote".to_string()),
+                    }
+                }
+            }
+            '\\' => word.get_or_insert_with(String::new).extend(chars.next()),
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+
+    Ok(words)
+}
+
+/// Quote `word` so [split_words] reads it back as one word, whatever it contains.
+pub(crate) fn quote_word(word: &str) -> String {
+    format!("'{}'", word.replace('\'', "'\\''"))
+}
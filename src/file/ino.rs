@@ -1,12 +1,19 @@
+use std::ops::RangeInclusive;
+
 use serde::{Deserialize, Serialize};
 
-const SPLIT: u64 = 32;
 const ROOT_INO: u64 = 1;
 
+/// The number of low inode bits holding the tag part, unless configured otherwise.
+pub const DEFAULT_SPLIT: u32 = 32;
+/// The supported splits, leaving both parts room for their reserved virtual range.
+pub const SPLITS: RangeInclusive<u32> = 16..=48;
+
 // inode
 // 64 bit |00000000000000000000000000000000|00000000000000000000000000000000|
-// first 32 bits are for file inode number giving us +- 4 billion files per tag
+// by default the first 32 bits are for file inode number giving us +- 4 billion files per tag
 // next 32 bits are for tag inode number giving us +- 4 billion tags
+// the split can be moved, e.g. 48/16 for millions of files but few tag directories
 // use the file bits to check if it's a file or a tag (file bits == 0 -> tag)
 // this allows us to assign unique inodes to files across folders and still identify them easily.
 
@@ -20,31 +27,69 @@ pub type FileNumber = u64;
 pub type TagNumber = u64;
 
 impl Ino {
-    pub fn is_tag(&self) -> bool {
-        self.file() == 0
+    pub fn is_tag(&self, split: Split) -> bool {
+        self.file(split) == 0
     }
 
-    pub fn is_file(&self) -> bool {
-        !self.is_tag()
+    pub fn is_file(&self, split: Split) -> bool {
+        !self.is_tag(split)
     }
 
-    pub fn file(&self) -> FileNumber {
-        self.0 >> SPLIT
+    pub fn is_virtual(&self, split: Split) -> bool {
+        self.file(split) >= split.virtual_files()
     }
 
-    pub fn tag(&self) -> TagNumber {
-        self.0 & (!0 >> SPLIT)
+    pub fn file(&self, split: Split) -> FileNumber {
+        self.0 >> split.0
+    }
+
+    pub fn tag(&self, split: Split) -> TagNumber {
+        self.0 & (!0 >> (64 - split.0))
     }
 
     /// Construct [Ino] from a file and tag part, both unshifted.
     /// If the file part is shifted, just use the Ino constructor.
-    pub fn from_parts(file: u64, tag: u64) -> Ino {
-        Ino((file << SPLIT) | tag)
+    pub fn from_parts(file: u64, tag: u64, split: Split) -> Ino {
+        Ino((file << split.0) | tag)
     }
 
+    /// The inode of a directory, whose file part is 0 whatever the split.
     pub fn from_tag(tag: u64) -> Ino {
-        Ino::from_parts(0, tag)
+        Ino(tag)
     }
 
     pub const ROOT: Ino = Ino(ROOT_INO);
 }
+
+/// Where inodes split into the file and tag parts, as the number of low bits holding the tag
+/// part. Fixed per index, as inodes handed out before are invalidated when it moves.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Split(u32);
+
+impl Split {
+    /// The split of `bits` bits, if it's one of the supported [SPLITS].
+    pub fn new(bits: u32) -> Option<Split> {
+        SPLITS.contains(&bits).then_some(Split(bits))
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// File numbers from here up to the maximum are reserved for virtual files generated by
+    /// tagfs.
+    pub fn virtual_files(self) -> FileNumber {
+        (!0 >> self.0) - 0xFF
+    }
+
+    /// Likewise, the top of the tag space is reserved for virtual directories.
+    pub fn virtual_dirs(self) -> TagNumber {
+        (!0 >> (64 - self.0)) - 0xFF
+    }
+}
+
+impl Default for Split {
+    fn default() -> Self {
+        Split(DEFAULT_SPLIT)
+    }
+}
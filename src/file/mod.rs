@@ -1,3 +1,7 @@
 pub use ino::*;
+pub use name::*;
+pub use nfc::*;
 
 mod ino;
+mod name;
+mod nfc;
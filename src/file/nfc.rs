@@ -0,0 +1,19 @@
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+
+use unicode_normalization::{is_nfc_quick, IsNormalized, UnicodeNormalization};
+
+/// `name` in Unicode normalization form C, e.g. with `e` followed by a combining acute accent as
+/// `é`, the way macOS stores names decomposed. Names that aren't UTF-8 are left as they are.
+pub fn nfc(name: &OsStr) -> Cow<'_, OsStr> {
+    let Some(text) = name.to_str() else { return Cow::Borrowed(name) };
+    if is_nfc_quick(text.chars()) == IsNormalized::Yes {
+        return Cow::Borrowed(name);
+    }
+
+    let composed = text.nfc().collect::<String>();
+    match composed == text {
+        true => Cow::Borrowed(name),
+        false => Cow::Owned(OsString::from(composed)),
+    }
+}
@@ -0,0 +1,117 @@
+use std::error::Error;
+use std::time::SystemTime;
+
+use fuser::Request;
+use log::error;
+
+use crate::file::TagNumber;
+use crate::fs::backing::BackingFS;
+use crate::fs::profile::state_file;
+use crate::fs::tag::TagFS;
+
+/// The append-only log of tag mutations, next to the savefile in the source dir.
+pub const AUDIT_LOG: &str = ".tagfs-audit";
+
+/// Who caused a mutation.
+#[derive(Copy, Clone, Debug)]
+pub struct Actor {
+    pub uid: u32,
+    pub pid: u32,
+}
+
+impl Actor {
+    /// The process behind a FUSE request.
+    pub fn of(req: &Request<'_>) -> Self {
+        Self {
+            uid: req.uid(),
+            pid: req.pid(),
+        }
+    }
+
+    /// This process, for changes made from the command line.
+    pub fn current() -> Self {
+        Self {
+            uid: unsafe { libc::getuid() },
+            pid: std::process::id(),
+        }
+    }
+}
+
+/// What an audited command did: the command, as its debug format `what`, and the first line of
+/// its report, which is a summary when a report goes on to list what it was applied to.
+pub fn command_detail(what: &str, report: &str) -> String {
+    let summary = report.lines().next().unwrap_or_default();
+
+    format!("{what}: {summary}")
+}
+
+/// `detail` with backslashes, tabs and line breaks escaped, so it fits a line of the audit log.
+fn escape(detail: &str) -> String {
+    let mut escaped = String::with_capacity(detail.len());
+    for c in detail.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+impl<B> TagFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    /// Append a mutation to the audit log, as a tab separated line of the time, uid, pid, action
+    /// and what it was applied to, see [escape].
+    pub fn audit(&self, actor: Actor, action: &str, detail: &str) {
+        let line = format!(
+            "{}\t{}\t{}\t{action}\t{}\n",
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+            actor.uid,
+            actor.pid,
+            escape(detail),
+        );
+
+        if let Err(error) = self.backing().append(state_file(AUDIT_LOG), line.as_bytes()) {
+            error!("failed to write the audit log: {error}");
+        }
+    }
+}
+
+impl<B: BackingFS> TagFS<B> {
+    /// The last `count` entries of the audit log, or all of them.
+    pub fn audit_log(&self, count: Option<usize>) -> Result<String, B::Error> {
+        self.read_log(&state_file(AUDIT_LOG), count)
+    }
+
+    /// The last `count` lines of a log in the source dir, or all of them.
+    pub(crate) fn read_log(&self, path: &str, count: Option<usize>) -> Result<String, B::Error> {
+        let handle = self.backing().open(path)?;
+        let log = self.backing().read(handle, 0, u64::MAX);
+        self.backing().release(handle);
+
+        let log = String::from_utf8_lossy(&log?).into_owned();
+        let lines = log.lines().collect::<Vec<_>>();
+        let skip = count
+            .map(|count| lines.len().saturating_sub(count))
+            .unwrap_or(0);
+
+        Ok(lines[skip..].join("\n"))
+    }
+
+    /// The tags of a directory as a path, e.g. `/music/live`.
+    pub(crate) fn tag_path(&self, tags: &[TagNumber]) -> String {
+        let names = tags
+            .iter()
+            .filter_map(|tag| self.tags().get_by_left(tag))
+            .map(|name| name.to_string_lossy())
+            .collect::<Vec<_>>();
+
+        format!("/{}", names.join("/"))
+    }
+}
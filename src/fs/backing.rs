@@ -1,32 +1,182 @@
-use std::cell::RefCell;
+use std::cell::{Cell, OnceCell, RefCell};
 use std::cmp::min;
 use std::collections::HashMap;
+use std::ffi::{CString, OsStr, OsString};
 use std::fs;
-use std::fs::File;
-use std::io::Write;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{ErrorKind, Read, Write};
 use std::os::unix::prelude::*;
 use std::path::{Path, PathBuf};
-use std::time::UNIX_EPOCH;
+use std::mem;
+use std::ptr;
+use std::sync::mpsc;
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
 
 use fuser::{FileAttr, FileType};
+use libc::c_void;
+use serde::{Deserialize, Serialize};
 
 use crate::fs::FileHandle;
 
 pub trait BackingFS {
     fn get_metadata<P: AsRef<Path>>(&self, path: P) -> Result<FileAttr, Self::Error>;
+    fn source_id<P: AsRef<Path>>(&self, path: P) -> Result<SourceId, Self::Error>;
     fn open<P: AsRef<Path>>(&self, path: P) -> Result<FileHandle, Self::Error>;
     fn create<P: AsRef<Path>>(&self, path: P) -> Result<FileHandle, Self::Error>;
     fn read(&self, handle: FileHandle, offset: u64, size: u64) -> Result<Vec<u8>, Self::Error>;
     fn write(&self, handle: FileHandle, data: &[u8]) -> Result<(), Self::Error>;
+    fn append<P: AsRef<Path>>(&self, path: P, data: &[u8]) -> Result<(), Self::Error>;
+    /// Wait for an exclusive lock on `path`, creating it if needed. Releasing the handle unlocks.
+    fn lock<P: AsRef<Path>>(&self, path: P) -> Result<FileHandle, Self::Error>;
     fn release(&self, handle: FileHandle);
+    fn getxattr<P: AsRef<Path>>(&self, path: P, name: &OsStr) -> Result<Vec<u8>, Self::Error>;
+    fn listxattr<P: AsRef<Path>>(&self, path: P) -> Result<Vec<OsString>, Self::Error>;
+    /// The files directly in directory `prefix` of the source, or also those in its subdirectories
+    /// for nested sources, as paths relative to the source, e.g. `list("")` for the root. Other
+    /// entries, and those that can't be read, are left out.
+    /// They're listed as the listing is consumed, which may be on another thread.
+    fn list<P: AsRef<Path>>(&self, prefix: P) -> Result<Self::Listing, Self::Error>;
+
+    /// Whether there's anything at `path`. Sources that can tell without a full stat do so.
+    fn exists<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.get_metadata(path).is_ok()
+    }
+
+    /// The files created, removed, renamed or written to in directory `prefix` of the source from
+    /// now on, as paths relative to the source like [BackingFS::list]. A file may be reported more
+    /// than once per change. `None` for sources that can't be watched, which have to be scanned.
+    fn watch<P: AsRef<Path>>(&self, _prefix: P) -> Option<Receiver<OsString>> {
+        None
+    }
+
+    /// What the source supports, for the mount to refuse what it doesn't up front.
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// [BackingFS::get_metadata] of every path, in order. Sources that can stat several files
+    /// at once do so. This doesn't save syscalls: Linux has no batched `statx`, so [ExternalFS]
+    /// still stats every path on its own, only on several threads.
+    fn get_metadata_many<P: AsRef<Path>>(
+        &self,
+        paths: &[P],
+    ) -> Vec<Result<FileAttr, Self::Error>> {
+        paths.iter().map(|path| self.get_metadata(path)).collect()
+    }
+
+    /// [BackingFS::source_id] of every path, in order, like [BackingFS::get_metadata_many].
+    fn source_ids<P: AsRef<Path>>(&self, paths: &[P]) -> Vec<Result<SourceId, Self::Error>> {
+        paths.iter().map(|path| self.source_id(path)).collect()
+    }
+
+    /// Both [BackingFS::get_metadata_many] and [BackingFS::source_ids], from a single stat of
+    /// every path where the source allows it.
+    fn stat_all<P: AsRef<Path>>(&self, paths: &[P]) -> Vec<Result<Stat, Self::Error>> {
+        let sources = self.source_ids(paths);
+        let attrs = self.get_metadata_many(paths).into_iter().zip(sources);
+
+        attrs
+            .map(|(attr, source)| {
+                Ok(Stat {
+                    attr: attr?,
+                    source: source.ok(),
+                })
+            })
+            .collect()
+    }
+
+    /// [BackingFS::read] into `buf`, returning how much was read: all of it unless the file ends
+    /// first. Sources that can read in place don't allocate a buffer of their own. This isn't
+    /// zero-copy: fuser 0.12 can't splice, so the data is still copied into the reply.
+    fn read_into(
+        &self,
+        handle: FileHandle,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, Self::Error> {
+        let data = self.read(handle, offset, buf.len() as u64)?;
+        buf[..data.len()].copy_from_slice(&data);
+
+        Ok(data.len())
+    }
 
     type Error;
+    /// See [BackingFS::list]
+    type Listing: Iterator<Item = OsString> + Send + 'static;
+}
+
+/// What a source supports, see [BackingFS::capabilities].
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Capabilities {
+    /// The savefile and the other state can be written, else the mount refuses changes
+    pub writable: bool,
+    /// Files have extended attributes, see [BackingFS::getxattr]
+    pub xattrs: bool,
+    /// Changes can be followed as they happen, see [BackingFS::watch]
+    pub watchable: bool,
+    /// Files can only be read front to back, so they're opened non-seekable
+    pub streaming_only: bool,
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self {
+            writable: true,
+            xattrs: true,
+            watchable: false,
+            streaming_only: false,
+        }
+    }
+}
+
+/// What a file of the source looks like, see [BackingFS::stat_all].
+#[derive(Debug, Copy, Clone)]
+pub struct Stat {
+    pub attr: FileAttr,
+    /// `None` for sources that can't tell the identity of their files
+    pub source: Option<SourceId>,
+}
+
+/// What identifies a file in the source regardless of its name: its device and inode number.
+#[derive(Serialize, Deserialize, Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub struct SourceId {
+    pub dev: u64,
+    pub ino: u64,
 }
 
 #[derive(Debug)]
 pub struct ExternalFS {
     source_path: PathBuf,
-    open_files: RefCell<HashMap<FileHandle, File>>,
+    open_files: RefCell<HashMap<FileHandle, OpenFile>>,
+    /// The id of the next handle, unrelated to the fd so a handle survives reopening its file
+    next_handle: Cell<u64>,
+    /// Ticks on every use of a handle, to find the least recently used one
+    clock: Cell<u64>,
+    /// How many files may be open at once, see [ExternalFS::evict]
+    max_open: Option<usize>,
+    /// How long operations may take before failing with EIO, see [ExternalFS::deadline]
+    timeout: Option<Duration>,
+    /// Run the operations with a deadline, started on first use
+    workers: OnceCell<Sender<Job>>,
+    /// Probed on first use, see [BackingFS::capabilities]
+    capabilities: Cell<Option<Capabilities>>,
+    /// Whether listings descend into subdirectories, see [ExternalFS::with_nested]
+    nested: bool,
+}
+
+/// A handle handed out by [ExternalFS]. Read-only files can be closed while idle and are reopened
+/// from their path on the next read.
+#[derive(Debug)]
+struct OpenFile {
+    path: PathBuf,
+    file: Option<File>,
+    /// Files opened for writing are never closed early, reopening them could lose data
+    writable: bool,
+    last_used: u64,
 }
 
 impl ExternalFS {
@@ -37,83 +187,600 @@ impl ExternalFS {
     pub fn new<P: AsRef<Path>>(path: P) -> Self {
         Self {
             open_files: RefCell::new(HashMap::new()),
+            next_handle: Cell::new(1),
+            clock: Cell::new(0),
             source_path: path.as_ref().to_path_buf(),
+            max_open: None,
+            timeout: None,
+            workers: OnceCell::new(),
+            capabilities: Cell::new(None),
+            nested: false,
+        }
+    }
+
+    /// Keep at most `max_open` files open, closing the least recently used idle ones beyond that.
+    pub fn with_max_open(mut self, max_open: Option<usize>) -> Self {
+        self.max_open = max_open;
+        self
+    }
+
+    /// List the files in subdirectories too, by their path relative to the source. Hidden
+    /// directories like `.git` are left out, and symlinks to directories aren't followed.
+    pub fn with_nested(mut self, nested: bool) -> Self {
+        self.nested = nested;
+        self
+    }
+
+    /// Fail operations that take longer than `timeout`, e.g. on a hung network mount.
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Run `op` with the configured timeout. The operation is handed to one of
+    /// [DEADLINE_WORKERS] threads and abandoned when it takes too long: a syscall stuck on a dead
+    /// server can't be cancelled, but at least the rest of the mount keeps working. A write that
+    /// timed out may still land later.
+    fn deadline<T, F>(&self, op: F) -> io::Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce() -> io::Result<T> + Send + 'static,
+    {
+        let Some(timeout) = self.timeout else {
+            return op();
+        };
+
+        let (tx, rx) = mpsc::channel();
+        let workers = self.workers.get_or_init(spawn_workers);
+        // The workers only stop once the sender is dropped
+        let _ = workers.send(Box::new(move || {
+            // Nobody's listening anymore if the deadline passed
+            let _ = tx.send(op());
+        }));
+
+        rx.recv_timeout(timeout).unwrap_or_else(|_| {
+            Err(io::Error::new(
+                ErrorKind::TimedOut,
+                format!("backing operation took longer than {timeout:?}"),
+            ))
+        })
+    }
+
+    fn tick(&self) -> u64 {
+        self.clock.set(self.clock.get() + 1);
+        self.clock.get()
+    }
+
+    /// Register a newly opened file under a fresh handle.
+    fn insert(&self, path: PathBuf, file: File, writable: bool) -> FileHandle {
+        self.evict();
+
+        let handle = FileHandle(self.next_handle.get());
+        self.next_handle.set(handle.0 + 1);
+
+        let open = OpenFile {
+            path,
+            file: Some(file),
+            writable,
+            last_used: self.tick(),
+        };
+        self.open_files.borrow_mut().insert(handle, open);
+
+        handle
+    }
+
+    /// Close the least recently used read-only files until there's room for one more under the
+    /// cap. Their handles stay valid, see [ExternalFS::file].
+    fn evict(&self) {
+        let Some(max_open) = self.max_open else {
+            return;
+        };
+
+        let mut files = self.open_files.borrow_mut();
+        let mut open = files.values().filter(|open| open.file.is_some()).count();
+        while open >= max_open.max(1) {
+            let lru = files
+                .values_mut()
+                .filter(|open| open.file.is_some() && !open.writable)
+                .min_by_key(|open| open.last_used);
+            // Only writable files left, those have to stay open
+            let Some(lru) = lru else {
+                return;
+            };
+
+            lru.file = None;
+            open -= 1;
         }
     }
 
+    /// The file behind a handle, reopening it if it was closed to stay under the cap.
+    fn file(&self, handle: FileHandle) -> io::Result<File> {
+        let mut files = self.open_files.borrow_mut();
+        let open = files
+            .get_mut(&handle)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))?;
+        open.last_used = self.tick();
+
+        if let Some(file) = &open.file {
+            return file.try_clone();
+        }
+
+        let path = open.path.clone();
+        drop(files);
+
+        let file = self.deadline(move || File::open(path))?;
+        self.evict();
+
+        let mut files = self.open_files.borrow_mut();
+        // Released while reopening is impossible, but don't panic over it
+        let open = files
+            .get_mut(&handle)
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))?;
+        open.file = Some(file.try_clone()?);
+
+        Ok(file)
+    }
+
     pub fn source_path(&self) -> &Path {
         self.source_path.as_path()
     }
+
+    /// Stat `paths` on [STAT_WORKERS] threads, all under one deadline.
+    fn stat_paths<P: AsRef<Path>>(&self, paths: &[P]) -> Vec<io::Result<fs::Metadata>> {
+        let paths = paths.iter().map(|path| self.relative_path(path)).collect::<Vec<_>>();
+        let count = paths.len();
+
+        match self.deadline(move || Ok(stat_parallel(&paths))) {
+            Ok(stats) => stats,
+            Err(e) => (0..count).map(|_| Err(io::Error::new(e.kind(), e.to_string()))).collect(),
+        }
+    }
+}
+
+/// How many operations with a deadline run at once, see [ExternalFS::deadline]. Operations stuck
+/// on a dead server keep their worker, so once all of them are stuck the next operations time out
+/// waiting for one rather than piling up threads.
+const DEADLINE_WORKERS: usize = 8;
+
+/// An operation for the [DEADLINE_WORKERS].
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Start the [DEADLINE_WORKERS], which run the jobs sent to them until the sender is dropped.
+fn spawn_workers() -> Sender<Job> {
+    let (sender, jobs) = mpsc::channel::<Job>();
+    let jobs = Arc::new(Mutex::new(jobs));
+    for _ in 0..DEADLINE_WORKERS {
+        let jobs = jobs.clone();
+        thread::spawn(move || loop {
+            // The lock is only held while waiting for the next job
+            let job = jobs.lock().map(|jobs| jobs.recv());
+            match job {
+                Ok(Ok(job)) => job(),
+                _ => return,
+            }
+        });
+    }
+
+    sender
+}
+
+/// How many files of the source are statted at once by [BackingFS::get_metadata_many], each with
+/// a syscall of its own. Spinning disks and network shares serve many outstanding requests far
+/// faster than one at a time.
+const STAT_WORKERS: usize = 16;
+
+/// Stat `paths` on several threads, which send their results back over a channel. The results are
+/// in the order of `paths`.
+fn stat_parallel(paths: &[PathBuf]) -> Vec<io::Result<fs::Metadata>> {
+    let (tx, rx) = mpsc::channel();
+    thread::scope(|scope| {
+        for worker in 0..STAT_WORKERS.min(paths.len()) {
+            let tx = tx.clone();
+            scope.spawn(move || {
+                for (idx, path) in paths.iter().enumerate().skip(worker).step_by(STAT_WORKERS) {
+                    let _ = tx.send((idx, fs::metadata(path)));
+                }
+            });
+        }
+    });
+    drop(tx);
+
+    let mut stats = rx.into_iter().collect::<Vec<_>>();
+    stats.sort_by_key(|&(idx, _)| idx);
+
+    stats.into_iter().map(|(_, md)| md).collect()
+}
+
+fn attr_of(md: &fs::Metadata) -> FileAttr {
+    let ctime = md.created().unwrap_or(UNIX_EPOCH);
+
+    FileAttr {
+        ino: md.ino(),
+        size: md.size(),
+        blocks: md.blocks(),
+        atime: md.accessed().unwrap_or(UNIX_EPOCH), // 1970-01-01 00:00:00
+        mtime: md.modified().unwrap_or(UNIX_EPOCH),
+        ctime,
+        crtime: ctime,
+        kind: FileType::RegularFile,
+        perm: md.permissions().mode() as u16,
+        nlink: md.nlink() as u32,
+        uid: md.uid(),
+        gid: md.gid(),
+        rdev: md.rdev() as u32,
+        flags: 0,
+        blksize: md.blksize() as u32,
+    }
+}
+
+fn source_id_of(md: &fs::Metadata) -> SourceId {
+    SourceId {
+        dev: md.dev(),
+        ino: md.ino(),
+    }
 }
 
 impl BackingFS for ExternalFS {
     fn get_metadata<P: AsRef<Path>>(&self, path: P) -> Result<FileAttr, Self::Error> {
-        fs::metadata(self.relative_path(path)).map(|md| {
-            let ctime = md.created().unwrap_or(UNIX_EPOCH);
-
-            FileAttr {
-                ino: md.ino(),
-                size: md.size(),
-                blocks: md.blocks(),
-                atime: md.accessed().unwrap_or(UNIX_EPOCH), // 1970-01-01 00:00:00
-                mtime: md.modified().unwrap_or(UNIX_EPOCH),
-                ctime,
-                crtime: ctime,
-                kind: FileType::RegularFile,
-                perm: md.permissions().mode() as u16,
-                nlink: md.nlink() as u32,
-                uid: md.uid(),
-                gid: md.gid(),
-                rdev: md.rdev() as u32,
-                flags: 0,
-                blksize: md.blksize() as u32,
-            }
-        })
+        let path = self.relative_path(path);
+        self.deadline(move || fs::metadata(path)).map(|md| attr_of(&md))
     }
 
-    fn open<P: AsRef<Path>>(&self, path: P) -> Result<FileHandle, Self::Error> {
-        let fh = File::open(self.relative_path(path))?;
+    fn source_id<P: AsRef<Path>>(&self, path: P) -> Result<SourceId, Self::Error> {
+        let path = self.relative_path(path);
+        self.deadline(move || fs::metadata(path)).map(|md| source_id_of(&md))
+    }
+
+    fn get_metadata_many<P: AsRef<Path>>(
+        &self,
+        paths: &[P],
+    ) -> Vec<Result<FileAttr, Self::Error>> {
+        let stats = self.stat_paths(paths);
 
-        let handle = FileHandle(fh.as_raw_fd() as u64);
+        stats.into_iter().map(|md| md.map(|md| attr_of(&md))).collect()
+    }
 
-        self.open_files.borrow_mut().insert(handle, fh);
+    fn source_ids<P: AsRef<Path>>(&self, paths: &[P]) -> Vec<Result<SourceId, Self::Error>> {
+        let stats = self.stat_paths(paths);
 
-        Ok(handle)
+        stats.into_iter().map(|md| md.map(|md| source_id_of(&md))).collect()
     }
 
-    fn create<P: AsRef<Path>>(&self, path: P) -> Result<FileHandle, Self::Error> {
-        let fh = File::create(self.relative_path(path))?;
+    fn stat_all<P: AsRef<Path>>(&self, paths: &[P]) -> Vec<Result<Stat, Self::Error>> {
+        let stats = self.stat_paths(paths);
+
+        stats
+            .into_iter()
+            .map(|md| {
+                md.map(|md| Stat {
+                    attr: attr_of(&md),
+                    source: Some(source_id_of(&md)),
+                })
+            })
+            .collect()
+    }
+
+    fn open<P: AsRef<Path>>(&self, path: P) -> Result<FileHandle, Self::Error> {
+        let path = self.relative_path(path);
+        let opened = path.clone();
+        let fh = self.deadline(move || File::open(opened))?;
 
-        let handle = FileHandle(fh.as_raw_fd() as u64);
+        Ok(self.insert(path, fh, false))
+    }
 
-        self.open_files.borrow_mut().insert(handle, fh);
+    fn create<P: AsRef<Path>>(&self, path: P) -> Result<FileHandle, Self::Error> {
+        let path = self.relative_path(path);
+        let created = path.clone();
+        let fh = self.deadline(move || File::create(created))?;
 
-        Ok(handle)
+        Ok(self.insert(path, fh, true))
     }
 
     fn read(&self, handle: FileHandle, offset: u64, size: u64) -> Result<Vec<u8>, Self::Error> {
-        let file = &self.open_files.borrow_mut()[&handle];
-        let file_size = file.metadata()?.len();
+        let file = self.file(handle)?;
+
+        self.deadline(move || {
+            let file_size = file.metadata()?.len();
 
-        let size = min(size, file_size.saturating_sub(offset));
+            let size = min(size, file_size.saturating_sub(offset));
 
-        let mut buf = vec![0; size as usize];
-        file.read_exact_at(&mut buf, offset)?;
+            let mut buf = vec![0; size as usize];
+            file.read_exact_at(&mut buf, offset)?;
 
-        Ok(buf)
+            Ok(buf)
+        })
+    }
+
+    fn read_into(
+        &self,
+        handle: FileHandle,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, Self::Error> {
+        // The buffer can't be lent to a thread that may outlive the deadline
+        if self.timeout.is_some() {
+            let data = self.read(handle, offset, buf.len() as u64)?;
+            buf[..data.len()].copy_from_slice(&data);
+            return Ok(data.len());
+        }
+
+        let file = self.file(handle)?;
+        let mut read = 0;
+        while read < buf.len() {
+            match file.read_at(&mut buf[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(read)
     }
 
     fn write(&self, handle: FileHandle, data: &[u8]) -> Result<(), Self::Error> {
-        let mut files = self.open_files.borrow_mut();
-        let file = files.get_mut(&handle).unwrap();
-        file.write_all(data)?;
+        // Writable files are never evicted, so this doesn't reopen anything
+        let mut file = self.file(handle)?;
+        match self.timeout {
+            // The data can't be lent to a thread that may outlive the deadline
+            Some(_) => {
+                let data = data.to_vec();
+                self.deadline(move || file.write_all(&data))
+            }
+            None => file.write_all(data),
+        }
+    }
+
+    fn append<P: AsRef<Path>>(&self, path: P, data: &[u8]) -> Result<(), Self::Error> {
+        let path = self.relative_path(path);
+        let data = data.to_vec();
 
-        Ok(())
+        self.deadline(move || {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            file.write_all(&data)
+        })
+    }
+
+    fn lock<P: AsRef<Path>>(&self, path: P) -> Result<FileHandle, Self::Error> {
+        let path = self.relative_path(path);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Held as long as the file is open, and writable files are never evicted
+        Ok(self.insert(path, file, true))
     }
 
     fn release(&self, handle: FileHandle) {
         self.open_files.borrow_mut().remove(&handle);
     }
 
+    fn getxattr<P: AsRef<Path>>(&self, path: P, name: &OsStr) -> Result<Vec<u8>, Self::Error> {
+        let path = CString::new(self.relative_path(path).into_os_string().into_vec())?;
+        let name = CString::new(name.as_bytes())?;
+
+        self.deadline(move || {
+            read_xattr(|buf, size| unsafe {
+                libc::getxattr(path.as_ptr(), name.as_ptr(), buf, size)
+            })
+        })
+    }
+
+    fn listxattr<P: AsRef<Path>>(&self, path: P) -> Result<Vec<OsString>, Self::Error> {
+        let path = CString::new(self.relative_path(path).into_os_string().into_vec())?;
+
+        let names = self.deadline(move || {
+            read_xattr(|buf, size| unsafe { libc::listxattr(path.as_ptr(), buf.cast(), size) })
+        })?;
+
+        // A sequence of null-terminated names
+        Ok(names
+            .split(|&b| b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| OsStr::from_bytes(name).to_os_string())
+            .collect())
+    }
+
+    fn list<P: AsRef<Path>>(&self, prefix: P) -> Result<Self::Listing, Self::Error> {
+        let prefix = prefix.as_ref().to_path_buf();
+        let dir = self.relative_path(&prefix);
+        let entries = self.deadline(move || fs::read_dir(dir))?;
+
+        Ok(SourceListing {
+            dirs: vec![(entries, prefix)],
+            nested: self.nested,
+        })
+    }
+
+    fn exists<P: AsRef<Path>>(&self, path: P) -> bool {
+        let path = self.relative_path(path);
+        self.deadline(move || path.try_exists()).unwrap_or(false)
+    }
+
+    /// A source on a read-only filesystem, or one this user can't write, isn't writable. When
+    /// that can't be told, it's taken to be writable and saves fail.
+    fn capabilities(&self) -> Capabilities {
+        if let Some(capabilities) = self.capabilities.get() {
+            return capabilities;
+        }
+
+        let writable = CString::new(self.source_path.as_os_str().as_bytes())
+            .map(|path| unsafe {
+                let mut stat = mem::zeroed::<libc::statvfs>();
+                let read_only = libc::statvfs(path.as_ptr(), &mut stat) == 0
+                    && stat.f_flag & libc::ST_RDONLY != 0;
+                let denied = libc::access(path.as_ptr(), libc::W_OK) != 0
+                    && io::Error::last_os_error().raw_os_error() == Some(libc::EACCES);
+                !read_only && !denied
+            })
+            .unwrap_or(true);
+        let capabilities = Capabilities {
+            writable,
+            watchable: true,
+            ..Capabilities::default()
+        };
+        self.capabilities.set(Some(capabilities));
+
+        capabilities
+    }
+
+    fn watch<P: AsRef<Path>>(&self, prefix: P) -> Option<Receiver<OsString>> {
+        let prefix = prefix.as_ref().to_path_buf();
+        let dir = CString::new(self.relative_path(&prefix).into_os_string().into_vec()).ok()?;
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return None;
+        }
+        // Closing the file closes the inotify instance
+        let inotify = unsafe { File::from_raw_fd(fd) };
+        let mask = libc::IN_CREATE
+            | libc::IN_DELETE
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO
+            | libc::IN_CLOSE_WRITE;
+        if unsafe { libc::inotify_add_watch(fd, dir.as_ptr(), mask) } < 0 {
+            return None;
+        }
+
+        let (changes, receiver) = mpsc::channel();
+        // Ends at the first change after the receiver is dropped
+        thread::spawn(move || watch_inotify(inotify, &prefix, &changes));
+
+        Some(receiver)
+    }
+
     type Error = std::io::Error;
+    type Listing = SourceListing;
+}
+
+/// The files in a directory of an [ExternalFS], see [BackingFS::list].
+#[derive(Debug)]
+pub struct SourceListing {
+    /// The directories being listed with their paths, the one listed right now last
+    dirs: Vec<(fs::ReadDir, PathBuf)>,
+    /// Whether subdirectories are listed too, see [ExternalFS::with_nested]
+    nested: bool,
+}
+
+impl Iterator for SourceListing {
+    type Item = OsString;
+
+    fn next(&mut self) -> Option<OsString> {
+        while let Some((entries, prefix)) = self.dirs.last_mut() {
+            let Some(entry) = entries.by_ref().flatten().next() else {
+                self.dirs.pop();
+                continue;
+            };
+            let Ok(kind) = entry.file_type() else { continue };
+            let path = prefix.join(entry.file_name());
+            if kind.is_file() {
+                return Some(path.into_os_string());
+            }
+
+            let hidden = entry.file_name().as_bytes().starts_with(b".");
+            if self.nested && kind.is_dir() && !hidden {
+                if let Ok(subdir) = fs::read_dir(entry.path()) {
+                    self.dirs.push((subdir, path));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// Send the files of `prefix` named by the events of `inotify` over `changes`, until it fails or
+/// nobody listens anymore.
+fn watch_inotify(mut inotify: File, prefix: &Path, changes: &Sender<OsString>) {
+    const HEADER: usize = mem::size_of::<libc::inotify_event>();
+    // Enough for at least one event with the longest name
+    let mut buf = [0u8; 4096];
+    loop {
+        let read = match inotify.read(&mut buf) {
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(_) => return,
+        };
+
+        let mut pos = 0;
+        while pos + HEADER <= read {
+            let event = buf[pos..].as_ptr().cast::<libc::inotify_event>();
+            let event = unsafe { ptr::read_unaligned(event) };
+            let name = &buf[pos + HEADER..min(read, pos + HEADER + event.len as usize)];
+            // Names are padded with nulls
+            let name = name.split(|&b| b == 0).next().unwrap_or_default();
+            pos += HEADER + event.len as usize;
+
+            let changed = prefix.join(OsStr::from_bytes(name)).into_os_string();
+            if !name.is_empty() && changes.send(changed).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Call an xattr syscall that fills a buffer, first asking it for the size of the buffer.
+fn read_xattr(call: impl Fn(*mut c_void, usize) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let size = call(ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let read = call(buf.as_mut_ptr().cast(), buf.len());
+        if read >= 0 {
+            buf.truncate(read as usize);
+            return Ok(buf);
+        }
+
+        // The value grew in between, try again
+        let error = io::Error::last_os_error();
+        if error.raw_os_error() != Some(libc::ERANGE) {
+            return Err(error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_a_few_workers_between_hung_operations() {
+        let source = std::env::temp_dir().join(format!("tagfs-hung-{}", std::process::id()));
+        fs::create_dir_all(&source).unwrap();
+        // Opening a fifo hangs until its other end is opened
+        let fifo = source.join("fifo");
+        let path = CString::new(fifo.clone().into_os_string().into_vec()).unwrap();
+        assert_eq!(unsafe { libc::mkfifo(path.as_ptr(), 0o600) }, 0);
+        let backing = ExternalFS::new(&source).with_timeout(Some(Duration::from_millis(20)));
+
+        for _ in 0..DEADLINE_WORKERS {
+            let error = backing.open("fifo").unwrap_err();
+            assert_eq!(error.kind(), ErrorKind::TimedOut);
+        }
+        // Every worker is stuck, so this waits for one instead of starting another
+        assert!(backing.get_metadata("").is_err());
+
+        // Once the source answers again, so do the workers
+        let writer = OpenOptions::new()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&fifo)
+            .unwrap();
+        let answered = (0..100).any(|_| {
+            thread::sleep(Duration::from_millis(10));
+            backing.get_metadata("").is_ok()
+        });
+        assert!(answered);
+
+        drop(writer);
+        fs::remove_dir_all(&source).unwrap();
+    }
 }
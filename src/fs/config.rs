@@ -0,0 +1,581 @@
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+pub const DAY: u64 = 24 * 60 * 60;
+
+/// Mount-time options changing how the tag hierarchy is presented.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Decorate tag directories with the number of files they lead to, e.g. `music (1482)`
+    pub show_counts: bool,
+    /// How far back `/@recent` looks for changes in tags
+    pub recent: Duration,
+    /// How many of the last scans that discovered files make up `/@new`
+    pub new_scans: u64,
+    /// How files are ordered in tags without their own sort order
+    pub sort: SortOrder,
+    /// How tag directories are ordered in listings
+    pub tag_order: TagOrder,
+    /// Ownership and permissions of the directories tagfs presents
+    pub dirs: DirAttrs,
+    /// Translation of the uids of the source to those presented by the mount
+    pub uid_map: Vec<IdMap>,
+    /// Translation of the gids of the source to those presented by the mount
+    pub gid_map: Vec<IdMap>,
+    /// A snapshot is mounted: changes are refused and nothing is saved
+    pub read_only: bool,
+    /// Other mounts use the same savefile: saves are merged with theirs, and their changes are
+    /// picked up this often
+    pub shared: Option<Duration>,
+    /// Files left out of the index, e.g. partial downloads
+    pub exclude: Vec<Glob>,
+    /// Files in subdirectories of the source are indexed too, by their path relative to it, and
+    /// shown by their basename
+    pub nested: bool,
+    /// Smaller files are left out of the index
+    pub min_size: Option<u64>,
+    /// Larger files are left out of the index
+    pub max_size: Option<u64>,
+    /// Only files of these types are indexed, all files when empty
+    pub types: Vec<MediaType>,
+    /// The tag newly found files get, until they're sorted out
+    pub inbox: Option<OsString>,
+    /// A shell command told about files being tagged and untagged, on stdin
+    pub hook: Option<String>,
+    /// Where files being tagged and untagged are posted to
+    pub webhook: Option<Webhook>,
+    /// Only changes of these tags are reported to the hooks, all when empty
+    pub hook_tags: Vec<OsString>,
+    /// How the kernel caches the data of files read through the mount
+    pub file_cache: FileCache,
+    /// How tag names are compared when looking tags up and creating them
+    pub tag_case: TagCase,
+    /// Files gone from the source are matched to new ones of the same size and mtime, when no
+    /// other file has both
+    pub match_renames: bool,
+    /// Files matching a rule are tagged with its tag whenever the source is scanned
+    pub tag_rules: Vec<TagRule>,
+    /// Newly found files get the tags other tools stored in their extended attributes
+    pub import_xattr_tags: bool,
+    /// What the root of the mount lists besides the virtual directories
+    pub root: RootListing,
+    /// The tags and queries listed at the root when it's curated
+    pub root_entries: Vec<RootEntry>,
+    /// The only tags that may be created and applied, besides those tagfs applies itself. Any
+    /// tag may when `None`
+    pub vocabulary: Option<Vec<OsString>>,
+}
+
+impl Config {
+    pub fn map_uid(&self, uid: u32) -> u32 {
+        IdMap::apply(&self.uid_map, uid)
+    }
+
+    pub fn map_gid(&self, gid: u32) -> u32 {
+        IdMap::apply(&self.gid_map, gid)
+    }
+
+    /// Whether the file `name` of the source is left out of the index, going by its name.
+    pub fn is_excluded(&self, name: &OsStr) -> bool {
+        let name = name.to_string_lossy();
+        let typed = self.types.is_empty() || self.types.contains(&MediaType::of(&name));
+
+        !typed || self.exclude.iter().any(|glob| glob.matches(&name))
+    }
+
+    /// Whether files are left out of the index by their size, see [Config::admits_size].
+    pub fn filters_size(&self) -> bool {
+        self.min_size.is_some() || self.max_size.is_some()
+    }
+
+    pub fn admits_size(&self, size: u64) -> bool {
+        self.min_size.map(|min| size >= min).unwrap_or(true)
+            && self.max_size.map(|max| size <= max).unwrap_or(true)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            show_counts: false,
+            recent: Duration::from_secs(7 * DAY),
+            new_scans: 1,
+            sort: SortOrder::default(),
+            tag_order: TagOrder::default(),
+            dirs: DirAttrs::default(),
+            uid_map: vec![],
+            gid_map: vec![],
+            read_only: false,
+            shared: None,
+            exclude: vec![],
+            nested: false,
+            min_size: None,
+            max_size: None,
+            types: vec![],
+            inbox: None,
+            hook: None,
+            webhook: None,
+            hook_tags: vec![],
+            file_cache: FileCache::default(),
+            tag_case: TagCase::default(),
+            match_renames: false,
+            tag_rules: vec![],
+            import_xattr_tags: false,
+            root: RootListing::default(),
+            root_entries: vec![],
+            vocabulary: None,
+        }
+    }
+}
+
+/// Ownership and permissions of tag and virtual directories, normally taken from the source dir.
+#[derive(Debug, Clone, Copy)]
+pub struct DirAttrs {
+    pub uid: u32,
+    pub gid: u32,
+    pub perm: u16,
+}
+
+impl Default for DirAttrs {
+    fn default() -> Self {
+        Self {
+            uid: 1000,
+            gid: 1000,
+            perm: 0o700,
+        }
+    }
+}
+
+/// The order in which the files of a directory are listed, after any manually pinned files.
+#[derive(Serialize, Deserialize, ValueEnum, Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum SortOrder {
+    #[default]
+    Name,
+    /// Last modified first
+    Mtime,
+    /// Largest first
+    Size,
+    /// Shuffled again every time the directory is opened
+    Random,
+}
+
+impl SortOrder {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SortOrder::Name => "name",
+            SortOrder::Mtime => "mtime",
+            SortOrder::Size => "size",
+            SortOrder::Random => "random",
+        }
+    }
+}
+
+/// The order in which the tag directories of a directory are listed, after any pinned tags at
+/// the root. File managers sorting by name reorder them anyway.
+#[derive(ValueEnum, Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum TagOrder {
+    /// In no particular order
+    #[default]
+    Any,
+    /// Most recently used first: listed, or a file added to or removed from the tag. Tags never
+    /// used come last
+    Recent,
+}
+
+/// How the kernel caches the data of files opened through the mount.
+#[derive(ValueEnum, Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum FileCache {
+    /// Cache reads, but drop the cached data when a file is opened again, in case it changed
+    #[default]
+    Revalidate,
+    /// Keep the cached data across opens, for media that doesn't change once it's written. Changes
+    /// made to the source directly may be missed until the cache is evicted
+    Keep,
+    /// Bypass the page cache, e.g. for databases that do their own caching
+    Direct,
+}
+
+/// What the root of the mount lists, besides its virtual directories. Roots of tens of thousands
+/// of files bury the tags.
+#[derive(ValueEnum, Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum RootListing {
+    /// Only the tags
+    Tags,
+    /// The tags, and the files without any tag
+    Untagged,
+    /// The tags and every file
+    #[default]
+    All,
+    /// Only the tags and queries given with --root-entry
+    Curated,
+}
+
+/// How tag names are compared, so tags named by tools that capitalise differently are the same.
+#[derive(ValueEnum, Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum TagCase {
+    /// `Music` and `music` are different tags
+    #[default]
+    Sensitive,
+    /// Tags differing only in the case of ASCII letters are the same
+    Insensitive,
+    /// Tags differing only in case are the same, for letters of any script, e.g. `ÉTÉ` and `été`
+    Fold,
+}
+
+impl TagCase {
+    /// Whether the tag names `a` and `b` name the same tag.
+    pub fn matches(self, a: &OsStr, b: &OsStr) -> bool {
+        match (self, a.to_str(), b.to_str()) {
+            (TagCase::Sensitive, _, _) => a == b,
+            (TagCase::Fold, Some(a), Some(b)) => folded(a).eq(folded(b)),
+            _ => a.as_encoded_bytes().eq_ignore_ascii_case(b.as_encoded_bytes()),
+        }
+    }
+}
+
+/// The lowercase letters of `name`, with the final sigma as any other sigma.
+fn folded(name: &str) -> impl Iterator<Item = char> + '_ {
+    name.chars()
+        .flat_map(char::to_lowercase)
+        .map(|c| if c == 'ς' { 'σ' } else { c })
+}
+
+/// The broad kind of a file, like the top level of its MIME type, told by its extension.
+#[derive(ValueEnum, Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MediaType {
+    Video,
+    Audio,
+    Image,
+    /// Plain text, like notes and subtitles
+    Text,
+    /// Documents and books, like PDFs and ebooks
+    Document,
+    Archive,
+    /// Anything else
+    Other,
+}
+
+impl MediaType {
+    pub fn of(name: &str) -> MediaType {
+        let extension = name.rsplit_once('.').map(|(_, ext)| ext.to_lowercase());
+        match extension.as_deref().unwrap_or_default() {
+            "mp4" | "mkv" | "avi" | "mov" | "webm" | "m4v" | "wmv" | "flv" | "mpg" | "mpeg"
+            | "ts" | "3gp" => MediaType::Video,
+            "mp3" | "flac" | "ogg" | "opus" | "wav" | "m4a" | "aac" | "wma" | "aiff" | "ape" => {
+                MediaType::Audio
+            }
+            "jpg" | "jpeg" | "png" | "gif" | "webp" | "bmp" | "tif" | "tiff" | "heic" | "heif"
+            | "avif" | "svg" | "raw" | "cr2" | "nef" | "arw" | "dng" => MediaType::Image,
+            "txt" | "md" | "csv" | "log" | "json" | "xml" | "yaml" | "yml" | "toml" | "html"
+            | "htm" | "srt" => MediaType::Text,
+            "pdf" | "epub" | "mobi" | "djvu" | "cbz" | "cbr" | "doc" | "docx" | "odt" | "rtf"
+            | "xls" | "xlsx" | "ods" | "ppt" | "pptx" | "odp" => MediaType::Document,
+            "zip" | "tar" | "gz" | "bz2" | "xz" | "zst" | "7z" | "rar" | "iso" => {
+                MediaType::Archive
+            }
+            _ => MediaType::Other,
+        }
+    }
+}
+
+/// An exclusive tag group as given on the command line, e.g. `status:{unread,reading,done}`.
+#[derive(Debug, Clone)]
+pub struct GroupSpec {
+    pub name: String,
+    pub tags: Vec<String>,
+}
+
+impl FromStr for GroupSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (name, tags) = spec
+            .split_once(':')
+            .ok_or_else(|| format!("expected <group>:{{<tag>,...}}, got '{spec}'"))?;
+        let tags = tags
+            .strip_prefix('{')
+            .and_then(|tags| tags.strip_suffix('}'))
+            .unwrap_or(tags);
+
+        let tags = tags
+            .split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        if name.is_empty() || tags.is_empty() {
+            return Err(format!("group '{spec}' needs a name and at least one tag"));
+        }
+
+        Ok(GroupSpec {
+            name: name.to_string(),
+            tags,
+        })
+    }
+}
+
+/// Tagging by file name as given on the command line, e.g. `music=*.flac,*.mp3`.
+#[derive(Debug, Clone)]
+pub struct TagRule {
+    pub tag: String,
+    pub patterns: Vec<Glob>,
+}
+
+impl TagRule {
+    pub fn matches(&self, name: &OsStr) -> bool {
+        let name = name.to_string_lossy();
+        self.patterns.iter().any(|glob| glob.matches(&name))
+    }
+}
+
+impl FromStr for TagRule {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (tag, patterns) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("expected <tag>=<glob>,..., got '{spec}'"))?;
+
+        let patterns = patterns
+            .split(',')
+            .map(str::trim)
+            .filter(|pattern| !pattern.is_empty())
+            .map(Glob::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+        if tag.is_empty() || patterns.is_empty() {
+            return Err(format!("rule '{spec}' needs a tag and at least one pattern"));
+        }
+
+        Ok(TagRule {
+            tag: tag.to_string(),
+            patterns,
+        })
+    }
+}
+
+/// A tag or query listed at the root of a curated mount, e.g. `music` or `rock=music/rock`.
+/// Queries are listed as symlinks to their directory, named after the tags unless named.
+#[derive(Debug, Clone)]
+pub struct RootEntry {
+    pub name: OsString,
+    pub path: Vec<OsString>,
+}
+
+impl RootEntry {
+    /// Whether the entry is a tag listed under its own name, rather than a symlink.
+    pub fn is_tag(&self) -> bool {
+        self.path.len() == 1 && self.path[0] == self.name
+    }
+}
+
+impl FromStr for RootEntry {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (name, path) = match spec.split_once('=') {
+            Some((name, path)) => (Some(name), path),
+            None => (None, spec),
+        };
+
+        let path = path
+            .split('/')
+            .filter(|tag| !tag.is_empty())
+            .map(OsString::from)
+            .collect::<Vec<_>>();
+        if path.is_empty() || name.is_some_and(|name| name.is_empty() || name.contains('/')) {
+            return Err(format!("expected [<name>=]<tag>[/<tag>...], got '{spec}'"));
+        }
+        let name = match name {
+            Some(name) => name.into(),
+            None => path.join(OsStr::new("+")),
+        };
+
+        Ok(RootEntry { name, path })
+    }
+}
+
+/// A shell-style pattern for file names, e.g. `*.part`: `*` matches any characters but `/`, `**`
+/// any characters at all and `?` a single character other than `/`.
+#[derive(Debug, Clone)]
+pub struct Glob(Vec<char>);
+
+impl Glob {
+    pub fn matches(&self, name: &str) -> bool {
+        glob_matches(&self.0, &name.chars().collect::<Vec<_>>())
+    }
+}
+
+impl FromStr for Glob {
+    type Err = String;
+
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        if pattern.is_empty() {
+            return Err("the pattern is empty".to_string());
+        }
+
+        Ok(Glob(pattern.chars().collect()))
+    }
+}
+
+fn glob_matches(pattern: &[char], name: &[char]) -> bool {
+    match pattern {
+        [] => name.is_empty(),
+        ['*', '*', rest @ ..] => (0..=name.len()).any(|skip| glob_matches(rest, &name[skip..])),
+        ['*', rest @ ..] => (0..=name.len())
+            .take_while(|&skip| skip == 0 || name[skip - 1] != '/')
+            .any(|skip| glob_matches(rest, &name[skip..])),
+        ['?', rest @ ..] => matches!(name, [c, ..] if *c != '/') && glob_matches(rest, &name[1..]),
+        [c, rest @ ..] => name.first() == Some(c) && glob_matches(rest, &name[1..]),
+    }
+}
+
+/// An `http://` URL that tag events are posted to. There's no TLS, so the receiver should be
+/// local, like a home automation server.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    pub host: String,
+    pub port: u16,
+    pub path: String,
+}
+
+impl Display for Webhook {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+impl FromStr for Webhook {
+    type Err = String;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("'{url}' isn't an http:// URL, https isn't supported"))?;
+        let (authority, path) = match rest.find('/') {
+            Some(idx) => rest.split_at(idx),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.rsplit_once(':') {
+            Some((host, port)) => {
+                let port = port.parse().map_err(|_| format!("'{url}' has an invalid port"))?;
+                (host, port)
+            }
+            None => (authority, 80),
+        };
+        if host.is_empty() {
+            return Err(format!("'{url}' has no host"));
+        }
+
+        Ok(Webhook {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+/// Ids reported for source ids outside of every mapped range, like the kernel's overflow id.
+pub const OVERFLOW_ID: u32 = 65534;
+
+/// A range of `count` ids starting at `source` in the source, shown as starting at `mount` through
+/// the mount, e.g. `0:100000:65536` like a user namespace mapping.
+#[derive(Debug, Clone, Copy)]
+pub struct IdMap {
+    pub source: u32,
+    pub mount: u32,
+    pub count: u32,
+}
+
+impl IdMap {
+    /// Translate a source id through `maps`. Without any maps, ids are left alone.
+    pub fn apply(maps: &[IdMap], id: u32) -> u32 {
+        if maps.is_empty() {
+            return id;
+        }
+
+        maps.iter()
+            .find(|map| id >= map.source && id - map.source < map.count)
+            .map(|map| map.mount + (id - map.source))
+            .unwrap_or(OVERFLOW_ID)
+    }
+}
+
+impl FromStr for IdMap {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let parts = spec
+            .split(':')
+            .map(|part| part.parse::<u32>())
+            .collect::<Result<Vec<_>, _>>();
+
+        match parts.as_deref() {
+            Ok(&[source, mount, count]) if mount.checked_add(count).is_some() => Ok(IdMap {
+                source,
+                mount,
+                count,
+            }),
+            _ => Err(format!(
+                "expected <source id>:<mount id>:<count>, got '{spec}'"
+            )),
+        }
+    }
+}
+
+/// Parse octal permission bits, e.g. `750`.
+pub fn parse_mode(mode: &str) -> Result<u16, String> {
+    u16::from_str_radix(mode, 8)
+        .ok()
+        .filter(|&mode| mode <= 0o777)
+        .ok_or_else(|| format!("'{mode}' isn't an octal mode like 700"))
+}
+
+/// The tags declared in a `--vocabulary` file.
+#[derive(Debug, Clone)]
+pub struct Vocabulary(pub Vec<String>);
+
+/// Read a vocabulary file: a tag per line, skipping blank lines and lines starting with `#`.
+pub fn read_vocabulary(path: &str) -> Result<Vocabulary, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("can't read '{path}': {e}"))?;
+    let tags = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect();
+
+    Ok(Vocabulary(tags))
+}
+
+/// Parse a size in bytes, with an optional binary suffix, e.g. `1M` for 1 MiB.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let split = size.find(|c: char| !c.is_ascii_digit()).unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let shift = match unit.to_ascii_uppercase().trim_end_matches(['B', 'I']) {
+        "" => 0,
+        "K" => 10,
+        "M" => 20,
+        "G" => 30,
+        "T" => 40,
+        _ => return Err(format!("'{size}' isn't a size like 500K or 1M")),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(1 << shift))
+        .ok_or_else(|| format!("'{size}' isn't a size like 500K or 1M"))
+}
+
+/// Strip a member count decoration, as added when [Config::show_counts] is set, from a tag name.
+pub fn strip_count(name: &str) -> Option<&str> {
+    let (name, count) = name.strip_suffix(')')?.rsplit_once(" (")?;
+
+    count.bytes().all(|b| b.is_ascii_digit()).then_some(name)
+}
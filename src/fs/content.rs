@@ -0,0 +1,177 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::time::SystemTime;
+
+use bincode::serde::Compat;
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+
+use crate::file::FileNumber;
+use crate::fs::backing::BackingFS;
+use crate::fs::profile::state_file;
+use crate::fs::tag::TagFS;
+
+/// The content index, next to the savefile in the source dir.
+pub const CONTENT_INDEX: &str = ".tagfs-index";
+
+/// Larger files are left out of the content index.
+const MAX_INDEXED: u64 = 16 * 1024 * 1024;
+
+/// The words in the contents of the files, for `/@search`. Only plain text files are indexed.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct ContentIndex {
+    words: HashMap<String, HashSet<FileNumber>>,
+    /// The mtime and size of every file when it was read, so unchanged files aren't read again
+    indexed: HashMap<FileNumber, (SystemTime, u64)>,
+}
+
+impl ContentIndex {
+    /// Whether `file` contains every word of `terms`.
+    pub fn matches(&self, file: FileNumber, terms: &str) -> bool {
+        words(terms).all(|word| {
+            self.words
+                .get(&word)
+                .map(|files| files.contains(&file))
+                .unwrap_or(false)
+        })
+    }
+
+    /// The files containing every word of `terms`.
+    pub fn search(&self, terms: &str) -> HashSet<FileNumber> {
+        let mut found: Option<HashSet<FileNumber>> = None;
+        for word in words(terms) {
+            let files = self.words.get(&word).cloned().unwrap_or_default();
+            found = Some(match found {
+                Some(found) => found.intersection(&files).copied().collect(),
+                None => files,
+            });
+        }
+
+        found.unwrap_or_default()
+    }
+
+    fn insert(&mut self, file: FileNumber, text: &str) {
+        for word in words(text) {
+            self.words.entry(word).or_default().insert(file);
+        }
+    }
+
+    fn remove(&mut self, file: FileNumber) {
+        self.indexed.remove(&file);
+        self.words.retain(|_, files| {
+            files.remove(&file);
+            !files.is_empty()
+        });
+    }
+}
+
+/// The lowercase words of a text, as they're indexed and searched.
+pub fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// The search terms of a `/@search` directory name as they're matched, `None` if it has no words.
+pub fn search_terms(name: &str) -> Option<String> {
+    let terms = words(name).collect::<Vec<_>>();
+
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+impl<B> TagFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    /// Read the content index kept next to the savefile, if there is one.
+    pub fn load_content_index(&mut self) -> anyhow::Result<()> {
+        let handle = self.backing().open(state_file(CONTENT_INDEX))?;
+        let index = self.backing().read(handle, 0, u64::MAX);
+        self.backing().release(handle);
+
+        let (Compat(index), _): (Compat<ContentIndex>, _) =
+            bincode::decode_from_slice(&index?, bincode::config::standard())?;
+        *self.content_index_mut() = index;
+
+        Ok(())
+    }
+
+    /// Bring the content index up to date with the source and save it, returning how many
+    /// files were read. Files that didn't change since they were indexed are skipped.
+    pub fn index_content(&mut self) -> anyhow::Result<usize> {
+        let gone = self
+            .content_index()
+            .indexed
+            .keys()
+            .copied()
+            .filter(|file| self.get_fnm_by_number(*file).is_none())
+            .collect::<Vec<_>>();
+        for file in gone {
+            self.content_index_mut().remove(file);
+        }
+
+        let files = self
+            .files()
+            .left_values()
+            .filter_map(|&file| Some((file, self.source_name(file)?.to_os_string())))
+            .collect::<Vec<_>>();
+        let mut read = 0;
+        for (file, name) in files {
+            let Ok(attr) = self.backing().get_metadata(&name) else {
+                continue;
+            };
+            let version = (attr.mtime, attr.size);
+            if self.content_index().indexed.get(&file) == Some(&version) {
+                continue;
+            }
+
+            self.content_index_mut().remove(file);
+            if attr.size <= MAX_INDEXED {
+                let content = self.backing().open(&name).and_then(|handle| {
+                    let content = self.backing().read(handle, 0, attr.size);
+                    self.backing().release(handle);
+                    content
+                });
+
+                // Binary files, and text in other encodings, are left out
+                match content.map(String::from_utf8) {
+                    Ok(Ok(text)) if !text.contains('\0') => {
+                        self.content_index_mut().insert(file, &text)
+                    }
+                    Ok(_) => {}
+                    Err(error) => debug!("can't index '{}': {error}", name.to_string_lossy()),
+                }
+            }
+            self.content_index_mut().indexed.insert(file, version);
+            read += 1;
+        }
+
+        let index =
+            bincode::encode_to_vec(Compat(self.content_index()), bincode::config::standard())?;
+        let handle = self.backing().create(state_file(CONTENT_INDEX))?;
+        let written = self.backing().write(handle, &index);
+        self.backing().release(handle);
+        written?;
+
+        info!("indexed the contents of {read} file(s)");
+        Ok(read)
+    }
+
+    /// The names of the files containing every word of `terms`, one per line.
+    pub fn search_report(&self, terms: &str) -> String {
+        let mut names = self
+            .content_index()
+            .search(terms)
+            .into_iter()
+            .filter_map(|file| self.get_fnm_by_number(file))
+            .map(|name| name.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        names.sort();
+
+        names
+            .into_iter()
+            .flat_map(|name| [name, "\n".to_string()])
+            .collect()
+    }
+}
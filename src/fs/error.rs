@@ -0,0 +1,74 @@
+use std::ffi::OsString;
+
+use libc::{c_int, EBUSY, EINVAL, EIO, ENOENT, ENOTEMPTY, EPERM, EROFS};
+use thiserror::Error;
+
+/// Errors from tag operations that are reachable from both FUSE callbacks and the control API.
+#[derive(Error, Debug)]
+pub enum TagError {
+    #[error("no such tag '{}'", .0.to_string_lossy())]
+    NoSuchTag(OsString),
+    #[error("no such file '{}'", .0.to_string_lossy())]
+    NoSuchFile(OsString),
+    #[error("tag '{}' isn't in the vocabulary", .0.to_string_lossy())]
+    Undeclared(OsString),
+    #[error("tag '{}' is locked", .0.to_string_lossy())]
+    Locked(OsString),
+    #[error("file '{}' isn't tagged '{}'", .0.to_string_lossy(), .1.to_string_lossy())]
+    NotTagged(OsString, OsString),
+    #[error("tag '{}' still has {1} member(s)", .0.to_string_lossy())]
+    NotEmpty(OsString, usize),
+    #[error("cannot move the members of tag '{}' into itself", .0.to_string_lossy())]
+    SelfMove(OsString),
+    #[error("metadata key '{0}' can't be edited")]
+    ReadOnlyKey(String),
+    /// A line of a mapping file that can't be applied. Only the reason is given, not the line:
+    /// the file may not be meant to be read back
+    #[error("line {0}: {1}")]
+    Mapping(usize, &'static str),
+    #[error("an inode split of {0} bits is unsupported or too small for the indexed files and tags")]
+    InoSplit(u32),
+    #[error("invalid command: {0}")]
+    Command(String),
+    #[error("{0}")]
+    Transaction(&'static str),
+    #[error("another handle of the control file has a transaction open")]
+    Busy,
+    #[error("the mount is frozen until a thaw")]
+    Frozen,
+    #[error("commands that take a path only run from the command line")]
+    TakesPath,
+    #[error("{0}")]
+    History(&'static str),
+    #[error("no such snapshot '{0}'")]
+    NoSuchSnapshot(String),
+    #[error("'{0}' can't be used as a snapshot name")]
+    SnapshotName(String),
+    #[error("the source failed: {0}")]
+    Source(String),
+    #[error("the other savefile can't be read: {0}")]
+    SyncState(String),
+    #[error("the backup can't be read: {0}")]
+    Backup(String),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+impl TagError {
+    /// The errno to reply with when this error surfaces through FUSE.
+    pub fn errno(&self) -> c_int {
+        match self {
+            TagError::NoSuchTag(_) | TagError::NoSuchFile(_) | TagError::NotTagged(..) => ENOENT,
+            TagError::NotEmpty(..) => ENOTEMPTY,
+            TagError::Undeclared(_) | TagError::Locked(_) | TagError::TakesPath => EPERM,
+            TagError::SelfMove(_) | TagError::ReadOnlyKey(_) | TagError::Mapping(..) => EINVAL,
+            TagError::InoSplit(_) | TagError::Command(_) | TagError::Transaction(_) => EINVAL,
+            TagError::Busy => EBUSY,
+            TagError::Frozen => EROFS,
+            TagError::History(_) | TagError::SnapshotName(_) => EINVAL,
+            TagError::NoSuchSnapshot(_) => ENOENT,
+            TagError::Source(_) | TagError::SyncState(_) | TagError::Backup(_) => EIO,
+            TagError::Io(error) => error.raw_os_error().unwrap_or(EIO),
+        }
+    }
+}
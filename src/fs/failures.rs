@@ -0,0 +1,107 @@
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt::Display;
+use std::time::{Instant, SystemTime};
+
+use log::error;
+
+use crate::fs::backing::BackingFS;
+use crate::fs::profile::state_file;
+use crate::fs::tag::TagFS;
+use crate::logging::REPEAT_WINDOW;
+
+/// The append-only log of failures, next to the savefile in the source dir.
+pub const ERROR_LOG: &str = ".tagfs-errors";
+
+/// How many failures `/.tagfs/errors` remembers.
+const RECENT_ERRORS: usize = 100;
+
+/// The most recent failures of this mount, oldest first.
+#[derive(Debug, Default)]
+pub struct RecentErrors {
+    lines: VecDeque<String>,
+    /// When each failure was last recorded, and how often it repeated since
+    repeats: HashMap<String, (Instant, u64)>,
+}
+
+impl RecentErrors {
+    fn push(&mut self, line: String) {
+        if self.lines.len() == RECENT_ERRORS {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+
+    /// Whether `failure` is worth recording again, with how often it repeated since it last was.
+    /// Failures recorded within [REPEAT_WINDOW] are only counted, like in the log.
+    fn record_repeat(&mut self, failure: &str) -> Option<u64> {
+        let now = Instant::now();
+        if self.repeats.len() > RECENT_ERRORS {
+            self.repeats.retain(|_, (at, _)| now.duration_since(*at) < REPEAT_WINDOW);
+        }
+
+        match self.repeats.get_mut(failure) {
+            Some((at, held)) if now.duration_since(*at) < REPEAT_WINDOW => {
+                *held += 1;
+                None
+            }
+            Some((at, held)) => {
+                *at = now;
+                Some(std::mem::take(held))
+            }
+            None => {
+                self.repeats.insert(failure.to_string(), (now, 0));
+                Some(0)
+            }
+        }
+    }
+
+    /// The failures as lines of text.
+    pub fn report(&self) -> String {
+        self.lines.iter().flat_map(|line| [line.as_str(), "\n"]).collect()
+    }
+}
+
+impl<B> TagFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    /// Remember a failure that no caller can report, as a tab separated line of the time, what
+    /// was being done and the error. It's kept for `/.tagfs/errors` and appended to the error log.
+    /// A failure repeating within a minute is only counted, e.g. a file manager retrying a read.
+    pub fn record_error(&mut self, what: &str, error: &dyn Display) {
+        error!("{what}: {error}");
+
+        let Some(held) = self.recent_errors_mut().record_repeat(&format!("{what}\t{error}")) else {
+            return;
+        };
+        let repeated = match held {
+            0 => String::new(),
+            held => format!(" (and {held} more time(s) before)"),
+        };
+        let line = format!(
+            "{}\t{what}\t{error}{repeated}",
+            humantime::format_rfc3339_seconds(SystemTime::now())
+        );
+        let appended = self.backing().append(state_file(ERROR_LOG), format!("{line}\n").as_bytes());
+        if let Err(error) = appended {
+            error!("failed to write the error log: {error}");
+        }
+
+        self.recent_errors_mut().push(line);
+    }
+
+    /// Save, recording a failure for callers that have already replied to the kernel. Nothing
+    /// is saved while a transaction is open, it's saved once committed, nor for a mounted
+    /// snapshot.
+    pub fn save_or_record(&mut self) {
+        if self.holds_changes() {
+            return;
+        }
+        match self.save() {
+            Ok(()) => self.fire_hooks(),
+            Err(error) => self.record_error("save", &error),
+        }
+    }
+}
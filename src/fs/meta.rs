@@ -0,0 +1,238 @@
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::file::FileNumber;
+use crate::fs::backing::SourceId;
+use crate::fs::config::SortOrder;
+
+/// Prefix under which tag metadata is exposed as extended attributes on tag directories.
+pub const XATTR_PREFIX: &str = "user.tagfs.";
+
+/// Whether an extended attribute of indexed files is read from the backing file. These are the
+/// ones tools like `ls`, backup software and SELinux rely on.
+pub fn is_passthrough_xattr(name: &OsStr) -> bool {
+    name == "system.posix_acl_access" || name.as_bytes().starts_with(b"security.")
+}
+
+/// Bookkeeping about a single indexed file.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FileMeta {
+    /// The last time a tag was added to or removed from the file
+    pub changed_at: Option<SystemTime>,
+    /// When the file was first indexed
+    pub indexed_at: Option<SystemTime>,
+    /// The number of the scan that discovered the file, see `TagFS::repopulate`
+    pub scan: u64,
+    /// The file was missing from the last scan. It keeps its tags, but isn't listed until it's back
+    pub offline: bool,
+    /// The identity of the file in the source, to recognise it after a rename
+    pub source: Option<SourceId>,
+    /// The attributes of the file as of the last scan
+    pub snapshot: Option<FileSnapshot>,
+    /// The name of the file in the source, when it isn't normalized like the name it's indexed by
+    pub source_name: Option<OsString>,
+    /// The number the file is shown with among the files of its basename in a nested source, 1
+    /// for the basename itself and 2 for `a (2).flac`, so it keeps its name as others come and go
+    pub shown_as: Option<u32>,
+}
+
+/// The layout of [FileMeta] in savefiles before version 3, which didn't keep
+/// [FileMeta::shown_as].
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct FileMetaV2 {
+    pub changed_at: Option<SystemTime>,
+    pub indexed_at: Option<SystemTime>,
+    pub scan: u64,
+    pub offline: bool,
+    pub source: Option<SourceId>,
+    pub snapshot: Option<FileSnapshot>,
+    pub source_name: Option<OsString>,
+}
+
+impl From<FileMetaV2> for FileMeta {
+    fn from(meta: FileMetaV2) -> Self {
+        FileMeta {
+            changed_at: meta.changed_at,
+            indexed_at: meta.indexed_at,
+            scan: meta.scan,
+            offline: meta.offline,
+            source: meta.source,
+            snapshot: meta.snapshot,
+            source_name: meta.source_name,
+            shown_as: None,
+        }
+    }
+}
+
+/// What a file looked like when it was last scanned, shown while the source is unreachable.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct FileSnapshot {
+    pub size: u64,
+    pub mtime: SystemTime,
+    pub perm: u16,
+}
+
+/// Where to place a file in the manual order of a tag.
+#[derive(Copy, Clone, Debug)]
+pub enum Position {
+    Before(FileNumber),
+    After(FileNumber),
+    End,
+}
+
+/// Presentational metadata attached to a single tag, for GUIs built on top of tagfs.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct TagMeta {
+    pub color: Option<String>,
+    pub icon: Option<String>,
+    pub description: Option<String>,
+    pub created_at: Option<SystemTime>,
+    /// The last time a file was added to or removed from the tag
+    pub modified_at: Option<SystemTime>,
+    /// The last time the tag directory was listed
+    pub entered_at: Option<SystemTime>,
+    /// Hidden tags are left out of directory listings, but can still be looked up
+    pub hidden: bool,
+    /// Locked tags can't be changed through the mount: no renames, unlinks or mkdirs below them
+    pub locked: bool,
+    /// Pinned tags are listed first at the root, by this rank, like the favorites of a file manager
+    pub pinned: Option<u32>,
+    /// Tags in the same group are mutually exclusive: a file carries at most one of them
+    pub group: Option<String>,
+    /// Files pinned to an explicit position, listed before all other files of the tag
+    pub order: Vec<FileNumber>,
+    /// Overrides the default sort order for the files of the tag
+    pub sort: Option<SortOrder>,
+    /// Files leave the tag this long after they were added to it
+    pub ttl: Option<Duration>,
+    /// The uid the owner bits of `mode` apply to
+    pub owner: Option<u32>,
+    /// Unix permission bits restricting who can enter the tag, e.g. `0o700` for a private tag.
+    /// Only the read bits are checked, see [TagMeta::may_read]
+    pub mode: Option<u16>,
+    /// Any other user-defined keys
+    pub extra: BTreeMap<String, String>,
+}
+
+impl TagMeta {
+    pub fn created_now() -> Self {
+        Self {
+            created_at: Some(SystemTime::now()),
+            ..Default::default()
+        }
+    }
+
+    /// Set `key` to `value`, or clear it when `value` is `None`.
+    /// Returns false if the key can't be edited.
+    pub fn set(&mut self, key: &str, value: Option<String>) -> bool {
+        match key {
+            "color" => self.color = value,
+            "icon" => self.icon = value,
+            "description" => self.description = value,
+            "created-at" | "modified-at" | "entered-at" | "hidden" | "locked" | "pinned"
+            | "group" | "sort" | "ttl" | "owner" | "mode" => return false,
+            _ => match value {
+                Some(value) => {
+                    self.extra.insert(key.to_string(), value);
+                }
+                None => {
+                    self.extra.remove(key);
+                }
+            },
+        }
+
+        true
+    }
+
+    /// Pin `file` at `position` in the manual order, pinning the anchor at the end if it wasn't yet.
+    pub fn place(&mut self, file: FileNumber, position: Position) {
+        self.order.retain(|&f| f != file);
+
+        let anchor = match position {
+            Position::End => {
+                self.order.push(file);
+                return;
+            }
+            Position::Before(anchor) | Position::After(anchor) => anchor,
+        };
+
+        let idx = match self.order.iter().position(|&f| f == anchor) {
+            Some(idx) => idx,
+            None => {
+                self.order.push(anchor);
+                self.order.len() - 1
+            }
+        };
+
+        match position {
+            Position::After(_) => self.order.insert(idx + 1, file),
+            _ => self.order.insert(idx, file),
+        }
+    }
+
+    /// The last time the tag was used: its directory listed, or a file added to or removed from it.
+    pub fn used_at(&self) -> Option<SystemTime> {
+        self.entered_at.max(self.modified_at)
+    }
+
+    /// Whether `uid` may enter the tag according to its mode: the owner bits apply to the owner,
+    /// the group bits to members of the group of the tag, as `in_group` tells, and the other bits
+    /// to everyone else. Root always may.
+    pub fn may_read(&self, uid: u32, in_group: impl FnOnce() -> bool) -> bool {
+        let Some(mode) = self.mode else { return true };
+
+        let bits = if uid == 0 {
+            return true;
+        } else if Some(uid) == self.owner {
+            mode >> 6
+        } else if in_group() {
+            mode >> 3
+        } else {
+            mode
+        };
+
+        bits & 0o4 != 0
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.entries()
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v)
+    }
+
+    /// All keys that are set, with timestamps rendered as seconds since the epoch.
+    pub fn entries(&self) -> Vec<(String, String)> {
+        let secs = |t: SystemTime| {
+            t.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+                .to_string()
+        };
+
+        [
+            ("color", self.color.clone()),
+            ("icon", self.icon.clone()),
+            ("description", self.description.clone()),
+            ("created-at", self.created_at.map(secs)),
+            ("modified-at", self.modified_at.map(secs)),
+            ("entered-at", self.entered_at.map(secs)),
+            ("hidden", self.hidden.then(|| "true".to_string())),
+            ("locked", self.locked.then(|| "true".to_string())),
+            ("pinned", self.pinned.map(|rank| rank.to_string())),
+            ("group", self.group.clone()),
+            ("sort", self.sort.map(|sort| sort.name().to_string())),
+            ("ttl", self.ttl.map(|ttl| humantime::format_duration(ttl).to_string())),
+            ("owner", self.owner.map(|owner| owner.to_string())),
+            ("mode", self.mode.map(|mode| format!("{mode:o}"))),
+        ]
+        .into_iter()
+        .filter_map(|(k, v)| v.map(|v| (k.to_string(), v)))
+        .chain(self.extra.clone())
+        .collect()
+    }
+}
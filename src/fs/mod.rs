@@ -1,7 +1,19 @@
 use serde::{Deserialize, Serialize};
 
+pub mod audit;
 pub mod backing;
+pub mod config;
+pub mod content;
+pub mod error;
+pub mod failures;
+pub mod meta;
+pub mod profile;
+pub mod range;
+pub mod retry;
+pub mod stats;
+pub mod suggest;
 pub mod tag;
+pub mod virt;
 
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Debug)]
 pub struct FileHandle(pub u64);
@@ -0,0 +1,39 @@
+use std::sync::OnceLock;
+
+/// The savefile of the index, in the root of the source.
+pub const SAVEFILE: &str = ".tagfs";
+
+/// The profile whose state is used, see [set_profile].
+static PROFILE: OnceLock<String> = OnceLock::new();
+
+/// Keep the state under the profile `name`, apart from that of other profiles over the same
+/// source: the savefile, logs, snapshots, content index and checksums are named with `.<name>`
+/// appended.
+/// Has to be set before any state is read, and only once.
+pub fn set_profile(name: &str) -> anyhow::Result<()> {
+    let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if name.is_empty() || !name.chars().all(valid) {
+        anyhow::bail!("profile names are letters, digits, '-' and '_', not '{name}'");
+    }
+    if PROFILE.set(name.to_string()).is_err() {
+        anyhow::bail!("the profile was already set");
+    }
+
+    Ok(())
+}
+
+/// The state file `base` of the current profile, e.g. `.tagfs.photos` for the savefile of the
+/// `photos` profile.
+pub fn state_file(base: &str) -> String {
+    match PROFILE.get() {
+        Some(profile) => format!("{base}.{profile}"),
+        None => base.to_string(),
+    }
+}
+
+/// Whether `name` is the state file `base` of any profile.
+pub fn is_state_file(name: &str, base: &str) -> bool {
+    name.strip_prefix(base)
+        .map(|rest| rest.is_empty() || rest.starts_with('.'))
+        .unwrap_or(false)
+}
@@ -0,0 +1,117 @@
+use std::ffi::OsStr;
+use std::hash::{Hash, Hasher};
+use std::ops::{Bound, RangeBounds};
+
+use crate::fs::config::TagCase;
+
+/// The number of a value tag, like the `2015` of `year=2015`. Never NaN nor negative zero, so
+/// numbers that compare equal also hash alike.
+#[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+struct Number(f64);
+
+impl Number {
+    fn parse(text: &str) -> Option<Self> {
+        let number = text.parse::<f64>().ok().filter(|number| number.is_finite())?;
+
+        // Adding zero turns a negative zero into zero
+        Some(Number(number + 0.0))
+    }
+}
+
+impl Eq for Number {}
+
+impl Hash for Number {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.to_bits().hash(state)
+    }
+}
+
+/// The directory name of a range over value tags, which are tags named `<key>=<number>`. Files
+/// with a value tag of the key whose number is in the range are in the directory.
+///
+/// Ranges are written as `year>=2015`, `year>2015`, `year<=2015`, `year<2015` or as
+/// `rating=3..5`, which includes both ends, either of which can be left out.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct ValueRange {
+    key: String,
+    min: Bound<Number>,
+    max: Bound<Number>,
+}
+
+impl ValueRange {
+    /// The range named `name`, `None` if it isn't one.
+    pub fn parse(name: &str) -> Option<Self> {
+        let (key, min, max) = if let Some((key, value)) = name.split_once(">=") {
+            (key, Bound::Included(Number::parse(value)?), Bound::Unbounded)
+        } else if let Some((key, value)) = name.split_once("<=") {
+            (key, Bound::Unbounded, Bound::Included(Number::parse(value)?))
+        } else if let Some((key, value)) = name.split_once('>') {
+            (key, Bound::Excluded(Number::parse(value)?), Bound::Unbounded)
+        } else if let Some((key, value)) = name.split_once('<') {
+            (key, Bound::Unbounded, Bound::Excluded(Number::parse(value)?))
+        } else {
+            let (key, span) = name.split_once('=')?;
+            let (low, high) = span.split_once("..")?;
+            let end = |value: &str| match value {
+                "" => Some(Bound::Unbounded),
+                value => Number::parse(value).map(Bound::Included),
+            };
+            (key, end(low)?, end(high)?)
+        };
+
+        let open = min == Bound::Unbounded && max == Bound::Unbounded;
+        if key.is_empty() || key.contains(['=', '<', '>']) || open {
+            return None;
+        }
+
+        Some(ValueRange { key: key.to_string(), min, max })
+    }
+
+    /// Whether `tag` names a value tag of the key with a number in the range, comparing keys as
+    /// set by `case`.
+    pub fn admits(&self, tag: &OsStr, case: TagCase) -> bool {
+        let Some((key, value)) = tag.to_str().and_then(|tag| tag.split_once('=')) else {
+            return false;
+        };
+
+        case.matches(OsStr::new(key), OsStr::new(&self.key))
+            && Number::parse(value).is_some_and(|number| (self.min, self.max).contains(&number))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether the range named `name` admits the value tag `tag`.
+    fn admits(name: &str, tag: &str) -> bool {
+        ValueRange::parse(name).unwrap().admits(OsStr::new(tag), TagCase::Sensitive)
+    }
+
+    #[test]
+    fn parses_ranges_over_value_tags() {
+        assert!(admits("year>=2015", "year=2015") && !admits("year>2015", "year=2015"));
+        assert!(admits("year<=2015", "year=2015") && !admits("year<2015", "year=2015"));
+        assert!(admits("rating=3..5", "rating=3") && admits("rating=3..5", "rating=5"));
+        assert!(!admits("rating=3..5", "rating=5.5") && admits("rating=3..", "rating=10"));
+        assert!(admits("rating=..5", "rating=-1") && admits("gain<0", "gain=-0.5"));
+
+        // Only tags of the key with a number count, `-0` is just 0
+        assert!(!admits("year>=2015", "month=2016") && !admits("year>=2015", "year=soon"));
+        assert!(!admits("year>=2015", "year") && !admits("year>=2015", "year=inf"));
+        assert_eq!(ValueRange::parse("gain<=-0"), ValueRange::parse("gain<=0"));
+
+        // Names that aren't ranges are left to be tags
+        let names = ["year", "year=2015", "=3..5", "year>=", "year=.."];
+        for name in names.into_iter().chain(["a=b>=3", "x>=NaN", "y=1..z"]) {
+            assert_eq!(ValueRange::parse(name), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn compares_keys_as_tag_names() {
+        let range = ValueRange::parse("Year>=2015").unwrap();
+        assert!(!range.admits(OsStr::new("year=2016"), TagCase::Sensitive));
+        assert!(range.admits(OsStr::new("year=2016"), TagCase::Insensitive));
+    }
+}
@@ -0,0 +1,208 @@
+use std::collections::hash_map::RandomState;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
+
+use fuser::FileAttr;
+use libc::{EAGAIN, EINTR};
+use log::debug;
+
+use crate::fs::backing::{BackingFS, Capabilities, SourceId, Stat};
+use crate::fs::FileHandle;
+
+/// How often and how patiently to retry operations that failed for a transient reason.
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt, 0 disables retrying
+    pub retries: u32,
+    /// The delay before the first retry, doubled for every next one
+    pub base: Duration,
+    /// The longest delay between attempts
+    pub max: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            base: Duration::from_millis(50),
+            max: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retry number `retry`, counting from 0. Randomly shortened by up to half,
+    /// so concurrent retries don't hit the source in lockstep.
+    fn delay(&self, retry: u32) -> Duration {
+        let delay = self.base.saturating_mul(1 << retry.min(16)).min(self.max);
+        let jitter = RandomState::new().build_hasher().finish() % 1000;
+
+        delay / 2 + delay / 2 * jitter as u32 / 1000
+    }
+}
+
+/// Wraps a backing fs, retrying idempotent operations that fail with EAGAIN, EINTR or a timeout.
+/// Writes are passed through as is, since they might have partially happened.
+#[derive(Debug)]
+pub struct RetryingFS<B> {
+    inner: B,
+    policy: RetryPolicy,
+}
+
+impl<B> RetryingFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + 'static,
+{
+    pub fn new(inner: B, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+
+    fn retry<T>(&self, mut op: impl FnMut(&B) -> Result<T, B::Error>) -> Result<T, B::Error> {
+        let mut retry = 0;
+        loop {
+            match op(&self.inner) {
+                Err(e) if retry < self.policy.retries && is_transient(&e) => {
+                    let delay = self.policy.delay(retry);
+                    debug!("retrying in {delay:?} after transient backing error: {e}");
+
+                    thread::sleep(delay);
+                    retry += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+fn is_transient(error: &(dyn Error + 'static)) -> bool {
+    let Some(error) = error.downcast_ref::<io::Error>() else { return false };
+
+    matches!(
+        error.kind(),
+        ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut
+    ) || matches!(error.raw_os_error(), Some(EAGAIN | EINTR))
+}
+
+impl<B> BackingFS for RetryingFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + 'static,
+{
+    fn get_metadata<P: AsRef<Path>>(&self, path: P) -> Result<FileAttr, Self::Error> {
+        self.retry(|inner| inner.get_metadata(path.as_ref()))
+    }
+
+    fn source_id<P: AsRef<Path>>(&self, path: P) -> Result<SourceId, Self::Error> {
+        self.retry(|inner| inner.source_id(path.as_ref()))
+    }
+
+    fn open<P: AsRef<Path>>(&self, path: P) -> Result<FileHandle, Self::Error> {
+        self.retry(|inner| inner.open(path.as_ref()))
+    }
+
+    fn create<P: AsRef<Path>>(&self, path: P) -> Result<FileHandle, Self::Error> {
+        self.inner.create(path)
+    }
+
+    fn read(&self, handle: FileHandle, offset: u64, size: u64) -> Result<Vec<u8>, Self::Error> {
+        self.retry(|inner| inner.read(handle, offset, size))
+    }
+
+    fn read_into(
+        &self,
+        handle: FileHandle,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> Result<usize, Self::Error> {
+        self.retry(|inner| inner.read_into(handle, offset, buf))
+    }
+
+    fn write(&self, handle: FileHandle, data: &[u8]) -> Result<(), Self::Error> {
+        self.inner.write(handle, data)
+    }
+
+    fn append<P: AsRef<Path>>(&self, path: P, data: &[u8]) -> Result<(), Self::Error> {
+        self.inner.append(path, data)
+    }
+
+    fn lock<P: AsRef<Path>>(&self, path: P) -> Result<FileHandle, Self::Error> {
+        self.retry(|inner| inner.lock(path.as_ref()))
+    }
+
+    fn release(&self, handle: FileHandle) {
+        self.inner.release(handle)
+    }
+
+    fn getxattr<P: AsRef<Path>>(&self, path: P, name: &OsStr) -> Result<Vec<u8>, Self::Error> {
+        self.retry(|inner| inner.getxattr(path.as_ref(), name))
+    }
+
+    fn listxattr<P: AsRef<Path>>(&self, path: P) -> Result<Vec<OsString>, Self::Error> {
+        self.retry(|inner| inner.listxattr(path.as_ref()))
+    }
+
+    fn list<P: AsRef<Path>>(&self, prefix: P) -> Result<Self::Listing, Self::Error> {
+        self.retry(|inner| inner.list(prefix.as_ref()))
+    }
+
+    fn exists<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.inner.exists(path)
+    }
+
+    fn watch<P: AsRef<Path>>(&self, prefix: P) -> Option<Receiver<OsString>> {
+        self.inner.watch(prefix)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.inner.capabilities()
+    }
+
+    fn get_metadata_many<P: AsRef<Path>>(
+        &self,
+        paths: &[P],
+    ) -> Vec<Result<FileAttr, Self::Error>> {
+        // Only the paths that failed are retried, one by one
+        let attrs = self.inner.get_metadata_many(paths).into_iter().zip(paths);
+
+        attrs
+            .map(|(attr, path)| match attr {
+                Err(e) if is_transient(&e) => self.get_metadata(path),
+                attr => attr,
+            })
+            .collect()
+    }
+
+    fn source_ids<P: AsRef<Path>>(&self, paths: &[P]) -> Vec<Result<SourceId, Self::Error>> {
+        let ids = self.inner.source_ids(paths).into_iter().zip(paths);
+
+        ids.map(|(id, path)| match id {
+            Err(e) if is_transient(&e) => self.source_id(path),
+            id => id,
+        })
+        .collect()
+    }
+
+    fn stat_all<P: AsRef<Path>>(&self, paths: &[P]) -> Vec<Result<Stat, Self::Error>> {
+        let stats = self.inner.stat_all(paths).into_iter().zip(paths);
+
+        stats
+            .map(|(stat, path)| match stat {
+                Err(e) if is_transient(&e) => {
+                    self.retry(|inner| inner.stat_all(&[path]).pop().expect("a stat per path"))
+                }
+                stat => stat,
+            })
+            .collect()
+    }
+
+    type Error = B::Error;
+    type Listing = B::Listing;
+}
@@ -0,0 +1,207 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::file::{FileNumber, TagNumber};
+use crate::fs::tag::TagFS;
+
+/// How many tag pairs to list in the co-occurrence report.
+const TOP_PAIRS: usize = 10;
+
+/// What's worked out from the tags and their members for reports and listings, kept until they
+/// change: the mutators forget it when they note a tag for the journal, see [TagFS::begin_step].
+#[derive(Debug, Default)]
+pub struct Derived {
+    /// [TagFS::tag_stats]
+    pub(crate) tag_stats: Option<String>,
+    /// The number of files tagged with all of some tags, by the sorted tags, see `--show-counts`
+    pub(crate) counts: HashMap<Vec<TagNumber>, usize>,
+}
+
+impl<B> TagFS<B> {
+    /// A short summary of the size of the index.
+    pub fn stats(&self) -> String {
+        format!(
+            "files\t{}\ntags\t{}\n",
+            self.files().len(),
+            self.tags().len()
+        )
+    }
+
+    /// Member count and last membership change per tag, followed by the tag pairs that are
+    /// most often applied together. Comparing every pair of tags takes a while, so the report is
+    /// kept until the tags change.
+    pub fn tag_stats(&self) -> String {
+        if let Some(stats) = &self.derived().tag_stats {
+            return stats.clone();
+        }
+
+        let stats = self.count_tag_stats();
+        self.derived().tag_stats = Some(stats.clone());
+
+        stats
+    }
+
+    fn count_tag_stats(&self) -> String {
+        let mut tags = self.tags().iter().collect::<Vec<_>>();
+        tags.sort_by_key(|&(_, name)| name);
+
+        let mut out = String::from("tag\tmembers\tmodified\n");
+        for (&tag, name) in tags.iter() {
+            let members = self.members(tag).map(|set| set.len()).unwrap_or(0);
+            let modified = self
+                .tag_meta(tag)
+                .and_then(|meta| meta.modified_at)
+                .map(|t| humantime::format_rfc3339_seconds(t).to_string())
+                .unwrap_or_else(|| "never".to_string());
+
+            let _ = writeln!(out, "{}\t{members}\t{modified}", name.to_string_lossy());
+        }
+
+        let mut pairs = Vec::new();
+        for (i, &(&a, a_name)) in tags.iter().enumerate() {
+            for &(&b, b_name) in &tags[i + 1..] {
+                let (Some(a), Some(b)) = (self.members(a), self.members(b)) else {
+                    continue;
+                };
+
+                let shared = a.intersection(b).count();
+                if shared > 0 {
+                    pairs.push((shared, a_name, b_name));
+                }
+            }
+        }
+        pairs.sort_by_key(|&(shared, ..)| Reverse(shared));
+
+        out.push_str("\ntag\ttag\tshared\n");
+        for (shared, a, b) in pairs.into_iter().take(TOP_PAIRS) {
+            let _ = writeln!(
+                out,
+                "{}\t{}\t{shared}",
+                a.to_string_lossy(),
+                b.to_string_lossy()
+            );
+        }
+
+        out
+    }
+
+    /// The size of the members of every tag, largest first: their total, and what's unique to the
+    /// tag, carried by no other tag, which is what removing its files would free. Sizes are those
+    /// of the last scan, so nothing is read from the source. In bytes, or readable units.
+    pub fn disk_usage(&self, bytes: bool) -> String {
+        let size = |file: FileNumber| {
+            let snapshot = self.file_meta(file).and_then(|meta| meta.snapshot);
+            snapshot.map(|snapshot| snapshot.size).unwrap_or(0)
+        };
+        let format = |size: u64| if bytes { size.to_string() } else { format_size(size) };
+
+        let mut carriers: HashMap<FileNumber, usize> = HashMap::new();
+        for &tag in self.tags().left_values() {
+            for &file in self.members(tag).into_iter().flatten() {
+                *carriers.entry(file).or_default() += 1;
+            }
+        }
+
+        let mut tags = self
+            .tags()
+            .iter()
+            .map(|(&tag, name)| {
+                let members = self.members(tag).into_iter().flatten().copied();
+                let (mut total, mut unique, mut count) = (0, 0, 0);
+                for file in members {
+                    total += size(file);
+                    if carriers.get(&file) == Some(&1) {
+                        unique += size(file);
+                    }
+                    count += 1;
+                }
+                (total, unique, count, name)
+            })
+            .collect::<Vec<_>>();
+        tags.sort_by_key(|&(total, _, _, name)| (Reverse(total), name));
+
+        let mut out = String::from("tag\ttotal\tunique\tfiles\n");
+        for (total, unique, count, name) in tags {
+            let _ = writeln!(
+                out,
+                "{}\t{}\t{}\t{count}",
+                name.to_string_lossy(),
+                format(total),
+                format(unique)
+            );
+        }
+
+        // Files with several tags are only counted once here
+        let tagged = carriers.keys().map(|&file| size(file)).sum();
+        let _ = writeln!(out, "\ntagged\t{}\t\t{}", format(tagged), carriers.len());
+
+        out
+    }
+
+    /// The tags as a Graphviz DOT graph, see `ControlCommand::Graph`. Nodes grow with the member
+    /// count of their tag, edges with the number of files the tags share, if at least
+    /// `min_shared`.
+    pub fn tag_graph(&self, min_shared: usize) -> String {
+        let mut tags = self.tags().iter().collect::<Vec<_>>();
+        tags.sort_by_key(|&(_, name)| name);
+        let members = |tag| self.members(tag).map(|set| set.len()).unwrap_or(0);
+
+        let mut out = String::from("graph tags {\n  node [shape=circle];\n");
+        let largest = tags.iter().map(|&(&tag, _)| members(tag)).max().unwrap_or(0).max(1);
+        for (idx, &(&tag, name)) in tags.iter().enumerate() {
+            let count = members(tag);
+            // By area, so a tag with four times the files looks four times as big
+            let width = 0.5 + 1.5 * (count as f64 / largest as f64).sqrt();
+            let label = dot_escape(&format!("{} ({count})", name.to_string_lossy()));
+            let _ = writeln!(out, "  t{idx} [label=\"{label}\", width={width:.2}];");
+        }
+
+        let mut pairs = Vec::new();
+        for (i, &(&a, _)) in tags.iter().enumerate() {
+            for (j, &(&b, _)) in tags.iter().enumerate().skip(i + 1) {
+                let (Some(a), Some(b)) = (self.members(a), self.members(b)) else {
+                    continue;
+                };
+
+                let shared = a.intersection(b).count();
+                if shared > 0 && shared >= min_shared {
+                    pairs.push((i, j, shared));
+                }
+            }
+        }
+        let most = pairs.iter().map(|&(.., shared)| shared).max().unwrap_or(1);
+        for (a, b, shared) in pairs {
+            let pen = 1.0 + 4.0 * shared as f64 / most as f64;
+            let _ = writeln!(
+                out,
+                "  t{a} -- t{b} [label=\"{shared}\", weight={shared}, penwidth={pen:.2}];"
+            );
+        }
+
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// `text` quoted for a DOT string.
+fn dot_escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// `size` in bytes in the largest binary unit that keeps it at least 1, e.g. `1.5 GiB`.
+fn format_size(size: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut scaled = size as f64;
+    let mut unit = 0;
+    while scaled >= 1024.0 && unit < UNITS.len() - 1 {
+        scaled /= 1024.0;
+        unit += 1;
+    }
+
+    match unit {
+        0 => format!("{size} B"),
+        _ => format!("{scaled:.1} {}", UNITS[unit]),
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fmt::Write;
+
+use crate::file::{FileNumber, TagNumber};
+use crate::fs::tag::TagFS;
+
+/// How many tags `suggest` recommends unless asked otherwise.
+pub const SUGGESTIONS: usize = 5;
+
+impl<B> TagFS<B> {
+    /// Tags `file` doesn't carry yet, best first, scored between 0 and 1. Half the score comes
+    /// from how often each tag goes along with the tags the file has, the other half from the
+    /// tags of files with similar names.
+    pub fn suggest(&self, file: FileNumber, count: usize) -> Vec<(TagNumber, f64)> {
+        let own = self.tags_of(file).into_iter().collect::<HashSet<_>>();
+        let candidates = self
+            .tags()
+            .left_values()
+            .copied()
+            .filter(|tag| !own.contains(tag))
+            .filter(|&tag| !self.tag_meta(tag).map(|meta| meta.hidden).unwrap_or(false))
+            .collect::<Vec<_>>();
+
+        // The chance a file carrying one of its tags carries the candidate too, averaged
+        let mut scores = HashMap::<TagNumber, f64>::new();
+        for &tag in own.iter() {
+            let Some(members) = self.members(tag).filter(|set| !set.is_empty()) else {
+                continue;
+            };
+            for &candidate in candidates.iter() {
+                let shared = self
+                    .members(candidate)
+                    .map(|set| set.intersection(members).count())
+                    .unwrap_or(0);
+                let score = shared as f64 / members.len() as f64 / own.len() as f64;
+                *scores.entry(candidate).or_default() += score / 2.0;
+            }
+        }
+
+        // The candidates of files with similar names, weighted by how similar
+        let words = self.get_fnm_by_number(file).map(|name| name_words(name)).unwrap_or_default();
+        let similar = self
+            .files()
+            .iter()
+            .filter(|&(&other, _)| other != file)
+            .map(|(&other, name)| (other, similarity(&words, &name_words(name))))
+            .filter(|&(_, similarity)| similarity > 0.0)
+            .collect::<Vec<_>>();
+        let total = similar.iter().map(|&(_, similarity)| similarity).sum::<f64>();
+        for &candidate in candidates.iter() {
+            let Some(members) = self.members(candidate) else {
+                continue;
+            };
+            let weight = similar
+                .iter()
+                .filter(|(other, _)| members.contains(other))
+                .map(|&(_, similarity)| similarity)
+                .sum::<f64>();
+            if weight > 0.0 {
+                *scores.entry(candidate).or_default() += weight / total / 2.0;
+            }
+        }
+
+        let mut scores = scores
+            .into_iter()
+            .filter(|&(_, score)| score > 0.0)
+            .collect::<Vec<_>>();
+        scores.sort_by(|(a_tag, a), (b_tag, b)| b.total_cmp(a).then(a_tag.cmp(b_tag)));
+        scores.truncate(count);
+
+        scores
+    }
+
+    /// The suggestions for `file` as lines of tag and score.
+    pub fn suggest_report(&self, file: FileNumber, count: usize) -> String {
+        let mut out = String::new();
+        for (tag, score) in self.suggest(file, count) {
+            let name = self.tags().get_by_left(&tag).cloned().unwrap_or_default();
+            let _ = writeln!(out, "{}\t{score:.2}", name.to_string_lossy());
+        }
+
+        out
+    }
+}
+
+/// The lowercase words of a file name without its extension, e.g. `holiday` and `2019` for
+/// `Holiday_2019-03.jpg`. Words shorter than 3 characters say little about the file.
+fn name_words(name: &OsStr) -> HashSet<String> {
+    let name = name.to_string_lossy();
+    let stem = name.rsplit_once('.').map(|(stem, _)| stem).unwrap_or(&name);
+
+    stem.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= 3)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// The share of words two names have in common.
+fn similarity(a: &HashSet<String>, b: &HashSet<String>) -> f64 {
+    let shared = a.intersection(b).count();
+    if shared == 0 {
+        return 0.0;
+    }
+
+    shared as f64 / a.union(b).count() as f64
+}
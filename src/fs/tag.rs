@@ -1,30 +1,106 @@
-use std::cell::RefCell;
-use std::cmp::min;
-use std::collections::{HashMap, HashSet};
+use std::cell::{RefCell, RefMut};
+use std::cmp::{min, Reverse};
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::error::Error;
 use std::ffi::{OsStr, OsString};
 use std::fmt::{Debug, Formatter};
+use std::hash::{BuildHasher, Hash, Hasher};
 use std::iter;
+use std::os::unix::ffi::OsStrExt;
+use std::ptr;
 use std::rc::{Rc, Weak};
-use std::time::{Duration, UNIX_EPOCH};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use bimap::BiMap;
 use bincode::serde::Compat;
 use fuser::{
-    FileAttr, Filesystem, ReplyAttr, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen,
-    Request,
+    FileAttr, FileType, Filesystem, KernelConfig, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyDirectoryPlus, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyWrite, ReplyXattr,
+    Request, TimeOrNow,
 };
-use fuser::FileType::{Directory, RegularFile};
-use indexmap::IndexMap;
-use libc::{EIO, ENOENT, ENOTDIR, ENOTSUP};
-use log::{debug, error, warn};
+use fuser::consts::{
+    FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE, FOPEN_NONSEEKABLE, FUSE_DO_READDIRPLUS,
+    FUSE_READDIRPLUS_AUTO,
+};
+use fuser::FileType::{Directory, RegularFile, Symlink};
+use indexmap::{IndexMap, IndexSet};
+use libc::{
+    c_int, EACCES, EBADF, EBUSY, EEXIST, EINVAL, EIO, EISDIR, ELOOP, ENODATA, ENOENT, ENOSYS,
+    ENOTDIR, ENOTSUP, EPERM, ERANGE, EROFS, ESTALE, O_ACCMODE, O_APPEND, O_CREAT, O_DIRECTORY,
+    O_RDONLY, O_TRUNC, W_OK,
+};
+use log::{debug, error, info, warn};
 use serde::{Deserialize, Serialize};
 
-use crate::file::{FileNumber, Ino, TagNumber};
-use crate::fs::backing::BackingFS;
+use crate::control::ControlCommand;
+use crate::file::{nfc, FileNumber, Ino, Name, Split, TagNumber, DEFAULT_SPLIT};
+use crate::fs::audit::{command_detail, Actor, AUDIT_LOG};
+use crate::fs::backing::{BackingFS, SourceId, Stat};
+use crate::fs::config::{strip_count, Config, DirAttrs, FileCache, SortOrder, TagCase, TagOrder};
+use crate::fs::content::{search_terms, ContentIndex, CONTENT_INDEX};
+use crate::fs::error::TagError;
+use crate::fs::failures::{RecentErrors, ERROR_LOG};
+use crate::fs::meta::{
+    is_passthrough_xattr, FileMeta, FileMetaV2, FileSnapshot, Position, TagMeta, XATTR_PREFIX,
+};
+use crate::fs::profile::{is_state_file, state_file, SAVEFILE};
+use crate::fs::range::ValueRange;
+use crate::fs::stats::Derived;
+use crate::fs::virt::{
+    extension, PseudoTag, VirtualDir, VirtualFile, VirtualNode, PSEUDO_TAGS, VIRTUAL_HANDLE,
+};
 use crate::fs::FileHandle;
+use archive::Archive;
+use copy::PendingCopy;
+pub use duplicates::group_duplicates;
+use history::{Batch, History, Journal};
+use hooks::{HookWorker, TagEvent};
+use ephemeral::{is_ephemeral_name, SkipEphemeral};
+pub use integrity::spawn_verifier;
+use integrity::{Verifier, CHECKSUMS};
+use listing::DirListing;
+use scan::{Scan, ScanSummary};
+use shared::{SaveVersion, LOCK_FILE};
+use snapshot::SNAPSHOT_PREFIX;
+use stamps::Stamps;
+use trace::OpStats;
+
+mod archive;
+mod backup;
+mod copy;
+mod duplicates;
+mod ephemeral;
+mod expiry;
+mod health;
+mod history;
+mod hooks;
+mod import;
+mod integrity;
+mod listing;
+mod nested;
+mod reload;
+mod root;
+mod scan;
+mod shared;
+mod snapshot;
+mod stamps;
+mod sync;
+mod trace;
+mod vocabulary;
 
 const TTL: Duration = Duration::new(0, 0);
+/// How long the groups of a user are trusted before they're looked up again, see [TagFS::in_group]
+const GROUPS_TTL: Duration = Duration::from_secs(60);
+
+/// A reply to `readdirplus` fills a page of 4 KiB, which holds at most 25 entries with their
+/// attributes. Only that many are statted ahead.
+const PLUS_ENTRIES: usize = 25;
+
+/// At most this many directories made up from looked up names, like searches and completion
+/// prefixes, are kept; the least recently used are forgotten and made again when looked up.
+const TRANSIENT_DIRS: usize = 256;
 
 macro_rules! err {
     ($reply:expr, $err:expr) => {{
@@ -33,7 +109,34 @@ macro_rules! err {
     }};
 }
 
-type FileName = OsString;
+type FileName = Name;
+
+/// A directory listing entry: the inode, its kind and its name.
+type DirEntry = (Ino, FileType, FileName);
+
+/// The well-known favorites tag, always present at the root of a mount.
+pub const STARRED: &str = "@starred";
+
+/// Tags named `user:<name>` are private to the user that made them, and stored as
+/// `user:<uid>:<name>` so users can have private tags of the same name.
+pub const USER_PREFIX: &str = "user:";
+
+/// Renaming a file to `<name>#+<tag>#-<tag>` adds and removes those tags instead, for tools that
+/// can only rename.
+const DIRECTIVE: char = '#';
+
+/// Savefiles start with these bytes and the [STATE_VERSION] of their layout. Those of releases
+/// before the layout was versioned start right away with the index.
+const STATE_MAGIC: &[u8] = b"tagfs\0";
+
+/// The version of [PersistentState] savefiles are written in, raised whenever its layout
+/// changes so [decode_state] can still read the older ones. From version 2 on, the version is
+/// followed by how many times the savefile was written, see [read_saves]. Version 3 keeps the
+/// numbers files are shown with, see [FileMeta::shown_as].
+const STATE_VERSION: u32 = 3;
+
+/// The length of the header of savefiles: [STATE_MAGIC], [STATE_VERSION] and the saves.
+const STATE_HEADER: usize = STATE_MAGIC.len() + 4 + 8;
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Tag(pub OsString);
@@ -80,6 +183,15 @@ impl Debug for TagNode {
     }
 }
 
+/// A node of the tag tree as stored in the savefile, so directories keep their inodes across
+/// mounts. Parents are saved before their children.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct SavedNode {
+    ino_part: u64,
+    parent: u64,
+    tag: TagNumber,
+}
+
 #[derive(Debug)]
 pub struct TagTree {
     root: Rc<RefCell<TagNode>>,
@@ -110,17 +222,80 @@ impl TagTree {
         }
     }
 
+    /// Rebuild a saved tree, dropping nodes of tags that no longer exist. New numbers are handed
+    /// out after `last` and the saved nodes, so new tags don't collide with saved ones.
+    fn restore(
+        nodes: Vec<SavedNode>,
+        last: TagNumber,
+        exists: impl Fn(TagNumber) -> bool,
+    ) -> Self {
+        let mut tree = Self::new();
+        tree.counter = tree.counter.max(last);
+
+        for node in nodes {
+            // The parent was dropped, or the saved tree is damaged
+            let Some(parent) = tree.lookup(node.parent) else { continue };
+            if !exists(node.tag) || tree.lookup(node.ino_part).is_some() {
+                continue;
+            }
+
+            tree.attach(parent, node.ino_part, node.tag);
+            tree.counter = tree.counter.max(node.ino_part);
+        }
+
+        tree
+    }
+
+    /// The nodes to save, parents first. Pseudo tags are numbered anew on every mount, so nodes
+    /// below them aren't saved.
+    fn save(&self) -> Vec<SavedNode> {
+        fn walk(node: &TagNode, saved: &mut Vec<SavedNode>) {
+            for child in node.children.iter() {
+                let child = child.borrow();
+                if child.tag >= PSEUDO_TAGS {
+                    continue;
+                }
+
+                saved.push(SavedNode {
+                    ino_part: child.ino_part,
+                    parent: node.ino_part,
+                    tag: child.tag,
+                });
+                walk(&child, saved);
+            }
+        }
+
+        let mut saved = vec![];
+        walk(&self.root.borrow(), &mut saved);
+
+        saved
+    }
+
     fn lookup(&self, tag: u64) -> Option<Rc<RefCell<TagNode>>> {
         self.cache.get(&tag).and_then(|w| w.upgrade())
     }
 
-    fn add_to(&mut self, node: Rc<RefCell<TagNode>>, tag: TagNumber) -> Rc<RefCell<TagNode>> {
-        // Increase the ino counter by 1
+    /// Hand out a fresh inode tag part.
+    fn allocate(&mut self) -> u64 {
         self.counter += 1;
+        self.counter
+    }
 
+    fn add_to(&mut self, node: Rc<RefCell<TagNode>>, tag: TagNumber) -> Rc<RefCell<TagNode>> {
+        let ino_part = self.allocate();
+        self.attach(node, ino_part, tag)
+    }
+
+    /// Add a child representing `tag` to `node`, under a given inode tag part.
+    fn attach(
+        &mut self,
+        node: Rc<RefCell<TagNode>>,
+        ino_part: u64,
+        tag: TagNumber,
+    ) -> Rc<RefCell<TagNode>> {
         // Create the new node, referencing its parent
         let new = Rc::new(RefCell::new(TagNode {
-            ino_part: self.counter,
+            ino_part,
             tag,
             parent: Some(node.clone()),
             children: vec![],
@@ -131,7 +306,7 @@ impl TagTree {
         // Add the new node to its parent's children
         node.borrow_mut().children.push(new.clone());
 
-        self.cache.insert(self.counter, weak);
+        self.cache.insert(ino_part, weak);
 
         new
     }
@@ -156,10 +331,65 @@ impl TagTree {
 
         tnb
     }
+
+    /// Drop every node representing `tag`, together with the subtrees below them.
+    fn remove_tag(&mut self, tag: TagNumber) {
+        fn prune(node: &Rc<RefCell<TagNode>>, tag: TagNumber) {
+            let removed = {
+                let mut node = node.borrow_mut();
+                let (removed, kept) = node.children.drain(..).partition(|c| c.borrow().tag == tag);
+                node.children = kept;
+                removed
+            };
+            removed.iter().for_each(detach);
+
+            for child in node.borrow().children.iter() {
+                prune(child, tag);
+            }
+        }
+
+        // Children keep their parent alive, so subtrees have to be torn down explicitly
+        fn detach(node: &Rc<RefCell<TagNode>>) {
+            let children = std::mem::take(&mut node.borrow_mut().children);
+            children.iter().for_each(detach);
+            node.borrow_mut().parent = None;
+        }
+
+        prune(&self.root, tag);
+        self.cache.retain(|_, weak| weak.strong_count() > 0);
+    }
 }
 
+/// The state saved in savefiles. Savefiles of earlier versions are decoded with the [FileMeta]
+/// of their layout as `M`, see [decode_state].
 #[derive(Serialize, Deserialize, Debug)]
-pub struct PersistentState {
+pub struct PersistentState<M = FileMeta> {
+    #[serde(with = "indexmap::serde_seq")]
+    tag_content: IndexMap<TagNumber, HashSet<FileNumber>>,
+    files: BiMap<FileNumber, FileName>,
+    tags: BiMap<TagNumber, FileName>,
+    tag_meta: HashMap<TagNumber, TagMeta>,
+    file_meta: HashMap<FileNumber, M>,
+    file_tally: FileNumber,
+    /// The number of scans that discovered new files
+    scan_tally: u64,
+    /// The inode bits holding the tag part, see [crate::file::split]
+    ino_split: u32,
+    /// The visited directories, see [TagTree::save]
+    tree: Vec<SavedNode>,
+    /// The changes that can be undone, see [TagFS::undo]
+    history: History,
+    /// Snapshots taken of the tags and when, see [TagFS::create_snapshot]
+    snapshots: BTreeMap<String, SystemTime>,
+    /// When tags and memberships were added and removed, for merging copies, see [TagFS::sync]
+    stamps: Stamps,
+    /// When the memberships of tags with a TTL end, see [TagFS::expire]
+    expiry: HashMap<(TagNumber, FileNumber), SystemTime>,
+}
+
+/// The savefile layout of releases before the savefile was versioned, which saved only the index.
+#[derive(Deserialize)]
+struct LegacyState {
     #[serde(with = "indexmap::serde_seq")]
     tag_content: IndexMap<TagNumber, HashSet<FileNumber>>,
     files: BiMap<FileNumber, FileName>,
@@ -167,14 +397,197 @@ pub struct PersistentState {
     file_tally: FileNumber,
 }
 
+impl From<LegacyState> for PersistentState {
+    fn from(legacy: LegacyState) -> Self {
+        PersistentState {
+            tag_content: legacy.tag_content,
+            files: legacy.files,
+            tags: legacy.tags,
+            tag_meta: Default::default(),
+            file_meta: Default::default(),
+            file_tally: legacy.file_tally,
+            scan_tally: 0,
+            ino_split: DEFAULT_SPLIT,
+            tree: vec![],
+            history: Default::default(),
+            snapshots: Default::default(),
+            stamps: Default::default(),
+            expiry: Default::default(),
+        }
+    }
+}
+
+impl<M> PersistentState<M> {
+    /// The state with the metadata of every file converted by `convert`.
+    fn map_file_meta<N>(self, mut convert: impl FnMut(M) -> N) -> PersistentState<N> {
+        PersistentState {
+            tag_content: self.tag_content,
+            files: self.files,
+            tags: self.tags,
+            tag_meta: self.tag_meta,
+            file_meta: self.file_meta.into_iter().map(|(file, m)| (file, convert(m))).collect(),
+            file_tally: self.file_tally,
+            scan_tally: self.scan_tally,
+            ino_split: self.ino_split,
+            tree: self.tree,
+            history: self.history,
+            snapshots: self.snapshots,
+            stamps: self.stamps,
+            expiry: self.expiry,
+        }
+    }
+}
+
+/// A [PersistentState] borrowed from a [TagFS], so saving doesn't copy the index, and without
+/// the ephemeral tags, see [SkipEphemeral]. Both encode the same way, as long as their fields are
+/// in the same order.
+#[derive(Serialize)]
+struct SavedState<'a> {
+    tag_content: SkipEphemeral<'a, IndexMap<TagNumber, HashSet<FileNumber>>>,
+    files: &'a BiMap<FileNumber, FileName>,
+    tags: SkipEphemeral<'a, BiMap<TagNumber, FileName>>,
+    tag_meta: SkipEphemeral<'a, HashMap<TagNumber, TagMeta>>,
+    file_meta: &'a HashMap<FileNumber, FileMeta>,
+    file_tally: FileNumber,
+    scan_tally: u64,
+    ino_split: u32,
+    tree: Vec<SavedNode>,
+    history: SkipEphemeral<'a, History>,
+    snapshots: &'a BTreeMap<String, SystemTime>,
+    stamps: SkipEphemeral<'a, Stamps>,
+    expiry: SkipEphemeral<'a, HashMap<(TagNumber, FileNumber), SystemTime>>,
+}
+
+/// A directory made up from a looked up name, see [TRANSIENT_DIRS].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Transient {
+    Node(VirtualNode),
+    Pseudo(TagNumber),
+}
+
+/// What the source of a rename is, see `TagFS::rename_source`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Renamed {
+    Tag(TagNumber),
+    File(FileNumber),
+}
+
+/// A batch of control commands between `begin` and `commit`, see [TagFS::run_control]. What they
+/// change is noted in the journal, which rolling back puts back.
+#[derive(Debug)]
+struct Transaction {
+    /// The control file handle that began it, only it may change the state until it's done
+    handle: FileHandle,
+    /// Audit log entries of the commands, written once they're committed
+    audits: Vec<String>,
+}
+
+/// The index and tags of a mount, owned by the thread running the FUSE session. It isn't behind
+/// locks: the tag tree is built on `Rc` and the accessors hand out references into the maps, so
+/// finer grained locking wouldn't let other threads change it anyway. Work done on other threads,
+/// like scans, the search for duplicates and `verify`, is sent over channels instead and taken
+/// in as requests come in.
 #[derive(Debug)]
 pub struct TagFS<B> {
     backing: B,
     tree: TagTree,
     tag_content: IndexMap<TagNumber, HashSet<FileNumber>>,
     files: BiMap<FileNumber, FileName>,
+    /// The names files are shown by in the mount when their names are paths, see
+    /// [TagFS::shown_names]
+    shown: BiMap<FileNumber, FileName>,
+    /// The files of each basename, in the order they were indexed
+    by_basename: HashMap<FileName, Vec<FileNumber>>,
     tags: BiMap<TagNumber, FileName>,
+    tag_meta: HashMap<TagNumber, TagMeta>,
+    file_meta: HashMap<FileNumber, FileMeta>,
     file_tally: FileNumber,
+    /// The number of scans that discovered new files
+    scan_tally: u64,
+    config: Config,
+    /// Where inodes split into the file and tag parts, see [TagFS::set_ino_split]
+    split: Split,
+    /// Filters computed from the files, numbered from [PSEUDO_TAGS]
+    pseudo_tags: BiMap<TagNumber, PseudoTag>,
+    /// The number of pseudo tags handed out, forgotten ones included
+    pseudo_tally: u64,
+    /// Sets of files with identical content, filled by `find_duplicates`
+    duplicates: Vec<Vec<FileNumber>>,
+    /// The groups of identical files from a search in the background, see
+    /// [TagFS::find_duplicates_in_background]
+    duplicate_search: Option<Receiver<Vec<Vec<OsString>>>>,
+    /// Prefixes looked up in `/.tagfs/complete`, by the number handed out for them
+    completions: BiMap<u64, FileName>,
+    completion_tally: u64,
+    /// Directories made up from looked up names, least recently used first, see [TRANSIENT_DIRS]
+    transient: IndexSet<Transient>,
+    /// Directories generated at runtime, keyed by the inode tag part handed out for them
+    virtual_nodes: BiMap<u64, VirtualNode>,
+    /// Contents of open virtual files, generated when they were opened
+    virtual_handles: HashMap<FileHandle, Vec<u8>>,
+    /// Open archives of tags, see [Archive]
+    archives: HashMap<FileHandle, Archive>,
+    /// Handles of files being copied into a tag directory, see `create`. Their data is compared
+    /// with the file that's already there, and dropped
+    copies: HashMap<FileHandle, PendingCopy>,
+    /// Reused by every read from the source, so streaming doesn't allocate per block. Replies
+    /// are still copied out of it: splicing the source file to the kernel is left out, as fuser
+    /// 0.12 has no splice support
+    read_buffer: Vec<u8>,
+    handle_tally: u64,
+    /// Listings of open directories, taken when they were opened so offsets keep pointing at the
+    /// same entries while the tags change
+    dir_handles: HashMap<u64, DirListing>,
+    /// Reports of the last commands written to `/.tagfs/control`
+    control_report: String,
+    /// What was written to each handle of `/.tagfs/control` after its last newline
+    control_lines: HashMap<FileHandle, Vec<u8>>,
+    /// Failures that couldn't be reported to whoever caused them, see `TagFS::record_error`
+    errors: RecentErrors,
+    /// The words in the files, see [TagFS::index_content]
+    content: ContentIndex,
+    /// The open transaction of the control file, if any
+    transaction: Option<Transaction>,
+    /// What the change being made touched, while it's one that can be undone, see
+    /// [TagFS::begin_step]
+    journal: Option<Journal>,
+    /// How the batch of control commands being run moved the history, see [TagFS::begin_batch]
+    batch: Option<Batch>,
+    /// Reports and counts worked out from the tags, until they change
+    derived: RefCell<Derived>,
+    /// The groups of the users that entered tags with a mode, and when they were looked up, see
+    /// [TagFS::in_group]
+    user_groups: RefCell<HashMap<u32, (Instant, Vec<u32>)>>,
+    /// Hashes files for `verify` in the background, see [TagFS::set_verifier]
+    verifier: Option<Verifier>,
+    /// Whether the mount is frozen for maintenance, see [TagFS::freeze]
+    frozen: bool,
+    /// Tags that are never saved, see [TagFS::make_ephemeral]
+    ephemeral: HashSet<TagNumber>,
+    history: History,
+    snapshots: BTreeMap<String, SystemTime>,
+    stamps: Stamps,
+    expiry: HashMap<(TagNumber, FileNumber), SystemTime>,
+    /// When expired memberships were last removed, see [TagFS::expire_due]
+    expired_at: Option<Instant>,
+    /// Changes for the hooks since the last save, see [TagFS::fire_hooks]
+    events: Vec<TagEvent>,
+    hooks: HookWorker,
+    /// The requests made since tracing was turned on, see [TagFS::set_tracing]
+    traced: Option<BTreeMap<&'static str, OpStats>>,
+    /// The savefile as this mount last read or wrote it, to notice other mounts saving
+    saved_version: Option<SaveVersion>,
+    /// When the savefile was last checked for changes of other mounts, see
+    /// [TagFS::refresh_shared]
+    refreshed_at: Option<Instant>,
+    /// The scan of the source while it runs in the background, see [TagFS::scan_in_background]
+    scan: Option<Scan>,
+    /// When the last scan of the source finished and what it changed, see [TagFS::repopulate]
+    last_scan: Option<(SystemTime, ScanSummary)>,
+    /// When tag directories were listed since the last save. They're only moved into
+    /// [TagMeta::entered_at] when saving, so listing a directory isn't a change to undo and keeps
+    /// derived data
+    entered: HashMap<TagNumber, SystemTime>,
 }
 
 impl<B> TagFS<B> {
@@ -184,8 +597,54 @@ impl<B> TagFS<B> {
             tree: Default::default(),
             tag_content: Default::default(),
             files: Default::default(),
+            shown: Default::default(),
+            by_basename: Default::default(),
             tags: Default::default(),
+            tag_meta: Default::default(),
+            file_meta: Default::default(),
             file_tally: 1,
+            scan_tally: 0,
+            config: Default::default(),
+            split: Default::default(),
+            pseudo_tags: Default::default(),
+            pseudo_tally: 0,
+            duplicates: Default::default(),
+            duplicate_search: None,
+            completions: Default::default(),
+            completion_tally: 0,
+            transient: Default::default(),
+            virtual_nodes: Default::default(),
+            virtual_handles: Default::default(),
+            archives: Default::default(),
+            copies: Default::default(),
+            read_buffer: vec![],
+            handle_tally: 0,
+            dir_handles: Default::default(),
+            control_report: Default::default(),
+            control_lines: Default::default(),
+            errors: Default::default(),
+            content: Default::default(),
+            transaction: None,
+            journal: None,
+            batch: None,
+            derived: Default::default(),
+            user_groups: Default::default(),
+            verifier: None,
+            frozen: false,
+            ephemeral: Default::default(),
+            history: Default::default(),
+            snapshots: Default::default(),
+            stamps: Default::default(),
+            expiry: Default::default(),
+            expired_at: None,
+            events: vec![],
+            hooks: Default::default(),
+            traced: None,
+            saved_version: None,
+            refreshed_at: None,
+            scan: None,
+            last_scan: None,
+            entered: Default::default(),
         }
     }
 
@@ -194,505 +653,4260 @@ impl<B> TagFS<B> {
         B: BackingFS,
         <B as BackingFS>::Error: Error + Send + Sync + 'static,
     {
-        // Leverage the simple implementation of backingfs to read out the savefile
-        let handle = backing.open(".tagfs")?;
-        let savefile = backing.read(handle, 0, u64::MAX)?;
-        backing.release(handle);
-
-        let (
-            Compat(PersistentState {
-                tag_content,
-                tags,
-                files,
-                file_tally,
-            }),
-            _,
-        ): (Compat<PersistentState>, _) =
-            bincode::decode_from_slice(&savefile, bincode::config::standard())?;
+        let mut fs = Self::new_from_file(backing, &state_file(SAVEFILE))?;
+        fs.saved_version = fs.saved_version();
+        // Only there once the contents were indexed
+        if let Err(error) = fs.load_content_index() {
+            debug!("no content index: {error}");
+        }
 
-        Ok(TagFS {
-            backing,
-            tree: Default::default(),
+        Ok(fs)
+    }
+
+    /// Like [TagFS::new_from_save], from a savefile at another path, e.g. a snapshot.
+    fn new_from_file(backing: B, path: &str) -> anyhow::Result<TagFS<B>>
+    where
+        B: BackingFS,
+        <B as BackingFS>::Error: Error + Send + Sync + 'static,
+    {
+        let state = read_state(&backing, path)?;
+
+        let split = Split::new(state.ino_split).ok_or(TagError::InoSplit(state.ino_split))?;
+
+        let mut fs = TagFS::new(backing);
+        fs.split = split;
+        fs.restore_state(state);
+
+        Ok(fs)
+    }
+
+    /// The part of the state that's saved, see [TagFS::save].
+    #[cfg(test)]
+    fn persistent_state(&self) -> PersistentState {
+        PersistentState {
+            tag_content: self.tag_content.clone(),
+            files: self.files.clone(),
+            tags: self.tags.clone(),
+            tag_meta: self.tag_meta.clone(),
+            file_meta: self.file_meta.clone(),
+            file_tally: self.file_tally,
+            scan_tally: self.scan_tally,
+            ino_split: self.split.bits(),
+            tree: self.tree.save(),
+            history: self.history.clone(),
+            snapshots: self.snapshots.clone(),
+            stamps: self.stamps.clone(),
+            expiry: self.expiry.clone(),
+        }
+    }
+
+    /// The savefile encoding of the state, see [TagFS::save].
+    fn encode_state(&self) -> Result<Vec<u8>, bincode::error::EncodeError> {
+        let saves = self.saved_version.unwrap_or_default() + 1;
+        let ephemeral = self.ephemeral_tags();
+        let mut tree = self.tree.save();
+        ephemeral.forget_nodes(&mut tree);
+
+        let state = SavedState {
+            tag_content: ephemeral.skip(&self.tag_content),
+            files: &self.files,
+            tags: ephemeral.skip(&self.tags),
+            tag_meta: ephemeral.skip(&self.tag_meta),
+            file_meta: &self.file_meta,
+            file_tally: self.file_tally,
+            scan_tally: self.scan_tally,
+            ino_split: self.split.bits(),
+            tree,
+            history: ephemeral.skip(&self.history),
+            snapshots: &self.snapshots,
+            stamps: ephemeral.skip(&self.stamps),
+            expiry: ephemeral.skip(&self.expiry),
+        };
+
+        encode_versioned(state, saves)
+    }
+
+    /// Go back to a state from [TagFS::persistent_state]. The inode split is left alone, and
+    /// inode tag parts handed out since aren't handed out again.
+    fn restore_state(&mut self, state: PersistentState) {
+        let PersistentState {
             tag_content,
             files,
             tags,
+            tag_meta,
+            file_meta,
             file_tally,
-        })
+            scan_tally,
+            ino_split: _,
+            tree,
+            history,
+            snapshots,
+            stamps,
+            expiry,
+        } = state;
+
+        self.tag_content = tag_content;
+        self.files = files;
+        self.tags = tags;
+        self.forget_derived();
+        self.tag_meta = tag_meta;
+        self.file_meta = file_meta;
+        self.file_tally = file_tally;
+        self.scan_tally = scan_tally;
+        self.history = history;
+        self.snapshots = snapshots;
+        self.stamps = stamps;
+        self.expiry = expiry;
+        self.restore_tree(tree);
+        self.rebuild_shown();
+    }
+
+    /// Rebuild the tag tree from saved nodes, keeping those of the current tags.
+    fn restore_tree(&mut self, tree: Vec<SavedNode>) {
+        let counter = self.tree.counter;
+        self.tree = TagTree::restore(
+            tree,
+            self.tags.left_values().copied().max().unwrap_or_default(),
+            |tag| self.tags.contains_left(&tag),
+        );
+        self.tree.counter = self.tree.counter.max(counter);
     }
 
+    /// The file named `name`, in any Unicode normalization.
     pub fn get_fnb_by_name<N: AsRef<OsStr>>(&self, name: N) -> Option<FileNumber> {
-        self.files.get_by_right(name.as_ref()).copied()
+        self.files.get_by_right(&*nfc(name.as_ref())).copied()
     }
 
     pub fn get_fnm_by_number(&self, number: FileNumber) -> Option<&FileName> {
         self.files.get_by_left(&number)
     }
 
-    pub fn get_tnb_by_name<N: AsRef<OsStr>>(&self, name: N) -> Option<TagNumber> {
-        self.tags.get_by_right(name.as_ref()).copied()
+    /// The name of a file in the source, which differs from its name in the index when it isn't
+    /// in Unicode normalization form C, e.g. when it was created on macOS.
+    pub fn source_name(&self, number: FileNumber) -> Option<&OsStr> {
+        let name = self.files.get_by_left(&number)?;
+        let source = self.file_meta(number).and_then(|meta| meta.source_name.as_deref());
+
+        Some(source.unwrap_or(name))
     }
 
-    pub fn calculate_intersection(&self, path: &[TagNumber]) -> HashSet<FileNumber> {
-        if path.is_empty() {
-            return self.files.left_values().copied().collect();
+    /// The tag named `name` in any Unicode normalization, compared as set by [Config::tag_case].
+    pub fn get_tnb_by_name<N: AsRef<OsStr>>(&self, name: N) -> Option<TagNumber> {
+        let name = &*nfc(name.as_ref());
+        let exact = self.tags.get_by_right(name).copied();
+        match self.config.tag_case {
+            TagCase::Sensitive => exact,
+            // The lowest number wins between tags that only differed in case before
+            case => exact.or_else(|| {
+                self.tags
+                    .iter()
+                    .filter(|(_, other)| case.matches(name, other))
+                    .map(|(&tag, _)| tag)
+                    .min()
+            }),
         }
+    }
 
-        let sets = self
-            .tag_content
-            .iter()
-            .filter(|(tn, _)| path.contains(tn))
-            .map(|(_, set)| set)
-            .collect::<Vec<_>>();
+    /// How many files are tagged with all tags of `path`, for `--show-counts`. Counts are kept
+    /// until the tags change, except those with pseudo tags, which follow the files themselves.
+    fn count_members(&self, mut path: Vec<TagNumber>) -> usize {
+        path.sort();
+        if let Some(&count) = self.derived().counts.get(&path) {
+            return count;
+        }
 
-        let (start, sets) = sets.split_first().unwrap();
-        let mut result = (*start).clone();
-        for set in sets {
-            result = result.intersection(set).copied().collect()
+        let count = self.calculate_intersection(&path).len();
+        if !path.iter().any(|tag| self.pseudo_tags.contains_left(tag)) {
+            self.derived().counts.insert(path, count);
         }
 
-        result
+        count
     }
 
-    pub fn create_tag(&mut self, tag: FileName) -> TagNumber {
-        let tnb = self.tree.create_new();
+    /// Like [TagFS::get_tnb_by_name], but also accepts tag names as they're listed in directories.
+    /// Private tags of `uid` are found by their `user:<name>` name.
+    fn get_tnb_by_listed_name(&self, name: &OsStr, uid: u32) -> Option<TagNumber> {
+        let find = |name: &OsStr| {
+            private_name(name, uid)
+                .and_then(|private| self.get_tnb_by_name(private))
+                .or_else(|| self.get_tnb_by_name(name))
+        };
 
-        self.tag_content.insert(tnb, Default::default());
-        self.tags.insert(tnb, tag);
+        find(name).or_else(|| {
+            if !self.config.show_counts {
+                return None;
+            }
 
-        tnb
+            find(strip_count(name.to_str()?)?.as_ref())
+        })
     }
 
-    pub fn add_file(&mut self, file: FileName) -> FileNumber {
-        self.file_tally += 1;
-        let fnb = self.file_tally;
-        self.files.insert(fnb, file);
+    /// Name a tag as `uid` would: `user:` names make it private to them. Fails with `EEXIST` when
+    /// another tag has the name: the names are a bimap, inserting it would take the name from
+    /// that tag and leave it without one.
+    fn name_tag_as(&mut self, tag: TagNumber, name: &OsStr, uid: u32) -> Result<(), c_int> {
+        let name = &*nfc(name);
+        let private = private_name(name, uid);
+        let stored = private.as_deref().unwrap_or(name);
+        if self.get_tnb_by_name(stored).is_some_and(|other| other != tag) {
+            return Err(EEXIST);
+        }
 
-        fnb
+        let old = self.tags.get_by_left(&tag).cloned();
+        self.note_name(tag);
+        self.tags.insert(tag, stored.into());
+        if private.is_some() {
+            let meta = self.tag_meta_mut(tag);
+            meta.owner = Some(uid);
+            meta.mode = Some(0o700);
+        }
+
+        let new = self.tags.get_by_left(&tag).cloned().unwrap_or_default();
+        if let Some(old) = old.filter(|old| *old != new) {
+            self.stamp_tag_renamed(&old, &new);
+        }
+
+        Ok(())
     }
 
-    pub fn add_file_to(&mut self, file: FileNumber, to: TagNumber) {
-        self.tag_content.get_mut(&to).unwrap().insert(file);
+    pub fn backing(&self) -> &B {
+        &self.backing
     }
 
-    pub fn remove_file_from(&mut self, file: FileNumber, from: TagNumber) {
-        self.tag_content.get_mut(&from).unwrap().remove(&file);
+    pub(crate) fn recent_errors_mut(&mut self) -> &mut RecentErrors {
+        &mut self.errors
     }
 
-    pub fn omit_file(&mut self, fnb: FileNumber) {
-        self.files.remove_by_left(&fnb);
-        self.tag_content.values_mut().for_each(|v| {
-            v.remove(&fnb);
-        });
+    pub fn content_index(&self) -> &ContentIndex {
+        &self.content
     }
-}
 
-impl<B> TagFS<B>
-where
-    B: BackingFS,
-    <B as BackingFS>::Error: Error + Send + Sync + 'static,
-{
-    /// Re-index the file-system, omitting any files not present in the new index,
-    /// but retaining any files that were there before.
-    pub fn repopulate(&mut self, files: impl IntoIterator<Item = FileName>) {
-        let mut files: HashSet<FileName> = files.into_iter().collect();
-        files.remove::<OsStr>(".tagfs".as_ref());
+    pub(crate) fn content_index_mut(&mut self) -> &mut ContentIndex {
+        &mut self.content
+    }
 
-        // Omit old files, and remove files that stay from the `files` set
-        self.files.retain(|fnb, fnm| {
-            if files.remove(fnm) {
-                // Great, this file is retained.
-                true
-            } else {
-                debug!("removing '{}'", fnm.to_string_lossy());
+    /// Whether changes are held back by an open transaction, see [TagFS::run_control], or by a
+    /// freeze, or never saved at all because a snapshot is mounted.
+    pub(crate) fn holds_changes(&self) -> bool {
+        self.transaction.is_some() || self.frozen || self.config.read_only
+    }
 
-                // This file has to be omitted!
-                self.tag_content.values_mut().for_each(|v| {
-                    v.remove(&fnb);
-                });
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+        self.rebuild_shown();
+    }
 
-                false
-            }
-        });
+    /// Give the tag part of inodes `split` bits and the file part the rest. Fails when the files
+    /// or tags indexed so far don't fit.
+    pub fn set_ino_split(&mut self, bits: u32) -> Result<(), TagError> {
+        let split = Split::new(bits).ok_or(TagError::InoSplit(bits))?;
+        let last_file = self.files.left_values().copied().max().unwrap_or_default();
+        let last_tag = self.tree.counter;
+        if last_file.max(self.file_tally) >= split.virtual_files()
+            || last_tag >= split.virtual_dirs()
+        {
+            return Err(TagError::InoSplit(bits));
+        }
 
-        // Everything in `files` is now new: add them as new files
-        files.into_iter().for_each(|f| {
-            debug!("adding new file '{}'", f.to_string_lossy());
+        self.split = split;
+        Ok(())
+    }
 
-            self.add_file(f);
-        });
+    pub fn files(&self) -> &BiMap<FileNumber, FileName> {
+        &self.files
+    }
 
-        if let Err(error) = self.save() {
-            error!("failed to save: {error}");
-        }
+    pub fn tags(&self) -> &BiMap<TagNumber, FileName> {
+        &self.tags
     }
 
-    pub fn save(&self) -> anyhow::Result<()> {
-        let vec = bincode::encode_to_vec(
-            Compat(PersistentState {
-                tag_content: self.tag_content.clone(),
-                files: self.files.clone(),
-                tags: self.tags.clone(),
-                file_tally: self.file_tally,
-            }),
-            bincode::config::standard(),
-        )?;
+    pub fn members(&self, tag: TagNumber) -> Option<&HashSet<FileNumber>> {
+        self.tag_content.get(&tag)
+    }
 
-        let handle = self.backing.create(".tagfs")?;
-        self.backing.write(handle, &vec)?;
+    pub fn require_tag<N: AsRef<OsStr>>(&self, name: N) -> Result<TagNumber, TagError> {
+        self.get_tnb_by_name(name.as_ref())
+            .ok_or_else(|| TagError::NoSuchTag(name.as_ref().to_os_string()))
+    }
 
-        Ok(())
+    pub fn require_file<N: AsRef<OsStr>>(&self, name: N) -> Result<FileNumber, TagError> {
+        self.get_fnb_by_name(name.as_ref())
+            .ok_or_else(|| TagError::NoSuchFile(name.as_ref().to_os_string()))
     }
-}
 
-impl<B: BackingFS> Filesystem for TagFS<B>
-where
-    <B as BackingFS>::Error: Debug + Error + Send + Sync + 'static,
-{
-    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
-        let parent_ino = Ino(parent);
-        if parent_ino.is_file() {
-            err!(reply, ENOTDIR);
-        }
+    pub fn require_files<N: AsRef<OsStr>>(&self, names: &[N]) -> Result<Vec<FileNumber>, TagError> {
+        names.iter().map(|name| self.require_file(name)).collect()
+    }
 
-        // Find the `TagNode` in the tag tree
-        let Some(parent) = self.tree.lookup(parent_ino.tag()) else {
-            err!(reply, ENOENT);
-        };
+    pub fn calculate_intersection(&self, path: &[TagNumber]) -> HashSet<FileNumber> {
+        let (pseudo, path): (Vec<_>, Vec<_>) = path
+            .iter()
+            .partition(|tn| self.pseudo_tags.contains_left(tn));
 
-        let file = match self.get_fnb_by_name(name) {
-            Some(file) => file, // Great, it's a file!
-            None => {
-                // Great, it's not a file, but it might be a tag.
-                let Some(tn) = self.tags.get_by_right(name).copied() else {
-                    // It's not a file and not a tag; get out!
-                    err!(reply, ENOENT)
-                };
+        let mut result = if path.is_empty() {
+            self.files.left_values().copied().collect()
+        } else {
+            let sets = self
+                .tag_content
+                .iter()
+                .filter(|(tn, _)| path.contains(tn))
+                .map(|(_, set)| set)
+                .collect::<Vec<_>>();
 
-                let node = self.tree.add_to_if_needed(parent, tn);
-                let ino = Ino::from_tag(node.borrow().ino_part);
-                reply.entry(&TTL, &create_folder_attrs(ino), 0);
-                return;
+            let (start, sets) = sets.split_first().unwrap();
+            let mut result = (*start).clone();
+            for set in sets {
+                result = result.intersection(set).copied().collect()
             }
+
+            result
         };
 
-        let path = parent.borrow().collect_tags();
-        // For the lookup to pass, `file` must be present in each of the tags in the path
-        if path.into_iter().all(|tag| {
-            self.tag_content
+        // Pseudo tags are evaluated against the files themselves
+        for tn in pseudo {
+            if let Some(PseudoTag::Range(range)) = self.pseudo_tags.get_by_left(tn) {
+                let members = self.range_members(range);
+                result.retain(|file| members.contains(file));
+            } else {
+                result.retain(|&file| self.has_tag(file, *tn));
+            }
+        }
+        result.retain(|&file| !self.is_offline(file));
+
+        result
+    }
+
+    /// Whether `file` carries `tag`, which may be a pseudo tag.
+    pub fn has_tag(&self, file: FileNumber, tag: TagNumber) -> bool {
+        match self.pseudo_tags.get_by_left(&tag) {
+            Some(PseudoTag::Content(terms)) => self.content.matches(file, terms),
+            Some(PseudoTag::Range(range)) => self
+                .value_tags(range)
+                .any(|tag| self.tag_content.get(&tag).is_some_and(|set| set.contains(&file))),
+            Some(pseudo) => self
+                .get_fnm_by_number(file)
+                .map(|name| pseudo.matches(name))
+                .unwrap_or(false),
+            None => self
+                .tag_content
                 .get(&tag)
                 .map(|set| set.contains(&file))
-                .unwrap_or(false)
-        }) {
-            let Ok(mut fa) = self.backing.get_metadata(name) else {
-                error!("Failed to get metadata for '{}' from backing fs", name.to_string_lossy());
-                err!(reply, EIO);
-            };
+                .unwrap_or(false),
+        }
+    }
 
-            fa.ino = Ino::from_parts(file, parent_ino.tag()).0;
+    /// The value tags with a number in `range`.
+    fn value_tags<'a>(&'a self, range: &'a ValueRange) -> impl Iterator<Item = TagNumber> + 'a {
+        self.tags
+            .iter()
+            .filter(|(_, name)| range.admits(name, self.config.tag_case))
+            .map(|(&tag, _)| tag)
+    }
 
-            reply.entry(&TTL, &fa, 0);
-        } else {
-            err!(reply, ENOENT);
-        }
+    /// The files with a value tag in `range`.
+    fn range_members(&self, range: &ValueRange) -> HashSet<FileNumber> {
+        self.value_tags(range)
+            .filter_map(|tag| self.tag_content.get(&tag))
+            .flatten()
+            .copied()
+            .collect()
     }
 
-    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
-        let ino = Ino(ino);
+    /// The tag number of a pseudo tag, handing out a new one on first use.
+    fn pseudo_tag(&mut self, pseudo: PseudoTag) -> TagNumber {
+        let transient = pseudo.is_transient();
+        let tag = match self.pseudo_tags.get_by_right(&pseudo) {
+            Some(&tag) => tag,
+            None => {
+                let tag = PSEUDO_TAGS + self.pseudo_tally;
+                self.pseudo_tally += 1;
+                self.pseudo_tags.insert(tag, pseudo);
+                tag
+            }
+        };
+        if transient {
+            self.touch_transient(Transient::Pseudo(tag));
+        }
 
-        if ino.is_tag() {
-            reply.attr(&TTL, &create_folder_attrs(ino))
-        } else {
-            let Some(name) = self.get_fnm_by_number(ino.file()) else { err!(reply, ENOENT) };
+        tag
+    }
 
-            let Ok(mut fa) = self.backing.get_metadata(name) else {
-                error!("Failed to get metadata for '{}' from backing fs", name.to_string_lossy());
-                err!(reply, EIO);
-            };
+    /// Mark a directory made up from a looked up name as used, forgetting the least recently used
+    /// ones beyond [TRANSIENT_DIRS].
+    fn touch_transient(&mut self, transient: Transient) {
+        self.transient.shift_remove(&transient);
+        self.transient.insert(transient);
 
-            fa.ino = ino.0;
-            reply.attr(&TTL, &fa);
+        while self.transient.len() > TRANSIENT_DIRS {
+            match self.transient.shift_remove_index(0) {
+                Some(Transient::Node(node)) => {
+                    self.virtual_nodes.remove_by_right(&node);
+                    if let VirtualNode::Complete(id) = node {
+                        self.completions.remove_by_left(&id);
+                    }
+                }
+                Some(Transient::Pseudo(tag)) => {
+                    self.pseudo_tags.remove_by_left(&tag);
+                    self.tree.remove_tag(tag);
+                }
+                None => break,
+            }
         }
     }
 
-    fn mkdir(
-        &mut self,
-        _req: &Request<'_>,
-        _parent: u64,
-        name: &OsStr,
-        _mode: u32,
-        _umask: u32,
-        reply: ReplyEntry,
-    ) {
-        if name == ".Trash-1000" {
-            err!(reply, ENOTSUP);
-        }
-        let tnb = self.create_tag(name.to_os_string());
+    pub fn create_tag(&mut self, tag: FileName) -> TagNumber {
+        let tag = tag.normalized();
+        let tnb = self.tree.create_new();
+
+        self.note_members(tnb);
+        self.note_name(tnb);
+        self.note_tag_meta(tnb);
+        self.tag_content.insert(tnb, Default::default());
+        self.stamp_tag_added(&tag);
+        self.tags.insert(tnb, tag);
+        self.tag_meta.insert(tnb, TagMeta::created_now());
 
-        reply.entry(&TTL, &create_folder_attrs(Ino::from_tag(tnb)), 0);
+        tnb
+    }
 
-        if let Err(error) = self.save() {
-            error!("failed to save: {error}");
+    pub fn get_or_create_tag(&mut self, tag: FileName) -> TagNumber {
+        match self.get_tnb_by_name(&tag) {
+            Some(tnb) => tnb,
+            None => self.create_tag(tag),
         }
     }
 
-    fn unlink(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
-        let parent = Ino(parent);
-        let Some(parent) = self.tree.lookup(parent.tag()) else {
-            err!(reply, ENOENT);
-        };
+    pub fn starred_tag(&mut self) -> TagNumber {
+        self.get_or_create_tag(STARRED.into())
+    }
 
-        // should we check if the file actually even exists under this tag?
-        // the operation will succeed without, but do nothing.
-        let Some(file) = self.get_fnb_by_name(name) else { err!(reply, ENOENT); };
+    /// Index the file `file` of the source, under its name in Unicode normalization form C.
+    pub fn add_file(&mut self, file: FileName) -> FileNumber {
+        self.file_tally += 1;
+        let fnb = self.file_tally;
+        let name = file.normalized();
+        if name != file {
+            self.file_meta_mut(fnb).source_name = Some(file.into());
+        }
+        self.files.insert(fnb, name);
+        self.show_file(fnb);
 
-        let tags = parent.borrow().collect_tags();
-        for tag in tags {
-            self.remove_file_from(file, tag);
+        fnb
+    }
+
+    pub fn add_file_to(&mut self, file: FileNumber, to: TagNumber) {
+        // Pseudo tags can't be applied
+        let Some(set) = self.tag_content.get(&to) else { return };
+        if set.contains(&file) {
+            return;
         }
 
-        reply.ok();
+        // Applying a tag of a group takes the file out of the rest of the group
+        if let Some(group) = self.tag_meta(to).and_then(|meta| meta.group.clone()) {
+            let others = self
+                .group_members(&group)
+                .into_iter()
+                .filter(|&tag| tag != to && self.tag_content[&tag].contains(&file))
+                .collect::<Vec<_>>();
 
-        if let Err(error) = self.save() {
-            error!("failed to save: {error}");
+            for other in others {
+                self.remove_file_from(file, other);
+            }
         }
+
+        self.note_members(to);
+        self.tag_content.get_mut(&to).unwrap().insert(file);
+        self.stamp_added(to, file);
+        self.start_expiry(to, file);
+        self.record_event(true, to, file);
+        self.tag_meta_mut(to).modified_at = Some(SystemTime::now());
+        self.file_meta_mut(file).changed_at = Some(SystemTime::now());
     }
 
-    fn rename(
-        &mut self,
-        _req: &Request<'_>,
-        parent: u64,
-        name: &OsStr,
-        newparent: u64,
-        newname: &OsStr,
-        _flags: u32,
-        reply: ReplyEmpty,
-    ) {
-        // If we're renaming a tag, the parent(s) don't matter
-        if let Some(tag) = self.get_tnb_by_name(name) {
-            self.tags.insert(tag, newname.to_os_string());
-            reply.ok();
+    /// The tags in an exclusive group.
+    pub fn group_members(&self, group: &str) -> Vec<TagNumber> {
+        self.tag_meta
+            .iter()
+            .filter(|(_, meta)| meta.group.as_deref() == Some(group))
+            .map(|(&tag, _)| tag)
+            .collect()
+    }
 
-            if let Err(error) = self.save() {
-                error!("failed to save: {error}");
-            }
+    /// Whether `tags` holds two tags of the same group, like the path `/unread/done` does. No
+    /// file can carry them all, so moving or copying files into such a path fails with EINVAL.
+    fn crosses_groups(&self, tags: &[TagNumber]) -> bool {
+        let mut groups = HashSet::new();
+        tags.iter()
+            .filter_map(|&tag| self.tag_meta(tag)?.group.as_deref())
+            .any(|group| !groups.insert(group))
+    }
+
+    /// Make `tags` mutually exclusive. Existing files carrying several of them keep their tags.
+    pub fn declare_group(&mut self, group: &str, tags: &[TagNumber]) {
+        for &tag in tags {
+            self.tag_meta_mut(tag).group = Some(group.to_string());
+        }
+    }
 
+    pub fn remove_file_from(&mut self, file: FileNumber, from: TagNumber) {
+        if !self.tag_content.get(&from).is_some_and(|set| set.contains(&file)) {
             return;
         }
+        self.note_members(from);
+        self.tag_content.get_mut(&from).unwrap().remove(&file);
+        self.stamp_removed(from, file);
+        self.expiry.remove(&(from, file));
+        self.record_event(false, from, file);
+
+        let meta = self.tag_meta_mut(from);
+        meta.order.retain(|&f| f != file);
+        meta.modified_at = Some(SystemTime::now());
+        self.file_meta_mut(file).changed_at = Some(SystemTime::now());
+    }
+
+    /// Pin a member of `tag` at a position in its manual order.
+    pub fn place_file(
+        &mut self,
+        tag: TagNumber,
+        file: FileNumber,
+        position: Position,
+    ) -> Result<(), TagError> {
+        let anchor = match position {
+            Position::Before(anchor) | Position::After(anchor) => Some(anchor),
+            Position::End => None,
+        };
+
+        let members = self.tag_content.get(&tag);
+        for f in iter::once(file).chain(anchor) {
+            if !members.map(|set| set.contains(&f)).unwrap_or(false) {
+                return Err(TagError::NotTagged(
+                    self.get_fnm_by_number(f).cloned().unwrap_or_default().into(),
+                    self.tags.get_by_left(&tag).cloned().unwrap_or_default().into(),
+                ));
+            }
+        }
 
-        // If we're moving a file, disallow renames
-        if name == newname && parent != newparent {
-            let Some(file) = self.get_fnb_by_name(name) else { err!(reply, ENOENT); };
+        self.tag_meta_mut(tag).place(file, position);
 
-            let parent = Ino(parent);
-            let Some(parent) = self.tree.lookup(parent.tag()) else {
-                err!(reply, ENOENT);
-            };
-            let newparent = Ino(newparent);
-            let Some(newparent) = self.tree.lookup(newparent.tag()) else {
-                err!(reply, ENOENT);
-            };
+        Ok(())
+    }
 
-            let oldtags = parent.borrow().collect_tags();
-            let newtags = newparent.borrow().collect_tags();
+    /// Remove a tag entirely. Members are moved to `move_to` when given; otherwise the tag
+    /// has to be empty unless `force` is set.
+    pub fn remove_tag(
+        &mut self,
+        tag: TagNumber,
+        move_to: Option<TagNumber>,
+        force: bool,
+    ) -> Result<(), TagError> {
+        let name = self.tags.get_by_left(&tag).cloned().unwrap_or_default();
+        let members = self.tag_content.get(&tag).map(|set| set.len()).unwrap_or(0);
 
-            for tag in oldtags {
-                self.remove_file_from(file, tag);
+        match move_to {
+            Some(to) if to == tag => return Err(TagError::SelfMove(name.into())),
+            Some(to) => {
+                let files = self.tag_content.get(&tag).cloned().unwrap_or_default();
+                self.note_members(to);
+                let members = self.tag_content.entry(to).or_default();
+                let moved = files.into_iter().filter(|&file| members.insert(file));
+                for file in moved.collect::<Vec<_>>() {
+                    self.stamp_added(to, file);
+                    self.start_expiry(to, file);
+                }
+                self.tag_meta_mut(to).modified_at = Some(SystemTime::now());
             }
-            for tag in newtags {
-                self.add_file_to(file, tag);
+            None if members > 0 && !force => return Err(TagError::NotEmpty(name.into(), members)),
+            None => {
+                // The members lose a tag
+                let files = self.tag_content.get(&tag).cloned().unwrap_or_default();
+                for file in files {
+                    self.file_meta_mut(file).changed_at = Some(SystemTime::now());
+                }
             }
+        }
 
-            reply.ok();
+        self.note_members(tag);
+        self.note_name(tag);
+        self.note_tag_meta(tag);
+        self.tag_content.shift_remove(&tag);
+        self.stamp_tag_removed(tag, &name);
+        self.expiry.retain(|&(t, _), _| t != tag);
+        self.tags.remove_by_left(&tag);
+        self.tag_meta.remove(&tag);
+        self.tree.remove_tag(tag);
 
-            if let Err(error) = self.save() {
-                error!("failed to save: {error}");
-            }
+        Ok(())
+    }
 
-            return;
+    /// What was worked out from the tags as they are, see [Derived].
+    pub(crate) fn derived(&self) -> RefMut<'_, Derived> {
+        self.derived.borrow_mut()
+    }
+
+    pub fn tag_meta(&self, tag: TagNumber) -> Option<&TagMeta> {
+        self.tag_meta.get(&tag)
+    }
+
+    pub fn tag_meta_mut(&mut self, tag: TagNumber) -> &mut TagMeta {
+        self.note_tag_meta(tag);
+        self.tag_meta.entry(tag).or_default()
+    }
+
+    pub fn file_meta(&self, file: FileNumber) -> Option<&FileMeta> {
+        self.file_meta.get(&file)
+    }
+
+    pub fn file_meta_mut(&mut self, file: FileNumber) -> &mut FileMeta {
+        self.note_file_meta(file);
+        self.file_meta.entry(file).or_default()
+    }
+
+    /// Files whose tags changed within the configured recent window, most recent first.
+    pub fn recently_changed(&self) -> Vec<FileNumber> {
+        let since = SystemTime::now() - self.config.recent;
+
+        let mut files = self
+            .file_meta
+            .iter()
+            .filter(|(_, meta)| !meta.offline)
+            .filter_map(|(&file, meta)| Some((file, meta.changed_at?)))
+            .filter(|&(file, changed_at)| changed_at >= since && self.files.contains_left(&file))
+            .collect::<Vec<_>>();
+        files.sort_by_key(|&(_, changed_at)| Reverse(changed_at));
+
+        files.into_iter().map(|(file, _)| file).collect()
+    }
+
+    /// Whether a file was discovered by one of the configured number of most recent scans.
+    fn is_new(&self, file: FileNumber) -> bool {
+        let scan = self.file_meta(file).map(|meta| meta.scan).unwrap_or(0);
+
+        scan > 0 && scan > self.scan_tally.saturating_sub(self.config.new_scans)
+    }
+
+    /// Whether a file was missing from the last scan of the source, see `repopulate`.
+    pub fn is_offline(&self, file: FileNumber) -> bool {
+        self.file_meta(file).map(|meta| meta.offline).unwrap_or(false)
+    }
+
+    /// Indexed files that are absent from the backing fs, e.g. on an unplugged drive, as far as
+    /// scans and failed operations on them could tell, see [TagFS::is_offline]. The source isn't
+    /// statted for them, so listing `/@missing` stays cheap.
+    pub fn missing_files(&self) -> Vec<FileNumber> {
+        let mut files = self
+            .files
+            .left_values()
+            .copied()
+            .filter(|&file| self.is_offline(file))
+            .collect::<Vec<_>>();
+        files.sort_by_cached_key(|&file| self.get_fnm_by_number(file).cloned());
+
+        files
+    }
+
+    /// Keep generated content around for reading, under a new virtual file handle.
+    fn open_virtual(&mut self, content: Vec<u8>) -> FileHandle {
+        self.handle_tally += 1;
+        let handle = FileHandle(VIRTUAL_HANDLE | self.handle_tally);
+        self.virtual_handles.insert(handle, content);
+
+        handle
+    }
+
+    /// Keep an archive around for reading, under a new virtual file handle.
+    fn open_archive(&mut self, archive: Archive) -> FileHandle {
+        self.handle_tally += 1;
+        let handle = FileHandle(VIRTUAL_HANDLE | self.handle_tally);
+        self.archives.insert(handle, archive);
+
+        handle
+    }
+
+    /// Whether `uid` may enter every tag on the path to the directory with inode tag part `dir`.
+    fn may_read_dir(&self, dir: u64, uid: u32) -> bool {
+        let Some(node) = self.tree.lookup(dir) else { return true };
+        let path = node.borrow().collect_tags();
+
+        path.into_iter().all(|tag| self.may_read(tag, uid))
+    }
+
+    fn is_locked(&self, tag: TagNumber) -> bool {
+        self.tag_meta(tag).map(|meta| meta.locked).unwrap_or(false)
+    }
+
+    /// Whether the directory with inode tag part `dir` is below a locked tag.
+    fn is_locked_dir(&self, dir: u64) -> bool {
+        let Some(node) = self.tree.lookup(dir) else { return false };
+        let path = node.borrow().collect_tags();
+
+        path.into_iter().any(|tag| self.is_locked(tag))
+    }
+
+    /// Whether `uid` may enter `tag`. The group of a tag is the one its directory is shown with.
+    fn may_read(&self, tag: TagNumber, uid: u32) -> bool {
+        let in_group = || self.in_group(uid, self.config.dirs.gid);
+        self.tag_meta(tag).map(|meta| meta.may_read(uid, in_group)).unwrap_or(true)
+    }
+
+    /// Whether user `uid` is in group `gid`, as its primary or a supplementary group in the user
+    /// database. The groups of a user are looked up again once they're [GROUPS_TTL] old.
+    fn in_group(&self, uid: u32, gid: u32) -> bool {
+        let mut groups = self.user_groups.borrow_mut();
+        let entry = groups.entry(uid).or_insert_with(|| (Instant::now(), user_groups(uid)));
+        if entry.0.elapsed() >= GROUPS_TTL {
+            *entry = (Instant::now(), user_groups(uid));
         }
 
-        reply.error(ENOTSUP);
+        entry.1.contains(&gid)
     }
 
-    fn open(&mut self, _req: &Request<'_>, ino: u64, _flags: i32, reply: ReplyOpen) {
-        let ino = Ino(ino);
-        if !ino.is_file() {
-            err!(reply, ENOENT)
+    /// The metadata of the tag represented by a directory inode, if any.
+    fn meta_for_ino(&self, ino: Ino) -> Option<&TagMeta> {
+        if ino.is_file(self.split) {
+            return None;
         }
 
-        let Some(filename) = self.get_fnm_by_number(ino.file()) else {
-            err!(reply, ENOENT)
-        };
+        let node = self.tree.lookup(ino.tag(self.split))?;
+        let tag = node.borrow().tag;
+        self.tag_meta(tag)
+    }
 
-        match self.backing.open(filename) {
-            Ok(fh) => {
-                reply.opened(fh.0, 0);
+    /// The tag represented by the directory with the given inode tag part.
+    fn node_tag(&self, ino_part: u64) -> Option<TagNumber> {
+        self.tree.lookup(ino_part).map(|node| node.borrow().tag)
+    }
+
+    /// Generate the contents of a virtual file in the directory with the given inode tag part.
+    /// Returns `None` if the file doesn't exist in that directory.
+    fn virtual_content(&self, file: VirtualFile, dir: u64) -> Option<Vec<u8>>
+    where
+        B: BackingFS,
+        <B as BackingFS>::Error: Error + Send + Sync + 'static,
+    {
+        if file.dir().map(|vd| vd.ino_part(self.split) != dir).unwrap_or(false) {
+            return None;
+        }
+
+        match file {
+            VirtualFile::Description => {
+                let description = self.tag_meta(self.node_tag(dir)?)?.description.as_ref()?;
+                Some(format!("{description}\n").into_bytes())
             }
-            Err(e) => {
-                error!(
-                    "failed to open file '{}' from backing: {e:?}",
-                    filename.to_string_lossy()
-                );
-                reply.error(EIO);
+            VirtualFile::TagStats => Some(self.tag_stats().into_bytes()),
+            VirtualFile::Control => Some(self.control_report.clone().into_bytes()),
+            VirtualFile::Stats => Some(self.stats().into_bytes()),
+            VirtualFile::Errors => Some(self.errors.report().into_bytes()),
+            VirtualFile::Version => Some(format!("{}\n", env!("CARGO_PKG_VERSION")).into_bytes()),
+            VirtualFile::LastScan => Some(self.last_scan_report().into_bytes()),
+            VirtualFile::DiskUsage => Some(self.disk_usage(false).into_bytes()),
+            VirtualFile::Health => Some(self.health().report().into_bytes()),
+        }
+    }
+
+    fn virtual_content_of(&self, ino: Ino) -> Option<Vec<u8>>
+    where
+        B: BackingFS,
+        <B as BackingFS>::Error: Error + Send + Sync + 'static,
+    {
+        let file = VirtualFile::from_number(ino.file(self.split), self.split)?;
+        self.virtual_content(file, ino.tag(self.split))
+    }
+
+    /// The size of [TagFS::virtual_content], without generating the reports that are costly to,
+    /// see [VirtualFile::is_unsized].
+    fn virtual_size(&self, file: VirtualFile, dir: u64) -> Option<usize>
+    where
+        B: BackingFS,
+        <B as BackingFS>::Error: Error + Send + Sync + 'static,
+    {
+        if file.is_unsized() {
+            return file.dir().is_some_and(|vd| vd.ino_part(self.split) == dir).then_some(0);
+        }
+
+        self.virtual_content(file, dir).map(|content| content.len())
+    }
+
+    /// The virtual files present in the directory with the given inode tag part.
+    fn virtual_files(&self, dir: u64) -> Vec<VirtualFile>
+    where
+        B: BackingFS,
+        <B as BackingFS>::Error: Error + Send + Sync + 'static,
+    {
+        VirtualFile::ALL
+            .into_iter()
+            .filter(|&vf| match vf.dir() {
+                // Don't generate reports just to see whether they exist
+                Some(vd) => vd.ino_part(self.split) == dir,
+                None => self.virtual_content(vf, dir).is_some(),
+            })
+            .collect()
+    }
+
+    /// The indexed files listed in a virtual directory.
+    fn virtual_dir_files(&self, dir: VirtualDir) -> Vec<FileNumber>
+    where
+        B: BackingFS,
+    {
+        match dir {
+            VirtualDir::Control
+            | VirtualDir::ByTag
+            | VirtualDir::ByExt
+            | VirtualDir::Duplicates
+            | VirtualDir::Complete
+            | VirtualDir::Search
+            | VirtualDir::Archives => vec![],
+            VirtualDir::All => self
+                .files
+                .left_values()
+                .copied()
+                .filter(|&file| !self.is_offline(file))
+                .collect(),
+            VirtualDir::Recent => self.recently_changed(),
+            VirtualDir::New => {
+                let mut files = self
+                    .files
+                    .left_values()
+                    .copied()
+                    .filter(|&file| self.is_new(file) && !self.is_offline(file))
+                    .collect::<Vec<_>>();
+                files.sort_by_key(|&file| Reverse(self.file_meta(file).map(|m| m.indexed_at)));
+
+                files
             }
+            VirtualDir::Missing => self.missing_files(),
         }
     }
 
-    fn read(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        fh: u64,
-        offset: i64,
-        size: u32,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        reply: ReplyData,
-    ) {
-        let result = self
-            .backing
-            .read(FileHandle(fh), offset as u64, size as u64);
+    fn virtual_dir_contains(&self, dir: VirtualDir, file: FileNumber) -> bool
+    where
+        B: BackingFS,
+    {
+        match dir {
+            VirtualDir::Control
+            | VirtualDir::ByTag
+            | VirtualDir::ByExt
+            | VirtualDir::Duplicates
+            | VirtualDir::Complete
+            | VirtualDir::Search
+            | VirtualDir::Archives => false,
+            VirtualDir::All => self.files.contains_left(&file) && !self.is_offline(file),
+            VirtualDir::Recent => self
+                .file_meta(file)
+                .filter(|meta| !meta.offline)
+                .and_then(|meta| meta.changed_at)
+                .map(|changed_at| changed_at >= SystemTime::now() - self.config.recent)
+                .unwrap_or(false),
+            VirtualDir::New => self.is_new(file) && !self.is_offline(file),
+            VirtualDir::Missing => self.is_offline(file),
+        }
+    }
+
+    /// The directories generated at runtime inside a virtual directory.
+    fn virtual_dir_subdirs(&mut self, dir: VirtualDir) -> Vec<(Ino, FileName)> {
+        match dir {
+            VirtualDir::ByTag => {
+                let files = self
+                    .shown_names()
+                    .iter()
+                    .map(|(&file, name)| (file, name.clone()))
+                    .collect::<Vec<_>>();
 
-        match result {
-            Ok(buf) => {
-                reply.data(&buf);
+                files
+                    .into_iter()
+                    .map(|(file, name)| {
+                        let part = self.virtual_node_part(VirtualNode::FileTags(file));
+                        (Ino::from_tag(part), name)
+                    })
+                    .collect()
             }
-            Err(e) => {
-                warn!("read failed because of backing error: {e:?}");
+            VirtualDir::ByExt => {
+                let extensions = self
+                    .files
+                    .right_values()
+                    .filter_map(|name| extension(name))
+                    .collect::<HashSet<_>>();
 
-                reply.error(EIO);
+                extensions
+                    .into_iter()
+                    .map(|ext| (self.pseudo_tag_ino(PseudoTag::Extension(ext.clone())), ext.into()))
+                    .collect()
             }
+            VirtualDir::Duplicates => (0..self.duplicates.len())
+                .filter_map(|idx| {
+                    let name = self.duplicates_name(idx)?.clone();
+                    let part = self.virtual_node_part(VirtualNode::Duplicates(idx));
+                    Some((Ino::from_tag(part), name))
+                })
+                .collect(),
+            _ => vec![],
         }
     }
 
-    fn release(
-        &mut self,
-        _req: &Request<'_>,
-        _ino: u64,
-        fh: u64,
-        _flags: i32,
-        _lock_owner: Option<u64>,
-        _flush: bool,
-        reply: ReplyEmpty,
-    ) {
-        self.backing.release(FileHandle(fh));
+    /// The inode of the directory for a pseudo tag, which lives below the root of the tag tree.
+    fn pseudo_tag_ino(&mut self, pseudo: PseudoTag) -> Ino {
+        let tag = self.pseudo_tag(pseudo);
+        let node = self.tree.add_to_if_needed(self.tree.root.clone(), tag);
+        let ino_part = node.borrow().ino_part;
 
-        reply.ok();
+        Ino::from_tag(ino_part)
     }
 
-    fn readdir(
-        &mut self,
-        _req: &Request<'_>,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-        mut reply: ReplyDirectory,
-    ) {
-        let mut offset = offset as usize;
-        let ino = Ino(ino);
-        if ino.is_file() {
-            err!(reply, ENOTDIR)
+    /// The name of a set of duplicates, which is the name of its first file.
+    fn duplicates_name(&self, idx: usize) -> Option<&FileName> {
+        let &file = self.duplicates.get(idx)?.first()?;
+        self.get_fnm_shown(file)
+    }
+
+    /// Look up a directory generated at runtime inside a virtual directory.
+    fn lookup_virtual_subdir(&mut self, dir: VirtualDir, name: &OsStr) -> Option<Ino> {
+        match dir {
+            VirtualDir::ByTag => {
+                let file = self.get_fnb_by_shown_name(name)?;
+                let part = self.virtual_node_part(VirtualNode::FileTags(file));
+                Some(Ino::from_tag(part))
+            }
+            VirtualDir::ByExt => {
+                let pseudo = PseudoTag::Extension(name.to_os_string());
+                let exists = self.files.right_values().any(|file| pseudo.matches(file));
+
+                exists.then(|| self.pseudo_tag_ino(pseudo))
+            }
+            VirtualDir::Duplicates => {
+                let idx = (0..self.duplicates.len())
+                    .find(|&idx| self.duplicates_name(idx).map(|n| n == name).unwrap_or(false))?;
+                let part = self.virtual_node_part(VirtualNode::Duplicates(idx));
+                Some(Ino::from_tag(part))
+            }
+            // Every search exists, even if nothing matches
+            VirtualDir::Search => {
+                let terms = search_terms(name.to_str()?)?;
+                Some(self.pseudo_tag_ino(PseudoTag::Content(terms)))
+            }
+            // Every prefix exists, even if no tag starts with it
+            VirtualDir::Complete => {
+                let id = match self.completions.get_by_right(name) {
+                    Some(&id) => id,
+                    None => {
+                        self.completion_tally += 1;
+                        self.completions.insert(self.completion_tally, name.into());
+                        self.completion_tally
+                    }
+                };
+                let part = self.virtual_node_part(VirtualNode::Complete(id));
+                Some(Ino::from_tag(part))
+            }
+            _ => None,
         }
+    }
 
-        // Find the `TagNode` in the tag tree
-        let Some(dir) = self.tree.lookup(ino.tag()) else {
-            err!(reply, ENOENT);
-        };
+    /// The listing of a virtual directory, which never contains tag directories.
+    fn virtual_dir_entries(&mut self, dir: VirtualDir) -> Vec<DirEntry>
+    where
+        B: BackingFS,
+        <B as BackingFS>::Error: Error + Send + Sync + 'static,
+    {
+        let ino = Ino::from_tag(dir.ino_part(self.split));
+        let mut entries = vec![
+            (ino, Directory, ".".into()),
+            (Ino::from_tag(dir.parent(self.split)), Directory, "..".into()),
+        ];
 
-        'full: {
-            // The `offset` counter, accounting for the . and .. entries
-            let mut idx = offset as i64 + 3;
+        let subdirs = self.virtual_dir_subdirs(dir).into_iter();
+        entries.extend(subdirs.map(|(ino, name)| (ino, Directory, name)));
+        entries.extend(
+            VirtualDir::ALL
+                .into_iter()
+                .filter(|vd| vd.parent(self.split) == dir.ino_part(self.split))
+                .map(|vd| (Ino::from_tag(vd.ino_part(self.split)), Directory, vd.name().into())),
+        );
+        entries.extend(self.virtual_files(dir.ino_part(self.split)).into_iter().map(|vf| {
+            let ino = Ino::from_parts(vf.number(self.split), ino.tag(self.split), self.split);
+            (ino, RegularFile, vf.name().into())
+        }));
+        entries.extend(self.virtual_dir_files(dir).into_iter().map(|file| {
+            let filename = self.get_fnm_shown(file).expect("file without a name");
+            let ino = Ino::from_parts(file, ino.tag(self.split), self.split);
+            (ino, RegularFile, filename.clone())
+        }));
 
-            // Skip . and .. or not?
-            if offset >= 2 {
-                offset -= 2;
-            } else {
-                if reply.add(ino.0, 1, Directory, ".") {
-                    break 'full;
-                }
-                if reply.add(Ino::from_tag(dir.borrow().ino_part).0, 2, Directory, "..") {
-                    break 'full;
-                }
+        entries
+    }
+
+    /// The inode tag part of a virtual node, handing out a new one on first use.
+    fn virtual_node_part(&mut self, node: VirtualNode) -> u64 {
+        let part = match self.virtual_nodes.get_by_right(&node) {
+            Some(&part) => part,
+            None => {
+                let part = self.tree.allocate();
+                self.virtual_nodes.insert(part, node);
+                part
             }
+        };
+        if node.is_transient() {
+            self.touch_transient(Transient::Node(node));
+        }
 
-            // Dirs to list
-            let used_tags = dir.borrow().collect_tags();
-            let tags = self.tags.clone();
+        part
+    }
 
-            // Only keep tags that aren't present in the current dir's tag list
-            let mut tags = tags
+    /// The entries of the virtual node with inode tag part `part` as seen by `uid`, excluding `.`
+    /// and `..`. `seed` is the handle of the open directory, see `opendir`.
+    fn virtual_node_entries(
+        &self,
+        node: VirtualNode,
+        part: u64,
+        uid: u32,
+        seed: u64,
+    ) -> Vec<DirEntry> {
+        match node {
+            VirtualNode::FileTags(file) => self
+                .tags_of(file)
                 .into_iter()
-                .filter(|(l, _)| !used_tags.contains(l))
-                .collect::<Vec<_>>();
+                .filter_map(|tag| {
+                    let name = self.tags.get_by_left(&tag)?;
+                    Some((Ino::from_parts(tag, part, self.split), Symlink, name.clone()))
+                })
+                .collect(),
+            VirtualNode::Shuffle { dir, count } => {
+                let mut files = self.dir_files(dir).into_iter().collect::<Vec<_>>();
+                shuffle(&mut files, seed);
 
-            let to_drain = min(tags.len(), offset);
-            tags.drain(0..to_drain);
-            offset = offset.saturating_sub(to_drain);
-
-            if offset == 0 {
-                // Turn those into TagNodes, generating them as required
-                for (tag, name) in tags.into_iter().map(|(tn, name)| {
-                    (
-                        self.tree
-                            .add_to_if_needed(dir.clone(), tn)
-                            .borrow()
-                            .ino_part,
-                        name,
-                    )
-                }) {
-                    if reply.add(Ino::from_tag(tag).0, idx, Directory, name) {
-                        break 'full;
-                    }
-                    idx += 1;
-                }
+                files
+                    .into_iter()
+                    .take(count)
+                    .filter_map(|file| {
+                        let name = self.get_fnm_shown(file)?;
+                        Some((Ino::from_parts(file, part, self.split), RegularFile, name.clone()))
+                    })
+                    .collect()
+            }
+            VirtualNode::Duplicates(idx) => self
+                .duplicates
+                .get(idx)
+                .into_iter()
+                .flatten()
+                .filter_map(|&file| {
+                    let name = self.get_fnm_shown(file)?;
+                    Some((Ino::from_parts(file, part, self.split), RegularFile, name.clone()))
+                })
+                .collect(),
+            // The tags a listing of the root would show
+            VirtualNode::Complete(id) => {
+                let Some(prefix) = self.completions.get_by_left(&id) else { return vec![] };
+
+                let mut tags = self
+                    .tags
+                    .iter()
+                    .filter(|(&tag, _)| {
+                        let hidden = self.tag_meta(tag).map(|meta| meta.hidden).unwrap_or(false);
+                        !hidden && self.may_read(tag, uid)
+                    })
+                    .map(|(&tag, name)| (tag, listed_name(name, uid)))
+                    .filter(|(_, name)| name.as_bytes().starts_with(prefix.as_bytes()))
+                    .collect::<Vec<_>>();
+                tags.sort_by(|(_, a), (_, b)| a.cmp(b));
+
+                tags.into_iter()
+                    .map(|(tag, name)| (Ino::from_parts(tag, part, self.split), Symlink, name))
+                    .collect()
             }
+        }
+    }
 
-            // Files to list
-            let mut files = self
-                .calculate_intersection(&used_tags)
+    /// Find the entry `name` of the virtual node with inode tag part `part` as seen by `uid`.
+    fn virtual_node_lookup(
+        &self,
+        node: VirtualNode,
+        part: u64,
+        uid: u32,
+        name: &OsStr,
+    ) -> Option<Ino> {
+        match node {
+            VirtualNode::FileTags(_) | VirtualNode::Duplicates(_) | VirtualNode::Complete(_) => self
+                .virtual_node_entries(node, part, uid, 0)
                 .into_iter()
-                .collect::<Vec<_>>();
+                .find(|(_, _, n)| n == name)
+                .map(|(ino, _, _)| ino),
+            // Any file of the sampled directory, the sample differs per `opendir`
+            VirtualNode::Shuffle { .. } => {
+                Some(Ino::from_parts(self.get_fnb_by_shown_name(name)?, part, self.split))
+            }
+        }
+    }
 
-            let to_drain = min(files.len(), offset);
-            files.drain(0..to_drain);
-            offset = offset.saturating_sub(to_drain);
+    /// The files listed in the tag tree directory with inode tag part `dir`.
+    fn dir_files(&self, dir: u64) -> HashSet<FileNumber> {
+        self.tree
+            .lookup(dir)
+            .map(|node| self.calculate_intersection(&node.borrow().collect_tags()))
+            .unwrap_or_default()
+    }
 
-            if offset == 0 {
-                for file in files {
-                    let filename = self.get_fnm_by_number(file).expect("file without a name");
-                    if reply.add(
-                        Ino::from_parts(file, ino.tag()).0,
-                        idx,
-                        RegularFile,
-                        filename,
-                    ) {
-                        break 'full;
-                    }
-                    idx += 1;
+    /// Where the symlink `entry` of a virtual node points to.
+    fn symlink_target(&self, node: VirtualNode, entry: u64) -> Option<FileName> {
+        match node {
+            VirtualNode::Shuffle { .. } | VirtualNode::Duplicates(_) => None,
+            VirtualNode::FileTags(file) => {
+                if !self.tag_content.get(&entry)?.contains(&file) {
+                    return None;
                 }
+
+                // Relative to the mount root, from /.bytag/<file>/<tag>
+                let mut target = OsString::from("../../");
+                target.push(self.tags.get_by_left(&entry)?);
+                Some(target.into())
+            }
+            VirtualNode::Complete(_) => {
+                // Relative to the mount root, from /.tagfs/complete/<prefix>/<tag>. Private tags
+                // are found by their stored name as well
+                let mut target = OsString::from("../../../");
+                target.push(self.tags.get_by_left(&entry)?);
+                Some(target.into())
             }
         }
+    }
 
-        reply.ok()
+    /// The tags a file carries.
+    pub fn tags_of(&self, file: FileNumber) -> Vec<TagNumber> {
+        self.tag_content
+            .iter()
+            .filter(|(_, set)| set.contains(&file))
+            .map(|(&tag, _)| tag)
+            .collect()
+    }
+
+    pub fn omit_file(&mut self, fnb: FileNumber) {
+        self.unshow_file(fnb);
+        self.files.remove_by_left(&fnb);
+        for tag in self.tags_of(fnb) {
+            self.note_members(tag);
+            self.tag_content[&tag].remove(&fnb);
+        }
+        let ordered = self.tag_meta.iter().filter(|(_, meta)| meta.order.contains(&fnb));
+        for tag in ordered.map(|(&tag, _)| tag).collect::<Vec<_>>() {
+            self.tag_meta_mut(tag).order.retain(|&f| f != fnb);
+        }
+        self.note_file_meta(fnb);
+        self.file_meta.remove(&fnb);
+        self.unstamp_file(fnb);
+        self.expiry.retain(|&(_, file), _| file != fnb);
     }
 }
 
-fn create_folder_attrs(ino: Ino) -> FileAttr {
-    FileAttr {
-        ino: ino.0,
-        size: 4096,
-        blocks: 8,
-        atime: UNIX_EPOCH,
-        mtime: UNIX_EPOCH,
-        ctime: UNIX_EPOCH,
-        crtime: UNIX_EPOCH,
-        kind: Directory,
-        perm: 0o700,
-        nlink: 1,
-        uid: 1000,
-        gid: 1000,
-        rdev: 0,
-        blksize: 512,
-        flags: 0,
+impl<B> TagFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    /// Order `files` by the manual order of `tag`, unpinned files go last, sorted by the sort
+    /// order of the tag or the configured default. `seed` keeps a random order stable for the
+    /// lifetime of a directory handle.
+    fn order_files(
+        &self,
+        files: HashSet<FileNumber>,
+        tag: Option<TagNumber>,
+        seed: u64,
+    ) -> Vec<FileNumber> {
+        let meta = tag.and_then(|tag| self.tag_meta(tag));
+        let order = meta.map(|meta| meta.order.as_slice()).unwrap_or_default();
+        let sort = meta.and_then(|meta| meta.sort).unwrap_or(self.config.sort);
+
+        let mut ordered = order
+            .iter()
+            .copied()
+            .filter(|f| files.contains(f))
+            .collect::<Vec<_>>();
+        let pinned = ordered.iter().copied().collect::<HashSet<_>>();
+
+        let mut rest = files
+            .into_iter()
+            .filter(|f| !pinned.contains(f))
+            .collect::<Vec<_>>();
+        self.sort_files(&mut rest, sort, seed);
+        ordered.extend(rest);
+
+        ordered
+    }
+
+    /// Sort `files` by `sort`. Times and sizes are those of the last scan, so listing a large
+    /// tag doesn't stat every file in the source.
+    fn sort_files(&self, files: &mut [FileNumber], sort: SortOrder, seed: u64) {
+        let name = |file: &FileNumber| self.get_fnm_by_number(*file).cloned().unwrap_or_default();
+        let snapshot = |file: &FileNumber| self.file_meta(*file).and_then(|meta| meta.snapshot);
+
+        match sort {
+            SortOrder::Name => files.sort_by_cached_key(name),
+            SortOrder::Mtime => files.sort_by_key(|f| Reverse(snapshot(f).map(|s| s.mtime))),
+            SortOrder::Size => files.sort_by_key(|f| Reverse(snapshot(f).map(|s| s.size))),
+            SortOrder::Random => shuffle(files, seed),
+        }
+    }
+
+    /// The attributes of the entry `ino` of a virtual node.
+    fn virtual_node_attrs(&self, node: VirtualNode, ino: Ino) -> Option<FileAttr> {
+        match node {
+            VirtualNode::FileTags(_) | VirtualNode::Complete(_) => {
+                let target = self.symlink_target(node, ino.file(self.split))?;
+                Some(create_symlink_attrs(ino, &target, self.config.dirs))
+            }
+            VirtualNode::Shuffle { dir, .. } => {
+                if !self.dir_files(dir).contains(&ino.file(self.split)) {
+                    return None;
+                }
+
+                self.file_attr(ino).ok()
+            }
+            VirtualNode::Duplicates(idx) => {
+                if !self.duplicates.get(idx)?.contains(&ino.file(self.split)) {
+                    return None;
+                }
+
+                self.file_attr(ino).ok()
+            }
+        }
+    }
+
+    /// The target of symlink `ino`, if it's one.
+    fn link_target(&self, ino: Ino) -> Option<OsString> {
+        self.virtual_nodes
+            .get_by_left(&ino.tag(self.split))
+            .and_then(|&node| self.symlink_target(node, ino.file(self.split)))
+            .map(OsString::from)
+            .or_else(|| self.root_query_link(ino))
+    }
+
+    /// Take a snapshot of the listing of directory `ino` as seen by `uid`, see `readdir`.
+    fn open_dir(&mut self, ino: Ino, uid: u32) -> Result<u64, c_int> {
+        // The handle seeds the random sort order, keeping it stable while the directory is read
+        let mut fh = RandomState::new().build_hasher().finish();
+        while self.dir_handles.contains_key(&fh) {
+            fh = fh.wrapping_add(1);
+        }
+
+        let listing = self.dir_listing(ino, uid, fh)?;
+        debug!("listing {} entries of {ino:?} in {} bytes", listing.len(), listing.heap_size());
+        self.dir_handles.insert(fh, listing);
+
+        Ok(fh)
+    }
+
+    /// Everything listed in directory `ino` as seen by `uid`, in order: `.`, `..`, tags, virtual
+    /// entries and files. `seed` is the handle of the open directory.
+    fn dir_listing(&mut self, ino: Ino, uid: u32, seed: u64) -> Result<DirListing, c_int> {
+        if ino.is_file(self.split) {
+            return Err(ENOTDIR);
+        }
+
+        if let Some(vd) = VirtualDir::from_ino_part(ino.tag(self.split), self.split) {
+            let mut entries = self.virtual_dir_entries(vd);
+            if vd == VirtualDir::Archives {
+                entries.extend(self.archive_entries(uid));
+            }
+            return Ok(DirListing::new(entries, ino.tag(self.split), vec![]));
+        }
+
+        if let Some(&node) = self.virtual_nodes.get_by_left(&ino.tag(self.split)) {
+            let mut entries = vec![
+                (ino, Directory, ".".into()),
+                (Ino::from_tag(node.parent(self.split)), Directory, "..".into()),
+            ];
+            entries.extend(self.virtual_node_entries(node, ino.tag(self.split), uid, seed));
+
+            return Ok(DirListing::new(entries, ino.tag(self.split), vec![]));
+        }
+
+        // Find the `TagNode` in the tag tree
+        let Some(dir) = self.tree.lookup(ino.tag(self.split)) else { return Err(ENOENT) };
+        if !self.may_read_dir(ino.tag(self.split), uid) {
+            return Err(EACCES);
+        }
+
+        let mut entries = vec![
+            (ino, Directory, ".".into()),
+            (Ino::from_tag(dir.borrow().ino_part), Directory, "..".into()),
+        ];
+
+        let tag = dir.borrow().tag;
+        if self.tags.contains_left(&tag) {
+            self.entered.insert(tag, SystemTime::now());
+        }
+
+        // Dirs to list
+        let used_tags = dir.borrow().collect_tags();
+
+        // Only keep tags that aren't present in the current dir's tag list, nor hidden
+        let mut tags = self
+            .tags
+            .iter()
+            .filter(|(l, _)| !used_tags.contains(l))
+            .filter(|(&l, _)| !self.tag_meta(l).map(|meta| meta.hidden).unwrap_or(false))
+            .filter(|(&l, _)| self.may_read(l, uid))
+            .filter(|(&l, _)| ino != Ino::ROOT || self.lists_at_root(l))
+            .map(|(&l, name)| (l, listed_name(name, uid)))
+            .collect::<Vec<_>>();
+        if self.config.tag_order == TagOrder::Recent {
+            let used_at = |tag| {
+                let used_at = self.tag_meta(tag).and_then(TagMeta::used_at);
+                used_at.max(self.entered.get(&tag).copied())
+            };
+            tags.sort_by_cached_key(|&(tag, _)| Reverse(used_at(tag)));
+        }
+        if ino == Ino::ROOT {
+            self.pinned_first(&mut tags);
+        }
+
+        // Decorate the names with the size of the intersection they lead to
+        if self.config.show_counts {
+            for (tn, name) in tags.iter_mut() {
+                let path = used_tags
+                    .iter()
+                    .copied()
+                    .chain(iter::once(*tn))
+                    .collect::<Vec<_>>();
+                let mut counted = OsString::from(name.clone());
+                counted.push(format!(" ({})", self.count_members(path)));
+                *name = counted.into();
+            }
+        }
+
+        // Turn those into TagNodes, generating them as required
+        for (tn, name) in tags {
+            let part = self.tree.add_to_if_needed(dir.clone(), tn).borrow().ino_part;
+            entries.push((Ino::from_tag(part), Directory, name));
+        }
+        if ino == Ino::ROOT {
+            entries.extend(self.root_query_entries());
+        }
+
+        // Files to list, starting with the virtual entries
+        entries.extend(
+            VirtualDir::ALL
+                .into_iter()
+                .filter(|vd| vd.parent(self.split) == ino.tag(self.split))
+                .map(|vd| (Ino::from_tag(vd.ino_part(self.split)), Directory, vd.name().into())),
+        );
+        entries.extend(self.virtual_files(ino.tag(self.split)).into_iter().map(|vf| {
+            let ino = Ino::from_parts(vf.number(self.split), ino.tag(self.split), self.split);
+            (ino, RegularFile, vf.name().into())
+        }));
+
+        let mut files = self.calculate_intersection(&used_tags);
+        if ino == Ino::ROOT {
+            self.retain_root_files(&mut files);
+        }
+        let files = self.order_files(files, self.node_tag(ino.tag(self.split)), seed);
+
+        Ok(DirListing::new(entries, ino.tag(self.split), files))
+    }
+
+    /// Re-index the file-system, omitting any files not present in the new index,
+    /// but retaining any files that were there before.
+    pub fn repopulate(&mut self, files: impl IntoIterator<Item = FileName>) -> ScanSummary {
+        let names = files.into_iter().collect::<Vec<_>>();
+        let stats = self.backing.stat_all(&names).into_iter().map(Result::ok);
+        let summary = self.index_source(names.into_iter().zip(stats));
+        self.record_scan(summary);
+
+        summary
+    }
+
+    /// Index the files of the source as they were statted, see [TagFS::repopulate], without
+    /// reporting what changed.
+    fn index_source(
+        &mut self,
+        files: impl IntoIterator<Item = (FileName, Option<Stat>)>,
+    ) -> ScanSummary {
+        let mut summary = ScanSummary::default();
+        // By their name in the index, with their name in the source
+        let mut files: HashMap<FileName, (FileName, Option<Stat>)> = files
+            .into_iter()
+            .filter(|(name, stat)| self.is_indexable(name, stat.as_ref().map(|stat| &stat.attr)))
+            .map(|(name, stat)| (name.normalized(), (name, stat)))
+            .collect();
+
+        // Find old files, and remove files that stay from the `files` set
+        let mut gone = vec![];
+        let mut renormalized = vec![];
+        let mut online = vec![];
+        let mut found = vec![];
+        for (&fnb, fnm) in self.files.iter() {
+            if let Some((source_name, stat)) = files.remove(fnm) {
+                found.push((fnb, stat));
+                // Great, this file is retained.
+                summary.retained += 1;
+                if self.is_offline(fnb) {
+                    debug!("'{}' is back online", fnm.to_string_lossy());
+                    online.push(fnb);
+                }
+                if self.source_name(fnb) != Some(&*source_name) {
+                    renormalized.push((fnb, source_name));
+                }
+            } else {
+                gone.push(fnb);
+            }
+        }
+
+        for fnb in online {
+            self.file_meta_mut(fnb).offline = false;
+        }
+
+        // Files renamed in the source to another normalization of the same name
+        for (fnb, source_name) in renormalized {
+            self.set_source_name(fnb, source_name);
+        }
+
+        // A new name with the identity of a gone file is that file, renamed in the source. Inodes
+        // can be reused after a delete, but only a file that vanished in the same scan qualifies.
+        let mut gone = gone
+            .into_iter()
+            .map(|fnb| (self.file_meta(fnb).and_then(|meta| meta.source), fnb))
+            .collect::<Vec<_>>();
+        let mut new = vec![];
+        for (source_name, stat) in files.into_values() {
+            let source = stat.and_then(|stat| stat.source);
+            let renamed = gone
+                .iter()
+                .position(|(id, _)| id.is_some() && *id == source)
+                .map(|idx| gone.swap_remove(idx).1);
+
+            match renamed {
+                Some(fnb) => {
+                    self.rename_found(fnb, source_name);
+                    found.push((fnb, stat));
+                    summary.renamed += 1;
+                }
+                None => new.push((source_name, stat)),
+            }
+        }
+        if self.config.match_renames {
+            summary.matched = self.match_renames(&mut gone, &mut new);
+        }
+        for (_, fnb) in gone {
+            let memberships = self.tag_content.values().filter(|set| set.contains(&fnb)).count();
+            match self.lose_file(fnb) {
+                true => summary.offline += 1,
+                false => {
+                    summary.removed += 1;
+                    summary.dropped += memberships;
+                }
+            }
+        }
+        summary.added = new.len();
+
+        // Everything in `new` is now new: add them as new files
+        if !new.is_empty() {
+            self.scan_tally += 1;
+        }
+        let scan = self.scan_tally;
+        new.into_iter().for_each(|(f, stat)| self.add_found_file(f, stat, scan));
+        self.apply_tag_rules();
+        for (fnb, stat) in found {
+            self.remember_stat(fnb, stat);
+        }
+
+        self.save_or_record();
+
+        summary
+    }
+
+    /// Whether the file `name` of the source belongs in the index: it isn't one of the files of
+    /// tagfs itself, nor filtered out by the config, by its attributes `attr` where it was
+    /// statted.
+    fn is_indexable(&self, name: &OsStr, attr: Option<&FileAttr>) -> bool {
+        let internal = [SAVEFILE, AUDIT_LOG, ERROR_LOG, LOCK_FILE, CONTENT_INDEX, CHECKSUMS];
+        let state = name.to_str().map(|name| internal.iter().any(|base| is_state_file(name, base)));
+        if state.unwrap_or(false)
+            || name.as_bytes().starts_with(SNAPSHOT_PREFIX.as_bytes())
+            || self.config.is_excluded(name)
+        {
+            return false;
+        }
+
+        match attr {
+            Some(fa) if self.config.filters_size() => self.config.admits_size(fa.size),
+            _ => true,
+        }
+    }
+
+    /// Record the name of an indexed file in the source, see [TagFS::source_name].
+    fn set_source_name(&mut self, fnb: FileNumber, source_name: FileName) {
+        let differs = self.get_fnm_by_number(fnb) != Some(&source_name);
+        if differs || self.file_meta(fnb).and_then(|meta| meta.source_name.as_ref()).is_some() {
+            self.file_meta_mut(fnb).source_name = differs.then(|| source_name.into());
+        }
+    }
+
+    /// Move the indexed file `fnb` to the name `source_name` it was found under in the source.
+    fn rename_found(&mut self, fnb: FileNumber, source_name: FileName) {
+        let name = source_name.normalized();
+        if let Some(old) = self.get_fnm_by_number(fnb) {
+            let (old, new) = (old.to_string_lossy(), name.to_string_lossy());
+            debug!("'{old}' was renamed to '{new}'");
+        }
+        self.rename_shown(fnb, name);
+        self.set_source_name(fnb, source_name);
+        self.file_meta_mut(fnb).offline = false;
+    }
+
+    /// Match files gone from the source to new ones with the same size and mtime, when no other
+    /// gone or new file has both, e.g. files renamed on another machine and synced over, whose
+    /// identity in the source changed too. Returns how many were matched.
+    fn match_renames(
+        &mut self,
+        gone: &mut Vec<(Option<SourceId>, FileNumber)>,
+        new: &mut Vec<(FileName, Option<Stat>)>,
+    ) -> usize {
+        // Empty files all look the same
+        let mut was: HashMap<_, Vec<usize>> = HashMap::new();
+        for (idx, &(_, fnb)) in gone.iter().enumerate() {
+            let snapshot = self.file_meta(fnb).and_then(|meta| meta.snapshot);
+            if let Some(snapshot) = snapshot.filter(|snapshot| snapshot.size > 0) {
+                was.entry((snapshot.size, snapshot.mtime)).or_default().push(idx);
+            }
+        }
+        let mut now: HashMap<_, Vec<usize>> = HashMap::new();
+        for (idx, (_, stat)) in new.iter().enumerate() {
+            if let Some(attr) = stat.map(|stat| stat.attr).filter(|attr| attr.size > 0) {
+                now.entry((attr.size, attr.mtime)).or_default().push(idx);
+            }
+        }
+
+        let mut gone_left = std::mem::take(gone).into_iter().map(Some).collect::<Vec<_>>();
+        let mut new_left = std::mem::take(new).into_iter().map(Some).collect::<Vec<_>>();
+        let mut matched = 0;
+        for (key, was) in was {
+            let (&[old], Some(&[found])) = (was.as_slice(), now.get(&key).map(Vec::as_slice)) else {
+                continue;
+            };
+            let (Some((_, fnb)), Some((name, Some(stat)))) =
+                (gone_left[old].take(), new_left[found].take())
+            else {
+                continue;
+            };
+
+            self.rename_found(fnb, name);
+            self.remember_stat(fnb, Some(stat));
+            matched += 1;
+        }
+        *gone = gone_left.into_iter().flatten().collect();
+        *new = new_left.into_iter().flatten().collect();
+
+        if matched > 0 {
+            info!("matched {matched} renamed file(s) by their size and mtime");
+        }
+
+        matched
+    }
+
+    /// Remember what file `fnb` looked like when the scan found it, for when the source becomes
+    /// unreachable, and its identity, which changes when a file is replaced, e.g. by an editor
+    /// saving a copy over it.
+    fn remember_stat(&mut self, fnb: FileNumber, stat: Option<Stat>) {
+        let Some(Stat { attr, source }) = stat else { return };
+        let meta = self.file_meta_mut(fnb);
+        meta.snapshot = Some(FileSnapshot {
+            size: attr.size,
+            mtime: attr.mtime,
+            perm: attr.perm,
+        });
+        if source.is_some() {
+            meta.source = source;
+        }
+    }
+
+    /// Index a file found by scan number `scan`, into the inbox if there is one, with the tags
+    /// other tools gave it when importing those.
+    fn add_found_file(&mut self, name: FileName, stat: Option<Stat>, scan: u64) {
+        debug!("adding new file '{}'", name.to_string_lossy());
+
+        let fnb = self.add_file(name);
+        let meta = self.file_meta_mut(fnb);
+        meta.indexed_at = Some(SystemTime::now());
+        meta.scan = scan;
+        self.remember_stat(fnb, stat);
+        if let Some(inbox) = self.config.inbox.clone() {
+            let inbox = self.get_or_create_tag(inbox.into());
+            self.add_file_to(fnb, inbox);
+        }
+        if self.config.import_xattr_tags {
+            self.import_xattr_tags(fnb);
+        }
+    }
+
+    /// Tag the indexed files matching the rules of `--tag`, creating their tags.
+    fn apply_tag_rules(&mut self) {
+        let rules = self.config.tag_rules.clone();
+        for rule in rules.iter() {
+            let tag = self.get_or_create_tag(rule.tag.as_str().into());
+            let matching = self
+                .files
+                .iter()
+                .filter(|&(&fnb, name)| rule.matches(name) && !self.has_tag(fnb, tag))
+                .map(|(&fnb, _)| fnb)
+                .collect::<Vec<_>>();
+            if !matching.is_empty() {
+                info!("tagged {} file(s) '{}' by --tag", matching.len(), rule.tag);
+            }
+
+            matching.into_iter().for_each(|fnb| self.add_file_to(fnb, tag));
+        }
+    }
+
+    /// Deal with a file that's gone from the source. Tagged files are kept offline, since they
+    /// might be on a drive that isn't plugged in; others are omitted. Returns whether it was kept.
+    fn lose_file(&mut self, fnb: FileNumber) -> bool {
+        let name = self.get_fnm_by_number(fnb).cloned().unwrap_or_default();
+
+        if self.tag_content.values().any(|v| v.contains(&fnb)) {
+            if !self.is_offline(fnb) {
+                warn!("'{}' is missing, keeping it offline", name.to_string_lossy());
+            }
+            self.file_meta_mut(fnb).offline = true;
+            true
+        } else {
+            debug!("removing '{}'", name.to_string_lossy());
+            self.omit_file(fnb);
+            false
+        }
+    }
+
+    /// Drop a file that vanished from the source while mounted, according to `errno` from an
+    /// operation on it. The kernel doesn't cache entries (see `TTL`), so there's nothing to
+    /// invalidate.
+    fn check_vanished(&mut self, fnb: FileNumber, errno: c_int) {
+        if self.is_gone(errno) {
+            self.lose_file(fnb);
+        }
+    }
+
+    /// Whether an operation failed with `errno` because the file is gone from the source, rather
+    /// than because the whole source is unreachable or for any other reason.
+    fn is_gone(&self, errno: c_int) -> bool {
+        (errno == ENOENT || errno == ESTALE) && self.source_reachable()
+    }
+
+    /// Whether the root of the source can be found, which it can't when the whole source is
+    /// gone, e.g. an unplugged drive.
+    fn source_reachable(&self) -> bool {
+        self.backing.exists("")
+    }
+
+    /// The attributes of an indexed file from the backing fs, presented under `ino`.
+    fn file_attr(&self, ino: Ino) -> Result<FileAttr, c_int> {
+        let name = self.source_name(ino.file(self.split)).ok_or(ENOENT)?;
+
+        self.presented_attr(ino, name, self.backing.get_metadata(name))
+    }
+
+    /// Present `attr`, the attributes of the indexed file `ino` called `name` in the source,
+    /// under `ino`. When the source is unreachable, the file is presented like it last was.
+    fn presented_attr(
+        &self,
+        ino: Ino,
+        name: &OsStr,
+        attr: Result<FileAttr, B::Error>,
+    ) -> Result<FileAttr, c_int> {
+        let mut fa = match attr {
+            Ok(fa) => fa,
+            Err(e) => {
+                let errno = errno_of(&e, EIO);
+                let snapshot = self.file_meta(ino.file(self.split)).and_then(|meta| meta.snapshot);
+                if let Some(snapshot) = snapshot.filter(|_| !self.source_reachable()) {
+                    return Ok(create_snapshot_attrs(ino, snapshot, self.config.dirs));
+                }
+                if errno != ENOENT {
+                    let name = name.to_string_lossy();
+                    error!("Failed to get metadata for '{name}' from backing fs");
+                }
+                return Err(errno);
+            }
+        };
+
+        fa.ino = ino.0;
+        fa.uid = self.config.map_uid(fa.uid);
+        fa.gid = self.config.map_gid(fa.gid);
+        Ok(fa)
+    }
+
+    /// The attributes of `ino`, see `getattr`.
+    fn get_attr(&mut self, ino: Ino) -> Result<FileAttr, c_int> {
+        if ino.is_tag(self.split) {
+            Ok(create_folder_attrs(ino, self.config.dirs))
+        } else if let Some(target) = self.root_query_link(ino) {
+            Ok(create_symlink_attrs(ino, &target, self.config.dirs))
+        } else if ino.is_virtual(self.split) {
+            let file = VirtualFile::from_number(ino.file(self.split), self.split).ok_or(ENOENT)?;
+            let size = self.virtual_size(file, ino.tag(self.split)).ok_or(ENOENT)?;
+
+            Ok(create_virtual_attrs(ino, size, self.split, self.config.dirs))
+        } else if let Some(&node) = self.virtual_nodes.get_by_left(&ino.tag(self.split)) {
+            self.virtual_node_attrs(node, ino).ok_or(ENOENT)
+        } else if ino.tag(self.split) == VirtualDir::Missing.ino_part(self.split) {
+            if !self.is_offline(ino.file(self.split)) {
+                return Err(ENOENT);
+            }
+
+            Ok(create_virtual_attrs(ino, 0, self.split, self.config.dirs))
+        } else if ino.tag(self.split) == VirtualDir::Archives.ino_part(self.split) {
+            if !self.has_archive(ino.file(self.split)) {
+                return Err(ENOENT);
+            }
+
+            Ok(self.archive_attrs(ino.file(self.split)))
+        } else {
+            if self.is_offline(ino.file(self.split)) {
+                return Err(ENOENT);
+            }
+
+            let attr = self.file_attr(ino);
+            if let Err(errno) = attr {
+                self.check_vanished(ino.file(self.split), errno);
+            }
+            attr
+        }
+    }
+
+    /// Whether [TagFS::get_attr] of `ino` stats an indexed file in the source.
+    fn stats_source(&self, ino: Ino) -> bool {
+        ino.is_file(self.split)
+            && !ino.is_virtual(self.split)
+            && !self.virtual_nodes.contains_left(&ino.tag(self.split))
+            && ino.tag(self.split) != VirtualDir::Missing.ino_part(self.split)
+            && ino.tag(self.split) != VirtualDir::Archives.ino_part(self.split)
+            && !self.is_offline(ino.file(self.split))
+    }
+
+    /// [TagFS::get_attr] of every inode, in order, `None` for those that are gone. The files are
+    /// statted in the source at once, see [BackingFS::get_metadata_many].
+    fn get_attrs(&mut self, inos: &[Ino]) -> Vec<Option<FileAttr>> {
+        let sources = inos
+            .iter()
+            .map(|&ino| {
+                let name = self.source_name(ino.file(self.split));
+                let name = name.filter(|_| self.stats_source(ino));
+                name.map(FileName::from)
+            })
+            .collect::<Vec<_>>();
+        let names = sources.iter().flatten().collect::<Vec<_>>();
+        let mut stats = self.backing.get_metadata_many(&names).into_iter();
+
+        let mut attrs = Vec::with_capacity(inos.len());
+        for (&ino, source) in inos.iter().zip(sources.iter()) {
+            let stat = source.as_ref().and_then(|name| Some((name, stats.next()?)));
+            let attr = match stat {
+                Some((name, stat)) => {
+                    let attr = self.presented_attr(ino, name, stat);
+                    if let Err(errno) = attr {
+                        self.check_vanished(ino.file(self.split), errno);
+                    }
+                    attr
+                }
+                None => self.get_attr(ino),
+            };
+            attrs.push(attr.ok());
+        }
+
+        attrs
+    }
+
+    /// Open `ino` for `uid`, returning the handle and the flags of the reply, see `open`.
+    fn open_file(&mut self, ino: Ino, flags: i32, uid: u32) -> Result<(u64, u32), c_int> {
+        if ino.is_tag(self.split) {
+            return Err(match flags & O_CREAT != 0 && flags & O_DIRECTORY != 0 {
+                true => EINVAL,
+                false => EISDIR,
+            });
+        }
+        // The kernel follows symlinks before opening, one only gets here with O_NOFOLLOW
+        if self.link_target(ino).is_some() {
+            return Err(ELOOP);
+        }
+
+        let writable = self.is_writable_file(ino);
+        check_open_flags(flags, writable)?;
+        if writable && flags & O_ACCMODE != O_RDONLY && !self.may_control(uid) {
+            return Err(EACCES);
+        }
+
+        if ino.tag(self.split) == VirtualDir::Archives.ino_part(self.split) {
+            let tag = ino.file(self.split);
+            if !self.has_archive(tag) || !self.may_read(tag, uid) {
+                return Err(ENOENT);
+            }
+
+            let archive = self.archive(tag);
+            return Ok((self.open_archive(archive).0, FOPEN_DIRECT_IO));
+        }
+
+        if ino.is_virtual(self.split) {
+            let content = self.virtual_content_of(ino).ok_or(ENOENT)?;
+
+            // The content is generated per handle, so the page cache would serve stale versions,
+            // and reports may claim a size of 0
+            return Ok((self.open_virtual(content).0, FOPEN_DIRECT_IO));
+        }
+
+        let filename = self.source_name(ino.file(self.split)).ok_or(ENOENT)?;
+        if self.is_offline(ino.file(self.split)) {
+            return Err(ENOENT);
+        }
+
+        match self.backing.open(filename) {
+            Ok(fh) => {
+                let flags = match self.config.file_cache {
+                    // The source may change behind our back, so let the kernel revalidate on open
+                    FileCache::Revalidate => 0,
+                    FileCache::Keep => FOPEN_KEEP_CACHE,
+                    FileCache::Direct => FOPEN_DIRECT_IO,
+                };
+                // Seeking fails with ESPIPE right away, rather than reads failing midway
+                let flags = match self.backing.capabilities().streaming_only {
+                    true => flags | FOPEN_NONSEEKABLE,
+                    false => flags,
+                };
+                Ok((fh.0, flags))
+            }
+            Err(e) => {
+                let what = format!("open '{}'", filename.to_string_lossy());
+                let errno = errno_of(&e, EIO);
+                self.check_vanished(ino.file(self.split), errno);
+                // The file is only known from its snapshot, there's nothing to read
+                match !self.source_reachable() {
+                    true => {
+                        self.record_error(&what, &e);
+                        Err(EIO)
+                    }
+                    false => {
+                        debug!("{what}: {e}");
+                        Err(errno)
+                    }
+                }
+            }
+        }
+    }
+
+    /// The extended attributes of `ino` for `listxattr`, as a sequence of null-terminated names:
+    /// the passthrough attributes of indexed files, or the metadata of tags. Fails like reading
+    /// the attributes of the file from the source does.
+    fn list_xattrs(&self, ino: Ino) -> Result<Vec<u8>, c_int> {
+        if ino.is_file(self.split) && !ino.is_virtual(self.split) {
+            let file = self.source_name(ino.file(self.split)).ok_or(ENOENT)?;
+            // Sources without extended attributes have none to list
+            let names = match self.backing.capabilities().xattrs {
+                true => self.backing.listxattr(file).map_err(|e| errno_of(&e, EIO))?,
+                false => vec![],
+            };
+
+            return Ok(names
+                .into_iter()
+                .filter(|name| is_passthrough_xattr(name))
+                .flat_map(|name| [name.as_bytes(), b"\0"].concat())
+                .collect());
+        }
+
+        Ok(self
+            .meta_for_ino(ino)
+            .map(|meta| meta.entries())
+            .unwrap_or_default()
+            .into_iter()
+            .flat_map(|(key, _)| format!("{XATTR_PREFIX}{key}\0").into_bytes())
+            .collect())
+    }
+
+    /// Whether file `ino` can be opened for writing, which only the control file can.
+    fn is_writable_file(&self, ino: Ino) -> bool {
+        !self.config.read_only
+            && self.backing.capabilities().writable
+            && ino.is_virtual(self.split)
+            && VirtualFile::from_number(ino.file(self.split), self.split)
+                .map(VirtualFile::is_writable)
+                .unwrap_or(false)
+    }
+
+    /// Check whether `uid` may access `ino` as `mask` asks, see `access`. Looking up and reading
+    /// need the tags on the path to be readable to `uid`. Writing needs a directory of the tree
+    /// that isn't below a locked tag, on a mount that takes changes, or the control file, which
+    /// only those who may control the mount can write.
+    fn check_access(&self, ino: Ino, mask: i32, uid: u32) -> Result<(), c_int> {
+        let dir = ino.tag(self.split);
+        if !self.may_read_dir(dir, uid) {
+            return Err(EACCES);
+        }
+        if mask & W_OK == 0 {
+            return Ok(());
+        }
+
+        if ino.is_file(self.split) {
+            return match self.is_writable_file(ino) {
+                true if self.may_control(uid) => Ok(()),
+                true => Err(EACCES),
+                false => Err(EROFS),
+            };
+        }
+        if let Some(errno) = self.refuses_changes() {
+            return Err(errno);
+        }
+        if self.tree.lookup(dir).is_none() {
+            return Err(EROFS);
+        }
+        if self.is_locked_dir(dir) {
+            return Err(EACCES);
+        }
+
+        Ok(())
+    }
+
+    /// Find `name` in directory `parent` as seen by `uid`, see `lookup`.
+    fn lookup_entry(&mut self, parent_ino: Ino, name: &OsStr, uid: u32) -> Result<FileAttr, c_int> {
+        if parent_ino.is_file(self.split) {
+            return Err(ENOTDIR);
+        }
+        let dir = parent_ino.tag(self.split);
+
+        if let Some(vd) = VirtualDir::find(dir, name, self.split) {
+            let ino = Ino::from_tag(vd.ino_part(self.split));
+            return Ok(create_folder_attrs(ino, self.config.dirs));
+        }
+
+        if let Some(vf) = VirtualFile::from_name(name) {
+            if let Some(size) = self.virtual_size(vf, dir) {
+                let ino = Ino::from_parts(vf.number(self.split), dir, self.split);
+                return Ok(create_virtual_attrs(ino, size, self.split, self.config.dirs));
+            }
+        }
+
+        if parent_ino == Ino::ROOT {
+            if let Some((ino, target)) = self.root_query(name) {
+                return Ok(create_symlink_attrs(ino, &target, self.config.dirs));
+            }
+        }
+
+        if let Some(&node) = self.virtual_nodes.get_by_left(&dir) {
+            let ino = self.virtual_node_lookup(node, dir, uid, name).ok_or(ENOENT)?;
+
+            return self.virtual_node_attrs(node, ino).ok_or(ENOENT);
+        }
+
+        if let Some(vd) = VirtualDir::from_ino_part(dir, self.split) {
+            if let Some(ino) = self.lookup_virtual_subdir(vd, name) {
+                return Ok(create_folder_attrs(ino, self.config.dirs));
+            }
+            if vd == VirtualDir::Archives {
+                let tag = self.archive_tag(name, uid).ok_or(ENOENT)?;
+                return Ok(self.archive_attrs(tag));
+            }
+
+            let file = self.get_fnb_by_shown_name(name).ok_or(ENOENT)?;
+            if !self.virtual_dir_contains(vd, file) {
+                return Err(ENOENT);
+            }
+
+            // There's nothing to stat, show an empty placeholder
+            if vd == VirtualDir::Missing {
+                let ino = Ino::from_parts(file, dir, self.split);
+                return Ok(create_virtual_attrs(ino, 0, self.split, self.config.dirs));
+            }
+
+            return self.file_attr(Ino::from_parts(file, dir, self.split));
+        }
+
+        // Find the `TagNode` in the tag tree
+        let parent = self.tree.lookup(dir).ok_or(ENOENT)?;
+        if !self.may_read_dir(dir, uid) {
+            return Err(EACCES);
+        }
+
+        if let Some(count) = VirtualNode::shuffle_count(name) {
+            let ino = Ino::from_tag(self.virtual_node_part(VirtualNode::Shuffle { dir, count }));
+            return Ok(create_folder_attrs(ino, self.config.dirs));
+        }
+
+        let file = match self.get_fnb_by_shown_name(name) {
+            Some(file) => file, // Great, it's a file!
+            None => {
+                // Great, it's not a file, but it might be a tag, or a range over value tags,
+                // which exists whenever it parses, like a search.
+                // It's not a file and not a tag; get out!
+                let tn = match self.get_tnb_by_listed_name(name, uid) {
+                    Some(tn) => tn,
+                    None => {
+                        let range = name.to_str().and_then(ValueRange::parse).ok_or(ENOENT)?;
+                        self.pseudo_tag(PseudoTag::Range(range))
+                    }
+                };
+                if !self.may_read(tn, uid) {
+                    return Err(EACCES);
+                }
+
+                let node = self.tree.add_to_if_needed(parent, tn);
+                let ino = Ino::from_tag(node.borrow().ino_part);
+                return Ok(create_folder_attrs(ino, self.config.dirs));
+            }
+        };
+
+        let path = parent.borrow().collect_tags();
+        // For the lookup to pass, `file` must be present in each of the tags in the path
+        if self.is_offline(file) || !path.into_iter().all(|tag| self.has_tag(file, tag)) {
+            return Err(ENOENT);
+        }
+
+        let result = self.file_attr(Ino::from_parts(file, dir, self.split));
+        if let Err(errno) = result {
+            self.check_vanished(file, errno);
+        }
+
+        result
+    }
+
+    /// Create the tag `name` on behalf of `actor`, see `mkdir`.
+    fn make_tag(&mut self, parent: Ino, name: &OsStr, actor: Actor) -> Result<FileAttr, c_int> {
+        if name == ".Trash-1000" {
+            return Err(ENOTSUP);
+        }
+        if VirtualDir::is_reserved(name) || self.get_tnb_by_listed_name(name, actor.uid).is_some() {
+            return Err(EEXIST);
+        }
+        let declared = is_ephemeral_name(name) || self.is_declared(name);
+        if self.is_locked_dir(parent.tag(self.split)) || !declared {
+            return Err(EPERM);
+        }
+        let uid = actor.uid;
+        let tnb = self.create_tag(private_name(name, uid).unwrap_or_else(|| name.into()).into());
+        self.name_tag_as(tnb, name, uid)?;
+        if is_ephemeral_name(name) {
+            self.make_ephemeral(tnb);
+        }
+        self.audit(actor, "create-tag", &self.tag_path(&[tnb]));
+
+        Ok(create_folder_attrs(Ino::from_tag(tnb), self.config.dirs))
+    }
+
+    /// Remove the file `name` from the tags of directory `parent`, see `unlink`.
+    fn untag(&mut self, parent: Ino, name: &OsStr, actor: Actor) -> Result<(), c_int> {
+        if self.is_locked_dir(parent.tag(self.split)) {
+            return Err(EPERM);
+        }
+        let parent = self.tree.lookup(parent.tag(self.split)).ok_or(ENOENT)?;
+
+        // should we check if the file actually even exists under this tag?
+        // the operation will succeed without, but do nothing.
+        let file = self.get_fnb_by_shown_name(name).ok_or(ENOENT)?;
+
+        let tags = parent.borrow().collect_tags();
+        for &tag in tags.iter() {
+            self.remove_file_from(file, tag);
+        }
+
+        let detail = format!("'{}' from {}", name.to_string_lossy(), self.tag_path(&tags));
+        self.audit(actor, "untag", &detail);
+
+        Ok(())
+    }
+
+    /// Remove the empty tag `name` in directory `parent`, see `rmdir`. The tag is found in the
+    /// parent like [TagFS::rename_source] does, but removing it removes it everywhere.
+    fn remove_dir(&mut self, parent: Ino, name: &OsStr, actor: Actor) -> Result<(), c_int> {
+        let tag = match self.rename_source(parent, name, actor.uid)? {
+            Renamed::Tag(tag) => tag,
+            Renamed::File(_) => return Err(ENOTDIR),
+        };
+        if self.is_locked(tag) {
+            return Err(EPERM);
+        }
+
+        let path = self.tag_path(&[tag]);
+        self.remove_tag(tag, None, false).map_err(|error| error.errno())?;
+        self.audit(actor, "remove-tag", &path);
+
+        Ok(())
+    }
+
+    /// Rename a tag, or move a file between directories, see `rename`.
+    fn rename_entry(
+        &mut self,
+        parent: Ino,
+        name: &OsStr,
+        newparent: Ino,
+        newname: &OsStr,
+        actor: Actor,
+    ) -> Result<(), c_int> {
+        let split = self.split;
+        if self.is_locked_dir(parent.tag(split)) || self.is_locked_dir(newparent.tag(split)) {
+            return Err(EPERM);
+        }
+
+        let file = match self.rename_source(parent, name, actor.uid)? {
+            Renamed::File(file) => file,
+            // If we're renaming a tag, the new parent doesn't matter
+            Renamed::Tag(tag) => return self.rename_tag(tag, newname, actor),
+        };
+
+        if let Some(directives) = tag_directives(name, newname) {
+            if parent != newparent {
+                return Err(ENOTSUP);
+            }
+
+            return self.apply_directives(file, &directives, actor);
+        }
+
+        // If we're moving a file, disallow renames
+        if name != newname || parent == newparent {
+            return Err(ENOTSUP);
+        }
+
+        let parent = self.tree.lookup(parent.tag(self.split)).ok_or(ENOENT)?;
+        let newparent = self.tree.lookup(newparent.tag(self.split)).ok_or(ENOENT)?;
+
+        let oldtags = parent.borrow().collect_tags();
+        let newtags = newparent.borrow().collect_tags();
+        if self.crosses_groups(&newtags) {
+            return Err(EINVAL);
+        }
+        let added = newtags.iter().filter(|tag| !oldtags.contains(tag));
+        if !added.clone().all(|&tag| self.may_apply(tag)) {
+            return Err(EPERM);
+        }
+
+        // Tags both directories have are left alone, so the file never loses them along the way
+        for &tag in oldtags.iter().filter(|tag| !newtags.contains(tag)) {
+            self.remove_file_from(file, tag);
+        }
+        for &tag in added {
+            self.add_file_to(file, tag);
+        }
+
+        let detail = format!(
+            "'{}' from {} to {}",
+            name.to_string_lossy(),
+            self.tag_path(&oldtags),
+            self.tag_path(&newtags)
+        );
+        self.audit(actor, "move", &detail);
+
+        Ok(())
+    }
+
+    /// What `name` is in the directory `parent`, resolved like `lookup_entry` does: a file of the
+    /// intersection wins over a tag of the same name, since that's what the directory shows. Tags
+    /// on the path of `parent` aren't in it.
+    fn rename_source(&self, parent: Ino, name: &OsStr, uid: u32) -> Result<Renamed, c_int> {
+        let file = self.get_fnb_by_shown_name(name);
+        let Some(dir) = self.tree.lookup(parent.tag(self.split)) else {
+            // Virtual directories only hold files
+            return file.map(Renamed::File).ok_or(ENOENT);
+        };
+
+        let path = dir.borrow().collect_tags();
+        let listed = |file: &FileNumber| {
+            !self.is_offline(*file) && path.iter().all(|&tag| self.has_tag(*file, tag))
+        };
+        if let Some(file) = file.filter(listed) {
+            return Ok(Renamed::File(file));
+        }
+
+        match self.get_tnb_by_listed_name(name, uid) {
+            Some(tag) if !path.contains(&tag) => Ok(Renamed::Tag(tag)),
+            _ => Err(ENOENT),
+        }
+    }
+
+    fn rename_tag(&mut self, tag: TagNumber, newname: &OsStr, actor: Actor) -> Result<(), c_int> {
+        if self.is_locked(tag) || !(self.is_ephemeral(tag) || self.is_declared(newname)) {
+            return Err(EPERM);
+        }
+        // Renaming onto a virtual dir would hide the tag
+        if VirtualDir::is_reserved(newname) {
+            return Err(EEXIST);
+        }
+        // Renaming onto another tag would take its name, merging is done explicitly
+        let existing = self.get_tnb_by_listed_name(newname, actor.uid);
+        if let Some(existing) = existing.filter(|&existing| existing != tag) {
+            let name = self.tags.get_by_left(&tag).map(|name| listed_name(name, actor.uid));
+            info!(
+                "not renaming {} onto the tag {}, merge them with `rm-tag {} --move-to {}`",
+                self.tag_path(&[tag]),
+                self.tag_path(&[existing]),
+                name.unwrap_or_default().to_string_lossy(),
+                newname.to_string_lossy()
+            );
+            return Err(EEXIST);
+        }
+
+        let old = self.tag_path(&[tag]);
+        self.name_tag_as(tag, newname, actor.uid)?;
+        let detail = format!("{old} to {}", self.tag_path(&[tag]));
+        self.audit(actor, "rename-tag", &detail);
+
+        Ok(())
+    }
+
+    /// Add `file` to the tags of `directives` that are `true` and remove it from the others, see
+    /// `tag_directives`. Tags to add are created when they don't exist yet.
+    fn apply_directives(
+        &mut self,
+        file: FileNumber,
+        directives: &[(bool, &OsStr)],
+        actor: Actor,
+    ) -> Result<(), c_int> {
+        // Check them all first, so a rename either applies every directive or none
+        for &(add, name) in directives {
+            match self.get_tnb_by_listed_name(name, actor.uid) {
+                Some(tag) if self.is_locked(tag) => return Err(EPERM),
+                Some(tag) if add && !self.may_apply(tag) => return Err(EPERM),
+                None if add && VirtualDir::is_reserved(name) => return Err(EEXIST),
+                None if add && !self.is_declared(name) => return Err(EPERM),
+                _ => {}
+            }
+        }
+
+        for &(add, name) in directives {
+            let tag = self.get_tnb_by_listed_name(name, actor.uid);
+            match (tag, add) {
+                (Some(tag), true) => self.add_file_to(file, tag),
+                (Some(tag), false) => self.remove_file_from(file, tag),
+                (None, true) => {
+                    let stored = private_name(name, actor.uid).unwrap_or_else(|| name.into());
+                    let tag = self.create_tag(stored.into());
+                    self.name_tag_as(tag, name, actor.uid)?;
+                    self.add_file_to(file, tag);
+                }
+                (None, false) => {}
+            }
+        }
+
+        let name = self.get_fnm_by_number(file).cloned().unwrap_or_default();
+        let detail = directives
+            .iter()
+            .map(|&(add, tag)| format!(" {}{}", if add { '+' } else { '-' }, tag.to_string_lossy()))
+            .collect::<String>();
+        self.audit(actor, "retag", &format!("'{}'{detail}", name.to_string_lossy()));
+
+        Ok(())
+    }
+
+    /// Whether `uid` may run commands through `/.tagfs/control`: the owner of the mount and root.
+    fn may_control(&self, uid: u32) -> bool {
+        uid == 0 || uid == self.config.dirs.uid
+    }
+
+    /// Run the commands written to `/.tagfs/control` through `handle`, one per line. Stops at the
+    /// first command that fails, which is recorded for `/.tagfs/errors`.
+    ///
+    /// Commands between `begin` and `commit` lines form a transaction, which may span writes: the
+    /// state is saved and audited once, on commit. A failing command or a `rollback` line undoes
+    /// the whole transaction, as does closing the handle before committing.
+    ///
+    /// A `freeze` line makes the mount read-only until a `thaw` line, see [TagFS::freeze].
+    fn run_control(
+        &mut self,
+        data: &[u8],
+        actor: Actor,
+        handle: FileHandle,
+    ) -> Result<(), TagError> {
+        self.control_report.clear();
+
+        let text = String::from_utf8_lossy(data);
+        let lines = text.lines().map(str::trim);
+        for line in lines.filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let result = match line {
+                "begin" => self.begin(handle),
+                "commit" => self.commit(handle, actor),
+                "rollback" => self.roll_back(handle),
+                "freeze" => self.freeze(),
+                "thaw" => self.thaw(),
+                line => self.run_control_line(line, actor, handle),
+            };
+
+            match result {
+                Ok(report) => {
+                    self.control_report.push_str(report.trim_end());
+                    self.control_report.push('\n');
+                }
+                Err(error) => {
+                    if self.transaction.as_ref().map(|tx| tx.handle) == Some(handle) {
+                        self.roll_back(handle)?;
+                    }
+                    self.record_error(&format!("control '{line}'"), &error);
+                    return Err(error);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn run_control_line(
+        &mut self,
+        line: &str,
+        actor: Actor,
+        handle: FileHandle,
+    ) -> Result<String, TagError> {
+        let command = ControlCommand::parse_line(line)?;
+        if command.takes_path() {
+            return Err(TagError::TakesPath);
+        }
+        self.check_control_tags(&command, actor.uid)?;
+        let read_only = command.is_read_only();
+        if !read_only && self.transaction.as_ref().is_some_and(|tx| tx.handle != handle) {
+            return Err(TagError::Busy);
+        }
+        if !read_only && self.frozen {
+            return Err(TagError::Frozen);
+        }
+
+        let what = format!("{command:?}");
+        let report = self.execute(command)?;
+        if !read_only {
+            let detail = command_detail(&what, &report);
+            match self.transaction.as_mut() {
+                Some(tx) => tx.audits.push(detail),
+                None => self.audit(actor, "command", &detail),
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Refuse a command on tags `uid` may not enter, as if they didn't exist like for a lookup,
+    /// and changes to locked tags, like mkdir, unlink and rename do. Locking and unlocking are let
+    /// through, they're how a lock is lifted.
+    fn check_control_tags(&self, command: &ControlCommand, uid: u32) -> Result<(), TagError> {
+        let unlocking = matches!(
+            command,
+            ControlCommand::Lock { .. } | ControlCommand::Unlock { .. }
+        );
+        let changes = !command.is_read_only() && !unlocking;
+        for name in command.tag_names() {
+            let Some(tag) = self.get_tnb_by_name(name) else { continue };
+            if !self.may_read(tag, uid) {
+                return Err(TagError::NoSuchTag(name.into()));
+            }
+            if changes && self.is_locked(tag) {
+                return Err(TagError::Locked(name.into()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run the complete lines written to `/.tagfs/control` through `handle` so far, keeping the
+    /// rest until its newline arrives, or until the handle is closed when `all` is set.
+    fn run_control_lines(
+        &mut self,
+        data: &[u8],
+        actor: Actor,
+        handle: FileHandle,
+        all: bool,
+    ) -> Result<(), c_int> {
+        let pending = self.control_lines.entry(handle).or_default();
+        pending.extend_from_slice(data);
+        let end = match all {
+            true => pending.len(),
+            false => pending.iter().rposition(|&byte| byte == b'\n').map_or(0, |end| end + 1),
+        };
+        let lines = pending.drain(..end).collect::<Vec<_>>();
+        if pending.is_empty() {
+            self.control_lines.remove(&handle);
+        }
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        if !self.holds_changes() {
+            self.begin_batch();
+        }
+        let ran = self.run_control(&lines, actor, handle);
+        // Earlier lines of a batch may have succeeded
+        let saved = self.save_batch();
+        ran.map_err(|error| error.errno())?;
+        saved
+    }
+
+    fn begin(&mut self, handle: FileHandle) -> Result<String, TagError> {
+        match &self.transaction {
+            Some(tx) if tx.handle == handle => {
+                Err(TagError::Transaction("a transaction is already open"))
+            }
+            Some(_) => Err(TagError::Busy),
+            None => {
+                self.transaction = Some(Transaction { handle, audits: vec![] });
+                // Its commands are recorded as one step, once it's committed
+                self.journal = Some(Journal::default());
+
+                Ok("began a transaction".to_string())
+            }
+        }
+    }
+
+    /// Save any pending changes, then refuse changes through the mount and its commands with
+    /// EROFS, and hold back saves, until [TagFS::thaw]. The savefile and logs stay as they are
+    /// meanwhile, for a backup, fsck or compaction of the state that shouldn't see them torn.
+    fn freeze(&mut self) -> Result<String, TagError> {
+        if self.frozen {
+            return Err(TagError::Transaction("the mount is already frozen"));
+        }
+        if self.transaction.is_some() {
+            return Err(TagError::Busy);
+        }
+
+        self.save_or_record();
+        self.frozen = true;
+        Ok("froze the mount".to_string())
+    }
+
+    /// Accept changes again after [TagFS::freeze], saving what a scan or expiry changed since.
+    fn thaw(&mut self) -> Result<String, TagError> {
+        if !self.frozen {
+            return Err(TagError::Transaction("the mount isn't frozen"));
+        }
+
+        self.frozen = false;
+        self.save_or_record();
+        Ok("thawed the mount".to_string())
+    }
+
+    /// End the transaction of `handle`, failing if it has none.
+    fn take_transaction(&mut self, handle: FileHandle) -> Result<Transaction, TagError> {
+        match &self.transaction {
+            Some(tx) if tx.handle == handle => Ok(self.transaction.take().expect("checked above")),
+            _ => Err(TagError::Transaction("no transaction is open")),
+        }
+    }
+
+    fn commit(&mut self, handle: FileHandle, actor: Actor) -> Result<String, TagError> {
+        let tx = self.take_transaction(handle)?;
+        for detail in tx.audits.iter() {
+            self.audit(actor, "command", detail);
+        }
+
+        let report = format!("committed {} command(s)", tx.audits.len());
+        self.record_step(report.clone());
+
+        Ok(report)
+    }
+
+    fn roll_back(&mut self, handle: FileHandle) -> Result<String, TagError> {
+        let tx = self.take_transaction(handle)?;
+        if let Some(journal) = self.journal.take() {
+            self.revert_journal(journal);
+        }
+        self.events.clear();
+
+        Ok(format!("rolled back {} command(s)", tx.audits.len()))
+    }
+
+    /// The errno changes through the mount are refused with, if they are: EROFS because a
+    /// snapshot is mounted, the mount is frozen or the source isn't writable, or EBUSY while a
+    /// transaction of the control file is open, as rolling it back would undo them too.
+    fn refuses_changes(&self) -> Option<c_int> {
+        if self.config.read_only || self.frozen || !self.backing.capabilities().writable {
+            return Some(EROFS);
+        }
+
+        self.transaction.is_some().then_some(EBUSY)
+    }
+
+    pub fn save(&mut self) -> anyhow::Result<()> {
+        for (tag, at) in std::mem::take(&mut self.entered) {
+            if self.tags.contains_left(&tag) {
+                self.tag_meta.entry(tag).or_default().entered_at = Some(at);
+            }
+        }
+        if self.config.shared.is_some() {
+            return self.save_shared();
+        }
+
+        self.write_state(&state_file(SAVEFILE))?;
+        self.saved_version = self.saved_version();
+
+        Ok(())
+    }
+
+    /// Write the savefile to `path`.
+    fn write_state(&self, path: &str) -> anyhow::Result<()> {
+        let vec = self.encode_state()?;
+
+        let handle = self.backing.create(path)?;
+        let written = self.backing.write(handle, &vec);
+        self.backing.release(handle);
+
+        Ok(written?)
+    }
+
+    /// Record a change made through the mount like [TagFS::record_step] and save it, before the
+    /// kernel is told it's done. When it can't be saved, the change is reverted, so it doesn't
+    /// look done until the next restart, and fails with EROFS for a read-only source or EIO.
+    /// A change that failed with `made` isn't recorded.
+    fn save_step<T>(&mut self, what: String, made: Result<T, c_int>) -> Result<T, c_int> {
+        let Ok(made) = made else {
+            self.abandon_step();
+            return made;
+        };
+        let recorded = self.record_step(what);
+
+        self.save_or_revert(|fs| {
+            if recorded {
+                fs.revert_step();
+            }
+        })
+        .map(|()| made)
+    }
+
+    /// Save what a batch of control commands changed, like [TagFS::save_step], reverting it with
+    /// [TagFS::revert_batch] when that fails. The batch may have begun or committed a
+    /// transaction, nothing is reverted for the latter.
+    fn save_batch(&mut self) -> Result<(), c_int> {
+        let saved = self.save_or_revert(Self::revert_batch);
+        self.batch = None;
+        saved
+    }
+
+    fn save_or_revert(&mut self, revert: impl FnOnce(&mut Self)) -> Result<(), c_int> {
+        if self.holds_changes() {
+            return Ok(());
+        }
+
+        match self.save() {
+            Ok(()) => {
+                self.fire_hooks();
+                Ok(())
+            }
+            Err(error) => {
+                self.record_error("save", &error);
+                revert(self);
+                self.events.clear();
+                Err(save_errno(&error))
+            }
+        }
+    }
+}
+
+impl<B: BackingFS> Filesystem for TagFS<B>
+where
+    <B as BackingFS>::Error: Debug + Error + Send + Sync + 'static,
+{
+    fn init(&mut self, _req: &Request<'_>, config: &mut KernelConfig) -> Result<(), c_int> {
+        // Let the kernel list with attributes when it's worth it, see `readdirplus`
+        if let Err(missing) = config.add_capabilities(FUSE_DO_READDIRPLUS | FUSE_READDIRPLUS_AUTO) {
+            debug!("the kernel can't list directories with attributes: {missing:#x}");
+        }
+
+        Ok(())
+    }
+
+    fn destroy(&mut self) {
+        self.await_scan();
+    }
+
+    fn lookup(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let started = Instant::now();
+        self.poll_scan();
+        self.poll_duplicates();
+        self.poll_verify();
+        self.refresh_shared();
+        let entry = self.lookup_entry(Ino(parent), name, req.uid());
+        self.log_op("lookup", parent, name, started, &entry);
+        match entry {
+            Ok(fa) => reply.entry(&TTL, &fa, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        let started = Instant::now();
+        let attr = self.get_attr(Ino(ino));
+        self.log_op("getattr", ino, OsStr::new(""), started, &attr);
+        match attr {
+            Ok(fa) => reply.attr(&TTL, &fa),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn mkdir(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        reply: ReplyEntry,
+    ) {
+        if let Some(errno) = self.refuses_changes() {
+            err!(reply, errno);
+        }
+
+        self.begin_step();
+        let started = Instant::now();
+        let made = self.make_tag(Ino(parent), name, Actor::of(req));
+        let made = self.save_step(format!("mkdir '{}'", name.to_string_lossy()), made);
+        self.log_op("mkdir", parent, name, started, &made);
+        match made {
+            Ok(fa) => reply.entry(&TTL, &fa, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// A file manager copying a file between tag directories creates it in the destination. The
+    /// file is the same one, so the copy tags it instead, and the data written is dropped.
+    fn create(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        _mode: u32,
+        _umask: u32,
+        _flags: i32,
+        reply: ReplyCreate,
+    ) {
+        if let Some(errno) = self.refuses_changes() {
+            err!(reply, errno);
+        }
+
+        let started = Instant::now();
+        let copied = self.start_copy(Ino(parent), name, Actor::of(req));
+        self.log_op("create", parent, name, started, &copied);
+        match copied {
+            Ok((fa, copy)) => {
+                let fh = self.open_virtual(vec![]);
+                self.copies.insert(fh, copy);
+                reply.created(&TTL, &fa, 0, fh.0, 0)
+            }
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn unlink(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if let Some(errno) = self.refuses_changes() {
+            err!(reply, errno);
+        }
+
+        self.begin_step();
+        let started = Instant::now();
+        let untagged = self.untag(Ino(parent), name, Actor::of(req));
+        let untagged = self.save_step(format!("unlink '{}'", name.to_string_lossy()), untagged);
+        self.log_op("unlink", parent, name, started, &untagged);
+        match untagged {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn rmdir(&mut self, req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+        if let Some(errno) = self.refuses_changes() {
+            err!(reply, errno);
+        }
+
+        self.begin_step();
+        let started = Instant::now();
+        let removed = self.remove_dir(Ino(parent), name, Actor::of(req));
+        let removed = self.save_step(format!("rmdir '{}'", name.to_string_lossy()), removed);
+        self.log_op("rmdir", parent, name, started, &removed);
+        match removed {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn rename(
+        &mut self,
+        req: &Request<'_>,
+        parent: u64,
+        name: &OsStr,
+        newparent: u64,
+        newname: &OsStr,
+        _flags: u32,
+        reply: ReplyEmpty,
+    ) {
+        if let Some(errno) = self.refuses_changes() {
+            err!(reply, errno);
+        }
+
+        self.begin_step();
+        let started = Instant::now();
+        let renamed = self.rename_entry(Ino(parent), name, Ino(newparent), newname, Actor::of(req));
+        let what = format!("rename '{}' to '{}'", name.to_string_lossy(), newname.to_string_lossy());
+        let renamed = self.save_step(what, renamed);
+        self.log_op("rename", parent, name, started, &renamed);
+        match renamed {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn open(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let started = Instant::now();
+        let opened = self.open_file(Ino(ino), flags, req.uid());
+        self.log_op("open", ino, OsStr::new(""), started, &opened);
+        match opened {
+            Ok((fh, flags)) => reply.opened(fh, flags),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        if let Some(archive) = self.archives.get_mut(&FileHandle(fh)) {
+            match archive.read(&self.backing, offset as u64, size as u64) {
+                Ok(data) => reply.data(&data),
+                Err(e) => {
+                    let tag = self.tags.get_by_left(&Ino(ino).file(self.split));
+                    let tag = tag.cloned().unwrap_or_default();
+                    self.record_error(&format!("archive '{}'", tag.to_string_lossy()), &e);
+                    reply.error(errno_of(&e, EIO));
+                }
+            }
+            return;
+        }
+
+        if let Some(content) = self.virtual_handles.get(&FileHandle(fh)) {
+            let start = min(offset as usize, content.len());
+            let end = min(start + size as usize, content.len());
+            reply.data(&content[start..end]);
+            return;
+        }
+
+        let started = Instant::now();
+        let mut buffer = std::mem::take(&mut self.read_buffer);
+        if buffer.len() < size as usize {
+            buffer.resize(size as usize, 0);
+        }
+        let result = self
+            .backing
+            .read_into(FileHandle(fh), offset as u64, &mut buffer[..size as usize]);
+
+        let read = match result {
+            Ok(read) => {
+                reply.data(&buffer[..read]);
+                Ok(read)
+            }
+            Err(e) => {
+                // An open file that's deleted stays readable, but not on every backing fs
+                let errno = errno_of(&e, EIO);
+                match self.source_reachable() {
+                    false => {
+                        let name = self.get_fnm_by_number(Ino(ino).file(self.split)).cloned();
+                        let name = name.unwrap_or_default();
+                        self.record_error(&format!("read '{}'", name.to_string_lossy()), &e);
+                    }
+                    true => warn!("read failed in the source: {e}"),
+                }
+                self.check_vanished(Ino(ino).file(self.split), errno);
+                reply.error(errno);
+                Err(errno)
+            }
+        };
+        self.log_op("read", ino, OsStr::new(""), started, &read);
+        self.read_buffer = buffer;
+    }
+
+    fn release(
+        &mut self,
+        req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        // Failures are recorded for `/.tagfs/errors`, there's nobody left to report them to
+        if self.control_lines.contains_key(&FileHandle(fh)) {
+            let _ = self.run_control_lines(&[], Actor::of(req), FileHandle(fh), true);
+        }
+        if self.transaction.as_ref().map(|tx| tx.handle) == Some(FileHandle(fh)) {
+            if let Ok(report) = self.roll_back(FileHandle(fh)) {
+                let reason = "the control file was closed without a commit";
+                self.record_error(&report, &reason);
+            }
+        }
+        // Copies that weren't flushed, like those of some NFS clients, are finished on release
+        if let Some(copy) = self.copies.remove(&FileHandle(fh)) {
+            if let Err(errno) = self.finish_copy(copy) {
+                let error = std::io::Error::from_raw_os_error(errno);
+                self.record_error("finish a copy on release", &error);
+            }
+        }
+        if let Some(mut archive) = self.archives.remove(&FileHandle(fh)) {
+            archive.close(&self.backing);
+        } else if self.virtual_handles.remove(&FileHandle(fh)).is_none() {
+            self.backing.release(FileHandle(fh));
+        }
+
+        reply.ok();
+    }
+
+    fn flush(&mut self, req: &Request<'_>, ino: u64, fh: u64, _owner: u64, reply: ReplyEmpty) {
+        // A last line without a newline runs on close, where its failure can still be reported
+        if self.control_lines.contains_key(&FileHandle(fh)) {
+            let ran = self.run_control_lines(&[], Actor::of(req), FileHandle(fh), true);
+            return match ran {
+                Ok(()) => reply.ok(),
+                Err(errno) => reply.error(errno),
+            };
+        }
+
+        let Some(copy) = self.copies.remove(&FileHandle(fh)) else { return reply.ok() };
+
+        let started = Instant::now();
+        let finished = self.finish_copy(copy);
+        self.log_op("flush", ino, OsStr::new(""), started, &finished);
+        match finished {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn write(
+        &mut self,
+        req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        data: &[u8],
+        _write_flags: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyWrite,
+    ) {
+        if let Some(mut copy) = self.copies.remove(&FileHandle(fh)) {
+            let written = self.write_copy(&mut copy, offset as u64, data);
+            self.copies.insert(FileHandle(fh), copy);
+            return match written {
+                Ok(()) => reply.written(data.len() as u32),
+                Err(errno) => reply.error(errno),
+            };
+        }
+
+        // Only the control file is ever opened for writing, besides copies
+        let ino = Ino(ino);
+        let split = self.split;
+        if !ino.is_virtual(split)
+            || VirtualFile::from_number(ino.file(split), split) != Some(VirtualFile::Control)
+            || !self.virtual_handles.contains_key(&FileHandle(fh))
+        {
+            err!(reply, EBADF);
+        }
+
+        // Writers may split a line over several writes, it runs once its newline is written
+        match self.run_control_lines(data, Actor::of(req), FileHandle(fh), false) {
+            Ok(()) => reply.written(data.len() as u32),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn setattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _mode: Option<u32>,
+        _uid: Option<u32>,
+        _gid: Option<u32>,
+        size: Option<u64>,
+        _atime: Option<TimeOrNow>,
+        _mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<u64>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<u32>,
+        reply: ReplyAttr,
+    ) {
+        // Copies set the attributes of what they copied, which the file already has
+        let ino = Ino(ino);
+        if fh.is_some_and(|fh| self.copies.contains_key(&FileHandle(fh))) {
+            match self.file_attr(ino) {
+                Ok(fa) => reply.attr(&TTL, &fa),
+                Err(errno) => reply.error(errno),
+            }
+            return;
+        }
+
+        // Shells truncate a file they redirect into, which is a no-op for the control file
+        let split = self.split;
+        if !ino.is_virtual(split)
+            || VirtualFile::from_number(ino.file(split), split) != Some(VirtualFile::Control)
+            || size != Some(0)
+        {
+            err!(reply, ENOSYS);
+        }
+        let Some(content) = self.virtual_content_of(ino) else { err!(reply, ENOENT) };
+
+        reply.attr(&TTL, &create_virtual_attrs(ino, content.len(), self.split, self.config.dirs));
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        let Some(target) = self.link_target(Ino(ino)) else { err!(reply, ENOENT) };
+
+        reply.data(target.as_bytes());
+    }
+
+    fn getxattr(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        name: &OsStr,
+        size: u32,
+        reply: ReplyXattr,
+    ) {
+        let ino = Ino(ino);
+        if ino.is_file(self.split) && !ino.is_virtual(self.split) && is_passthrough_xattr(name) {
+            let Some(file) = self.source_name(ino.file(self.split)) else { err!(reply, ENOENT) };
+            if !self.backing.capabilities().xattrs {
+                err!(reply, ENOTSUP);
+            }
+
+            match self.backing.getxattr(file, name) {
+                Ok(value) => reply_xattr(reply, &value, size),
+                Err(e) => reply.error(errno_of(&e, ENODATA)),
+            }
+            return;
+        }
+
+        let Some(value) = self.meta_for_ino(ino).and_then(|meta| {
+            let key = name.to_str()?.strip_prefix(XATTR_PREFIX)?;
+            meta.get(key)
+        }) else {
+            err!(reply, ENODATA);
+        };
+
+        reply_xattr(reply, value.as_bytes(), size);
+    }
+
+    fn listxattr(&mut self, _req: &Request<'_>, ino: u64, size: u32, reply: ReplyXattr) {
+        match self.list_xattrs(Ino(ino)) {
+            Ok(names) => reply_xattr(reply, &names, size),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn access(&mut self, req: &Request<'_>, ino: u64, mask: i32, reply: ReplyEmpty) {
+        match self.check_access(Ino(ino), mask, req.uid()) {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn opendir(&mut self, req: &Request<'_>, ino: u64, flags: i32, reply: ReplyOpen) {
+        let started = Instant::now();
+        self.poll_scan();
+        self.poll_duplicates();
+        self.poll_verify();
+        self.refresh_shared();
+        self.expire_due();
+        let opened = check_dir_flags(flags).and_then(|()| self.open_dir(Ino(ino), req.uid()));
+        self.log_op("opendir", ino, OsStr::new(""), started, &opened);
+        match opened {
+            Ok(fh) => reply.opened(fh, 0),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let started = Instant::now();
+        let listed = self.dir_handles.get(&fh).ok_or(EBADF).map(|listing| {
+            listing.fill(self.shown_names(), self.split, offset, |ino, offset, kind, name| {
+                reply.add(ino, offset, kind, name)
+            })
+        });
+        self.log_op("readdir", ino, OsStr::new(""), started, &listed);
+        match listed {
+            Ok(_) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    /// Like `readdir`, with the attributes of the entries, which saves the kernel a lookup of
+    /// each one, e.g. for `ls -l`. The files of a reply are statted in the source at once.
+    fn readdirplus(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectoryPlus,
+    ) {
+        let started = Instant::now();
+        let page = self.dir_handles.get(&fh).ok_or(EBADF).map(|listing| {
+            let mut page = vec![];
+            listing.fill(self.shown_names(), self.split, offset, |ino, offset, _, name| {
+                if page.len() == PLUS_ENTRIES {
+                    return true;
+                }
+                page.push((Ino(ino), offset, FileName::from(name)));
+                false
+            });
+            page
+        });
+        let listed = page.map(|page| {
+            let inos = page.iter().map(|&(ino, _, _)| ino).collect::<Vec<_>>();
+            let attrs = self.get_attrs(&inos);
+            // Entries that are gone since the directory was opened are left out
+            let entries = page.into_iter().zip(attrs);
+            for ((ino, offset, name), attr) in entries.filter_map(|(e, attr)| Some((e, attr?))) {
+                if reply.add(ino.0, offset, &name, &TTL, &attr, 0) {
+                    break;
+                }
+            }
+        });
+        self.log_op("readdirplus", ino, OsStr::new(""), started, &listed);
+        match listed {
+            Ok(()) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn releasedir(
+        &mut self,
+        _req: &Request<'_>,
+        _ino: u64,
+        fh: u64,
+        _flags: i32,
+        reply: ReplyEmpty,
+    ) {
+        self.dir_handles.remove(&fh);
+        reply.ok()
+    }
+}
+
+/// Read a savefile from the backing fs.
+fn read_state<B>(backing: &B, path: &str) -> anyhow::Result<PersistentState>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    // Leverage the simple implementation of backingfs to read out the savefile
+    let handle = backing.open(path)?;
+    let savefile = backing.read(handle, 0, u64::MAX);
+    backing.release(handle);
+
+    decode_state(&savefile?)
+}
+
+/// How many times the savefile at `path` was written, from its header alone. `None` while there
+/// is none, and 0 for savefiles of layouts that didn't count.
+fn read_saves<B: BackingFS>(backing: &B, path: &str) -> Option<u64> {
+    let handle = backing.open(path).ok()?;
+    let header = backing.read(handle, 0, STATE_HEADER as u64);
+    backing.release(handle);
+
+    let header = header.ok()?;
+    let Some(versioned) = header.strip_prefix(STATE_MAGIC) else {
+        return Some(0);
+    };
+    match versioned.split_first_chunk() {
+        Some((version, saves)) if u32::from_le_bytes(*version) >= 2 => {
+            Some(u64::from_le_bytes(*saves.first_chunk()?))
+        }
+        _ => Some(0),
+    }
+}
+
+/// Encode `state` as the contents of a savefile: [STATE_MAGIC], the [STATE_VERSION] of the
+/// layout, the number of times it was written with this time, `saves`, and the state itself.
+fn encode_versioned<T: Serialize>(
+    state: T,
+    saves: u64,
+) -> Result<Vec<u8>, bincode::error::EncodeError> {
+    let mut savefile = STATE_MAGIC.to_vec();
+    savefile.extend_from_slice(&STATE_VERSION.to_le_bytes());
+    savefile.extend_from_slice(&saves.to_le_bytes());
+    bincode::encode_into_std_write(Compat(state), &mut savefile, bincode::config::standard())?;
+
+    Ok(savefile)
+}
+
+/// Decode the contents of a savefile, of this layout or of an earlier one. Whatever the earlier
+/// layouts didn't save starts out empty.
+fn decode_state(savefile: &[u8]) -> anyhow::Result<PersistentState> {
+    let Some(versioned) = savefile.strip_prefix(STATE_MAGIC) else {
+        let (Compat(legacy), read): (Compat<LegacyState>, _) =
+            bincode::decode_from_slice(savefile, bincode::config::standard())?;
+        if read != savefile.len() {
+            anyhow::bail!("the savefile has {} unknown trailing bytes", savefile.len() - read);
+        }
+
+        info!("read savefile of an unversioned layout, it is saved as version {STATE_VERSION}");
+        return Ok(legacy.into());
+    };
+
+    let Some((version, state)) = versioned.split_first_chunk() else {
+        anyhow::bail!("the savefile is truncated");
+    };
+    let version = u32::from_le_bytes(*version);
+    // Version 1 is laid out like version 2, without counting the saves
+    let state = match version {
+        1 => state,
+        2..=STATE_VERSION => match state.split_first_chunk::<8>() {
+            Some((_, state)) => state,
+            None => anyhow::bail!("the savefile is truncated"),
+        },
+        _ => anyhow::bail!(
+            "the savefile is of version {version}, this tagfs reads up to version {STATE_VERSION}"
+        ),
+    };
+
+    let config = bincode::config::standard();
+    if version < 3 {
+        let (Compat(state), _): (Compat<PersistentState<FileMetaV2>>, _) =
+            bincode::decode_from_slice(state, config)?;
+        return Ok(state.map_file_meta(FileMeta::from));
+    }
+    let (Compat(state), _): (Compat<PersistentState>, _) =
+        bincode::decode_from_slice(state, config)?;
+
+    Ok(state)
+}
+
+/// Shuffle `files` into an order determined by `seed`.
+fn shuffle(files: &mut [FileNumber], seed: u64) {
+    files.sort_by_cached_key(|file| {
+        let mut hasher = DefaultHasher::new();
+        (seed, file).hash(&mut hasher);
+        hasher.finish()
+    });
+}
+
+/// The stored name of a `user:` tag named by `uid`, see [USER_PREFIX].
+fn private_name(name: &OsStr, uid: u32) -> Option<OsString> {
+    let name = name.to_str()?.strip_prefix(USER_PREFIX)?;
+
+    Some(format!("{USER_PREFIX}{uid}:{name}").into())
+}
+
+/// The tags to add (`true`) and remove when `name` is renamed to `newname`, if that's `name`
+/// followed by directives like `#+favorite#-live`. Tag names can't hold a `#` this way.
+fn tag_directives<'a>(name: &OsStr, newname: &'a OsStr) -> Option<Vec<(bool, &'a OsStr)>> {
+    let directives = newname.to_str()?.strip_prefix(name.to_str()?)?.strip_prefix(DIRECTIVE)?;
+
+    directives
+        .split(DIRECTIVE)
+        .map(|directive| {
+            let (add, tag) = match directive.split_at_checked(1)? {
+                ("+", tag) => (true, tag),
+                ("-", tag) => (false, tag),
+                _ => return None,
+            };
+
+            (!tag.is_empty()).then_some((add, OsStr::new(tag)))
+        })
+        .collect()
+}
+
+/// The primary and supplementary groups of user `uid` in the user database, none if it has no
+/// entry there.
+fn user_groups(uid: u32) -> Vec<u32> {
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf: Vec<libc::c_char> = vec![0; 1024];
+    let mut found = ptr::null_mut();
+    loop {
+        let len = buf.len();
+        match unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), len, &mut found) } {
+            ERANGE => buf.resize(len * 2, 0),
+            0 if !found.is_null() => break,
+            _ => return vec![],
+        }
+    }
+
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut count = groups.len() as c_int;
+        let (name, gid) = (passwd.pw_name, passwd.pw_gid);
+        if unsafe { libc::getgrouplist(name, gid, groups.as_mut_ptr(), &mut count) } >= 0 {
+            groups.truncate(count as usize);
+            return groups;
+        }
+        // The count is set to the number of groups the user has, if it's more
+        groups.resize((count as usize).max(groups.len() * 2), 0);
+    }
+}
+
+/// The name of a stored tag as listed to `uid`, which sees its own private tags as `user:<name>`.
+fn listed_name(name: &FileName, uid: u32) -> FileName {
+    let own = format!("{USER_PREFIX}{uid}:");
+    match name.to_str().and_then(|name| name.strip_prefix(&own)) {
+        Some(name) => format!("{USER_PREFIX}{name}").into(),
+        None => name.clone(),
+    }
+}
+
+/// The errno behind an error of the backing fs, if it has one.
+fn errno_of(error: &(dyn Error + 'static), default: c_int) -> c_int {
+    error
+        .downcast_ref::<std::io::Error>()
+        .and_then(|error| error.raw_os_error())
+        .unwrap_or(default)
+}
+
+/// The errno for a change that couldn't be saved: EROFS if the source is read-only, else EIO.
+fn save_errno(error: &anyhow::Error) -> c_int {
+    match errno_of(error.as_ref(), EIO) {
+        EROFS => EROFS,
+        _ => EIO,
+    }
+}
+
+/// Reply with either the size of `data` or `data` itself, depending on what the caller asked for.
+fn reply_xattr(reply: ReplyXattr, data: &[u8], size: u32) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() > size as usize {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+/// Validate the flags passed to `open` on a file. Only `writable` files, like the control file,
+/// accept writes. For the others anything that would modify the file fails with EROFS rather than
+/// silently opening read-only.
+fn check_open_flags(flags: i32, writable: bool) -> Result<(), c_int> {
+    if flags & O_DIRECTORY != 0 {
+        return Err(if flags & O_CREAT != 0 { EINVAL } else { ENOTDIR });
+    }
+    if !writable && (flags & O_ACCMODE != O_RDONLY || flags & (O_TRUNC | O_APPEND | O_CREAT) != 0) {
+        return Err(EROFS);
+    }
+
+    Ok(())
+}
+
+/// Validate the flags passed to `opendir`. Directories can only be opened for reading, and asking
+/// to create one with `open` is invalid, like on Linux.
+fn check_dir_flags(flags: i32) -> Result<(), c_int> {
+    if flags & O_CREAT != 0 && flags & O_DIRECTORY != 0 {
+        return Err(EINVAL);
+    }
+    if flags & O_ACCMODE != O_RDONLY || flags & (O_TRUNC | O_APPEND | O_CREAT) != 0 {
+        return Err(EISDIR);
+    }
+
+    Ok(())
+}
+
+fn create_symlink_attrs(ino: Ino, target: &OsStr, dirs: DirAttrs) -> FileAttr {
+    FileAttr {
+        size: target.len() as u64,
+        blocks: 0,
+        kind: Symlink,
+        perm: 0o777,
+        ..create_folder_attrs(ino, dirs)
+    }
+}
+
+/// The attributes of a file that's only known from its snapshot, owned like the tag directories.
+fn create_snapshot_attrs(ino: Ino, snapshot: FileSnapshot, dirs: DirAttrs) -> FileAttr {
+    FileAttr {
+        size: snapshot.size,
+        blocks: snapshot.size.div_ceil(512),
+        atime: snapshot.mtime,
+        mtime: snapshot.mtime,
+        ctime: snapshot.mtime,
+        kind: RegularFile,
+        perm: snapshot.perm,
+        ..create_folder_attrs(ino, dirs)
+    }
+}
+
+fn create_virtual_attrs(ino: Ino, size: usize, split: Split, dirs: DirAttrs) -> FileAttr {
+    FileAttr {
+        size: size as u64,
+        blocks: (size as u64).div_ceil(512),
+        kind: RegularFile,
+        perm: match VirtualFile::from_number(ino.file(split), split).map(VirtualFile::is_writable) {
+            Some(true) => 0o644,
+            _ => 0o444,
+        },
+        ..create_folder_attrs(ino, dirs)
+    }
+}
+
+fn create_folder_attrs(ino: Ino, dirs: DirAttrs) -> FileAttr {
+    FileAttr {
+        ino: ino.0,
+        size: 4096,
+        blocks: 8,
+        atime: UNIX_EPOCH,
+        mtime: UNIX_EPOCH,
+        ctime: UNIX_EPOCH,
+        crtime: UNIX_EPOCH,
+        kind: Directory,
+        perm: dirs.perm,
+        nlink: 1,
+        uid: dirs.uid,
+        gid: dirs.gid,
+        rdev: 0,
+        blksize: 512,
+        flags: 0,
+    }
+}
+
+#[cfg(test)]
+mod sim;
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+    use crate::cli::Args;
+    use crate::control::quote_word;
+    use crate::fs::backing::ExternalFS;
+    use crate::fs::tag::sim::MemFS;
+
+    /// A mount of `count` files tagged `music`. Listing never reads the files, so they don't exist.
+    fn mount(count: usize) -> TagFS<ExternalFS> {
+        let mut fs = TagFS::new(ExternalFS::new("/nonexistent"));
+        fs.repopulate((0..count).map(|i| format!("{i:05}.mp3").into()));
+
+        let music = fs.create_tag("music".into());
+        for file in fs.files.left_values().copied().collect::<Vec<_>>() {
+            fs.add_file_to(file, music);
+        }
+
+        fs
+    }
+
+    /// Read an open directory like the kernel does, fitting `capacity` entries in every reply and
+    /// calling `between` after each one.
+    fn read_dir(
+        fs: &mut TagFS<ExternalFS>,
+        fh: u64,
+        capacity: usize,
+        mut between: impl FnMut(&mut TagFS<ExternalFS>),
+    ) -> Vec<(u64, FileName)> {
+        let mut listed = Vec::new();
+        let mut offset = 0;
+        loop {
+            let mut page = Vec::new();
+            fs.dir_handles[&fh].fill(&fs.files, fs.split, offset, |ino, offset, _, name| {
+                if page.len() == capacity {
+                    return true;
+                }
+                page.push((offset, ino, FileName::from(name)));
+                false
+            });
+
+            let Some(&(last, _, _)) = page.last() else { return listed };
+            offset = last;
+            listed.extend(page.into_iter().map(|(_, ino, name)| (ino, name)));
+
+            between(fs);
+        }
+    }
+
+    /// The listing of an open directory as it was taken, by names of now.
+    fn snapshot(fs: &TagFS<ExternalFS>, fh: u64) -> Vec<(u64, FileName)> {
+        let mut listed = Vec::new();
+        fs.dir_handles[&fh].fill(&fs.files, fs.split, 0, |ino, _, _, name| {
+            listed.push((ino, FileName::from(name)));
+            false
+        });
+
+        listed
+    }
+
+    #[test]
+    fn lists_large_directories_completely() {
+        let mut fs = mount(10_000);
+        let fh = fs.open_dir(Ino::ROOT, 1000).unwrap();
+        let expected = snapshot(&fs, fh);
+
+        for capacity in [1, 7, 100, 4096, 20_000] {
+            let listed = read_dir(&mut fs, fh, capacity, |_| {});
+            assert_eq!(listed, expected, "capacity {capacity}");
+        }
+
+        let names = expected.iter().map(|(_, name)| name).collect::<HashSet<_>>();
+        assert_eq!(names.len(), expected.len(), "duplicate entries");
+        assert!(names.contains(&FileName::from("music")));
+        assert!(names.contains(&FileName::from("09999.mp3")));
+    }
+
+    #[test]
+    fn holds_large_listings_compactly() {
+        let mut fs = mount(100_000);
+        let fh = fs.open_dir(Ino::ROOT, 1000).unwrap();
+        let listing = &fs.dir_handles[&fh];
+
+        let head = listing.len() - 100_000;
+        assert!(head < 32, "{head} entries besides the files");
+        let bound = 100_000 * size_of::<FileNumber>() + head * size_of::<DirEntry>();
+        assert!(listing.heap_size() <= bound, "{} > {bound} bytes", listing.heap_size());
+    }
+
+    #[test]
+    fn reads_pages_of_large_directories_from_where_they_left_off() {
+        let mut fs = mount(100_000);
+        let fh = fs.open_dir(Ino::ROOT, 1000).unwrap();
+        let listing = &fs.dir_handles[&fh];
+
+        // Like the kernel, which fits about a hundred entries in a reply
+        let (mut offset, mut listed, mut last) = (0, 0, None);
+        loop {
+            let mut page = Vec::new();
+            let mut handed = 0;
+            listing.fill(&fs.files, fs.split, offset, |_, next, _, name| {
+                handed += 1;
+                if page.len() == 100 {
+                    return true;
+                }
+                page.push((next, FileName::from(name)));
+                false
+            });
+            let Some((next, name)) = page.last().cloned() else { break };
+
+            // Every page starts right after the last one, and goes no further than what fits
+            assert_eq!(page[0].0, offset + 1);
+            assert!(handed <= page.len() + 1);
+            (offset, listed, last) = (next, listed + page.len(), Some(name));
+        }
+        assert_eq!(listed, listing.len());
+        assert_eq!(last, Some(FileName::from("99999.mp3")));
+    }
+
+    #[test]
+    fn skips_files_leaving_the_index_while_listing() {
+        let mut fs = mount(10_000);
+        let fh = fs.open_dir(Ino::ROOT, 1000).unwrap();
+        let expected = snapshot(&fs, fh);
+
+        // Each file is removed before its page is read
+        let gone = (1..20).map(|round| format!("{:05}.mp3", round * 500)).collect::<Vec<_>>();
+        let mut round = 0;
+        let listed = read_dir(&mut fs, fh, 333, |fs| {
+            if let Some(name) = gone.get(round) {
+                let file = fs.get_fnb_by_name(name).unwrap();
+                fs.files.remove_by_left(&file);
+            }
+            round += 1;
+        });
+
+        let is_gone = |name: &FileName| gone.iter().any(|gone| name == gone.as_str());
+        assert!(listed.iter().eq(expected.iter().filter(|(_, name)| !is_gone(name))));
+    }
+
+    #[test]
+    fn continues_from_tags_into_files() {
+        let mut fs = mount(10_000);
+        for i in 0..50 {
+            fs.create_tag(format!("tag{i}").into());
+        }
+        let fh = fs.open_dir(Ino::ROOT, 1000).unwrap();
+        let expected = snapshot(&fs, fh);
+
+        // Fill the first reply exactly up to the last tag
+        let tags = expected
+            .iter()
+            .take_while(|(ino, _)| !Ino(*ino).is_file(fs.split))
+            .filter(|(ino, _)| {
+                VirtualDir::from_ino_part(Ino(*ino).tag(fs.split), fs.split).is_none()
+            })
+            .count();
+        let listed = read_dir(&mut fs, fh, tags, |_| {});
+        assert_eq!(listed, expected);
+    }
+
+    #[test]
+    fn indexes_files_as_the_scan_statted_them() {
+        let mut fs = TagFS::new(ExternalFS::new("/nonexistent"));
+        let (sender, batches) = std::sync::mpsc::channel();
+        fs.scan_in_background(batches);
+
+        // The source can't be statted here, only the scanning thread did
+        let attr = create_virtual_attrs(Ino(2), 42, fs.split, fs.config.dirs);
+        let source = Some(SourceId { dev: 1, ino: 2 });
+        sender.send(vec![("a.mp3".into(), Some(Stat { attr, source }))]).unwrap();
+        fs.poll_scan();
+        let file = fs.get_fnb_by_name("a.mp3").unwrap();
+        assert_eq!(fs.file_meta(file).unwrap().source, source);
+
+        drop(sender);
+        fs.poll_scan();
+        let meta = fs.file_meta(file).unwrap();
+        assert_eq!(meta.snapshot.map(|snapshot| snapshot.size), Some(42));
+        assert!(!meta.offline);
+    }
+
+    #[test]
+    fn follows_files_replaced_in_the_source() {
+        let source = std::env::temp_dir().join(format!("tagfs-replaced-{}", std::process::id()));
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join("a.txt"), "draft").unwrap();
+        let mut fs = TagFS::new(ExternalFS::new(source.to_str().unwrap()));
+        fs.repopulate([FileName::from("a.txt")]);
+        let file = fs.get_fnb_by_name("a.txt").unwrap();
+        let before = fs.file_meta(file).unwrap().source.unwrap();
+
+        // Saved like editors do, as a new file moved over the old one
+        std::fs::write(source.join("a.txt.new"), "final").unwrap();
+        std::fs::rename(source.join("a.txt.new"), source.join("a.txt")).unwrap();
+        fs.repopulate([FileName::from("a.txt")]);
+        let after = fs.file_meta(file).unwrap().source.unwrap();
+        assert_ne!(before, after);
+        assert_eq!(fs.backing.source_id("a.txt").ok(), Some(after));
+
+        std::fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_inode_split_per_mount() {
+        let mut narrow = mount(10);
+        let mut wide = mount(10);
+        assert!(matches!(narrow.set_ino_split(64), Err(TagError::InoSplit(64))));
+        narrow.set_ino_split(16).unwrap();
+
+        // Each numbers its virtual directories by its own split
+        let control = OsStr::new(VirtualDir::Control.name());
+        let narrow_ino = narrow.lookup_entry(Ino::ROOT, control, 1000).unwrap().ino;
+        let wide_ino = wide.lookup_entry(Ino::ROOT, control, 1000).unwrap().ino;
+        assert_eq!(narrow_ino, Split::new(16).unwrap().virtual_dirs());
+        assert_eq!(wide_ino, Split::default().virtual_dirs());
+        assert!(narrow.lookup_entry(Ino(narrow_ino), OsStr::new("stats"), 1000).is_ok());
+    }
+
+    #[test]
+    fn offsets_survive_changes_while_listing() {
+        let mut fs = mount(10_000);
+        let music = fs.get_tnb_by_name("music").unwrap();
+        let root = fs.open_dir(Ino::ROOT, 1000).unwrap();
+        let root = snapshot(&fs, root);
+        let (music_ino, _) = root.iter().find(|(_, name)| name == "music").unwrap();
+        let music_ino = Ino(*music_ino);
+
+        let fh = fs.open_dir(music_ino, 1000).unwrap();
+        let expected = snapshot(&fs, fh);
+        assert_eq!(expected.len(), 10_002);
+
+        let mut round = 0;
+        let listed = read_dir(&mut fs, fh, 333, |fs| {
+            round += 1;
+            fs.create_tag(format!("new{round}").into());
+            let file = fs.get_fnb_by_name(format!("{round:05}.mp3")).unwrap();
+            fs.remove_file_from(file, music);
+        });
+        assert_eq!(listed, expected);
+
+        // A new handle sees the changes
+        let fh = fs.open_dir(music_ino, 1000).unwrap();
+        let names = snapshot(&fs, fh).into_iter().map(|(_, name)| name).collect::<Vec<_>>();
+        assert!(names.contains(&FileName::from("new1")));
+        assert!(!names.contains(&FileName::from("00001.mp3")));
+    }
+
+    /// A mount holding the file `live` tagged `music`, next to a tag called `live` and the file
+    /// `other`, with the inode of the `music` directory.
+    fn clashing_names() -> (TagFS<ExternalFS>, Ino) {
+        let mut fs = TagFS::new(ExternalFS::new("/nonexistent"));
+        fs.repopulate(["live", "other"].map(FileName::from));
+        let music = fs.create_tag("music".into());
+        fs.create_tag("live".into());
+        fs.create_tag("rock".into());
+        fs.add_file_to(fs.get_fnb_by_name("live").unwrap(), music);
+
+        let dir = fs.lookup_entry(Ino::ROOT, OsStr::new("music"), 1000).unwrap();
+        (fs, Ino(dir.ino))
+    }
+
+    const USER: Actor = Actor { uid: 1000, pid: 1 };
+
+    #[test]
+    fn renames_files_named_like_tags_as_files() {
+        let (mut fs, music_dir) = clashing_names();
+        let rock_dir = Ino(fs.lookup_entry(Ino::ROOT, OsStr::new("rock"), 1000).unwrap().ino);
+
+        // `music` lists the file `live`, so that's what's moved, the tag stays as it is
+        let moved = fs.rename_entry(music_dir, "live".as_ref(), rock_dir, "live".as_ref(), USER);
+        assert_eq!(moved, Ok(()));
+        let file = fs.get_fnb_by_name("live").unwrap();
+        let [music, live, rock] = ["music", "live", "rock"].map(|tag| fs.get_tnb_by_name(tag));
+        assert!(!fs.has_tag(file, music.unwrap()));
+        assert!(fs.has_tag(file, rock.unwrap()));
+        assert_eq!(fs.tags().get_by_left(&live.unwrap()), Some(&FileName::from("live")));
+
+        // Likewise for directives, which retag the file
+        let retag = "live#+favorite".as_ref();
+        assert_eq!(fs.rename_entry(rock_dir, "live".as_ref(), rock_dir, retag, USER), Ok(()));
+        assert!(fs.has_tag(file, fs.get_tnb_by_name("favorite").unwrap()));
+        assert!(fs.get_tnb_by_name("live#+favorite").is_none());
+    }
+
+    #[test]
+    fn renames_tags_only_where_they_are_listed() {
+        let (mut fs, music_dir) = clashing_names();
+        let live = fs.get_tnb_by_name("live").unwrap();
+        let music = fs.get_tnb_by_name("music").unwrap();
+
+        // `other` isn't tagged `music`, so there's no file `other` in it, nor a tag
+        let other = "other".as_ref();
+        let renamed = fs.rename_entry(music_dir, other, music_dir, "others".as_ref(), USER);
+        assert_eq!(renamed, Err(ENOENT));
+        assert!(fs.get_fnb_by_name("other").is_some());
+
+        // The tag `live` is listed at the root only when no file `live` is: here the file wins
+        let renamed = fs.rename_entry(Ino::ROOT, "live".as_ref(), Ino::ROOT, "gigs".as_ref(), USER);
+        assert_eq!(renamed, Err(ENOTSUP));
+        assert_eq!(fs.tags().get_by_left(&live), Some(&FileName::from("live")));
+
+        // Untagging the file from `music` leaves only the tag `live` in its directory
+        fs.remove_file_from(fs.get_fnb_by_name("live").unwrap(), music);
+        let renamed = fs.rename_entry(music_dir, "live".as_ref(), music_dir, "gigs".as_ref(), USER);
+        assert_eq!(renamed, Ok(()));
+        assert_eq!(fs.tags().get_by_left(&live), Some(&FileName::from("gigs")));
+
+        // A directory doesn't hold the tags of its own path
+        let tunes = "tunes".as_ref();
+        let renamed = fs.rename_entry(music_dir, "music".as_ref(), music_dir, tunes, USER);
+        assert_eq!(renamed, Err(ENOENT));
+        assert_eq!(fs.tags().get_by_left(&music), Some(&FileName::from("music")));
+    }
+
+    #[test]
+    fn removes_tags_only_where_they_are_listed() {
+        let (mut fs, music_dir) = clashing_names();
+        let rock = fs.get_tnb_by_name("rock").unwrap();
+
+        // `music` lists the file `live`, which isn't a directory, and not the tag `music` itself
+        assert_eq!(fs.remove_dir(music_dir, "live".as_ref(), USER), Err(ENOTDIR));
+        assert_eq!(fs.remove_dir(music_dir, "music".as_ref(), USER), Err(ENOENT));
+        assert!(fs.get_tnb_by_name("live").is_some());
+        assert!(fs.get_tnb_by_name("music").is_some());
+
+        assert_eq!(fs.remove_dir(music_dir, "rock".as_ref(), USER), Ok(()));
+        assert!(!fs.tags().contains_left(&rock));
+    }
+
+    #[test]
+    fn refuses_to_move_files_into_paths_crossing_a_group() {
+        let mut fs = mount(1);
+        let [unread, done] = ["unread", "done"].map(|tag| fs.create_tag(tag.into()));
+        fs.declare_group("status", &[unread, done]);
+        let file = fs.get_fnb_by_name("00000.mp3").unwrap();
+        let lookup = |fs: &mut TagFS<_>, parent, name: &str| {
+            Ino(fs.lookup_entry(parent, name.as_ref(), 1000).unwrap().ino)
+        };
+        let music_dir = lookup(&mut fs, Ino::ROOT, "music");
+        let unread_dir = lookup(&mut fs, Ino::ROOT, "unread");
+        let crossed = lookup(&mut fs, unread_dir, "done");
+
+        // The file would end up in `done` only, and not in the directory it was moved to
+        let name = "00000.mp3".as_ref();
+        assert_eq!(fs.rename_entry(music_dir, name, crossed, name, USER), Err(EINVAL));
+        assert!(fs.start_copy(crossed, name, USER).is_err_and(|errno| errno == EINVAL));
+        assert!(fs.tags_of(file).iter().all(|&tag| tag != unread && tag != done));
+    }
+
+    #[test]
+    fn reads_savefiles_of_earlier_layouts() {
+        let fs = mount(3);
+        let music = fs.get_tnb_by_name("music").unwrap();
+
+        // Releases before the savefile was versioned wrote just the index, without a header
+        let tag_content = fs.tag_content.iter().collect::<Vec<_>>();
+        let legacy = (tag_content, &fs.files, &fs.tags, fs.file_tally);
+        let savefile = bincode::encode_to_vec(Compat(legacy), bincode::config::standard()).unwrap();
+        let state = decode_state(&savefile).unwrap();
+        assert_eq!(state.tag_content[&music].len(), 3);
+        assert_eq!((state.files, state.tags), (fs.files.clone(), fs.tags.clone()));
+        assert_eq!(state.ino_split, DEFAULT_SPLIT);
+        assert!(state.tree.is_empty() && state.tag_meta.is_empty());
+
+        let savefile = fs.encode_state().unwrap();
+        assert!(savefile.starts_with(STATE_MAGIC));
+        assert_eq!(decode_state(&savefile).unwrap().tag_content[&music].len(), 3);
+
+        // Versions 1 and 2 didn't keep the numbers files are shown with, 1 didn't count the saves
+        let earlier = fs.persistent_state().map_file_meta(|_| FileMetaV2::default());
+        let mut second = encode_versioned(earlier, 7).unwrap();
+        second[STATE_MAGIC.len()..][..4].copy_from_slice(&2u32.to_le_bytes());
+        assert_eq!(decode_state(&second).unwrap().tag_content[&music].len(), 3);
+        let mut first = STATE_MAGIC.to_vec();
+        first.extend_from_slice(&1u32.to_le_bytes());
+        first.extend_from_slice(&second[STATE_HEADER..]);
+        assert_eq!(decode_state(&first).unwrap().tag_content[&music].len(), 3);
+
+        // Newer layouts and truncated savefiles aren't guessed at
+        let mut newer = savefile.clone();
+        newer[STATE_MAGIC.len()..][..4].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        assert!(decode_state(&newer).is_err());
+        assert!(decode_state(&savefile[..STATE_MAGIC.len() + 2]).is_err());
+    }
+
+    #[test]
+    fn forgets_least_recently_used_lookups() {
+        let mut fs = mount(3);
+        let first = fs.lookup_virtual_subdir(VirtualDir::Complete, "m".as_ref()).unwrap();
+        let search = fs.lookup_virtual_subdir(VirtualDir::Search, "00001".as_ref()).unwrap();
+
+        for i in 0..10 * TRANSIENT_DIRS {
+            let name = format!("prefix{i}");
+            fs.lookup_virtual_subdir(VirtualDir::Complete, name.as_ref()).unwrap();
+            fs.lookup_virtual_subdir(VirtualDir::Search, name.as_ref()).unwrap();
+            // Still in use, so it keeps its inode
+            if i % 100 == 0 {
+                let again = fs.lookup_virtual_subdir(VirtualDir::Search, "00001".as_ref());
+                assert_eq!(again, Some(search));
+            }
+        }
+
+        assert!(fs.transient.len() <= TRANSIENT_DIRS);
+        assert!(fs.completions.len() <= TRANSIENT_DIRS);
+        assert!(fs.virtual_nodes.len() <= TRANSIENT_DIRS);
+        assert!(fs.pseudo_tags.len() <= TRANSIENT_DIRS);
+        assert!(fs.tree.cache.len() <= TRANSIENT_DIRS + 2);
+
+        // A forgotten prefix is made again, under a new inode, and still lists its tags
+        let made = fs.lookup_virtual_subdir(VirtualDir::Complete, "m".as_ref()).unwrap();
+        assert_ne!(made, first);
+        let node = VirtualNode::Complete(fs.completion_tally);
+        let entries = fs.virtual_node_entries(node, made.tag(fs.split), 1000, 0);
+        assert!(entries.iter().any(|(_, _, name)| name == "music"));
+    }
+
+    #[test]
+    fn runs_control_lines_once_complete() {
+        let mut fs = TagFS::new(MemFS::with_files(&["a.mp3"]));
+        fs.repopulate([FileName::from("a.mp3")]);
+        let handle = fs.open_virtual(vec![]);
+
+        // A line split over writes runs once its newline is written, the rest waits for the next
+        assert_eq!(fs.run_control_lines(b"tag a.mp3 ro", USER, handle, false), Ok(()));
+        assert!(fs.get_tnb_by_name("ro").is_none());
+        assert_eq!(fs.run_control_lines(b"ck\ntag a.mp3", USER, handle, false), Ok(()));
+        assert!(fs.get_tnb_by_name("rock").is_some());
+        assert_eq!(fs.run_control_lines(b" live", USER, handle, false), Ok(()));
+        assert!(fs.get_tnb_by_name("live").is_none());
+
+        // Another handle doesn't finish its line
+        let other = fs.open_virtual(vec![]);
+        assert_eq!(fs.run_control_lines(b"\n", USER, other, false), Ok(()));
+        assert!(fs.get_tnb_by_name("live").is_none());
+
+        // Closing runs a last line without a newline
+        assert_eq!(fs.run_control_lines(&[], USER, handle, true), Ok(()));
+        let file = fs.get_fnb_by_name("a.mp3").unwrap();
+        assert!(fs.has_tag(file, fs.get_tnb_by_name("live").unwrap()));
+        assert!(fs.control_lines.is_empty());
+    }
+
+    #[test]
+    fn takes_back_control_lines_that_cant_be_saved() {
+        // The source doesn't exist, so nothing can be saved
+        let mut fs = mount(3);
+        let handle = fs.open_virtual(vec![]);
+        fs.execute(ControlCommand::parse_line("tag 00000.mp3 live").unwrap()).unwrap();
+
+        let lines = b"undo\ntag 00001.mp3 rock\n";
+        assert!(fs.run_control_lines(lines, USER, handle, false).is_err());
+        let file = fs.get_fnb_by_name("00000.mp3").unwrap();
+        assert!(fs.has_tag(file, fs.get_tnb_by_name("live").unwrap()));
+        assert_eq!(fs.get_tnb_by_name("rock"), None);
+        // The history is as it was too
+        assert!(fs.undo().unwrap().starts_with("undid: tagged '00000.mp3'"));
+        assert!(fs.redo().is_ok());
+
+        // A transaction rolls back what its journal noted, and can't undo
+        let lines = b"begin\ntag 00001.mp3 rock\nuntag 00000.mp3 live\nrollback\n";
+        assert!(fs.run_control_lines(lines, USER, handle, false).is_err());
+        assert!(fs.has_tag(file, fs.get_tnb_by_name("live").unwrap()));
+        assert_eq!(fs.get_tnb_by_name("rock"), None);
+        let lines = b"begin\nundo\n";
+        assert_eq!(fs.run_control_lines(lines, USER, handle, false), Err(EINVAL));
+        assert!(fs.transaction.is_none());
+    }
+
+    #[test]
+    fn quotes_names_for_control_lines() {
+        let name = "it's \"live\" \\ 1.mp3";
+        let mut fs = TagFS::new(MemFS::with_files(&[name]));
+        fs.repopulate([FileName::from(name)]);
+        let handle = fs.open_virtual(vec![]);
+
+        // What the tui writes to the control file of a mount
+        let line = format!("tag {} {}\n", quote_word(name), quote_word("rock 'n' roll"));
+        assert_eq!(fs.run_control_lines(line.as_bytes(), USER, handle, false), Ok(()));
+        let file = fs.get_fnb_by_name(name).unwrap();
+        assert!(fs.has_tag(file, fs.get_tnb_by_name("rock 'n' roll").unwrap()));
+    }
+
+    #[test]
+    fn refuses_control_commands_taking_paths() {
+        let mut fs = TagFS::new(MemFS::with_files(&["a.mp3"]));
+        fs.repopulate([FileName::from("a.mp3")]);
+        let handle = fs.open_virtual(vec![]);
+        let run = |fs: &mut TagFS<MemFS>, line: &str| {
+            fs.run_control_lines(format!("{line}\n").as_bytes(), USER, handle, false)
+        };
+
+        // They'd be read with the privileges of the mount
+        assert_eq!(run(&mut fs, "apply --from-file /etc/shadow"), Err(EPERM));
+        assert!(!fs.recent_errors_mut().report().contains("root"));
+        assert_eq!(run(&mut fs, "sync /root/.tagfs"), Err(EPERM));
+        assert_eq!(run(&mut fs, "backup /etc/cron.d/backup.tar"), Err(EPERM));
+        assert_eq!(run(&mut fs, "restore-backup /tmp/backup.tar"), Err(EPERM));
+    }
+
+    #[test]
+    fn leaves_the_lines_of_mappings_out_of_errors() {
+        let mut fs = mount(1);
+        let mapping = std::env::temp_dir().join(format!("tagfs-apply-{}", std::process::id()));
+        std::fs::write(&mapping, "00000.mp3\trock\nroot:secret:19000\n").unwrap();
+
+        let error = fs.execute(ControlCommand::Apply { from_file: mapping.clone() }).unwrap_err();
+        assert_eq!(error.to_string(), "line 2: no file is indexed by that name");
+        assert!(fs.get_tnb_by_name("rock").is_none());
+
+        std::fs::remove_file(&mapping).unwrap();
+    }
+
+    #[test]
+    fn checks_tags_of_control_commands() {
+        let mut fs = TagFS::new(MemFS::with_files(&["a.mp3"]));
+        fs.repopulate([FileName::from("a.mp3")]);
+        let handle = fs.open_virtual(vec![]);
+        let run = |fs: &mut TagFS<MemFS>, line: &str| {
+            fs.run_control_lines(format!("{line}\n").as_bytes(), USER, handle, false)
+        };
+
+        assert_eq!(run(&mut fs, "tag a.mp3 rock"), Ok(()));
+        assert_eq!(run(&mut fs, "lock rock"), Ok(()));
+        // Like through the directories, a locked tag isn't changed
+        assert_eq!(run(&mut fs, "untag a.mp3 rock"), Err(EPERM));
+        assert_eq!(run(&mut fs, "rm-tag rock --force"), Err(EPERM));
+        assert_eq!(run(&mut fs, "create-tags live"), Ok(()));
+        assert_eq!(run(&mut fs, "rm-tag live --move-to rock"), Err(EPERM));
+        // But can be read and unlocked
+        assert_eq!(run(&mut fs, "meta rock"), Ok(()));
+        assert_eq!(run(&mut fs, "unlock rock"), Ok(()));
+        assert_eq!(run(&mut fs, "untag a.mp3 rock"), Ok(()));
+
+        // A tag only its owner may enter doesn't exist for others
+        assert_eq!(run(&mut fs, "chmod rock 700 --owner 0"), Ok(()));
+        assert_eq!(run(&mut fs, "meta rock"), Err(ENOENT));
+        assert_eq!(run(&mut fs, "tag a.mp3 rock"), Err(ENOENT));
+        assert!(fs.tag_content[&fs.get_tnb_by_name("rock").unwrap()].is_empty());
+    }
+
+    #[test]
+    fn refuses_to_make_existing_tags() {
+        let mut fs = mount(3);
+        let music = fs.get_tnb_by_name("music").unwrap();
+
+        // Making it again would take its name from the tag holding the files
+        assert_eq!(fs.make_tag(Ino::ROOT, "music".as_ref(), USER).err(), Some(EEXIST));
+        assert_eq!(fs.get_tnb_by_name("music"), Some(music));
+        assert_eq!(fs.tag_content[&music].len(), 3);
+        assert_eq!(fs.tags.len(), 1);
+
+        // Nor is a virtual directory shadowed by a tag
+        assert_eq!(fs.make_tag(Ino::ROOT, "@all".as_ref(), USER).err(), Some(EEXIST));
+        assert!(fs.make_tag(Ino::ROOT, "live".as_ref(), USER).is_ok());
+    }
+
+    #[test]
+    fn refuses_to_rename_tags_onto_taken_names() {
+        let mut fs = mount(3);
+        let music = fs.get_tnb_by_name("music").unwrap();
+        let live = fs.create_tag("live".into());
+        let rename = |fs: &mut TagFS<ExternalFS>, name: &str, newname: &str| {
+            fs.rename_entry(Ino::ROOT, name.as_ref(), Ino::ROOT, newname.as_ref(), USER)
+        };
+
+        // Tags are merged explicitly, not by taking another's name
+        assert_eq!(rename(&mut fs, "live", "music"), Err(EEXIST));
+        assert_eq!(fs.tags.get_by_left(&live), Some(&FileName::from("live")));
+        assert_eq!(fs.get_tnb_by_name("music"), Some(music));
+        assert_eq!(fs.tag_content[&music].len(), 3);
+
+        // Nor does a tag hide a virtual directory
+        assert_eq!(rename(&mut fs, "live", "@all"), Err(EEXIST));
+        assert_eq!(rename(&mut fs, "live", "gigs"), Ok(()));
+        assert_eq!(fs.tags.get_by_left(&live), Some(&FileName::from("gigs")));
+    }
+
+    #[test]
+    fn releases_the_savefile_after_saving() {
+        let source = MemFS::with_files(&["a.mp3"]);
+        let mut fs = TagFS::new(source.clone());
+        fs.repopulate([FileName::from("a.mp3")]);
+        fs.create_tag("music".into());
+
+        for _ in 0..3 {
+            fs.save().unwrap();
+            assert_eq!(source.open_handles(), 0);
+        }
+        let loaded = TagFS::new_from_save(source.clone()).unwrap();
+        assert!(loaded.get_tnb_by_name("music").is_some());
+        assert_eq!(source.open_handles(), 0);
+    }
+
+    #[test]
+    fn compares_copies_with_the_file_as_they_are_written() {
+        let source = MemFS::with_files(&["a.mp3"]);
+        let mut fs = TagFS::new(source.clone());
+        fs.repopulate([FileName::from("a.mp3")]);
+        let music = fs.create_tag("music".into());
+        let file = fs.get_fnb_by_name("a.mp3").unwrap();
+        let dir = fs.lookup_entry(Ino::ROOT, OsStr::new("music"), 1000).unwrap().ino;
+        let actor = Actor { uid: 1000, pid: 1 };
+
+        // Its content is its name: a copy of the same data is tagged instead of duplicated
+        let (_, mut copy) = fs.start_copy(Ino(dir), OsStr::new("a.mp3"), actor).unwrap();
+        assert_eq!(fs.write_copy(&mut copy, 0, b"a."), Ok(()));
+        assert_eq!(fs.write_copy(&mut copy, 2, b"mp3"), Ok(()));
+        assert_eq!(fs.finish_copy(copy), Ok(()));
+        assert_eq!(fs.tags_of(file), vec![music]);
+        assert_eq!(source.open_handles(), 0);
+
+        // Another file of the same name fails at the first chunk that differs
+        fs.remove_file_from(file, music);
+        let (_, mut copy) = fs.start_copy(Ino(dir), OsStr::new("a.mp3"), actor).unwrap();
+        assert_eq!(fs.write_copy(&mut copy, 0, b"a."), Ok(()));
+        assert_eq!(fs.write_copy(&mut copy, 2, b"ogg"), Err(EEXIST));
+        assert_eq!(fs.write_copy(&mut copy, 5, b"!"), Err(EEXIST));
+        assert_eq!(fs.finish_copy(copy), Err(EEXIST));
+        assert!(fs.tags_of(file).is_empty());
+        assert_eq!(source.open_handles(), 0);
+    }
+
+    #[test]
+    fn lists_only_identical_files_as_duplicates() {
+        let source = MemFS::with_files(&[]);
+        for (name, content) in [("a.mp3", "song"), ("b.mp3", "song"), ("c.mp3", "sing")] {
+            source.append(name, content.as_bytes()).unwrap();
+        }
+        let mut fs = TagFS::new(source.clone());
+        fs.repopulate(["a.mp3", "b.mp3", "c.mp3"].map(FileName::from));
+
+        fs.find_duplicates();
+        let names = |files: &Vec<FileNumber>| {
+            files.iter().map(|&file| fs.get_fnm_by_number(file).unwrap().clone()).collect()
+        };
+        let groups = fs.duplicates.iter().map(names).collect::<Vec<Vec<_>>>();
+        assert_eq!(groups, vec![vec![FileName::from("a.mp3"), FileName::from("b.mp3")]]);
+        assert_eq!(source.open_handles(), 0);
+    }
+
+    #[test]
+    fn tells_unreachable_sources_by_their_root() {
+        let source = MemFS::with_files(&["a.mp3"]);
+        let mut fs = TagFS::new(source.clone());
+        fs.repopulate([FileName::from("a.mp3")]);
+        let file = fs.get_fnb_by_name("a.mp3").unwrap();
+
+        // Without a savefile, e.g. before the first save, a file gone from the source is just gone
+        source.remove(&state_file(SAVEFILE));
+        source.remove("a.mp3");
+        assert!(fs.is_gone(ENOENT));
+        fs.check_vanished(file, ENOENT);
+        assert!(fs.get_fnm_by_number(file).is_none());
+
+        assert!(!mount(1).is_gone(ENOENT));
+    }
+
+    #[test]
+    fn lists_files_missing_as_last_seen() {
+        let source = MemFS::with_files(&["a.mp3", "b.mp3"]);
+        let mut fs = TagFS::new(source.clone());
+        fs.repopulate(["a.mp3", "b.mp3"].map(FileName::from));
+        let music = fs.create_tag("music".into());
+        let file = fs.get_fnb_by_name("a.mp3").unwrap();
+        fs.add_file_to(file, music);
+
+        // Listing @missing doesn't go looking, the file is missing once an operation on it failed
+        source.remove("a.mp3");
+        assert!(fs.missing_files().is_empty());
+        fs.check_vanished(file, ENOENT);
+        assert_eq!(fs.missing_files(), vec![file]);
+
+        let report = fs.execute(ControlCommand::Fsck).unwrap();
+        assert_eq!(report, "1 missing file(s)\na.mp3\tmusic\n");
+    }
+
+    #[test]
+    fn keeps_tag_stats_until_the_tags_change() {
+        let mut fs = mount(3);
+        let live = fs.create_tag("live".into());
+        let stats = fs.tag_stats();
+        assert!(stats.contains("live\t0\t"));
+        assert_eq!(fs.derived().tag_stats.as_ref(), Some(&stats));
+
+        let file = fs.get_fnb_by_name("00000.mp3").unwrap();
+        fs.add_file_to(file, live);
+        assert!(fs.derived().tag_stats.is_none());
+        assert!(fs.tag_stats().contains("live\t1\t"));
+
+        // Undo puts the members back without the mutators
+        fs.begin_step();
+        fs.remove_file_from(file, live);
+        fs.record_step("untag".into());
+        fs.tag_stats();
+        fs.undo().unwrap();
+        assert!(fs.tag_stats().contains("live\t1\t"));
+    }
+
+    #[test]
+    fn keeps_member_counts_until_the_tags_change() {
+        let mut fs = mount(3);
+        let music = fs.get_tnb_by_name("music").unwrap();
+        let live = fs.create_tag("live".into());
+        assert_eq!(fs.count_members(vec![live, music]), 0);
+        assert_eq!(fs.derived().counts.get(&vec![music, live]), Some(&0));
+
+        let file = fs.get_fnb_by_name("00000.mp3").unwrap();
+        fs.add_file_to(file, live);
+        assert!(fs.derived().counts.is_empty());
+        assert_eq!(fs.count_members(vec![music, live]), 1);
+    }
+
+    #[test]
+    fn sorts_by_the_last_scan() {
+        // The source doesn't exist, so only the snapshots know the sizes
+        let mut fs = mount(3);
+        let mut files = fs.files.left_values().copied().collect::<Vec<_>>();
+        for (size, &file) in [(10, &files[0]), (30, &files[1]), (20, &files[2])] {
+            let snapshot = FileSnapshot { size, mtime: UNIX_EPOCH, perm: 0o644 };
+            fs.file_meta_mut(file).snapshot = Some(snapshot);
+        }
+        let by_size = vec![files[1], files[2], files[0]];
+
+        fs.sort_files(&mut files, SortOrder::Size, 0);
+        assert_eq!(files, by_size);
+    }
+
+    #[test]
+    fn reads_archives_without_telling_their_size() {
+        let source = MemFS::with_files(&["a.mp3"]);
+        let mut fs = TagFS::new(source.clone());
+        fs.repopulate([FileName::from("a.mp3")]);
+        let music = fs.create_tag("music".into());
+        fs.add_file_to(fs.get_fnb_by_name("a.mp3").unwrap(), music);
+        assert_eq!(fs.archive_attrs(music).size, 0);
+
+        let mut archive = fs.archive(music);
+        let tar = archive.read(&fs.backing, 0, 1 << 20).unwrap();
+        archive.close(&fs.backing);
+        let mut tar = tar::Archive::new(tar.as_slice());
+        let mut entries = tar.entries().unwrap().map(Result::unwrap);
+        let mut entry = entries.next().unwrap();
+        let mut content = String::new();
+        std::io::Read::read_to_string(&mut entry, &mut content).unwrap();
+        assert_eq!(entry.path().unwrap().to_str(), Some("a.mp3"));
+        assert_eq!(content, "a.mp3");
+    }
+
+    #[test]
+    fn verifies_files_in_the_background() {
+        let source = std::env::temp_dir().join(format!("tagfs-verify-{}", std::process::id()));
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join("a.txt"), "intact").unwrap();
+        let backing = || ExternalFS::new(source.to_str().unwrap());
+        let mut fs = TagFS::new(backing());
+        fs.repopulate([FileName::from("a.txt")]);
+        fs.set_verifier(spawn_verifier(backing()));
+        let verify = |fs: &mut TagFS<ExternalFS>| {
+            assert!(fs.verify(None).unwrap().starts_with("verifying 1 file(s)"));
+            loop {
+                let progress = fs.verify_progress();
+                if !progress.starts_with("verifying") {
+                    return progress;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        };
+        assert!(verify(&mut fs).starts_with("0 intact, 0 corrupt, 0 modified, 1 new file(s)"));
+
+        // Changed behind its back, with the same size and mtime
+        let mtime = std::fs::metadata(source.join("a.txt")).unwrap().modified().unwrap();
+        std::fs::write(source.join("a.txt"), "broken").unwrap();
+        let file = std::fs::File::options().write(true).open(source.join("a.txt")).unwrap();
+        file.set_modified(mtime).unwrap();
+        assert!(verify(&mut fs).starts_with("0 intact, 1 corrupt"));
+        let corrupt = fs.get_tnb_by_name("corrupt").unwrap();
+        assert_eq!(fs.tags_of(fs.get_fnb_by_name("a.txt").unwrap()), vec![corrupt]);
+
+        std::fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[test]
+    fn lets_the_group_of_a_tag_in_as_its_mode_says() {
+        let mut fs = mount(3);
+        let music = fs.get_tnb_by_name("music").unwrap();
+        let dir = Ino(fs.lookup_entry(Ino::ROOT, OsStr::new("music"), USER.uid).unwrap().ino);
+        let first = *fs.tag_content[&music].iter().next().unwrap();
+        let file = Ino::from_parts(first, dir.tag(fs.split), fs.split);
+        let meta = fs.tag_meta_mut(music);
+        meta.mode = Some(0o750);
+        meta.owner = Some(0);
+
+        let in_group = |fs: &TagFS<ExternalFS>, groups: Vec<u32>| {
+            fs.user_groups.borrow_mut().insert(USER.uid, (Instant::now(), groups));
+        };
+        in_group(&fs, vec![fs.config.dirs.gid]);
+        assert_eq!(fs.check_access(dir, libc::R_OK | libc::X_OK, USER.uid), Ok(()));
+        assert_eq!(fs.check_access(file, libc::R_OK, USER.uid), Ok(()));
+        // Files can't be written through the mount, whatever the mode
+        assert_eq!(fs.check_access(file, W_OK, USER.uid), Err(EROFS));
+
+        in_group(&fs, vec![]);
+        assert_eq!(fs.check_access(dir, libc::F_OK, USER.uid), Err(EACCES));
+        assert_eq!(fs.check_access(file, libc::R_OK, USER.uid), Err(EACCES));
+        assert_eq!(fs.check_access(dir, libc::R_OK, 0), Ok(()));
+    }
+
+    #[test]
+    fn audits_every_change_on_a_line_of_its_own() {
+        let mut fs = TagFS::new(MemFS::with_files(&["a.mp3"]));
+        fs.repopulate([FileName::from("a.mp3")]);
+        let handle = fs.open_virtual(vec![]);
+
+        assert_eq!(fs.run_control_lines(b"tag a.mp3 rock\n", USER, handle, false), Ok(()));
+        // Names may hold line breaks and tabs
+        fs.audit(USER, "retag", "'a\n\tb.mp3' +rock");
+
+        let log = fs.audit_log(None).unwrap();
+        let lines = log.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("\tcommand\tTag"));
+        assert!(lines[1].ends_with("\tretag\t'a\\n\\tb.mp3' +rock"));
+    }
+
+    #[test]
+    fn keeps_the_numbers_files_of_a_basename_are_shown_with() {
+        let mut fs = TagFS::new(ExternalFS::new("/nonexistent"));
+        fs.set_config(Config { nested: true, ..Default::default() });
+        for name in ["x/a.flac", "y/a.flac", "z/a.flac"] {
+            fs.add_file(name.into());
+        }
+        let shown = |fs: &TagFS<ExternalFS>, name: &str| {
+            let file = fs.get_fnb_by_name(name).unwrap();
+            fs.get_fnm_shown(file).unwrap().to_string_lossy().into_owned()
+        };
+        assert_eq!(shown(&fs, "z/a.flac"), "a (3).flac");
+
+        // Files don't move up when one before them leaves, newcomers take the free numbers
+        fs.omit_file(fs.get_fnb_by_name("y/a.flac").unwrap());
+        assert_eq!(shown(&fs, "z/a.flac"), "a (3).flac");
+        fs.add_file("w/a.flac".into());
+        assert_eq!(shown(&fs, "w/a.flac"), "a (2).flac");
+        fs.omit_file(fs.get_fnb_by_name("x/a.flac").unwrap());
+        assert_eq!(shown(&fs, "z/a.flac"), "a (3).flac");
+
+        // Nor after the savefile is read again
+        let state = decode_state(&fs.encode_state().unwrap()).unwrap();
+        fs.restore_state(state);
+        assert_eq!(shown(&fs, "w/a.flac"), "a (2).flac");
+        assert_eq!(shown(&fs, "z/a.flac"), "a (3).flac");
+    }
+
+    #[test]
+    fn fails_to_list_attributes_the_source_cannot() {
+        let source = std::env::temp_dir().join(format!("tagfs-xattrs-{}", std::process::id()));
+        std::fs::create_dir_all(&source).unwrap();
+        std::fs::write(source.join("a.mp3"), "a").unwrap();
+        let mut fs = TagFS::new(ExternalFS::new(source.to_str().unwrap()));
+        fs.repopulate([FileName::from("a.mp3")]);
+        let file = Ino::from_parts(fs.get_fnb_by_name("a.mp3").unwrap(), 0, fs.split);
+        assert!(fs.list_xattrs(file).is_ok());
+
+        // Gone from the source, rather than without attributes
+        std::fs::remove_file(source.join("a.mp3")).unwrap();
+        assert_eq!(fs.list_xattrs(file), Err(ENOENT));
+
+        std::fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[test]
+    fn matches_tag_names_as_the_tag_case_says() {
+        let mut fs = mount(3);
+        let music = fs.get_tnb_by_name("music").unwrap();
+        let summer = fs.create_tag("été".into());
+        let greek = fs.create_tag("ΟΔΟΣ".into());
+        assert_eq!(fs.get_tnb_by_name("Music"), None);
+
+        fs.set_config(Config { tag_case: TagCase::Insensitive, ..Default::default() });
+        assert_eq!(fs.get_tnb_by_name("MUSIC"), Some(music));
+        assert_eq!(fs.get_tnb_by_name("ÉTÉ"), None);
+        // Making or tagging with another case of a tag is the tag itself
+        assert_eq!(fs.make_tag(Ino::ROOT, "Music".as_ref(), USER).err(), Some(EEXIST));
+        fs.execute(ControlCommand::parse_line("tag 00000.mp3 MUSIC").unwrap()).unwrap();
+        assert_eq!(fs.tags.len(), 3);
+
+        // Folding goes beyond ASCII, a final sigma is any other sigma
+        fs.set_config(Config { tag_case: TagCase::Fold, ..Default::default() });
+        assert_eq!(fs.get_tnb_by_name("ÉTÉ"), Some(summer));
+        assert_eq!(fs.get_tnb_by_name("οδος"), Some(greek));
+        assert_eq!(fs.get_tnb_by_name("οδοσ"), Some(greek));
+    }
+
+    #[test]
+    fn runs_commands_with_the_tag_case_and_rules_of_the_flags() {
+        let flags = ["tagfs", "-s", "/nonexistent", "--tag-case", "insensitive", "--tag", "new=*"];
+        let mut fs = mount(3);
+        fs.set_config(Args::try_parse_from(flags).unwrap().index_config());
+        fs.repopulate(["00000.mp3".into()]);
+
+        assert!(fs.get_tnb_by_name("MUSIC").is_some());
+        assert!(fs.get_tnb_by_name("New").is_some());
+    }
+
+    #[test]
+    fn lists_entered_tags_first_without_changing_the_index() {
+        let mut fs = mount(3);
+        fs.set_config(Config { tag_order: TagOrder::Recent, ..Default::default() });
+        let old = fs.make_tag(Ino::ROOT, "old".as_ref(), USER).unwrap().ino;
+        fs.make_tag(Ino::ROOT, "new".as_ref(), USER).unwrap();
+
+        fs.open_dir(Ino(old), USER.uid).unwrap();
+        let fh = fs.open_dir(Ino::ROOT, USER.uid).unwrap();
+        let listed = read_dir(&mut fs, fh, usize::MAX, |_| ());
+        assert_eq!(listed[2].1, FileName::from("old"));
+        // Entering a tag doesn't change its metadata until saving, it isn't a change to undo
+        let old = fs.get_tnb_by_name("old").unwrap();
+        assert_eq!(fs.tag_meta(old).and_then(|meta| meta.entered_at), None);
+    }
+
+    #[test]
+    fn generates_costly_reports_only_when_opened() {
+        let mut fs = mount(3);
+        let control = Ino::from_tag(VirtualDir::Control.ino_part(fs.split));
+        let health = fs.lookup_entry(control, "health".as_ref(), USER.uid).unwrap();
+        assert_eq!(health.size, 0);
+        assert_eq!(fs.get_attr(Ino(health.ino)).unwrap().size, 0);
+        assert!(fs.lookup_entry(control, "stats".as_ref(), USER.uid).unwrap().size > 0);
+
+        let (fh, flags) = fs.open_file(Ino(health.ino), O_RDONLY, USER.uid).unwrap();
+        assert_eq!(flags, FOPEN_DIRECT_IO);
+        assert!(fs.virtual_handles[&FileHandle(fh)].starts_with(b"status\t"));
+    }
+
+    #[test]
+    fn notices_saves_of_the_same_size() {
+        let source = MemFS::with_files(&["a.mp3"]);
+        let mut one = TagFS::new(source.clone());
+        one.repopulate([FileName::from("a.mp3")]);
+        one.save().unwrap();
+        let mut other = TagFS::new_from_save(source.clone()).unwrap();
+
+        // The source has no mtimes, and saving the same tags again doesn't change the size
+        other.save().unwrap();
+        assert_ne!(one.saved_version(), one.saved_version);
+        assert_eq!(other.saved_version(), other.saved_version);
     }
 }
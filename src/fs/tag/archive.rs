@@ -0,0 +1,225 @@
+use std::cmp::min;
+use std::error::Error;
+use std::ffi::OsStr;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::time::UNIX_EPOCH;
+
+use fuser::FileAttr;
+use fuser::FileType::RegularFile;
+
+use crate::file::{Ino, TagNumber};
+use crate::fs::backing::BackingFS;
+use crate::fs::tag::backup::{tar_header, BLOCK};
+use crate::fs::tag::{create_virtual_attrs, listed_name, DirEntry, FileName, TagFS};
+use crate::fs::virt::VirtualDir;
+use crate::fs::FileHandle;
+
+/// Archives in `/.tagfs/archives` are named after their tag, with this extension.
+const EXTENSION: &str = ".tar";
+
+/// A file in a tag archive: its headers, followed by its content padded to whole blocks.
+#[derive(Debug)]
+struct Member {
+    /// The name of the file in the source
+    source: FileName,
+    /// Where its headers start in the archive
+    start: u64,
+    headers: Vec<u8>,
+    /// Its size when the archive was opened
+    size: u64,
+}
+
+impl Member {
+    fn content_start(&self) -> u64 {
+        self.start + self.headers.len() as u64
+    }
+
+    fn end(&self) -> u64 {
+        self.content_start() + self.size.next_multiple_of(BLOCK as u64)
+    }
+}
+
+/// `/.tagfs/archives/<tag>.tar`: a tar of the files of a tag, laid out when it's opened and
+/// read from the source as it's read, so a whole tag can be downloaded without staging a copy.
+#[derive(Debug)]
+pub(super) struct Archive {
+    members: Vec<Member>,
+    /// The member read last, with its handle in the source
+    open: Option<(usize, FileHandle)>,
+}
+
+impl Archive {
+    pub fn size(&self) -> u64 {
+        let end = self.members.last().map(Member::end).unwrap_or(0);
+
+        end + 2 * BLOCK as u64
+    }
+
+    /// Read up to `size` bytes at `offset`. Files that shrank since the archive was opened are
+    /// padded with zeros, and those that grew are cut off, so the layout stays the same.
+    pub fn read<B: BackingFS>(
+        &mut self,
+        backing: &B,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, B::Error> {
+        let end = min(offset.saturating_add(size), self.size());
+        let mut out = Vec::with_capacity(end.saturating_sub(offset) as usize);
+        let mut idx = self.members.partition_point(|member| member.end() <= offset);
+        let mut pos = offset;
+        while pos < end {
+            // The zero blocks closing the archive
+            let Some(member) = self.members.get(idx) else {
+                out.resize((end - offset) as usize, 0);
+                break;
+            };
+
+            let (content_start, member_end) = (member.content_start(), member.end());
+            let content_end = content_start + member.size;
+            if pos < content_start {
+                let from = (pos - member.start) as usize;
+                let to = (min(end, content_start) - member.start) as usize;
+                out.extend_from_slice(&member.headers[from..to]);
+            } else if pos < content_end {
+                let want = min(end, content_end) - pos;
+                let content = self.read_member(backing, idx, pos - content_start, want)?;
+                out.extend_from_slice(&content);
+                out.resize(out.len() + (want as usize - content.len()), 0);
+            } else {
+                out.resize(out.len() + (min(end, member_end) - pos) as usize, 0);
+            }
+
+            pos = offset + out.len() as u64;
+            if pos >= member_end {
+                idx += 1;
+            }
+        }
+
+        Ok(out)
+    }
+
+    fn read_member<B: BackingFS>(
+        &mut self,
+        backing: &B,
+        idx: usize,
+        offset: u64,
+        size: u64,
+    ) -> Result<Vec<u8>, B::Error> {
+        let handle = match self.open {
+            Some((open, handle)) if open == idx => handle,
+            _ => {
+                self.close(backing);
+                let handle = backing.open(&self.members[idx].source)?;
+                self.open = Some((idx, handle));
+                handle
+            }
+        };
+
+        let mut content = backing.read(handle, offset, size)?;
+        content.truncate(size as usize);
+        Ok(content)
+    }
+
+    /// Release the member still open in the source, once the archive is closed.
+    pub fn close<B: BackingFS>(&mut self, backing: &B) {
+        if let Some((_, handle)) = self.open.take() {
+            backing.release(handle);
+        }
+    }
+}
+
+impl<B> TagFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    /// The tag of the archive called `name` as seen by `uid`, if it may read it.
+    pub(super) fn archive_tag(&self, name: &OsStr, uid: u32) -> Option<TagNumber> {
+        let tag = name.as_bytes().strip_suffix(EXTENSION.as_bytes())?;
+        let tag = self.get_tnb_by_listed_name(OsStr::from_bytes(tag), uid)?;
+
+        self.has_archive(tag).then_some(tag).filter(|&tag| self.may_read(tag, uid))
+    }
+
+    /// Whether `tag` is a tag with an archive. Their inodes have the tag as their file part.
+    pub(super) fn has_archive(&self, tag: TagNumber) -> bool {
+        self.tags.contains_left(&tag) && tag < self.split.virtual_files()
+    }
+
+    /// The archives listed in `/.tagfs/archives` for `uid`, one per tag it may read.
+    pub(super) fn archive_entries(&self, uid: u32) -> Vec<DirEntry> {
+        let dir = VirtualDir::Archives.ino_part(self.split);
+        let mut entries = self
+            .tags
+            .iter()
+            .filter(|(&tag, _)| self.has_archive(tag) && self.may_read(tag, uid))
+            .filter(|(&tag, _)| !self.tag_meta(tag).map(|meta| meta.hidden).unwrap_or(false))
+            .map(|(&tag, name)| {
+                let mut name = listed_name(name, uid).to_os_string();
+                name.push(EXTENSION);
+                (Ino::from_parts(tag, dir, self.split), RegularFile, name.into())
+            })
+            .collect::<Vec<DirEntry>>();
+        entries.sort_by(|(_, _, a), (_, _, b)| a.cmp(b));
+
+        entries
+    }
+
+    /// The attributes of the archive of `tag`. Telling its size would take statting every member,
+    /// so it's 0 like files in `/proc`: archives are opened with direct I/O, which reads them to
+    /// their end regardless.
+    pub(super) fn archive_attrs(&self, tag: TagNumber) -> FileAttr {
+        let dir = VirtualDir::Archives.ino_part(self.split);
+        let ino = Ino::from_parts(tag, dir, self.split);
+
+        create_virtual_attrs(ino, 0, self.split, self.config.dirs)
+    }
+
+    /// Lay out the archive of the files of `tag` as they are now, in the order of their names.
+    /// Files that can't be found in the source are left out.
+    pub(super) fn archive(&self, tag: TagNumber) -> Archive {
+        let mut files = self.calculate_intersection(&[tag]).into_iter().collect::<Vec<_>>();
+        files.sort_by_cached_key(|&file| self.get_fnm_by_number(file).cloned());
+        let names = files
+            .iter()
+            .filter_map(|&file| Some((self.get_fnm_by_number(file)?, self.source_name(file)?)))
+            .collect::<Vec<_>>();
+        let sources = names.iter().map(|&(_, source)| source).collect::<Vec<_>>();
+        let attrs = self.backing.get_metadata_many(&sources);
+
+        let mut members = Vec::with_capacity(names.len());
+        let mut start = 0;
+        for ((name, source), attr) in names.into_iter().zip(attrs) {
+            let Ok(attr) = attr else { continue };
+            let mtime = attr.mtime.duration_since(UNIX_EPOCH).map(|since| since.as_secs());
+            let mtime = mtime.unwrap_or(0);
+            let Ok(headers) = member_headers(name, attr.size, attr.perm, mtime) else { continue };
+
+            let member = Member {
+                source: source.into(),
+                start,
+                headers,
+                size: attr.size,
+            };
+            start = member.end();
+            members.push(member);
+        }
+
+        Archive {
+            members,
+            open: None,
+        }
+    }
+}
+
+/// The headers of a member called `name`, as the tar crate writes them before its content. Names
+/// that don't fit a ustar header get a GNU long name entry before it.
+fn member_headers(name: &OsStr, size: u64, mode: u16, mtime: u64) -> io::Result<Vec<u8>> {
+    let mut header = tar_header(size, mode as u32, mtime);
+    let mut builder = tar::Builder::new(vec![]);
+    // Without the content, which is read from the source as the archive is read
+    builder.append_data(&mut header, name, io::empty())?;
+
+    Ok(builder.get_ref().clone())
+}
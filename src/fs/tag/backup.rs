@@ -0,0 +1,319 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
+use std::iter;
+use std::mem;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use tar::{EntryType, Header};
+
+use crate::fs::audit::AUDIT_LOG;
+use crate::fs::backing::BackingFS;
+use crate::fs::error::TagError;
+use crate::fs::profile::{state_file, SAVEFILE};
+use crate::fs::tag::snapshot::SNAPSHOT_PREFIX;
+use crate::fs::tag::{decode_state, TagFS};
+
+/// The list of indexed files in a backup, see [TagFS::manifest].
+const MANIFEST: &str = "manifest.tsv";
+
+/// Tar archives are made of blocks of this size.
+pub(super) const BLOCK: usize = 512;
+
+/// The zstd level backups are compressed with, the default of the `zstd` tool.
+const ZSTD_LEVEL: i32 = 3;
+
+impl<B> TagFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    /// Write the savefile, the audit log, the snapshots and a manifest of the indexed files to
+    /// the tar archive `out`, compressed with zstd when its name ends in `.zst`.
+    pub fn backup(&self, out: &Path) -> Result<String, TagError> {
+        let state = self.encode_state().map_err(|error| TagError::Source(error.to_string()))?;
+
+        let mut entries = vec![(SAVEFILE.to_string(), state)];
+        let snapshots = self.snapshots.keys().map(|name| format!("{SNAPSHOT_PREFIX}{name}"));
+        for path in iter::once(AUDIT_LOG.to_string()).chain(snapshots) {
+            // The audit log doesn't exist until something changed. A backup of a profile holds
+            // its files under the names they have without one
+            if let Ok(content) = self.read_source(&state_file(&path)) {
+                entries.push((path, content));
+            }
+        }
+        entries.push((MANIFEST.to_string(), self.manifest().into_bytes()));
+
+        let file = File::create(out)?;
+        match is_zstd(out) {
+            true => {
+                let mut encoder = zstd::Encoder::new(file, ZSTD_LEVEL)?;
+                write_tar(&entries, &mut encoder)?;
+                encoder.finish()?.sync_all()?;
+            }
+            false => write_tar(&entries, &file)?.sync_all()?,
+        }
+
+        Ok(format!(
+            "backed up {} tag(s) and {} file(s) to '{}'",
+            self.tags.len(),
+            self.files.len(),
+            out.display()
+        ))
+    }
+
+    /// Restore the tags of a backup from [TagFS::backup] onto the files indexed now, matched by
+    /// name, or by their identity in the source when they were renamed since. Files that are gone
+    /// lose their tags. Snapshots and the audit log are put back when they're missing.
+    pub fn restore_backup(&mut self, archive: &Path) -> Result<String, TagError> {
+        let file = File::open(archive)?;
+        let entries = match is_zstd(archive) {
+            true => untar(zstd::Decoder::new(file)?)?,
+            false => untar(file)?,
+        };
+        let (_, savefile) = entries
+            .iter()
+            .find(|(name, _)| name == SAVEFILE)
+            .ok_or_else(|| TagError::Backup("it holds no savefile".to_string()))?;
+        let state = decode_state(savefile).map_err(|error| TagError::Backup(error.to_string()))?;
+        if state.ino_split != self.split.bits() {
+            return Err(TagError::Backup(format!(
+                "it uses an inode split of {} bits instead of {}",
+                state.ino_split,
+                self.split.bits()
+            )));
+        }
+
+        let sources = self
+            .file_meta
+            .iter()
+            .filter_map(|(&file, meta)| Some((meta.source?, file)))
+            .collect::<HashMap<_, _>>();
+        let files = state
+            .files
+            .iter()
+            .filter_map(|(&old, name)| {
+                let new = self.get_fnb_by_name(name).or_else(|| {
+                    let source = state.file_meta.get(&old)?.source?;
+                    sources.get(&source).copied()
+                })?;
+                Some((old, new))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut tag_content = state.tag_content;
+        for members in tag_content.values_mut() {
+            *members = members.iter().filter_map(|file| files.get(file)).copied().collect();
+        }
+        let mut tag_meta = state.tag_meta;
+        for meta in tag_meta.values_mut() {
+            meta.order = meta.order.iter().filter_map(|file| files.get(file)).copied().collect();
+        }
+        self.expiry = state
+            .expiry
+            .into_iter()
+            .filter_map(|((tag, file), at)| Some(((tag, *files.get(&file)?), at)))
+            .collect();
+
+        self.note_all_tags(state.tags.left_values().copied());
+        let tag_content = mem::replace(&mut self.tag_content, tag_content);
+        let tags = mem::replace(&mut self.tags, state.tags);
+        self.tag_meta = tag_meta;
+        self.stamp_replaced(&tag_content, &tags);
+        self.restore_tree(state.tree);
+
+        let mut put_back = 0;
+        for (path, content) in entries.iter() {
+            let snapshot = path.strip_prefix(SNAPSHOT_PREFIX);
+            if (snapshot.is_none() && path != AUDIT_LOG) || path.contains('/') {
+                continue;
+            }
+            if let Some(name) = snapshot {
+                let at = state.snapshots.get(name).copied().unwrap_or_else(SystemTime::now);
+                self.snapshots.entry(name.to_string()).or_insert(at);
+            }
+            let path = state_file(path);
+            if self.backing.get_metadata(&path).is_err() {
+                self.write_source(&path, content)
+                    .map_err(|error| TagError::Source(error.to_string()))?;
+                put_back += 1;
+            }
+        }
+
+        Ok(format!(
+            "restored {} tag(s) on {} of {} file(s), put back {put_back} missing file(s)",
+            self.tags.len(),
+            files.len(),
+            state.files.len()
+        ))
+    }
+
+    fn read_source(&self, path: &str) -> Result<Vec<u8>, B::Error> {
+        let handle = self.backing.open(path)?;
+        let content = self.backing.read(handle, 0, u64::MAX);
+        self.backing.release(handle);
+
+        content
+    }
+
+    fn write_source(&self, path: &str, content: &[u8]) -> Result<(), B::Error> {
+        let handle = self.backing.create(path)?;
+        let written = self.backing.write(handle, content);
+        self.backing.release(handle);
+
+        written.map(|_| ())
+    }
+}
+
+impl<B> TagFS<B> {
+    /// The indexed files with their identity in the source as of the last scan, one per line:
+    /// number, device, inode, size, mtime in seconds and name, separated by tabs. Unknown
+    /// values are `-`.
+    fn manifest(&self) -> String {
+        let mut files = self.files.iter().collect::<Vec<_>>();
+        files.sort_by_key(|(&file, _)| file);
+
+        let mut manifest = "# number\tdev\tino\tsize\tmtime\tname\n".to_string();
+        for (&file, name) in files {
+            let meta = self.file_meta.get(&file);
+            let source = meta.and_then(|meta| meta.source);
+            let snapshot = meta.and_then(|meta| meta.snapshot);
+
+            let _ = writeln!(
+                manifest,
+                "{file}\t{}\t{}\t{}\t{}\t{}",
+                field(source.map(|source| source.dev)),
+                field(source.map(|source| source.ino)),
+                field(snapshot.map(|snapshot| snapshot.size)),
+                field(snapshot.and_then(|snapshot| unix_secs(snapshot.mtime))),
+                name.to_string_lossy()
+            );
+        }
+
+        manifest
+    }
+}
+
+/// A manifest field, `-` when unknown.
+fn field(value: Option<u64>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_else(|| "-".to_string())
+}
+
+fn unix_secs(time: SystemTime) -> Option<u64> {
+    time.duration_since(UNIX_EPOCH).ok().map(|since| since.as_secs())
+}
+
+fn is_zstd(path: &Path) -> bool {
+    path.extension().map(|extension| extension == "zst").unwrap_or(false)
+}
+
+/// Write a tar archive of regular files with their content to `out`, and hand it back.
+fn write_tar<W: Write>(entries: &[(String, Vec<u8>)], out: W) -> io::Result<W> {
+    let mtime = unix_secs(SystemTime::now()).unwrap_or_default();
+
+    let mut builder = tar::Builder::new(out);
+    for (name, content) in entries {
+        let mut header = tar_header(content.len() as u64, 0o644, mtime);
+        builder.append_data(&mut header, name, content.as_slice())?;
+    }
+
+    builder.into_inner()
+}
+
+/// The header of a regular file, its name is set when it's appended to an archive.
+pub(super) fn tar_header(size: u64, mode: u32, mtime: u64) -> Header {
+    let mut header = Header::new_ustar();
+    header.set_entry_type(EntryType::Regular);
+    header.set_size(size);
+    header.set_mode(mode);
+    header.set_mtime(mtime);
+
+    header
+}
+
+/// The regular files in a tar archive with their content.
+fn untar<R: Read>(archive: R) -> Result<Vec<(String, Vec<u8>)>, TagError> {
+    let damaged = |error: io::Error| TagError::Backup(format!("the archive is damaged: {error}"));
+
+    let mut entries = vec![];
+    let mut archive = tar::Archive::new(archive);
+    for entry in archive.entries().map_err(damaged)? {
+        let mut entry = entry.map_err(damaged)?;
+        if entry.header().entry_type() != EntryType::Regular {
+            continue;
+        }
+
+        let name = entry.path().map_err(damaged)?.to_string_lossy().into_owned();
+        let mut content = vec![];
+        entry.read_to_end(&mut content).map_err(damaged)?;
+        entries.push((name, content));
+    }
+
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+    use crate::fs::tag::sim::MemFS;
+    use crate::fs::tag::FileName;
+
+    /// A mount of `names` tagged `music`, with a file name too long for a ustar header.
+    fn mount() -> (TagFS<MemFS>, [String; 2]) {
+        let names = ["a.mp3".to_string(), format!("{}.flac", "b".repeat(150))];
+        let mut fs = TagFS::new(MemFS::with_files(&names.each_ref().map(String::as_str)));
+        fs.repopulate(names.iter().map(|name| FileName::from(name.as_str())));
+        let music = fs.create_tag("music".into());
+        for file in fs.files.left_values().copied().collect::<Vec<_>>() {
+            fs.add_file_to(file, music);
+        }
+
+        (fs, names)
+    }
+
+    #[test]
+    fn restores_backups_in_tar_and_zstd() {
+        let (fs, names) = mount();
+        for name in ["backup.tar", "backup.tar.zst"] {
+            let out = std::env::temp_dir().join(format!("tagfs-{}-{name}", std::process::id()));
+            fs.backup(&out).unwrap();
+
+            let file = File::open(&out).unwrap();
+            let entries = match is_zstd(&out) {
+                true => untar(zstd::Decoder::new(file).unwrap()).unwrap(),
+                false => untar(file).unwrap(),
+            };
+            let (_, manifest) = entries.iter().find(|(path, _)| path == MANIFEST).unwrap();
+            assert!(String::from_utf8_lossy(manifest).contains(&names[1]));
+
+            let (mut restored, _) = mount();
+            let music = restored.get_tnb_by_name("music").unwrap();
+            restored.remove_file_from(restored.get_fnb_by_name(&names[1]).unwrap(), music);
+            restored.restore_backup(&out).unwrap();
+            let music = restored.get_tnb_by_name("music").unwrap();
+            assert_eq!(restored.tag_content[&music].len(), 2);
+
+            std::fs::remove_file(&out).unwrap();
+        }
+    }
+
+    #[test]
+    fn lays_out_tag_archives_tar_can_read() {
+        let (fs, names) = mount();
+        let mut archive = fs.archive(fs.get_tnb_by_name("music").unwrap());
+        let size = archive.size();
+        let data = archive.read(&fs.backing, 0, size).unwrap();
+        assert_eq!(data.len() as u64, size);
+
+        // Members are in the order of their names, each with its content
+        let entries = untar(Cursor::new(data)).unwrap();
+        let expected = names.iter().map(|name| (name.clone(), name.as_bytes().to_vec()));
+        assert!(entries.into_iter().eq(expected));
+    }
+}
@@ -0,0 +1,127 @@
+use std::error::Error;
+use std::ffi::OsStr;
+
+use fuser::FileAttr;
+use libc::{c_int, EEXIST, EINVAL, EIO, ENOENT, EPERM, EROFS};
+use log::debug;
+
+use crate::file::{FileNumber, Ino, TagNumber};
+use crate::fs::audit::Actor;
+use crate::fs::backing::BackingFS;
+use crate::fs::tag::{errno_of, TagFS};
+use crate::fs::FileHandle;
+
+/// A file being copied into a tag directory, see `create`. When the data written to it turns out
+/// to be the indexed file of the same name, the file is tagged with the tags of the directory
+/// instead of being duplicated.
+#[derive(Debug)]
+pub(super) struct PendingCopy {
+    file: FileNumber,
+    parent: Ino,
+    actor: Actor,
+    /// The indexed file, open to compare the written data with
+    source: FileHandle,
+    /// How much was written, in order from the start, all of it the same as the indexed file's
+    written: u64,
+    /// Some data wasn't the indexed file's at its offset, or wasn't written right after the data
+    /// before it
+    differs: bool,
+}
+
+impl<B> TagFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    /// Start copying the file `name` into directory `parent`, see `create`. Only files that are
+    /// already indexed can be copied in, anything else fails with EROFS. Returns the attributes
+    /// of the file, which is only tagged by [TagFS::finish_copy].
+    pub(super) fn start_copy(
+        &mut self,
+        parent: Ino,
+        name: &OsStr,
+        actor: Actor,
+    ) -> Result<(FileAttr, PendingCopy), c_int> {
+        let file = self.get_fnb_by_shown_name(name).ok_or(EROFS)?;
+        if self.is_offline(file) {
+            return Err(ENOENT);
+        }
+        self.copy_tags(parent)?;
+        let attr = self.file_attr(Ino::from_parts(file, parent.tag(self.split), self.split))?;
+
+        let name = self.source_name(file).ok_or(ENOENT)?;
+        let source = self.backing.open(name).map_err(|e| errno_of(&e, EIO))?;
+        let copy = PendingCopy { file, parent, actor, source, written: 0, differs: false };
+
+        Ok((attr, copy))
+    }
+
+    /// Take in `data` written to `copy` at `offset`, failing with EEXIST as soon as it isn't the
+    /// indexed file's data there. Data written out of order can't be compared and fails too.
+    pub(super) fn write_copy(
+        &self,
+        copy: &mut PendingCopy,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<(), c_int> {
+        if !copy.differs && offset == copy.written {
+            let mut have = vec![0; data.len()];
+            let read = self.backing.read_into(copy.source, offset, &mut have);
+            copy.differs = read.map_err(|e| errno_of(&e, EIO))? != data.len() || have != data;
+        } else {
+            copy.differs = true;
+        }
+
+        if copy.differs {
+            return Err(EEXIST);
+        }
+        copy.written += data.len() as u64;
+
+        Ok(())
+    }
+
+    /// Tag the file of `copy` with the tags of its directory, if the data written to it is the
+    /// same as the file's, and fail with EEXIST otherwise: that's another file of the same name.
+    pub(super) fn finish_copy(&mut self, copy: PendingCopy) -> Result<(), c_int> {
+        self.backing.release(copy.source);
+        let name = self.source_name(copy.file).ok_or(ENOENT)?.to_os_string();
+        let size = self.backing.get_metadata(&name).map_err(|e| errno_of(&e, EIO))?.size;
+        if copy.differs || copy.written != size {
+            debug!("copy of '{}' differs from the indexed file", name.to_string_lossy());
+            return Err(EEXIST);
+        }
+
+        if let Some(errno) = self.refuses_changes() {
+            return Err(errno);
+        }
+        let tags = self.copy_tags(copy.parent)?;
+        self.begin_step();
+        for &tag in tags.iter() {
+            self.add_file_to(copy.file, tag);
+        }
+
+        let shown = self.get_fnm_shown(copy.file).cloned().unwrap_or_default();
+        let detail = format!("'{}' into {}", shown.to_string_lossy(), self.tag_path(&tags));
+        self.audit(copy.actor, "copy", &detail);
+
+        self.save_step(format!("copy '{}'", shown.to_string_lossy()), Ok(()))
+    }
+
+    /// The tags files copied into directory `parent` get, if they may.
+    fn copy_tags(&self, parent: Ino) -> Result<Vec<TagNumber>, c_int> {
+        if self.is_locked_dir(parent.tag(self.split)) {
+            return Err(EPERM);
+        }
+        let node = self.tree.lookup(parent.tag(self.split)).ok_or(ENOENT)?;
+
+        let tags = node.borrow().collect_tags();
+        if !tags.iter().all(|&tag| self.may_apply(tag)) {
+            return Err(EPERM);
+        }
+        if self.crosses_groups(&tags) {
+            return Err(EINVAL);
+        }
+
+        Ok(tags)
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::OsString;
+use std::sync::mpsc::{Receiver, TryRecvError};
+
+use log::{debug, warn};
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::file::FileNumber;
+use crate::fs::backing::BackingFS;
+use crate::fs::tag::TagFS;
+
+/// How much of a file is read at once while hashing and comparing
+const CHUNK: u64 = 1 << 20;
+
+/// Group `files` of `backing` by content, see [TagFS::find_duplicates]. Only files sharing their
+/// size with another file are read: they're hashed to find candidates, and the candidates are
+/// compared byte for byte, so files only end up together when they're identical.
+pub fn group_duplicates<B: BackingFS>(
+    backing: &B,
+    files: impl IntoIterator<Item = OsString>,
+) -> Vec<Vec<OsString>>
+where
+    <B as BackingFS>::Error: Error,
+{
+    let mut by_size: HashMap<u64, Vec<OsString>> = HashMap::new();
+    for name in files {
+        match backing.get_metadata(&name) {
+            // Empty files are all the same, that's not interesting
+            Ok(attr) if attr.size == 0 => {}
+            Ok(attr) => by_size.entry(attr.size).or_default().push(name),
+            Err(e) => warn!("Couldn't stat '{}': {e}", name.to_string_lossy()),
+        }
+    }
+
+    let mut candidates: HashMap<(u64, u64), Vec<OsString>> = HashMap::new();
+    for (size, names) in by_size.into_iter().filter(|(_, names)| names.len() > 1) {
+        for name in names {
+            match hash(backing, &name) {
+                Ok(hash) => candidates.entry((size, hash)).or_default().push(name),
+                Err(e) => warn!("Couldn't hash '{}': {e}", name.to_string_lossy()),
+            }
+        }
+    }
+
+    let mut groups = vec![];
+    for names in candidates.into_values().filter(|names| names.len() > 1) {
+        // Nearly always a single group, unless the hashes collided
+        let mut same: Vec<Vec<OsString>> = vec![];
+        for name in names {
+            let group = same.iter_mut().find(|group| match identical(backing, &group[0], &name) {
+                Ok(identical) => identical,
+                Err(e) => {
+                    warn!("Couldn't compare '{}': {e}", name.to_string_lossy());
+                    false
+                }
+            });
+            match group {
+                Some(group) => group.push(name),
+                None => same.push(vec![name]),
+            }
+        }
+        groups.extend(same.into_iter().filter(|group| group.len() > 1));
+    }
+
+    groups
+}
+
+fn hash<B: BackingFS>(backing: &B, name: &OsString) -> Result<u64, B::Error> {
+    let handle = backing.open(name)?;
+    let mut hasher = Xxh3::new();
+    let mut offset = 0;
+    let result = loop {
+        match backing.read(handle, offset, CHUNK) {
+            Ok(data) if data.is_empty() => break Ok(hasher.digest()),
+            Ok(data) => {
+                hasher.update(&data);
+                offset += data.len() as u64;
+            }
+            Err(e) => break Err(e),
+        }
+    };
+    backing.release(handle);
+
+    result
+}
+
+/// Whether files `a` and `b` have the same content.
+fn identical<B: BackingFS>(backing: &B, a: &OsString, b: &OsString) -> Result<bool, B::Error> {
+    let a = backing.open(a)?;
+    let b = match backing.open(b) {
+        Ok(b) => b,
+        Err(e) => {
+            backing.release(a);
+            return Err(e);
+        }
+    };
+    let mut offset = 0;
+    let result = loop {
+        let chunk = |handle| backing.read(handle, offset, CHUNK);
+        match chunk(a).and_then(|ca| Ok((ca, chunk(b)?))) {
+            Ok((ca, cb)) if ca != cb => break Ok(false),
+            Ok((ca, _)) if ca.is_empty() => break Ok(true),
+            Ok((ca, _)) => offset += ca.len() as u64,
+            Err(e) => break Err(e),
+        }
+    };
+    backing.release(a);
+    backing.release(b);
+
+    result
+}
+
+impl<B> TagFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    /// Group the indexed files by content for `/@duplicates`, see [group_duplicates].
+    pub fn find_duplicates(&mut self) {
+        let names = self
+            .files
+            .left_values()
+            .filter(|&&file| !self.is_offline(file))
+            .filter_map(|&file| Some(self.source_name(file)?.to_os_string()))
+            .collect::<Vec<_>>();
+        let groups = group_duplicates(&self.backing, names);
+        self.set_duplicates(groups);
+    }
+
+    /// Take the groups of identical files from another thread running [group_duplicates] on the
+    /// files of the source, so mounting doesn't wait for every file to be read. They're listed in
+    /// `/@duplicates` once the scan is done as well, see [TagFS::poll_duplicates].
+    pub fn find_duplicates_in_background(&mut self, groups: Receiver<Vec<Vec<OsString>>>) {
+        self.duplicate_search = Some(groups);
+    }
+
+    /// List the groups found by the search in the background, if it's done.
+    pub fn poll_duplicates(&mut self) {
+        // Files the scan didn't index yet would be left out
+        if self.scan.is_some() {
+            return;
+        }
+        let Some(search) = &self.duplicate_search else { return };
+        match search.try_recv() {
+            Ok(groups) => self.set_duplicates(groups),
+            Err(TryRecvError::Empty) => return,
+            Err(TryRecvError::Disconnected) => warn!("the search for duplicates failed"),
+        }
+
+        self.duplicate_search = None;
+    }
+
+    /// List the groups of source names in `/@duplicates`, leaving out files that aren't indexed.
+    fn set_duplicates(&mut self, groups: Vec<Vec<OsString>>) {
+        let name = |file: &FileNumber| self.get_fnm_by_number(*file).cloned().unwrap_or_default();
+        let mut duplicates = groups
+            .into_iter()
+            .map(|names| {
+                let names = names.into_iter().filter_map(|name| self.get_fnb_by_name(name));
+                let mut files = names.collect::<Vec<_>>();
+                files.sort_by_cached_key(name);
+                files
+            })
+            .filter(|files| files.len() > 1)
+            .collect::<Vec<_>>();
+        duplicates.sort_by_cached_key(|files| name(&files[0]));
+
+        debug!("Found {} sets of duplicate files", duplicates.len());
+        self.duplicates = duplicates;
+    }
+}
@@ -0,0 +1,215 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::time::SystemTime;
+
+use bimap::BiMap;
+use indexmap::IndexMap;
+use serde::ser::{SerializeMap, SerializeSeq, SerializeStruct};
+use serde::{Serialize, Serializer};
+
+use crate::file::{FileNumber, TagNumber};
+use crate::fs::meta::TagMeta;
+use crate::fs::tag::stamps::Stamps;
+use crate::fs::tag::{FileName, SavedNode, TagFS};
+
+/// Tags made with `mkdir` under a name starting with this are ephemeral, see
+/// [TagFS::make_ephemeral].
+pub const EPHEMERAL_PREFIX: &str = ".tmp-";
+
+/// Whether a tag directory called `name` makes an ephemeral tag.
+pub(super) fn is_ephemeral_name(name: &OsStr) -> bool {
+    let name = name.as_bytes();
+
+    name.len() > EPHEMERAL_PREFIX.len() && name.starts_with(EPHEMERAL_PREFIX.as_bytes())
+}
+
+impl<B> TagFS<B> {
+    /// Never save `tag`, so it's gone once the mount is, along with its memberships. Meant for
+    /// one-off working sets that shouldn't end up among the saved tags.
+    pub fn make_ephemeral(&mut self, tag: TagNumber) {
+        self.ephemeral.insert(tag);
+    }
+
+    pub fn is_ephemeral(&self, tag: TagNumber) -> bool {
+        self.ephemeral.contains(&tag)
+    }
+
+    /// The ephemeral tags, to leave out of the savefile.
+    pub(super) fn ephemeral_tags(&self) -> Ephemeral<'_> {
+        Ephemeral {
+            tags: &self.ephemeral,
+            names: &self.tags,
+        }
+    }
+}
+
+/// The ephemeral tags of a mount, along with the names of its tags.
+#[derive(Clone, Copy)]
+pub(super) struct Ephemeral<'a> {
+    tags: &'a HashSet<TagNumber>,
+    names: &'a BiMap<TagNumber, FileName>,
+}
+
+impl<'a> Ephemeral<'a> {
+    pub(super) fn contains(&self, tag: &TagNumber) -> bool {
+        self.tags.contains(tag)
+    }
+
+    fn contains_name(&self, name: &FileName) -> bool {
+        self.names.get_by_right(name).is_some_and(|tag| self.contains(tag))
+    }
+
+    /// `part` of the state, to serialize without the ephemeral tags.
+    pub(super) fn skip<T>(self, part: &'a T) -> SkipEphemeral<'a, T> {
+        SkipEphemeral {
+            part,
+            ephemeral: self,
+        }
+    }
+
+    /// Drop the directories of the ephemeral tags from `tree`, and those below them.
+    pub(super) fn forget_nodes(&self, tree: &mut Vec<SavedNode>) {
+        // Parents are saved before their children, so the directories below go along
+        let mut dropped = HashSet::new();
+        tree.retain(|node| {
+            let drop = self.contains(&node.tag) || dropped.contains(&node.parent);
+            if drop {
+                dropped.insert(node.ino_part);
+            }
+            !drop
+        });
+    }
+}
+
+/// A part of the state borrowed for saving, which serializes like the part itself but without
+/// the ephemeral tags and all that refers to them, like their memberships, stamps and undo
+/// steps. So the state isn't copied to leave them out.
+pub(super) struct SkipEphemeral<'a, T> {
+    pub(super) part: &'a T,
+    pub(super) ephemeral: Ephemeral<'a>,
+}
+
+impl Serialize for SkipEphemeral<'_, IndexMap<TagNumber, HashSet<FileNumber>>> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // As a sequence, like `indexmap::serde_seq` in the savefile
+        let tag_content = self.part.iter().filter(|(tag, _)| !self.ephemeral.contains(tag));
+        Items(tag_content).serialize(serializer)
+    }
+}
+
+impl Serialize for SkipEphemeral<'_, BiMap<TagNumber, FileName>> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let tags = self.part.iter().filter(|(tag, _)| !self.ephemeral.contains(tag));
+        Entries(tags).serialize(serializer)
+    }
+}
+
+impl Serialize for SkipEphemeral<'_, HashMap<TagNumber, TagMeta>> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let tag_meta = self.part.iter().filter(|(tag, _)| !self.ephemeral.contains(tag));
+        Entries(tag_meta).serialize(serializer)
+    }
+}
+
+impl Serialize for SkipEphemeral<'_, HashMap<(TagNumber, FileNumber), SystemTime>> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let expiry = self.part.iter().filter(|((tag, _), _)| !self.ephemeral.contains(tag));
+        Entries(expiry).serialize(serializer)
+    }
+}
+
+impl Serialize for SkipEphemeral<'_, Stamps> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let ephemeral = self.ephemeral;
+        let tags = self.part.tags.iter().filter(|(name, _)| !ephemeral.contains_name(name));
+        let memberships = self.part.memberships.iter();
+        let memberships = memberships.filter(|((tag, _), _)| !ephemeral.contains(tag));
+
+        let mut stamps = serializer.serialize_struct("Stamps", 2)?;
+        stamps.serialize_field("tags", &Entries(tags))?;
+        stamps.serialize_field("memberships", &Entries(memberships))?;
+        stamps.end()
+    }
+}
+
+/// Entries serialized as a map. Serde wants to know how many up front, so they're counted first.
+pub(super) struct Entries<I>(pub(super) I);
+
+impl<I, K, V> Serialize for Entries<I>
+where
+    I: Iterator<Item = (K, V)> + Clone,
+    K: Serialize,
+    V: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.clone().count()))?;
+        for (key, value) in self.0.clone() {
+            map.serialize_entry(&key, &value)?;
+        }
+        map.end()
+    }
+}
+
+/// Items serialized as a sequence, counted first like [Entries].
+pub(super) struct Items<I>(pub(super) I);
+
+impl<I> Serialize for Items<I>
+where
+    I: Iterator + Clone,
+    I::Item: Serialize,
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.clone().count()))?;
+        for item in self.0.clone() {
+            seq.serialize_element(&item)?;
+        }
+        seq.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::ControlCommand;
+    use crate::file::Ino;
+    use crate::fs::audit::Actor;
+    use crate::fs::backing::ExternalFS;
+    use crate::fs::tag::{decode_state, FileName};
+
+    const USER: Actor = Actor { uid: 1000, pid: 1 };
+
+    fn run(fs: &mut TagFS<ExternalFS>, line: &str) {
+        fs.execute(ControlCommand::parse_line(line).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn leaves_ephemeral_tags_out_of_saves() {
+        let mut fs = TagFS::new(ExternalFS::new("/nonexistent"));
+        fs.repopulate(["a.flac", "b.flac"].map(FileName::from));
+        run(&mut fs, "tag a.flac music");
+        let work = fs.make_tag(Ino::ROOT, ".tmp-work".as_ref(), USER).unwrap();
+        run(&mut fs, "create-tags --ephemeral scratch");
+        run(&mut fs, "tag a.flac .tmp-work scratch");
+        run(&mut fs, "tag b.flac scratch music");
+        // A directory visited below one goes too
+        fs.lookup_entry(Ino(work.ino), "music".as_ref(), USER.uid).unwrap();
+
+        let state = decode_state(&fs.encode_state().unwrap()).unwrap();
+        let names = state.tags.right_values().map(|name| name.to_string_lossy());
+        assert_eq!(names.collect::<Vec<_>>(), ["music"]);
+        assert_eq!(state.tag_content.values().map(HashSet::len).sum::<usize>(), 2);
+        assert!(state.stamps.memberships.keys().all(|(tag, _)| state.tags.contains_left(tag)));
+        assert!(state.stamps.tags.keys().all(|name| name == "music"));
+        assert!(state.tree.iter().all(|node| state.tags.contains_left(&node.tag)));
+        // So are the undo steps that only changed ephemeral tags, like creating them
+        let mut copy = TagFS::new(ExternalFS::new("/nonexistent"));
+        copy.restore_state(state);
+        assert!((0..3).all(|_| copy.undo().is_ok()));
+        assert!(copy.undo().is_err());
+
+        // They're still there until the mount is gone
+        let scratch = fs.get_tnb_by_name("scratch").unwrap();
+        assert!(fs.is_ephemeral(scratch) && fs.tag_content[&scratch].len() == 2);
+    }
+}
@@ -0,0 +1,73 @@
+use std::error::Error;
+use std::time::{Duration, Instant, SystemTime};
+
+use log::info;
+
+use crate::file::{FileNumber, TagNumber};
+use crate::fs::backing::BackingFS;
+use crate::fs::tag::TagFS;
+
+/// How often the mount looks for expired memberships, see [TagFS::expire_due].
+const EXPIRY_CHECK: Duration = Duration::from_secs(60);
+
+impl<B> TagFS<B> {
+    /// Give files leave `tag` `ttl` after they were added to it, or keep them when `None`. Files
+    /// already in the tag get the full `ttl` from now.
+    pub fn set_ttl(&mut self, tag: TagNumber, ttl: Option<Duration>) {
+        self.tag_meta_mut(tag).ttl = ttl;
+
+        self.expiry.retain(|&(t, _), _| t != tag);
+        let members = self.tag_content.get(&tag).cloned().unwrap_or_default();
+        for file in members {
+            self.start_expiry(tag, file);
+        }
+    }
+
+    /// Start the TTL of `tag` for `file`, which was just added to it.
+    pub(super) fn start_expiry(&mut self, tag: TagNumber, file: FileNumber) {
+        if let Some(ttl) = self.tag_meta(tag).and_then(|meta| meta.ttl) {
+            self.expiry.insert((tag, file), SystemTime::now() + ttl);
+        }
+    }
+
+    /// Remove the memberships whose TTL ran out, returning how many.
+    pub fn expire(&mut self) -> usize {
+        let now = SystemTime::now();
+        let expired = self
+            .expiry
+            .iter()
+            .filter(|&(_, &at)| at <= now)
+            .map(|(&membership, _)| membership)
+            .collect::<Vec<_>>();
+
+        for &(tag, file) in expired.iter() {
+            self.expiry.remove(&(tag, file));
+            self.remove_file_from(file, tag);
+        }
+
+        expired.len()
+    }
+}
+
+impl<B> TagFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    /// Expire memberships while mounted, at most once per [EXPIRY_CHECK], and save if any were.
+    pub fn expire_due(&mut self) {
+        if self.expired_at.map(|at| at.elapsed() < EXPIRY_CHECK).unwrap_or(false) {
+            return;
+        }
+        self.expired_at = Some(Instant::now());
+        if self.holds_changes() {
+            return;
+        }
+
+        let expired = self.expire();
+        if expired > 0 {
+            info!("{expired} membership(s) expired");
+            self.save_or_record();
+        }
+    }
+}
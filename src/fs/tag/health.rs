@@ -0,0 +1,180 @@
+use std::error::Error;
+use std::ffi::CStr;
+use std::fmt::Write as _;
+use std::path::Path;
+use std::{fs, mem};
+
+use crate::fs::backing::BackingFS;
+use crate::fs::profile::{state_file, SAVEFILE};
+use crate::fs::tag::TagFS;
+
+/// Fewer file descriptors than this left to open is worth a warning.
+const FD_HEADROOM: u64 = 64;
+
+/// How a check of [TagFS::health] came out, from best to worst.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "fail",
+        }
+    }
+}
+
+/// What the checks of [TagFS::health] found, by the name of the check.
+#[derive(Debug)]
+pub struct Health {
+    checks: Vec<(&'static str, Status, String)>,
+}
+
+impl Health {
+    /// Whether any check failed, so the mount can't be relied on.
+    pub fn failed(&self) -> bool {
+        self.status() == Status::Fail
+    }
+
+    fn status(&self) -> Status {
+        self.checks.iter().map(|&(_, status, _)| status).max().unwrap_or(Status::Ok)
+    }
+
+    /// A `status` line with the worst outcome, then a line per check of its name, `ok`, `warn` or
+    /// `fail` and what it found, separated by tabs so monitoring scripts can pick them apart.
+    pub fn report(&self) -> String {
+        let mut report = format!("status\t{}\n", self.status().name());
+        for (check, status, found) in self.checks.iter() {
+            let _ = writeln!(report, "{check}\t{}\t{found}", status.name());
+        }
+
+        report
+    }
+}
+
+impl<B> TagFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    /// Run quick checks of the source, the savefile, the state in memory, the file descriptors
+    /// left and the kernel side of FUSE. None of them reads the files themselves.
+    pub fn health(&self) -> Health {
+        let checks = vec![
+            ("source", self.check_source()),
+            ("savefile", self.check_savefile()),
+            ("state", self.check_state()),
+            ("fds", check_fds()),
+            ("kernel", check_kernel()),
+        ];
+
+        Health {
+            checks: checks
+                .into_iter()
+                .map(|(check, (status, found))| (check, status, found))
+                .collect(),
+        }
+    }
+
+    fn check_source(&self) -> (Status, String) {
+        if self.backing.exists(state_file(SAVEFILE)) {
+            (Status::Ok, "reachable".to_string())
+        } else {
+            let found = "the savefile can't be found, the source is unreachable or was never saved";
+            (Status::Fail, found.to_string())
+        }
+    }
+
+    fn check_savefile(&self) -> (Status, String) {
+        if self.config.read_only {
+            return (Status::Ok, "a snapshot is mounted, nothing is saved".to_string());
+        }
+        if !self.backing.capabilities().writable {
+            return (Status::Fail, "the source is read-only, changes are refused".to_string());
+        }
+
+        // Mounts sharing the savefile merge what the others saved
+        let exclusive = self.config.shared.is_none();
+        match self.saved_version() {
+            None => (Status::Warn, "not saved yet".to_string()),
+            Some(version) if exclusive && Some(version) != self.saved_version => {
+                let found = "written by something else since it was last read or saved, which the \
+                             next save overwrites";
+                (Status::Warn, found.to_string())
+            }
+            Some(saves) => {
+                let saved = self.backing.get_metadata(state_file(SAVEFILE)).map(|attr| {
+                    let at = humantime::format_rfc3339_seconds(attr.mtime);
+                    format!("{} bytes, saved {at}", attr.size)
+                });
+                let saved = saved.unwrap_or_else(|_| "saved".to_string());
+                (Status::Ok, format!("{saved}, {saves} save(s) so far"))
+            }
+        }
+    }
+
+    /// Whether the memberships only refer to known files and tags, and whether changes are held
+    /// back from the savefile.
+    fn check_state(&self) -> (Status, String) {
+        let dangling: usize = self
+            .tag_content
+            .iter()
+            .map(|(tag, files)| {
+                if self.tags.contains_left(tag) {
+                    files.iter().filter(|file| !self.files.contains_left(file)).count()
+                } else {
+                    files.len().max(1)
+                }
+            })
+            .sum();
+        if dangling > 0 {
+            let found = format!("{dangling} tag membership(s) of unknown files or tags");
+            return (Status::Fail, found);
+        }
+        if self.transaction.is_some() {
+            return (Status::Warn, "changes are held back by an open transaction".to_string());
+        }
+        if self.frozen {
+            return (Status::Warn, "changes are held back by a freeze".to_string());
+        }
+
+        let found = format!("{} file(s), {} tag(s)", self.files.len(), self.tags.len());
+        (Status::Ok, found)
+    }
+}
+
+/// How many file descriptors are open, against the limit of the process.
+fn check_fds() -> (Status, String) {
+    let open = fs::read_dir("/proc/self/fd").map(|fds| fds.count() as u64);
+    let mut limit = unsafe { mem::zeroed::<libc::rlimit>() };
+    let limited = unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == 0;
+
+    match open {
+        Ok(open) if limited => {
+            let left = limit.rlim_cur.saturating_sub(open);
+            let status = if left < FD_HEADROOM { Status::Warn } else { Status::Ok };
+            (status, format!("{open} of {} open", limit.rlim_cur))
+        }
+        _ => (Status::Warn, "can't count the open file descriptors".to_string()),
+    }
+}
+
+/// Whether the kernel can serve FUSE mounts, with its release.
+fn check_kernel() -> (Status, String) {
+    let mut uts = unsafe { mem::zeroed::<libc::utsname>() };
+    let release = match unsafe { libc::uname(&mut uts) } {
+        0 => unsafe { CStr::from_ptr(uts.release.as_ptr()) }.to_string_lossy().into_owned(),
+        _ => "unknown".to_string(),
+    };
+
+    if Path::new("/dev/fuse").exists() {
+        (Status::Ok, format!("linux {release}, /dev/fuse present"))
+    } else {
+        (Status::Fail, format!("linux {release} without /dev/fuse, is the fuse module loaded?"))
+    }
+}
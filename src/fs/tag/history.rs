@@ -0,0 +1,491 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::file::{FileNumber, TagNumber};
+use crate::fs::error::TagError;
+use crate::fs::meta::{FileMeta, TagMeta};
+use crate::fs::stats::Derived;
+use crate::fs::tag::ephemeral::{Ephemeral, Items, SkipEphemeral};
+use crate::fs::tag::{FileName, TagFS};
+
+/// How many changes can be undone.
+const UNDO_STEPS: usize = 20;
+
+/// The changes that can be undone and redone, newest last.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct History {
+    undo: VecDeque<Step>,
+    redo: Vec<Step>,
+}
+
+/// What one change replaced: the old values of the entries it touched, `None` for entries it
+/// created. Applying a step reverts the change, and yields the step that reverts that again.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+struct Step {
+    /// What the change was, e.g. the report of a control command
+    what: String,
+    tag_content: Vec<(TagNumber, Option<HashSet<FileNumber>>)>,
+    tags: Vec<(TagNumber, Option<FileName>)>,
+    tag_meta: Vec<(TagNumber, Option<TagMeta>)>,
+    file_meta: Vec<(FileNumber, Option<FileMeta>)>,
+}
+
+/// The steps without the ephemeral tags, leaving out those that only changed ephemeral tags.
+impl Serialize for SkipEphemeral<'_, History> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let ephemeral = self.ephemeral;
+        let saved = move |step: &&Step| !ephemeral.skip(*step).is_empty();
+        let undo = self.part.undo.iter().filter(saved).map(|step| ephemeral.skip(step));
+        let redo = self.part.redo.iter().filter(saved).map(|step| ephemeral.skip(step));
+
+        let mut history = serializer.serialize_struct("History", 2)?;
+        history.serialize_field("undo", &Items(undo))?;
+        history.serialize_field("redo", &Items(redo))?;
+        history.end()
+    }
+}
+
+impl SkipEphemeral<'_, Step> {
+    fn is_empty(&self) -> bool {
+        let (step, ephemeral) = (self.part, self.ephemeral);
+        saved_entries(&step.tag_content, ephemeral).next().is_none()
+            && saved_entries(&step.tags, ephemeral).next().is_none()
+            && saved_entries(&step.tag_meta, ephemeral).next().is_none()
+            && step.file_meta.is_empty()
+    }
+}
+
+impl Serialize for SkipEphemeral<'_, Step> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let (step, ephemeral) = (self.part, self.ephemeral);
+        let tag_content = Items(saved_entries(&step.tag_content, ephemeral));
+
+        let mut saved = serializer.serialize_struct("Step", 5)?;
+        saved.serialize_field("what", &step.what)?;
+        saved.serialize_field("tag_content", &tag_content)?;
+        saved.serialize_field("tags", &Items(saved_entries(&step.tags, ephemeral)))?;
+        saved.serialize_field("tag_meta", &Items(saved_entries(&step.tag_meta, ephemeral)))?;
+        saved.serialize_field("file_meta", &step.file_meta)?;
+        saved.end()
+    }
+}
+
+/// The entries of a step that aren't about ephemeral tags.
+fn saved_entries<'a, V>(
+    entries: &'a [(TagNumber, V)],
+    ephemeral: Ephemeral<'a>,
+) -> impl Iterator<Item = &'a (TagNumber, V)> + Clone {
+    entries.iter().filter(move |(tag, _)| !ephemeral.contains(tag))
+}
+
+impl Step {
+    fn is_empty(&self) -> bool {
+        self.tag_content.is_empty()
+            && self.tags.is_empty()
+            && self.tag_meta.is_empty()
+            && self.file_meta.is_empty()
+    }
+}
+
+/// The old values of the entries a change touched in the part of the state it can be undone in:
+/// memberships, tag names and metadata, `None` for entries that didn't exist. The files follow the
+/// source, so they're left out. The mutators note an entry before they first change it, see
+/// [TagFS::begin_step], so recording a step doesn't compare whole copies of the state.
+#[derive(Debug, Default)]
+pub(super) struct Journal {
+    tag_content: HashMap<TagNumber, Option<HashSet<FileNumber>>>,
+    tags: HashMap<TagNumber, Option<FileName>>,
+    tag_meta: HashMap<TagNumber, Option<TagMeta>>,
+    file_meta: HashMap<FileNumber, Option<FileMeta>>,
+}
+
+/// How the history moved during a batch of control commands, so the batch can be reverted when
+/// it can't be saved, see [TagFS::begin_batch].
+#[derive(Debug, Default)]
+pub(super) struct Batch(Vec<Moved>);
+
+#[derive(Debug)]
+enum Moved {
+    /// A step was recorded, pushing out the oldest step, if any, and forgetting what could be
+    /// redone
+    Recorded { dropped: Option<Step>, redo: Vec<Step> },
+    Undid,
+    Redid,
+}
+
+impl<B> TagFS<B> {
+    /// Start noting what a change touches, to record it with [TagFS::record_step]. Inside a
+    /// transaction the journal of the transaction goes on instead.
+    pub(crate) fn begin_step(&mut self) {
+        if self.transaction.is_none() {
+            self.journal = Some(Journal::default());
+        }
+    }
+
+    /// Stop noting a change that failed, which isn't recorded. A transaction goes on noting.
+    pub(crate) fn abandon_step(&mut self) {
+        if self.transaction.is_none() {
+            self.journal = None;
+        }
+    }
+
+    /// Start noting the steps a batch of control commands records, undoes and redoes, to take
+    /// them back with [TagFS::revert_batch] until the batch is saved.
+    pub(super) fn begin_batch(&mut self) {
+        self.batch = Some(Batch::default());
+    }
+
+    /// Revert what the batch begun with [TagFS::begin_batch] changed and forget it, for a batch
+    /// that couldn't be saved. The history is left as it was before the batch.
+    pub(super) fn revert_batch(&mut self) {
+        let Some(Batch(moves)) = self.batch.take() else { return };
+        for moved in moves.into_iter().rev() {
+            match moved {
+                Moved::Recorded { dropped, redo } => {
+                    self.revert_step();
+                    if let Some(step) = dropped {
+                        self.history.undo.push_front(step);
+                    }
+                    self.history.redo = redo;
+                }
+                Moved::Undid => {
+                    if let Some(step) = self.history.redo.pop() {
+                        let undo = self.apply_step(step);
+                        self.history.undo.push_back(undo);
+                    }
+                }
+                Moved::Redid => {
+                    if let Some(step) = self.history.undo.pop_back() {
+                        let redo = self.apply_step(step);
+                        self.history.redo.push(redo);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Put back what `journal` noted, for the changes of a transaction that's rolled back.
+    pub(super) fn revert_journal(&mut self, journal: Journal) {
+        self.apply_step(Step {
+            what: String::new(),
+            tag_content: journal.tag_content.into_iter().collect(),
+            tags: journal.tags.into_iter().collect(),
+            tag_meta: journal.tag_meta.into_iter().collect(),
+            file_meta: journal.file_meta.into_iter().collect(),
+        });
+    }
+
+    fn note_move(&mut self, moved: Moved) {
+        if let Some(Batch(moves)) = self.batch.as_mut() {
+            moves.push(moved);
+        }
+    }
+
+    /// Drop what was worked out from the tags as they were, see [Derived].
+    pub(super) fn forget_derived(&mut self) {
+        *self.derived.get_mut() = Derived::default();
+    }
+
+    /// Note the members of `tag` before they change.
+    pub(super) fn note_members(&mut self, tag: TagNumber) {
+        self.forget_derived();
+        if let Some(journal) = self.journal.as_mut() {
+            let old = || self.tag_content.get(&tag).cloned();
+            journal.tag_content.entry(tag).or_insert_with(old);
+        }
+    }
+
+    /// Note the name of `tag` before it changes.
+    pub(super) fn note_name(&mut self, tag: TagNumber) {
+        self.forget_derived();
+        if let Some(journal) = self.journal.as_mut() {
+            let old = || self.tags.get_by_left(&tag).cloned();
+            journal.tags.entry(tag).or_insert_with(old);
+        }
+    }
+
+    /// Note the metadata of `tag` before it changes.
+    pub(super) fn note_tag_meta(&mut self, tag: TagNumber) {
+        self.forget_derived();
+        if let Some(journal) = self.journal.as_mut() {
+            let old = || self.tag_meta.get(&tag).cloned();
+            journal.tag_meta.entry(tag).or_insert_with(old);
+        }
+    }
+
+    /// Note the metadata of `file` before it changes.
+    pub(super) fn note_file_meta(&mut self, file: FileNumber) {
+        if let Some(journal) = self.journal.as_mut() {
+            let old = || self.file_meta.get(&file).cloned();
+            journal.file_meta.entry(file).or_insert_with(old);
+        }
+    }
+
+    /// Note every tag, and the tags `incoming`, before the tags are all replaced, e.g. by those
+    /// of a snapshot.
+    pub(super) fn note_all_tags(&mut self, incoming: impl IntoIterator<Item = TagNumber>) {
+        self.forget_derived();
+        if self.journal.is_none() {
+            return;
+        }
+
+        let tags = self.tags.left_values().chain(self.tag_content.keys()).copied();
+        let tags = tags.chain(self.tag_meta.keys().copied()).collect::<Vec<_>>();
+        for tag in tags.into_iter().chain(incoming) {
+            self.note_members(tag);
+            self.note_name(tag);
+            self.note_tag_meta(tag);
+        }
+    }
+
+    /// Remember the change noted since [TagFS::begin_step], so it can be undone. This forgets
+    /// what could be redone. Changes in a transaction are recorded as one, once it's committed.
+    /// Returns whether there was a change to remember.
+    pub(crate) fn record_step(&mut self, what: String) -> bool {
+        if self.transaction.is_some() {
+            return false;
+        }
+        let Some(journal) = self.journal.take() else { return false };
+
+        let step = Step {
+            what,
+            tag_content: changed(journal.tag_content, |tag| self.tag_content.get(tag)),
+            tags: changed(journal.tags, |tag| self.tags.get_by_left(tag)),
+            tag_meta: changed(journal.tag_meta, |tag| self.tag_meta.get(tag)),
+            file_meta: changed(journal.file_meta, |file| self.file_meta.get(file)),
+        };
+        if step.is_empty() {
+            return false;
+        }
+
+        let mut dropped = None;
+        if self.history.undo.len() == UNDO_STEPS {
+            dropped = self.history.undo.pop_front();
+        }
+        self.history.undo.push_back(step);
+        let redo = std::mem::take(&mut self.history.redo);
+        self.note_move(Moved::Recorded { dropped, redo });
+        true
+    }
+
+    /// Revert the change recorded last and forget it, for a change that didn't go through after
+    /// all. It can't be redone.
+    pub(super) fn revert_step(&mut self) {
+        if let Some(step) = self.history.undo.pop_back() {
+            self.apply_step(step);
+        }
+    }
+
+    /// Revert the last change, returning what it was. Not in a transaction, whose changes are
+    /// only recorded once it's committed.
+    pub fn undo(&mut self) -> Result<String, TagError> {
+        self.refuse_in_transaction()?;
+        let step = self
+            .history
+            .undo
+            .pop_back()
+            .ok_or(TagError::History("nothing to undo"))?;
+        let redo = self.apply_step(step);
+        let report = format!("undid: {}", redo.what);
+        self.history.redo.push(redo);
+        self.note_move(Moved::Undid);
+
+        Ok(report)
+    }
+
+    /// Make the last undone change again, returning what it was. Not in a transaction either.
+    pub fn redo(&mut self) -> Result<String, TagError> {
+        self.refuse_in_transaction()?;
+        let step = self
+            .history
+            .redo
+            .pop()
+            .ok_or(TagError::History("nothing to redo"))?;
+        let undo = self.apply_step(step);
+        let report = format!("redid: {}", undo.what);
+        self.history.undo.push_back(undo);
+        self.note_move(Moved::Redid);
+
+        Ok(report)
+    }
+
+    fn refuse_in_transaction(&self) -> Result<(), TagError> {
+        match self.transaction {
+            Some(_) => Err(TagError::Transaction("undo and redo can't be part of a transaction")),
+            None => Ok(()),
+        }
+    }
+
+    /// Put back the values of a step, returning the step that reverts it.
+    fn apply_step(&mut self, step: Step) -> Step {
+        self.forget_derived();
+        let tag_content = put(step.tag_content, |tag, set| match set {
+            Some(set) => self.tag_content.insert(tag, set),
+            None => self.tag_content.shift_remove(&tag),
+        });
+        // Names may have moved between tags, so they're all taken off before any is put back
+        let tags = step
+            .tags
+            .iter()
+            .map(|&(tag, _)| (tag, self.tags.remove_by_left(&tag).map(|(_, name)| name)))
+            .collect::<Vec<_>>();
+        for (tag, name) in step.tags {
+            if let Some(name) = name {
+                self.tags.insert(tag, name);
+            }
+        }
+        let tag_meta = put(step.tag_meta, |tag, meta| match meta {
+            Some(meta) => self.tag_meta.insert(tag, meta),
+            None => self.tag_meta.remove(&tag),
+        });
+        let file_meta = put(step.file_meta, |file, meta| match meta {
+            Some(meta) => self.file_meta.insert(file, meta),
+            None => self.file_meta.remove(&file),
+        });
+
+        // What was replaced is what the state was before
+        let members = tag_content.iter().map(|(tag, set)| (*tag, set.as_ref())).collect();
+        let names = tags.iter().map(|(tag, name)| (*tag, name.as_ref())).collect();
+        self.stamp_changes(&members, &names);
+
+        // Directories of tags that are gone again
+        for &(tag, _) in tags.iter() {
+            if !self.tags.contains_left(&tag) {
+                self.tree.remove_tag(tag);
+            }
+        }
+
+        Step {
+            what: step.what,
+            tag_content,
+            tags,
+            tag_meta,
+            file_meta,
+        }
+    }
+}
+
+/// The entries of `journal` whose old value is different from the one `now` has.
+fn changed<'a, K, V>(
+    journal: HashMap<K, Option<V>>,
+    now: impl Fn(&K) -> Option<&'a V>,
+) -> Vec<(K, Option<V>)>
+where
+    V: PartialEq + 'a,
+{
+    journal
+        .into_iter()
+        .filter(|(key, old)| old.as_ref() != now(key))
+        .collect()
+}
+
+/// Apply `changes` through `set`, which returns the value it replaced.
+fn put<K, V>(
+    changes: Vec<(K, Option<V>)>,
+    mut set: impl FnMut(K, Option<V>) -> Option<V>,
+) -> Vec<(K, Option<V>)>
+where
+    K: Copy,
+{
+    changes
+        .into_iter()
+        .map(|(key, value)| (key, set(key, value)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::ControlCommand;
+    use crate::fs::backing::ExternalFS;
+
+    fn mount() -> TagFS<ExternalFS> {
+        let mut fs = TagFS::new(ExternalFS::new("/nonexistent"));
+        fs.repopulate(["a.flac", "b.flac"].map(FileName::from));
+        fs
+    }
+
+    fn tag(fs: &mut TagFS<ExternalFS>, file: &str, tags: &[&str]) {
+        let tags = tags.iter().map(Into::into).collect();
+        fs.execute(ControlCommand::Tag { file: file.into(), tags }).unwrap();
+    }
+
+    /// The names of the tags of `file`, sorted.
+    fn tags_of(fs: &TagFS<ExternalFS>, file: &str) -> Vec<String> {
+        let file = fs.get_fnb_by_name(file).unwrap();
+        let tags = fs.tags_of(file).into_iter().map(|tag| fs.tags().get_by_left(&tag).unwrap());
+        let mut names = tags.map(|name| name.to_string_lossy().into_owned()).collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn undoes_and_redoes_changes() {
+        let mut fs = mount();
+        tag(&mut fs, "a.flac", &["music", "live"]);
+        tag(&mut fs, "b.flac", &["music"]);
+        let rm = ControlCommand::RmTag { name: "live".into(), move_to: None, force: true };
+        fs.execute(rm).unwrap();
+        assert_eq!(tags_of(&fs, "a.flac"), ["music"]);
+
+        // The removed tag comes back with its members
+        fs.undo().unwrap();
+        assert_eq!(tags_of(&fs, "a.flac"), ["live", "music"]);
+        fs.undo().unwrap();
+        assert!(tags_of(&fs, "b.flac").is_empty());
+
+        fs.redo().unwrap();
+        assert_eq!(tags_of(&fs, "b.flac"), ["music"]);
+        fs.redo().unwrap();
+        assert_eq!(tags_of(&fs, "a.flac"), ["music"]);
+        assert!(fs.get_tnb_by_name("live").is_none());
+        assert!(fs.redo().is_err());
+
+        // A new change forgets what could be redone
+        fs.undo().unwrap();
+        tag(&mut fs, "b.flac", &["rock"]);
+        assert!(fs.redo().is_err());
+    }
+
+    #[test]
+    fn records_only_changes() {
+        let mut fs = mount();
+        tag(&mut fs, "a.flac", &["music"]);
+        tag(&mut fs, "a.flac", &["music"]);
+
+        // The second command changed nothing, the first created the tag as well
+        fs.undo().unwrap();
+        assert!(tags_of(&fs, "a.flac").is_empty());
+        assert!(fs.get_tnb_by_name("music").is_none());
+        assert!(fs.undo().is_err());
+    }
+
+    #[test]
+    fn keeps_the_latest_steps() {
+        let mut fs = mount();
+        for i in 0..UNDO_STEPS + 5 {
+            tag(&mut fs, "a.flac", &[&format!("tag{i}")]);
+        }
+
+        for _ in 0..UNDO_STEPS {
+            fs.undo().unwrap();
+        }
+        assert!(fs.undo().is_err());
+        assert_eq!(tags_of(&fs, "a.flac"), ["tag0", "tag1", "tag2", "tag3", "tag4"]);
+    }
+
+    #[test]
+    fn forgets_failed_changes() {
+        let mut fs = mount();
+        tag(&mut fs, "a.flac", &["music"]);
+        let missing = ControlCommand::Tag { file: "c.flac".into(), tags: vec!["live".into()] };
+        assert!(fs.execute(missing).is_err());
+
+        // Nothing is noted until the next change begins
+        assert!(fs.journal.is_none());
+        fs.undo().unwrap();
+        assert!(fs.get_tnb_by_name("music").is_none());
+    }
+}
@@ -0,0 +1,246 @@
+use std::io::Write;
+use std::mem;
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+use log::warn;
+
+use crate::file::{FileNumber, TagNumber};
+use crate::fs::config::Webhook;
+use crate::fs::tag::{FileName, TagFS};
+use crate::logging::json_string;
+
+/// How long a webhook gets to answer, connecting included.
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A file being tagged or untagged, reported to the hooks once it's saved.
+#[derive(Debug, Clone)]
+pub(super) struct TagEvent {
+    tagged: bool,
+    tag: FileName,
+    file: FileName,
+}
+
+impl TagEvent {
+    fn kind(&self) -> &'static str {
+        match self.tagged {
+            true => "tagged",
+            false => "untagged",
+        }
+    }
+}
+
+/// A run of a hook, for the events of one save.
+#[derive(Debug)]
+enum HookRun {
+    Command { command: String, lines: String },
+    Webhook { webhook: Webhook, body: String },
+}
+
+impl HookRun {
+    /// Failures are only logged, there's nobody to report them to.
+    fn run(self) {
+        match self {
+            HookRun::Command { command, lines } => run_hook(&command, &lines),
+            HookRun::Webhook { webhook, body } => {
+                if let Err(e) = post(&webhook, &body) {
+                    warn!("webhook {webhook} failed: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// The thread running the hooks one after the other, in the order of the saves. It's started for
+/// the first of them.
+#[derive(Debug, Default)]
+pub(super) struct HookWorker(Option<Sender<HookRun>>);
+
+impl HookWorker {
+    fn send(&mut self, run: HookRun) {
+        let sender = self.0.get_or_insert_with(|| {
+            let (sender, runs) = mpsc::channel::<HookRun>();
+            thread::spawn(move || runs.into_iter().for_each(HookRun::run));
+            sender
+        });
+        // The worker only stops once the sender is dropped
+        let _ = sender.send(run);
+    }
+}
+
+impl<B> TagFS<B> {
+    /// Keep `file` being tagged or untagged with `tag` for the hooks, if there are any.
+    pub(super) fn record_event(&mut self, tagged: bool, tag: TagNumber, file: FileNumber) {
+        if self.config.hook.is_none() && self.config.webhook.is_none() {
+            return;
+        }
+        let (Some(tag), Some(file)) = (self.tags.get_by_left(&tag), self.get_fnm_by_number(file))
+        else {
+            return;
+        };
+        let hooked = &self.config.hook_tags;
+        if !hooked.is_empty() && !hooked.iter().any(|hooked| **hooked == **tag) {
+            return;
+        }
+
+        let event = TagEvent {
+            tagged,
+            tag: tag.clone(),
+            file: file.clone(),
+        };
+        self.events.push(event);
+    }
+
+    /// Report the events since the last save to the hooks. They run on a thread of their own, so
+    /// a slow hook doesn't hold up the mount, only the hooks of later saves.
+    pub(crate) fn fire_hooks(&mut self) {
+        if self.events.is_empty() {
+            return;
+        }
+        let events = mem::take(&mut self.events);
+
+        if let Some(command) = self.config.hook.clone() {
+            let lines = events
+                .iter()
+                .map(|event| {
+                    let (tag, file) = (event.tag.to_string_lossy(), event.file.to_string_lossy());
+                    format!("{}\t{tag}\t{file}\n", event.kind())
+                })
+                .collect::<String>();
+            self.hooks.send(HookRun::Command { command, lines });
+        }
+        if let Some(webhook) = self.config.webhook.clone() {
+            let body = webhook_body(&events);
+            self.hooks.send(HookRun::Webhook { webhook, body });
+        }
+    }
+}
+
+/// Run `command` through the shell with the events on stdin, one per line.
+fn run_hook(command: &str, lines: &str) {
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdin(Stdio::piped())
+        .spawn();
+    let status = child.and_then(|mut child| {
+        // The hook doesn't have to read them
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(lines.as_bytes());
+        }
+        child.wait()
+    });
+
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!("hook '{command}' failed: {status}"),
+        Err(e) => warn!("can't run hook '{command}': {e}"),
+    }
+}
+
+/// The events as a JSON object, e.g. `{"events":[{"event":"tagged","tag":"ready","file":"a"}]}`.
+fn webhook_body(events: &[TagEvent]) -> String {
+    let events = events
+        .iter()
+        .map(|event| {
+            format!(
+                "{{\"event\":{},\"tag\":{},\"file\":{}}}",
+                json_string(event.kind()),
+                json_string(&event.tag.to_string_lossy()),
+                json_string(&event.file.to_string_lossy())
+            )
+        })
+        .collect::<Vec<_>>();
+
+    format!("{{\"events\":[{}]}}", events.join(","))
+}
+
+/// POST `body` to `webhook`, failing unless it answers with a 2xx status.
+fn post(webhook: &Webhook, body: &str) -> Result<(), ureq::Error> {
+    let agent = ureq::Agent::config_builder()
+        .timeout_global(Some(WEBHOOK_TIMEOUT))
+        .max_redirects(0)
+        .build()
+        .new_agent();
+    let response = agent
+        .post(webhook.to_string())
+        .header("Content-Type", "application/json")
+        .send(body)?;
+
+    match response.status().is_success() {
+        true => Ok(()),
+        false => Err(ureq::Error::StatusCode(response.status().as_u16())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    use super::*;
+
+    /// A webhook answering one request with `status`, and the request it got.
+    fn serve(status: &'static str) -> (Webhook, thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://127.0.0.1:{}/hook", listener.local_addr().unwrap().port());
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![0; 4096];
+            let mut read = 0;
+            while !String::from_utf8_lossy(&request[..read]).ends_with('}') {
+                read += stream.read(&mut request[read..]).unwrap();
+            }
+            write!(stream, "HTTP/1.1 {status}\r\nContent-Length: 0\r\n\r\n").unwrap();
+            String::from_utf8_lossy(&request[..read]).into_owned()
+        });
+
+        (url.parse().unwrap(), server)
+    }
+
+    #[test]
+    fn posts_events_to_webhooks() {
+        let events = [TagEvent {
+            tagged: true,
+            tag: "ready".into(),
+            file: "a \"b\".mkv".into(),
+        }];
+        let body = webhook_body(&events);
+
+        let (webhook, server) = serve("204 No Content");
+        assert!(post(&webhook, &body).is_ok());
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /hook HTTP/1.1\r\n"));
+        assert!(request.to_lowercase().contains("content-type: application/json"));
+        let json = r#"{"events":[{"event":"tagged","tag":"ready","file":"a \"b\".mkv"}]}"#;
+        assert!(request.ends_with(json));
+
+        let (webhook, server) = serve("500 Internal Server Error");
+        assert!(post(&webhook, &body).is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn runs_hooks_in_order_on_one_thread() {
+        let out = std::env::temp_dir().join(format!("tagfs-hooks-{}", std::process::id()));
+        let out_path = out.display();
+        let command = format!("read line; sleep 0.${{line%% *}}; echo $line >> '{out_path}'");
+        let mut worker = HookWorker::default();
+        for lines in ["3 first\n", "1 second\n"] {
+            let (command, lines) = (command.clone(), lines.to_string());
+            worker.send(HookRun::Command { command, lines });
+        }
+
+        // The quicker second hook still runs after the first
+        let read = || std::fs::read_to_string(&out).unwrap_or_default();
+        let started = std::time::Instant::now();
+        while read().lines().count() < 2 && started.elapsed() < Duration::from_secs(10) {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(read(), "3 first\n1 second\n");
+        std::fs::remove_file(&out).unwrap();
+    }
+}
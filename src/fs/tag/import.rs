@@ -0,0 +1,139 @@
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+
+use log::{debug, info};
+
+use crate::file::FileNumber;
+use crate::fs::backing::BackingFS;
+use crate::fs::error::TagError;
+use crate::fs::tag::TagFS;
+use crate::fs::virt::VirtualDir;
+
+/// The extended attributes other tools keep the tags of a file in, as comma separated lists:
+/// `user.xdg.tags` by Dolphin, GNOME and the others following the freedesktop.org
+/// recommendation, and `user.tags` by some older ones.
+const TAG_XATTRS: [&str; 2] = ["user.xdg.tags", "user.tags"];
+
+/// The tags in the value of one of [TAG_XATTRS]. Names that can't be tag directories are left
+/// out.
+fn parse_tags(value: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(value)
+        .split(',')
+        .map(str::trim)
+        .filter(|tag| !tag.is_empty() && !tag.contains('/') && !matches!(*tag, "." | ".."))
+        .filter(|tag| !VirtualDir::is_reserved(OsStr::new(tag)))
+        .map(str::to_string)
+        .collect()
+}
+
+impl<B> TagFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    /// Tag `file` with the tags other tools stored in its extended attributes in the source,
+    /// see [TAG_XATTRS], creating them as needed. Tags the vocabulary doesn't declare are left
+    /// out. Returns how many tags the file got.
+    pub fn import_xattr_tags(&mut self, file: FileNumber) -> usize {
+        let Some(name) = self.source_name(file).map(OsStr::to_os_string) else { return 0 };
+
+        let tags = TAG_XATTRS
+            .iter()
+            .filter_map(|xattr| self.backing.getxattr(&name, OsStr::new(xattr)).ok())
+            .flat_map(|value| parse_tags(&value))
+            .collect::<Vec<_>>();
+
+        let mut imported = 0;
+        for tag in tags {
+            if !self.is_declared(OsStr::new(&tag)) {
+                debug!("not importing undeclared tag '{tag}' of '{}'", name.to_string_lossy());
+                continue;
+            }
+
+            let tag = self.get_or_create_tag(tag.into());
+            if !self.has_tag(file, tag) {
+                self.add_file_to(file, tag);
+                imported += 1;
+            }
+        }
+
+        imported
+    }
+
+    /// [TagFS::import_xattr_tags] of the files `names`, or of every file when there are none,
+    /// for the `import-xattr-tags` command.
+    pub(crate) fn import_xattr_tags_of(&mut self, names: &[OsString]) -> Result<String, TagError> {
+        let files = match names.is_empty() {
+            true => self.files.left_values().copied().collect(),
+            false => self.require_files(names)?,
+        };
+
+        let (mut tagged, mut imported) = (0, 0);
+        for &file in files.iter() {
+            let count = self.import_xattr_tags(file);
+            tagged += usize::from(count > 0);
+            imported += count;
+        }
+        info!("imported {imported} tag(s) of {tagged} file(s) from extended attributes");
+
+        Ok(format!(
+            "imported {imported} tag(s) of {tagged} of {} file(s) from extended attributes",
+            files.len()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    use super::*;
+    use crate::fs::backing::ExternalFS;
+    use crate::fs::config::Config;
+    use crate::fs::tag::FileName;
+
+    fn set_xattr(path: &Path, name: &str, value: &str) {
+        let path = CString::new(path.as_os_str().as_bytes()).unwrap();
+        let name = CString::new(name).unwrap();
+        let set = unsafe {
+            libc::setxattr(path.as_ptr(), name.as_ptr(), value.as_ptr().cast(), value.len(), 0)
+        };
+        assert_eq!(set, 0);
+    }
+
+    #[test]
+    fn leaves_out_names_that_cannot_be_tags() {
+        assert_eq!(parse_tags(b" rock, live ,,a/b,..,@all"), ["rock", "live"]);
+    }
+
+    #[test]
+    fn imports_the_tags_of_other_tools() {
+        let source = std::env::temp_dir().join(format!("tagfs-import-{}", std::process::id()));
+        std::fs::create_dir_all(&source).unwrap();
+        for name in ["a.mp3", "b.mp3"] {
+            std::fs::write(source.join(name), name).unwrap();
+        }
+        set_xattr(&source.join("a.mp3"), "user.xdg.tags", "rock,live");
+        set_xattr(&source.join("b.mp3"), "user.tags", "rock");
+
+        // Files get their tags as they're first indexed
+        let mut fs = TagFS::new(ExternalFS::new(source.to_str().unwrap()));
+        fs.set_config(Config { import_xattr_tags: true, ..Default::default() });
+        fs.repopulate(["a.mp3", "b.mp3"].map(FileName::from));
+        let members = |fs: &TagFS<ExternalFS>, tag: &str| {
+            fs.tag_content.get(&fs.get_tnb_by_name(tag).unwrap()).map(|files| files.len())
+        };
+        assert_eq!(members(&fs, "rock"), Some(2));
+        assert_eq!(members(&fs, "live"), Some(1));
+
+        // And on demand, which only adds the tags they don't have yet
+        set_xattr(&source.join("b.mp3"), "user.tags", "rock,jazz");
+        let report = fs.import_xattr_tags_of(&[]).unwrap();
+        assert!(report.starts_with("imported 1 tag(s) of 1 of 2 file(s)"));
+        assert_eq!(members(&fs, "jazz"), Some(1));
+
+        std::fs::remove_dir_all(&source).unwrap();
+    }
+}
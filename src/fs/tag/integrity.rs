@@ -0,0 +1,412 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::{OsStr, OsString};
+use std::fmt::Display;
+use std::hash::Hasher;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+use std::time::SystemTime;
+
+use bincode::serde::Compat;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::file::FileNumber;
+use crate::fs::backing::BackingFS;
+use crate::fs::error::TagError;
+use crate::fs::profile::state_file;
+use crate::fs::tag::TagFS;
+
+/// The checksums of the files, next to the savefile in the source dir.
+pub const CHECKSUMS: &str = ".tagfs-checksums";
+
+/// The tag given to files whose contents changed behind their back, see [TagFS::verify].
+pub const CORRUPT: &str = "corrupt";
+
+/// The contents of a file when it was last verified, and the mtime and size it had then.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+struct Checksum {
+    hash: u64,
+    size: u64,
+    mtime: SystemTime,
+}
+
+/// A run over all files, which may take several `verify --limit` runs.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Pass {
+    started: Option<SystemTime>,
+    /// The last file checked, files are checked in the order of their numbers
+    after: Option<FileNumber>,
+    checked: usize,
+    corrupt: usize,
+    /// When the last pass went through all files
+    finished: Option<SystemTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Checksums {
+    files: HashMap<FileNumber, Checksum>,
+    pass: Pass,
+}
+
+/// 64-bit FNV-1a. Unlike the std hashers, it's sure to hash the same in every build, so the
+/// checksums stay valid across upgrades.
+struct Fnv(u64);
+
+impl Default for Fnv {
+    fn default() -> Self {
+        Fnv(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for Fnv {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100_0000_01b3);
+        }
+    }
+}
+
+/// Hashes files for [TagFS::verify] on a thread of its own, with an instance of the source of its
+/// own, so a mount goes on answering while a run reads the files. See [spawn_verifier].
+#[derive(Debug)]
+pub struct Verifier {
+    files: Sender<(FileNumber, OsString)>,
+    checked: Receiver<(FileNumber, Option<Checksum>)>,
+    /// The run whose files are being hashed
+    run: Option<Run>,
+    /// The report of the last run that finished, for `verify --status`
+    report: Option<String>,
+}
+
+/// Start hashing the files sent to the [Verifier] in `backing`, in the order they're sent.
+pub fn spawn_verifier<B>(backing: B) -> Verifier
+where
+    B: BackingFS + Send + 'static,
+    <B as BackingFS>::Error: Display,
+{
+    let (files, queue) = mpsc::channel::<(FileNumber, OsString)>();
+    let (done, checked) = mpsc::channel();
+    thread::spawn(move || {
+        for (file, name) in queue {
+            let checksum = checksum(&backing, &name);
+            if let Err(e) = &checksum {
+                debug!("can't verify '{}': {e}", name.to_string_lossy());
+            }
+            if done.send((file, checksum.ok())).is_err() {
+                return;
+            }
+        }
+    });
+
+    Verifier {
+        files,
+        checked,
+        run: None,
+        report: None,
+    }
+}
+
+/// A run of [TagFS::verify], as its files are checked.
+#[derive(Debug)]
+struct Run {
+    checksums: Checksums,
+    /// How many files the run checks, and how many it checked so far
+    files: usize,
+    checked: usize,
+    /// Whether the run takes the pass to its end
+    finishes_pass: bool,
+    /// The files per [Outcome]
+    counts: [usize; 4],
+    corrupt: Vec<FileNumber>,
+}
+
+impl Run {
+    /// Take in the checksum of `file` as it is now, `None` if it couldn't be read.
+    fn check(&mut self, file: FileNumber, name: &OsStr, checked: Option<Checksum>) {
+        self.checked += 1;
+        let Some(checked) = checked else { return };
+
+        let outcome = match self.checksums.files.insert(file, checked) {
+            None => Outcome::Recorded,
+            Some(known) if (known.size, known.mtime) != (checked.size, checked.mtime) => {
+                Outcome::Updated
+            }
+            Some(known) if known.hash != checked.hash => {
+                warn!("'{}' changed without being modified", name.to_string_lossy());
+                // Keep the checksum of the intact contents, to check a restored copy against
+                self.checksums.files.insert(file, known);
+                self.corrupt.push(file);
+                Outcome::Corrupt
+            }
+            Some(_) => Outcome::Intact,
+        };
+        self.counts[outcome as usize] += 1;
+        self.checksums.pass.checked += 1;
+    }
+}
+
+/// What a verify run found in one file.
+enum Outcome {
+    /// It had no checksum yet
+    Recorded,
+    /// It was modified since, so it got a new checksum
+    Updated,
+    Intact,
+    /// It has another checksum with the same mtime and size
+    Corrupt,
+}
+
+impl<B> TagFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    /// Verify files in the background with `verifier`, see [TagFS::verify].
+    pub fn set_verifier(&mut self, verifier: Verifier) {
+        self.verifier = Some(verifier);
+    }
+
+    /// Hash the files and compare them with their checksums of the last pass, tagging those whose
+    /// contents changed while their mtime and size didn't with [CORRUPT]. Files that were modified
+    /// get a new checksum, files without one get their first. Continues the pass where the last
+    /// run stopped, checking at most `limit` files. With a [Verifier], the files are hashed in
+    /// the background and the run finishes as the mount is used, see [TagFS::poll_verify].
+    pub fn verify(&mut self, limit: Option<usize>) -> Result<String, TagError> {
+        if self.verifier.as_ref().is_some_and(|verifier| verifier.run.is_some()) {
+            return Ok(format!("already verifying\n{}", self.verify_progress()));
+        }
+
+        let mut checksums = self.load_checksums();
+        checksums.files.retain(|file, _| self.files.contains_left(file));
+        if checksums.pass.after.is_none() {
+            checksums.pass = Pass {
+                started: Some(SystemTime::now()),
+                finished: checksums.pass.finished,
+                ..Pass::default()
+            };
+        }
+
+        let mut files = self
+            .files
+            .left_values()
+            .copied()
+            .filter(|&file| checksums.pass.after.is_none_or(|after| file > after))
+            .filter(|&file| !self.is_offline(file))
+            .collect::<Vec<_>>();
+        files.sort_unstable();
+        let remaining = files.len();
+        files.truncate(limit.unwrap_or(usize::MAX));
+        checksums.pass.after = files.last().copied().or(checksums.pass.after);
+        let finishes_pass = files.len() == remaining;
+
+        let files = files
+            .into_iter()
+            .filter_map(|file| Some((file, self.source_name(file)?.to_os_string())))
+            .collect::<Vec<_>>();
+        let mut run = Run {
+            checksums,
+            files: files.len(),
+            checked: 0,
+            finishes_pass,
+            counts: [0; 4],
+            corrupt: vec![],
+        };
+
+        if let Some(verifier) = self.verifier.as_mut() {
+            let count = files.len();
+            for file in files {
+                verifier.files.send(file).map_err(|_| TagError::Source("verifier gone".into()))?;
+            }
+            verifier.run = Some(run);
+            let report = format!("verifying {count} file(s) in the background, see verify --status");
+            return Ok(report + "\n");
+        }
+
+        for (file, name) in files {
+            run.check(file, &name, checksum(&self.backing, &name).ok());
+        }
+        self.finish_verify(run)
+    }
+
+    /// Take in the files the [Verifier] hashed since it was last polled, finishing its run once
+    /// they're all in and changes can be saved.
+    pub fn poll_verify(&mut self) {
+        let holds_changes = self.holds_changes();
+        let Some(verifier) = self.verifier.as_mut() else { return };
+        let Some(mut run) = verifier.run.take() else { return };
+        loop {
+            match verifier.checked.try_recv() {
+                Ok((file, checked)) => {
+                    let name = self.files.get_by_left(&file).cloned().unwrap_or_default();
+                    run.check(file, &name, checked);
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    warn!("the verifier stopped, giving up the run");
+                    return;
+                }
+            }
+        }
+        if run.checked < run.files || holds_changes {
+            verifier.run = Some(run);
+            return;
+        }
+
+        self.begin_step();
+        let report = self.finish_verify(run);
+        self.record_step("verify".to_string());
+        self.save_or_record();
+
+        let report = report.unwrap_or_else(|error| format!("the run failed: {error}\n"));
+        if let Some(verifier) = self.verifier.as_mut() {
+            verifier.report = Some(report);
+        }
+    }
+
+    /// Tag the corrupt files `run` found, and keep its checksums for the next run.
+    fn finish_verify(&mut self, mut run: Run) -> Result<String, TagError> {
+        if !run.corrupt.is_empty() {
+            let tag = self.get_or_create_tag(CORRUPT.into());
+            for &file in run.corrupt.iter() {
+                self.add_file_to(file, tag);
+            }
+        }
+        let checksums = &mut run.checksums;
+        checksums.pass.corrupt += run.corrupt.len();
+        if run.finishes_pass {
+            checksums.pass.after = None;
+            checksums.pass.finished = Some(SystemTime::now());
+        }
+        self.save_checksums(checksums)?;
+
+        let [recorded, updated, intact, _] = run.counts;
+        info!("verified {} file(s), {} corrupt", run.files, run.corrupt.len());
+        let mut report = format!(
+            "{intact} intact, {} corrupt, {updated} modified, {recorded} new file(s)\n",
+            run.corrupt.len()
+        );
+        for &file in run.corrupt.iter() {
+            let name = self.get_fnm_by_number(file).cloned().unwrap_or_default();
+            report.push_str(&format!("{CORRUPT}\t{}\n", name.to_string_lossy()));
+        }
+        report.push_str(&self.verify_progress_of(checksums));
+
+        Ok(report)
+    }
+
+    /// How far the current pass of [TagFS::verify] got, and when the last one finished. With a
+    /// [Verifier], also how far its run got, or what the last run found.
+    pub fn verify_progress(&mut self) -> String {
+        self.poll_verify();
+        let Some(verifier) = &self.verifier else {
+            return self.verify_progress_of(&self.load_checksums());
+        };
+
+        match &verifier.run {
+            Some(run) => format!(
+                "verifying in the background: {} of {} file(s) checked\n{}",
+                run.checked,
+                run.files,
+                self.verify_progress_of(&self.load_checksums())
+            ),
+            None => {
+                let report = verifier.report.clone();
+                report.unwrap_or_else(|| self.verify_progress_of(&self.load_checksums()))
+            }
+        }
+    }
+
+    fn verify_progress_of(&self, checksums: &Checksums) -> String {
+        let pass = &checksums.pass;
+        let time = |at: Option<SystemTime>| at.map(humantime::format_rfc3339_seconds);
+        let mut report = match (pass.after, time(pass.started)) {
+            (Some(after), Some(started)) => {
+                let left = self.files.left_values().filter(|&&file| file > after).count();
+                format!(
+                    "pass started {started}: {} file(s) checked, {left} to go, {} corrupt\n",
+                    pass.checked, pass.corrupt
+                )
+            }
+            _ => String::new(),
+        };
+        match time(pass.finished) {
+            Some(finished) if pass.after.is_none() => report.push_str(&format!(
+                "last pass finished {finished}: {} file(s) checked, {} corrupt\n",
+                pass.checked, pass.corrupt
+            )),
+            Some(finished) => report.push_str(&format!("last pass finished {finished}\n")),
+            None if pass.after.is_none() => report.push_str("no files verified yet\n"),
+            None => {}
+        }
+
+        report
+    }
+
+    /// The checksums kept next to the savefile, none if there are none yet.
+    fn load_checksums(&self) -> Checksums {
+        let read = self.backing.open(state_file(CHECKSUMS)).and_then(|handle| {
+            let checksums = self.backing.read(handle, 0, u64::MAX);
+            self.backing.release(handle);
+            checksums
+        });
+        let decoded = read.map(|checksums| {
+            bincode::decode_from_slice(&checksums, bincode::config::standard())
+                .map(|(Compat(checksums), _)| checksums)
+        });
+
+        match decoded {
+            Ok(Ok(checksums)) => checksums,
+            Ok(Err(e)) => {
+                warn!("the checksums can't be read, starting over: {e}");
+                Checksums::default()
+            }
+            Err(e) => {
+                debug!("no checksums: {e}");
+                Checksums::default()
+            }
+        }
+    }
+
+    fn save_checksums(&self, checksums: &Checksums) -> Result<(), TagError> {
+        let encoded = bincode::encode_to_vec(Compat(checksums), bincode::config::standard())
+            .map_err(|error| TagError::Source(error.to_string()))?;
+        let handle = self.backing.create(state_file(CHECKSUMS));
+        let handle = handle.map_err(|error| TagError::Source(error.to_string()))?;
+        let written = self.backing.write(handle, &encoded);
+        self.backing.release(handle);
+
+        written.map(|_| ()).map_err(|error| TagError::Source(error.to_string()))
+    }
+}
+
+/// The checksum of file `name` of `backing` as it is now.
+fn checksum<B: BackingFS>(backing: &B, name: &OsStr) -> Result<Checksum, B::Error> {
+    const CHUNK: u64 = 1 << 20;
+
+    let attr = backing.get_metadata(name)?;
+    let handle = backing.open(name)?;
+    let mut hasher = Fnv::default();
+    let mut offset = 0;
+    let result = loop {
+        match backing.read(handle, offset, CHUNK) {
+            Ok(data) if data.is_empty() => break Ok(hasher.finish()),
+            Ok(data) => {
+                hasher.write(&data);
+                offset += data.len() as u64;
+            }
+            Err(e) => break Err(e),
+        }
+    };
+    backing.release(handle);
+
+    Ok(Checksum {
+        hash: result?,
+        size: attr.size,
+        mtime: attr.mtime,
+    })
+}
@@ -0,0 +1,72 @@
+use std::ffi::OsStr;
+use std::mem::size_of;
+
+use bimap::BiMap;
+use fuser::FileType;
+use fuser::FileType::RegularFile;
+
+use crate::file::{FileNumber, Ino, Split};
+use crate::fs::tag::{DirEntry, FileName};
+
+/// The listing of an open directory, taken when it was opened so offsets keep pointing at the
+/// same entries while the tags change, see `readdir`. Files are kept by number and only named as
+/// they're read, so a directory of 100k files costs 8 bytes per file rather than a full entry.
+#[derive(Debug, Default)]
+pub(super) struct DirListing {
+    /// `.`, `..`, tags and virtual entries, listed before the files
+    entries: Vec<DirEntry>,
+    /// The tag part of the inodes of the files
+    dir: u64,
+    files: Vec<FileNumber>,
+}
+
+impl DirListing {
+    pub fn new(mut entries: Vec<DirEntry>, dir: u64, mut files: Vec<FileNumber>) -> Self {
+        entries.shrink_to_fit();
+        files.shrink_to_fit();
+        Self {
+            entries,
+            dir,
+            files,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len() + self.files.len()
+    }
+
+    /// The memory taken by the listing, besides the names of its tags and virtual entries.
+    pub fn heap_size(&self) -> usize {
+        self.entries.capacity() * size_of::<DirEntry>()
+            + self.files.capacity() * size_of::<FileNumber>()
+    }
+
+    /// Pass the entries after `offset` to `add` until it reports the reply is full, naming files
+    /// by `names` and numbering them by `split`. The offset of an entry is its index plus one, so
+    /// the next call continues right after the last entry that fit. Files that left the index
+    /// since are skipped.
+    pub fn fill(
+        &self,
+        names: &BiMap<FileNumber, FileName>,
+        split: Split,
+        offset: i64,
+        mut add: impl FnMut(u64, i64, FileType, &OsStr) -> bool,
+    ) {
+        let skip = usize::try_from(offset).unwrap_or(0);
+        for (idx, (ino, kind, name)) in self.entries.iter().enumerate().skip(skip) {
+            if add(ino.0, idx as i64 + 1, *kind, name) {
+                return;
+            }
+        }
+
+        let skip = skip.saturating_sub(self.entries.len());
+        let files = self.files.iter().enumerate().skip(skip);
+        for (idx, &file) in files {
+            let Some(name) = names.get_by_left(&file) else { continue };
+            let offset = (self.entries.len() + idx) as i64 + 1;
+            if add(Ino::from_parts(file, self.dir, split).0, offset, RegularFile, name) {
+                return;
+            }
+        }
+    }
+}
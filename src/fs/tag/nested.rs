@@ -0,0 +1,179 @@
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+
+use bimap::BiMap;
+
+use crate::file::{nfc, FileNumber};
+use crate::fs::tag::{FileName, TagFS};
+
+/// The last component of the relative path `name` of a file.
+fn basename_of(name: &OsStr) -> FileName {
+    Path::new(name).file_name().unwrap_or(name).into()
+}
+
+/// `basename` as the `n`th file of that basename is shown: `a.flac`, then `a (2).flac`.
+fn numbered(basename: &FileName, n: u32) -> FileName {
+    if n == 1 {
+        return basename.clone();
+    }
+
+    let path = Path::new(&**basename);
+    let mut name = OsString::from(path.file_stem().unwrap_or(basename));
+    name.push(format!(" ({n})"));
+    if let Some(ext) = path.extension() {
+        name.push(".");
+        name.push(ext);
+    }
+
+    name.into()
+}
+
+impl<B> TagFS<B> {
+    /// The names files are listed and looked up by through the mount. Sources indexed with
+    /// [Config::nested](crate::fs::config::Config::nested) name files by relative paths like
+    /// `albums/2021/a.flac`, which are shown by their basename instead.
+    pub(super) fn shown_names(&self) -> &BiMap<FileNumber, FileName> {
+        match self.config.nested {
+            true => &self.shown,
+            false => &self.files,
+        }
+    }
+
+    /// The file shown as `name` in the mount, see [TagFS::shown_names].
+    pub(super) fn get_fnb_by_shown_name(&self, name: &OsStr) -> Option<FileNumber> {
+        self.shown_names().get_by_right(&*nfc(name)).copied()
+    }
+
+    pub(super) fn get_fnm_shown(&self, file: FileNumber) -> Option<&FileName> {
+        self.shown_names().get_by_left(&file)
+    }
+
+    /// Show the newly indexed `file` by its basename.
+    pub(super) fn show_file(&mut self, file: FileNumber) {
+        let Some(name) = self.files.get_by_left(&file).filter(|_| self.config.nested) else {
+            return;
+        };
+
+        let basename = basename_of(name);
+        let group = self.by_basename.entry(basename.clone()).or_default();
+        let idx = group.partition_point(|&other| other < file);
+        group.insert(idx, file);
+        self.reshow(&basename);
+    }
+
+    /// Stop showing `file`, before it leaves the index or is renamed.
+    pub(super) fn unshow_file(&mut self, file: FileNumber) {
+        let Some(name) = self.files.get_by_left(&file).filter(|_| self.config.nested) else {
+            return;
+        };
+
+        let basename = basename_of(name);
+        self.shown.remove_by_left(&file);
+        if let Some(group) = self.by_basename.get_mut(&basename) {
+            group.retain(|&other| other != file);
+            if group.is_empty() {
+                self.by_basename.remove(&basename);
+            }
+        }
+        self.reshow(&basename);
+    }
+
+    /// Index `file` by `name` instead. It keeps the number it's shown with when it keeps its
+    /// basename, like when it moved to another directory of the source.
+    pub(super) fn rename_shown(&mut self, file: FileNumber, name: FileName) {
+        let old = self.files.get_by_left(&file).map(|old| basename_of(old));
+        self.unshow_file(file);
+        if old.is_some_and(|old| old != basename_of(&name)) && self.config.nested {
+            self.note_file_meta(file);
+            self.file_meta.entry(file).or_default().shown_as = None;
+        }
+        self.files.insert(file, name);
+        self.show_file(file);
+    }
+
+    /// Show every file again, after the index was replaced or nesting was turned on or off.
+    pub(super) fn rebuild_shown(&mut self) {
+        self.shown.clear();
+        self.by_basename.clear();
+        if !self.config.nested {
+            return;
+        }
+
+        let mut files = self.files.left_values().copied().collect::<Vec<_>>();
+        files.sort_unstable();
+        for file in files {
+            let basename = basename_of(self.files.get_by_left(&file).unwrap());
+            self.by_basename.entry(basename).or_default().push(file);
+        }
+        let basenames = self.by_basename.keys().cloned().collect::<Vec<_>>();
+        for basename in basenames {
+            self.reshow(&basename);
+        }
+    }
+
+    /// Name the files with `basename`: each by the number it was shown with before, see
+    /// [FileMeta::shown_as](crate::fs::meta::FileMeta::shown_as), as long as it's free, and the
+    /// others in the order they were indexed by the lowest free number, the first by the basename
+    /// itself. A suffixed file that held the basename of another file moves on to a number of
+    /// its own.
+    fn reshow(&mut self, basename: &FileName) {
+        let group = self.by_basename.get(basename).cloned().unwrap_or_default();
+        for file in group.iter() {
+            self.shown.remove_by_left(file);
+        }
+
+        let mut displaced = vec![];
+        let mut unnumbered = vec![];
+        for &file in group.iter() {
+            match self.file_meta.get(&file).and_then(|meta| meta.shown_as) {
+                Some(n) if self.is_free(basename, n, &group) => {
+                    self.show_as(file, basename, n, &mut displaced)
+                }
+                _ => unnumbered.push(file),
+            }
+        }
+        let mut n = 0;
+        for file in unnumbered {
+            n = (n + 1..).find(|&n| self.is_free(basename, n, &group)).expect("a free number");
+            self.show_as(file, basename, n, &mut displaced);
+        }
+
+        for file in displaced {
+            if let Some(name) = self.files.get_by_left(&file) {
+                self.reshow(&basename_of(name));
+            }
+        }
+    }
+
+    /// Whether a file of `group`, the files with `basename`, may be shown as its `n`th file. The
+    /// basename itself is theirs, the numbered names are free unless another file is shown by or
+    /// has that name.
+    fn is_free(&self, basename: &FileName, n: u32, group: &[FileNumber]) -> bool {
+        let name = numbered(basename, n);
+        match self.shown.get_by_right(&name) {
+            Some(other) => n == 1 && !group.contains(other),
+            None => n == 1 || !self.by_basename.contains_key(&name),
+        }
+    }
+
+    /// Show `file` as the `n`th file of `basename` and remember the number, taking the name from
+    /// a file of another basename, which is added to `displaced`.
+    fn show_as(
+        &mut self,
+        file: FileNumber,
+        basename: &FileName,
+        n: u32,
+        displaced: &mut Vec<FileNumber>,
+    ) {
+        let name = numbered(basename, n);
+        if let Some((other, _)) = self.shown.remove_by_right(&name) {
+            displaced.push(other);
+        }
+        self.shown.insert(file, name);
+
+        if self.file_meta.get(&file).and_then(|meta| meta.shown_as) != Some(n) {
+            self.note_file_meta(file);
+            self.file_meta.entry(file).or_default().shown_as = Some(n);
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use std::error::Error;
+use std::time::Duration;
+
+use log::info;
+
+use crate::control::Settings;
+use crate::fs::backing::BackingFS;
+use crate::fs::config::DAY;
+use crate::fs::error::TagError;
+use crate::fs::tag::{FileName, TagFS};
+use crate::logging;
+
+impl<B> TagFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    /// Replace the settings given in `settings` while mounted, leaving the others as they are.
+    /// New exclusions send the source through a scan, so files that are now left out are treated
+    /// as gone and files that aren't are indexed, and new tag rules are applied to the files.
+    pub(crate) fn reload(&mut self, settings: Settings) -> Result<String, TagError> {
+        let mut report = String::new();
+        if let Some(level) = settings.log_level {
+            let level = logging::set_level(level);
+            report.push_str(&format!("logging up to {}\n", level.as_str().to_lowercase()));
+        }
+        if let Some(days) = settings.recent_days {
+            self.config.recent = Duration::from_secs(days * DAY);
+            report.push_str(&format!("/@recent lists changes of the last {days} day(s)\n"));
+        }
+
+        let rules = (!settings.tag_rules.is_empty() || settings.no_tag_rules)
+            .then_some(settings.tag_rules);
+        let retag = rules.is_some();
+        if let Some(rules) = rules {
+            report.push_str(&format!("{} tag rule(s)\n", rules.len()));
+            self.config.tag_rules = rules;
+        }
+        let exclude = (!settings.exclude.is_empty() || settings.no_exclude)
+            .then_some(settings.exclude);
+        let rescan = exclude.is_some();
+        if let Some(exclude) = exclude {
+            report.push_str(&format!("{} exclusion(s)\n", exclude.len()));
+            self.config.exclude = exclude;
+        }
+
+        if rescan {
+            self.await_scan();
+            let files = self.backing.list("");
+            let files = files.map_err(|error| TagError::Source(error.to_string()))?;
+            let summary = self.repopulate(files.map(FileName::from));
+            report.push_str(&format!("rescanned: {summary}\n"));
+        } else if retag {
+            self.apply_tag_rules();
+        }
+
+        if report.is_empty() {
+            return Ok("nothing to reload".to_string());
+        }
+        info!("reloaded settings: {}", report.trim_end().replace('\n', ", "));
+
+        Ok(report)
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+
+use fuser::FileType::Symlink;
+
+use crate::file::{FileNumber, Ino, Split, TagNumber};
+use crate::fs::config::RootListing;
+use crate::fs::tag::{DirEntry, TagFS};
+
+/// Queries at the root of a curated mount are numbered from here in the virtual file space, after
+/// the virtual files, so they can't collide with the files listed at the root.
+const ROOT_QUERIES: u64 = 0x80;
+
+/// How many queries fit in the rest of the virtual file space.
+const MAX_ROOT_QUERIES: usize = 0x80;
+
+fn root_query_ino(idx: usize, split: Split) -> Ino {
+    let file = split.virtual_files() + ROOT_QUERIES + idx as u64;
+    Ino::from_parts(file, Ino::ROOT.tag(split), split)
+}
+
+impl<B> TagFS<B> {
+    /// Whether `tag` is listed at the root, which is all tags unless the root is curated.
+    pub(super) fn lists_at_root(&self, tag: TagNumber) -> bool {
+        if self.config.root != RootListing::Curated {
+            return true;
+        }
+
+        self.config
+            .root_entries
+            .iter()
+            .any(|entry| entry.is_tag() && self.get_tnb_by_name(&entry.name) == Some(tag))
+    }
+
+    /// Pin `tag` to the top of the root listing, after the tags pinned before it. Returns
+    /// whether it wasn't pinned yet.
+    pub fn pin_tag(&mut self, tag: TagNumber) -> bool {
+        if self.tag_meta(tag).is_some_and(|meta| meta.pinned.is_some()) {
+            return false;
+        }
+
+        let last = self.tag_meta.values().filter_map(|meta| meta.pinned).max();
+        self.tag_meta_mut(tag).pinned = Some(last.map_or(0, |last| last + 1));
+        true
+    }
+
+    /// List `tag` among the others at the root again. Returns whether it was pinned.
+    pub fn unpin_tag(&mut self, tag: TagNumber) -> bool {
+        self.tag_meta_mut(tag).pinned.take().is_some()
+    }
+
+    /// Put the pinned tags of a root listing first, in the order they were pinned. The order of
+    /// the other tags is kept.
+    pub(super) fn pinned_first<T>(&self, tags: &mut [(TagNumber, T)]) {
+        tags.sort_by_key(|&(tag, _)| {
+            let rank = self.tag_meta(tag).and_then(|meta| meta.pinned);
+            (rank.is_none(), rank)
+        });
+    }
+
+    /// Leave the files out of `files` that the root doesn't list, see [RootListing].
+    pub(super) fn retain_root_files(&self, files: &mut HashSet<FileNumber>) {
+        match self.config.root {
+            RootListing::All => {}
+            RootListing::Untagged => {
+                files.retain(|file| !self.tag_content.values().any(|set| set.contains(file)))
+            }
+            RootListing::Tags | RootListing::Curated => files.clear(),
+        }
+    }
+
+    /// The symlinks to the queries of a curated root.
+    pub(super) fn root_query_entries(&self) -> Vec<DirEntry> {
+        (0..self.config.root_entries.len())
+            .filter(|&idx| self.root_query_target(idx).is_some())
+            .map(|idx| {
+                let name = self.config.root_entries[idx].name.clone();
+                (root_query_ino(idx, self.split), Symlink, name.into())
+            })
+            .collect()
+    }
+
+    /// The query of a curated root called `name`, with its target.
+    pub(super) fn root_query(&self, name: &OsStr) -> Option<(Ino, OsString)> {
+        let idx = self.config.root_entries.iter().position(|entry| entry.name == name)?;
+
+        Some((root_query_ino(idx, self.split), self.root_query_target(idx)?))
+    }
+
+    /// The target of the symlink to a query of a curated root with inode `ino`.
+    pub(super) fn root_query_link(&self, ino: Ino) -> Option<OsString> {
+        if ino.tag(self.split) != Ino::ROOT.tag(self.split) {
+            return None;
+        }
+        let idx = ino.file(self.split).checked_sub(self.split.virtual_files() + ROOT_QUERIES)?;
+
+        self.root_query_target(usize::try_from(idx).ok()?)
+    }
+
+    /// The path of query `idx` relative to the root, if the root is curated and its tags exist.
+    fn root_query_target(&self, idx: usize) -> Option<OsString> {
+        let entry = self.config.root_entries.get(idx)?;
+        if self.config.root != RootListing::Curated || entry.is_tag() || idx >= MAX_ROOT_QUERIES {
+            return None;
+        }
+
+        let mut target = OsString::new();
+        for tag in entry.path.iter() {
+            let tag = self.get_tnb_by_name(tag)?;
+            if !target.is_empty() {
+                target.push("/");
+            }
+            target.push(self.tags.get_by_left(&tag)?);
+        }
+
+        Some(target)
+    }
+}
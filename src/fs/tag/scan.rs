@@ -0,0 +1,192 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::time::SystemTime;
+
+use log::info;
+
+use crate::file::FileNumber;
+use crate::fs::backing::{BackingFS, SourceId, Stat};
+use crate::fs::tag::{FileName, TagFS};
+
+/// A scan of the source running in the background, see [TagFS::scan_in_background].
+#[derive(Debug)]
+pub(super) struct Scan {
+    batches: Receiver<Vec<(OsString, Option<Stat>)>>,
+    /// Every file found so far, with what it looked like then
+    found: HashMap<FileName, Option<Stat>>,
+    /// Indexed files that weren't found yet, by their identity. A new name with one of these may
+    /// be that file renamed, which can only be told once the scan is done
+    unseen: HashMap<SourceId, FileNumber>,
+    /// The scan number of the files it found, once it found a new one
+    number: Option<u64>,
+    /// How many new files it indexed as it went
+    added: usize,
+}
+
+/// What a scan of the source changed in the index, see [TagFS::repopulate].
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ScanSummary {
+    /// New files that were indexed
+    pub added: usize,
+    /// Indexed files found under the same name
+    pub retained: usize,
+    /// Indexed files found under another name by their identity in the source
+    pub renamed: usize,
+    /// Indexed files found under another name by their size and mtime, see `--match-renames`
+    pub matched: usize,
+    /// Tagged files that are missing, kept offline with their tags
+    pub offline: usize,
+    /// Untagged files that are missing, taken out of the index
+    pub removed: usize,
+    /// Tag memberships of the files taken out of the index
+    pub dropped: usize,
+}
+
+impl Display for ScanSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} retained, {} renamed, {} matched by size and mtime, {} offline, {} \
+             removed, {} tag membership(s) dropped",
+            self.added,
+            self.retained,
+            self.renamed,
+            self.matched,
+            self.offline,
+            self.removed,
+            self.dropped
+        )
+    }
+}
+
+impl ScanSummary {
+    /// The summary one field per line, as `name<TAB>count`.
+    pub fn report(&self) -> String {
+        [
+            ("added", self.added),
+            ("retained", self.retained),
+            ("renamed", self.renamed),
+            ("matched", self.matched),
+            ("offline", self.offline),
+            ("removed", self.removed),
+            ("dropped", self.dropped),
+        ]
+        .iter()
+        .map(|(name, count)| format!("{name}\t{count}\n"))
+        .collect()
+    }
+}
+
+impl<B> TagFS<B> {
+    /// Log what a scan changed, and keep it for [TagFS::last_scan_report].
+    pub(super) fn record_scan(&mut self, summary: ScanSummary) {
+        info!("scanned the source: {summary}");
+        self.last_scan = Some((SystemTime::now(), summary));
+    }
+
+    /// What the last scan of the source changed, with when it finished.
+    pub fn last_scan_report(&self) -> String {
+        match &self.last_scan {
+            Some((at, summary)) => {
+                let at = humantime::format_rfc3339_seconds(*at);
+                format!("finished\t{at}\n{}", summary.report())
+            }
+            None => "no scan finished yet\n".to_string(),
+        }
+    }
+}
+
+impl<B> TagFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    /// Take the files of the source from `batches` as another thread lists and stats them, so
+    /// the mount can be used before the scan is done and doesn't wait for the source meanwhile.
+    /// New files are indexed as they arrive, see [TagFS::poll_scan]. Once the sender is dropped,
+    /// the scan is finished like [TagFS::repopulate]: renamed and gone files are dealt with, and
+    /// the index is saved.
+    pub fn scan_in_background(&mut self, batches: Receiver<Vec<(OsString, Option<Stat>)>>) {
+        let unseen = self
+            .files
+            .left_values()
+            .filter_map(|&fnb| Some((self.file_meta(fnb)?.source?, fnb)))
+            .collect();
+
+        self.scan = Some(Scan {
+            batches,
+            found: HashMap::new(),
+            unseen,
+            number: None,
+            added: 0,
+        });
+    }
+
+    /// Index the files the background scan found since it was last polled.
+    pub fn poll_scan(&mut self) {
+        let Some(mut scan) = self.scan.take() else { return };
+        loop {
+            match scan.batches.try_recv() {
+                Ok(batch) => self.index_batch(&mut scan, batch),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return self.finish_scan(scan),
+            }
+        }
+
+        self.scan = Some(scan);
+    }
+
+    /// Wait for the background scan to be done, e.g. before unmounting.
+    pub fn await_scan(&mut self) {
+        let Some(mut scan) = self.scan.take() else { return };
+        while let Ok(batch) = scan.batches.recv() {
+            self.index_batch(&mut scan, batch);
+        }
+
+        self.finish_scan(scan);
+    }
+
+    fn index_batch(&mut self, scan: &mut Scan, batch: Vec<(OsString, Option<Stat>)>) {
+        for (name, stat) in batch {
+            let name = FileName::from(name);
+            let attr = stat.as_ref().map(|stat| &stat.attr);
+            if !self.is_indexable(&name, attr) || scan.found.contains_key(&name) {
+                continue;
+            }
+            scan.found.insert(name.clone(), stat);
+            if let Some(fnb) = self.get_fnb_by_name(&name) {
+                if let Some(source) = self.file_meta(fnb).and_then(|meta| meta.source) {
+                    scan.unseen.remove(&source);
+                }
+                continue;
+            }
+
+            // Possibly renamed, left for the end of the scan
+            let source = stat.and_then(|stat| stat.source);
+            if source.map(|source| scan.unseen.contains_key(&source)).unwrap_or(false) {
+                continue;
+            }
+
+            let number = *scan.number.get_or_insert_with(|| {
+                self.scan_tally += 1;
+                self.scan_tally
+            });
+            self.add_found_file(name, stat, number);
+            scan.added += 1;
+        }
+    }
+
+    fn finish_scan(&mut self, scan: Scan) {
+        info!("scanned {} file(s) in the background", scan.found.len());
+
+        // The files indexed as the scan went were already there as far as the index can tell
+        let mut summary = self.index_source(scan.found);
+        summary.retained = summary.retained.saturating_sub(scan.added);
+        summary.added += scan.added;
+        self.record_scan(summary);
+    }
+}
@@ -0,0 +1,100 @@
+use std::error::Error;
+use std::time::Instant;
+
+use log::info;
+
+use crate::fs::backing::BackingFS;
+use crate::fs::profile::{state_file, SAVEFILE};
+use crate::fs::tag::{read_saves, read_state, TagFS};
+
+/// Held by a mount while it reads and writes the shared savefile, see [TagFS::save_shared].
+pub const LOCK_FILE: &str = ".tagfs-lock";
+
+/// How many times the savefile was written, as counted in its header, when it was last read or
+/// written. Unlike its mtime and size, that changes with every save.
+pub(super) type SaveVersion = u64;
+
+impl<B> TagFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    /// Save to a savefile other mounts save to as well. Under the lock, what they saved since
+    /// this mount last looked is merged in first, so neither overwrites the other.
+    pub(super) fn save_shared(&mut self) -> anyhow::Result<()> {
+        let lock = self.backing.lock(state_file(LOCK_FILE))?;
+        let saved = self
+            .merge_saved()
+            .and_then(|()| self.write_state(&state_file(SAVEFILE)));
+        if saved.is_ok() {
+            self.saved_version = self.saved_version();
+        }
+        self.backing.release(lock);
+
+        saved
+    }
+
+    /// Pick up what other mounts saved, at most once per [crate::fs::config::Config::shared].
+    /// The attributes and entries of the mount are never cached, so the kernel sees the changes
+    /// right away.
+    pub fn refresh_shared(&mut self) {
+        let Some(every) = self.config.shared else { return };
+        if self.refreshed_at.map(|at| at.elapsed() < every).unwrap_or(false) {
+            return;
+        }
+        self.refreshed_at = Some(Instant::now());
+        if self.holds_changes() {
+            return;
+        }
+
+        let refreshed = self.backing.lock(state_file(LOCK_FILE)).map_err(anyhow::Error::from);
+        let refreshed = refreshed.and_then(|lock| {
+            let merged = self.merge_saved();
+            self.backing.release(lock);
+            merged
+        });
+        if let Err(error) = refreshed {
+            self.record_error("refresh the shared savefile", &error);
+        }
+    }
+
+    /// Merge the savefile if another mount wrote it since this one last looked.
+    fn merge_saved(&mut self) -> anyhow::Result<()> {
+        let version = self.saved_version();
+        if version.is_none() || version == self.saved_version {
+            return Ok(());
+        }
+
+        let state = read_state(&self.backing, &state_file(SAVEFILE))?;
+        // Files the other mount found since this one scanned the source
+        let found = state
+            .files
+            .iter()
+            .filter(|(_, name)| !self.files.contains_right(*name))
+            .map(|(file, name)| {
+                match state.file_meta.get(file).and_then(|meta| meta.source_name.as_deref()) {
+                    Some(source_name) => source_name.into(),
+                    None => name.clone(),
+                }
+            })
+            .filter(|name| match self.backing.get_metadata(name) {
+                Ok(attr) => self.is_indexable(name, Some(&attr)),
+                Err(_) => false,
+            })
+            .collect::<Vec<_>>();
+        for name in found {
+            self.add_file(name);
+        }
+
+        let report = self.merge_state(&state)?;
+        info!("merged the savefile of another mount: {report}");
+        self.saved_version = version;
+
+        Ok(())
+    }
+
+    /// The current version of the savefile, `None` while there is none.
+    pub(super) fn saved_version(&self) -> Option<SaveVersion> {
+        read_saves(&self.backing, &state_file(SAVEFILE))
+    }
+}
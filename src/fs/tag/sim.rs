@@ -0,0 +1,493 @@
+//! Randomized sequences of FUSE operations against an in-memory source, checked against a model
+//! of the tag memberships after every step. A failing sequence is shrunk to a minimal one, which
+//! proptest prints and keeps in `proptest-regressions` to run first from then on.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use proptest::prelude::*;
+use proptest::sample::select;
+
+use super::*;
+use crate::fs::backing::{BackingFS, Capabilities, SourceId};
+
+const UID: u32 = 1000;
+const FILES: [&str; 6] = ["a.mp3", "b.mp3", "c.flac", "d.jpg", "e.jpg", "f"];
+/// Names to create tags with, including a private one and a reserved one
+const TAGS: [&str; 7] = [
+    "music",
+    "live",
+    "photos",
+    "todo",
+    "done",
+    "user:mine",
+    "@all",
+];
+const RUNS: u32 = 300;
+const STEPS: usize = 80;
+
+/// A source directory held in memory. Clones share their contents, like two mounts of one dir.
+#[derive(Debug, Default, Clone)]
+pub(super) struct MemFS {
+    files: Rc<RefCell<HashMap<PathBuf, Vec<u8>>>>,
+    handles: Rc<RefCell<HashMap<FileHandle, PathBuf>>>,
+    handle_tally: Rc<Cell<u64>>,
+}
+
+impl MemFS {
+    pub(super) fn with_files(names: &[&str]) -> Self {
+        let fs = Self::default();
+        for name in names {
+            let content = name.as_bytes().to_vec();
+            fs.files.borrow_mut().insert(name.into(), content);
+        }
+
+        fs
+    }
+
+    /// Take file `name` out of the source, like another program deleting it.
+    pub(super) fn remove(&self, name: &str) {
+        self.files.borrow_mut().remove(Path::new(name));
+    }
+
+    /// How many handles are open on the source.
+    pub(super) fn open_handles(&self) -> usize {
+        self.handles.borrow().len()
+    }
+
+    fn handle(&self, path: &Path) -> FileHandle {
+        self.handle_tally.set(self.handle_tally.get() + 1);
+        let handle = FileHandle(self.handle_tally.get());
+        self.handles.borrow_mut().insert(handle, path.to_path_buf());
+
+        handle
+    }
+
+    fn path_of(&self, handle: FileHandle) -> io::Result<PathBuf> {
+        self.handles
+            .borrow()
+            .get(&handle)
+            .cloned()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EBADF))
+    }
+}
+
+impl BackingFS for MemFS {
+    fn get_metadata<P: AsRef<Path>>(&self, path: P) -> Result<FileAttr, Self::Error> {
+        let files = self.files.borrow();
+        let content = files.get(path.as_ref()).ok_or(ErrorKind::NotFound)?;
+
+        Ok(FileAttr {
+            size: content.len() as u64,
+            kind: RegularFile,
+            perm: 0o644,
+            ..create_folder_attrs(Ino(0), DirAttrs::default())
+        })
+    }
+
+    /// The root is always there, the source is never unplugged
+    fn exists<P: AsRef<Path>>(&self, path: P) -> bool {
+        path.as_ref().as_os_str().is_empty() || self.files.borrow().contains_key(path.as_ref())
+    }
+
+    fn source_id<P: AsRef<Path>>(&self, _path: P) -> Result<SourceId, Self::Error> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    fn open<P: AsRef<Path>>(&self, path: P) -> Result<FileHandle, Self::Error> {
+        if !self.files.borrow().contains_key(path.as_ref()) {
+            return Err(ErrorKind::NotFound.into());
+        }
+
+        Ok(self.handle(path.as_ref()))
+    }
+
+    fn create<P: AsRef<Path>>(&self, path: P) -> Result<FileHandle, Self::Error> {
+        self.files.borrow_mut().insert(path.as_ref().into(), vec![]);
+
+        Ok(self.handle(path.as_ref()))
+    }
+
+    fn read(&self, handle: FileHandle, offset: u64, size: u64) -> Result<Vec<u8>, Self::Error> {
+        let files = self.files.borrow();
+        let content = files
+            .get(&self.path_of(handle)?)
+            .ok_or(ErrorKind::NotFound)?;
+        let start = min(offset, content.len() as u64) as usize;
+        let end = min(offset.saturating_add(size), content.len() as u64) as usize;
+
+        Ok(content[start..end].to_vec())
+    }
+
+    fn write(&self, handle: FileHandle, data: &[u8]) -> Result<(), Self::Error> {
+        let path = self.path_of(handle)?;
+        self.files
+            .borrow_mut()
+            .entry(path)
+            .or_default()
+            .extend_from_slice(data);
+
+        Ok(())
+    }
+
+    fn append<P: AsRef<Path>>(&self, path: P, data: &[u8]) -> Result<(), Self::Error> {
+        self.files
+            .borrow_mut()
+            .entry(path.as_ref().into())
+            .or_default()
+            .extend_from_slice(data);
+
+        Ok(())
+    }
+
+    fn lock<P: AsRef<Path>>(&self, path: P) -> Result<FileHandle, Self::Error> {
+        self.files.borrow_mut().entry(path.as_ref().into()).or_default();
+
+        Ok(self.handle(path.as_ref()))
+    }
+
+    fn release(&self, handle: FileHandle) {
+        self.handles.borrow_mut().remove(&handle);
+    }
+
+    fn getxattr<P: AsRef<Path>>(&self, _path: P, _name: &OsStr) -> Result<Vec<u8>, Self::Error> {
+        Err(io::Error::from_raw_os_error(ENODATA))
+    }
+
+    fn listxattr<P: AsRef<Path>>(&self, _path: P) -> Result<Vec<OsString>, Self::Error> {
+        Ok(vec![])
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            xattrs: false,
+            ..Capabilities::default()
+        }
+    }
+
+    fn list<P: AsRef<Path>>(&self, prefix: P) -> Result<Self::Listing, Self::Error> {
+        let files = self.files.borrow();
+        let listed = files
+            .keys()
+            .filter(|path| path.parent() == Some(prefix.as_ref()))
+            .map(|path| path.clone().into_os_string())
+            .collect::<Vec<_>>();
+
+        Ok(listed.into_iter())
+    }
+
+    type Error = io::Error;
+    type Listing = std::vec::IntoIter<OsString>;
+}
+
+type TagPath = Vec<&'static str>;
+
+#[derive(Debug, Clone)]
+enum Op {
+    Mkdir(&'static str),
+    Rmdir(&'static str),
+    RenameTag(&'static str, &'static str),
+    Move(&'static str, TagPath, TagPath),
+    Unlink(TagPath, &'static str),
+    Lookup(TagPath, &'static str),
+    Readdir(TagPath),
+    SaveLoad,
+}
+
+/// What the tags should contain, by the names `UID` sees.
+#[derive(Debug, Default)]
+struct Model {
+    tags: BTreeMap<&'static str, BTreeSet<&'static str>>,
+}
+
+impl Model {
+    fn is_path(&self, path: &[&str]) -> bool {
+        path.iter().all(|tag| self.tags.contains_key(tag))
+    }
+
+    fn intersection(&self, path: &[&str]) -> BTreeSet<&'static str> {
+        FILES
+            .into_iter()
+            .filter(|file| path.iter().all(|tag| self.tags[tag].contains(file)))
+            .collect()
+    }
+}
+
+/// A directory of up to two distinct tags. Virtual dirs aren't modelled.
+fn path() -> impl Strategy<Value = TagPath> {
+    let names = TAGS.into_iter().filter(|tag| !VirtualDir::is_reserved(tag.as_ref()));
+    let names = names.collect::<Vec<_>>();
+
+    prop::collection::vec(select(names), 0..3).prop_map(|mut path| {
+        path.dedup();
+        path
+    })
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let tag = || select(TAGS.to_vec());
+    let file = || select(FILES.to_vec());
+
+    prop_oneof![
+        3 => tag().prop_map(Op::Mkdir),
+        1 => tag().prop_map(Op::Rmdir),
+        1 => (tag(), tag()).prop_map(|(name, newname)| Op::RenameTag(name, newname)),
+        4 => (file(), path(), path()).prop_map(|(file, from, to)| Op::Move(file, from, to)),
+        1 => (path(), file()).prop_map(|(path, file)| Op::Unlink(path, file)),
+        2 => (path(), prop_oneof![file(), tag()]).prop_map(|(path, name)| Op::Lookup(path, name)),
+        3 => path().prop_map(Op::Readdir),
+        1 => Just(Op::SaveLoad),
+    ]
+}
+
+struct Sim {
+    fs: TagFS<MemFS>,
+    source: MemFS,
+    model: Model,
+}
+
+impl Sim {
+    fn new() -> Self {
+        let source = MemFS::with_files(&FILES);
+        let mut fs = TagFS::new(source.clone());
+        fs.repopulate(FILES.into_iter().map(FileName::from));
+
+        Self {
+            fs,
+            source,
+            model: Model::default(),
+        }
+    }
+
+    fn actor() -> Actor {
+        Actor { uid: UID, pid: 1 }
+    }
+
+    /// Walk down the tag tree like the kernel does before any operation on a path.
+    fn resolve(&mut self, path: &[&str]) -> Result<Ino, c_int> {
+        let mut ino = Ino::ROOT;
+        for tag in path {
+            ino = Ino(self.fs.lookup_entry(ino, OsStr::new(tag), UID)?.ino);
+        }
+
+        Ok(ino)
+    }
+
+    /// Resolve `path`, which must succeed exactly when the model knows all of its tags.
+    fn dir(&mut self, path: &[&str]) -> Option<Ino> {
+        let ino = self.resolve(path);
+        assert_eq!(
+            ino.is_ok(),
+            self.model.is_path(path),
+            "resolving {path:?}: {ino:?}"
+        );
+
+        ino.ok()
+    }
+
+    fn apply(&mut self, op: &Op) {
+        match op.clone() {
+            Op::Mkdir(name) => {
+                let result = self.fs.make_tag(Ino::ROOT, name.as_ref(), Self::actor());
+                let expected =
+                    VirtualDir::is_reserved(name.as_ref()) || self.model.tags.contains_key(name);
+                assert_eq!(result.err(), expected.then_some(EEXIST));
+
+                if result.is_ok() {
+                    self.model.tags.insert(name, BTreeSet::new());
+                }
+            }
+            Op::Rmdir(name) => {
+                let result = self.fs.remove_dir(Ino::ROOT, name.as_ref(), Self::actor());
+                let expected = match self.model.tags.get(name) {
+                    None => Err(ENOENT),
+                    Some(files) if !files.is_empty() => Err(libc::ENOTEMPTY),
+                    Some(_) => Ok(()),
+                };
+                assert_eq!(result, expected);
+
+                if result.is_ok() {
+                    self.model.tags.remove(name);
+                }
+            }
+            Op::RenameTag(name, newname) => {
+                let root = Ino::ROOT;
+                let result = self.fs.rename_entry(
+                    root,
+                    name.as_ref(),
+                    root,
+                    newname.as_ref(),
+                    Self::actor(),
+                );
+                let expected = match self.model.tags.contains_key(name) {
+                    // The root holds no file by a tag's name either
+                    false => Err(ENOENT),
+                    true if VirtualDir::is_reserved(newname.as_ref()) => Err(EEXIST),
+                    true if name != newname && self.model.tags.contains_key(newname) => Err(EEXIST),
+                    true => Ok(()),
+                };
+                assert_eq!(result, expected);
+
+                if result.is_ok() {
+                    let files = self.model.tags.remove(name).unwrap();
+                    self.model.tags.insert(newname, files);
+                }
+            }
+            Op::Move(file, from, to) => {
+                let (Some(parent), Some(newparent)) = (self.dir(&from), self.dir(&to)) else {
+                    return;
+                };
+                let result = self.fs.rename_entry(
+                    parent,
+                    file.as_ref(),
+                    newparent,
+                    file.as_ref(),
+                    Self::actor(),
+                );
+                // Only files listed in the directory can be moved out of it
+                let listed = from.iter().all(|tag| self.model.tags[tag].contains(file));
+                let expected = match parent == newparent {
+                    _ if !listed => Err(ENOENT),
+                    true => Err(ENOTSUP),
+                    false => Ok(()),
+                };
+                assert_eq!(result, expected);
+
+                // Only the tags that differ between the directories change
+                if result.is_ok() {
+                    for tag in from.iter().filter(|tag| !to.contains(tag)) {
+                        self.model.tags.get_mut(tag).unwrap().remove(file);
+                    }
+                    for tag in to.iter().filter(|tag| !from.contains(tag)) {
+                        self.model.tags.get_mut(tag).unwrap().insert(file);
+                    }
+                }
+            }
+            Op::Unlink(path, file) => {
+                let Some(parent) = self.dir(&path) else {
+                    return;
+                };
+                assert_eq!(self.fs.untag(parent, file.as_ref(), Self::actor()), Ok(()));
+
+                for tag in path {
+                    self.model.tags.get_mut(tag).unwrap().remove(file);
+                }
+            }
+            Op::Lookup(path, name) => {
+                let Some(parent) = self.dir(&path) else {
+                    return;
+                };
+                let result = self.fs.lookup_entry(parent, name.as_ref(), UID);
+
+                let expected = match FILES.contains(&name) {
+                    true => self.model.intersection(&path).contains(name),
+                    false if path.is_empty() && VirtualDir::is_reserved(name.as_ref()) => true,
+                    false => self.model.tags.contains_key(name),
+                };
+                // Looking up a tag that's already in the path isn't specified
+                if !path.contains(&name) {
+                    assert_eq!(result.is_ok(), expected, "{result:?}");
+                }
+            }
+            Op::Readdir(path) => {
+                let Some(dir) = self.dir(&path) else { return };
+                let fh = self.fs.open_dir(dir, UID).unwrap();
+                let listing = self.fs.dir_handles.remove(&fh).unwrap();
+                let mut entries = vec![];
+                let split = self.fs.split;
+                listing.fill(self.fs.shown_names(), split, 0, |ino, _, kind, name| {
+                    entries.push((Ino(ino), kind, name.to_os_string()));
+                    false
+                });
+
+                let mut files = BTreeSet::new();
+                let mut tags = BTreeSet::new();
+                for (ino, kind, name) in entries {
+                    let name = name.to_str().unwrap().to_string();
+                    match kind {
+                        RegularFile if !ino.is_virtual(split) => assert!(files.insert(name)),
+                        Directory if name == "." || name == ".." => {}
+                        Directory if VirtualDir::is_reserved(name.as_ref()) => {}
+                        Directory => assert!(tags.insert(name), "listed twice"),
+                        _ => {}
+                    }
+                }
+
+                let expected_files = self.model.intersection(&path);
+                let expected_tags = self.model.tags.keys().filter(|tag| !path.contains(tag));
+                assert!(files.iter().map(String::as_str).eq(expected_files));
+                assert!(tags.iter().map(String::as_str).eq(expected_tags.copied()));
+
+                let numbers = path
+                    .iter()
+                    .map(|tag| self.fs.get_tnb_by_listed_name(tag.as_ref(), UID).unwrap())
+                    .collect::<Vec<_>>();
+                assert_eq!(self.fs.calculate_intersection(&numbers).len(), files.len());
+            }
+            Op::SaveLoad => {
+                self.fs.save().unwrap();
+                let loaded = TagFS::new_from_save(self.source.clone()).unwrap();
+
+                assert_eq!(loaded.files, self.fs.files);
+                assert_eq!(loaded.tags, self.fs.tags);
+                assert_eq!(loaded.tag_content, self.fs.tag_content);
+                assert_eq!(loaded.file_tally, self.fs.file_tally);
+                assert_eq!(loaded.scan_tally, self.fs.scan_tally);
+                // Directories keep their inodes
+                assert_eq!(loaded.tree.save(), self.fs.tree.save());
+                assert_eq!(
+                    loaded.tag_meta.keys().collect::<HashSet<_>>(),
+                    self.fs.tag_meta.keys().collect::<HashSet<_>>(),
+                );
+            }
+        }
+    }
+
+    /// Invariants that hold regardless of the operations.
+    fn check(&self) {
+        let fs = &self.fs;
+
+        // Every tag has exactly one name and one member set
+        let named = fs.tags.left_values().collect::<HashSet<_>>();
+        let stored = fs.tag_content.keys().collect::<HashSet<_>>();
+        assert_eq!(named, stored, "tags and tag_content disagree");
+        assert!(fs.tag_meta.keys().all(|tag| named.contains(tag)));
+
+        // Members are indexed files
+        for files in fs.tag_content.values() {
+            assert!(files.iter().all(|file| fs.files.contains_left(file)));
+        }
+        assert!(fs.file_meta.keys().all(|file| fs.files.contains_left(file)));
+
+        // The memberships match the model
+        assert_eq!(fs.tags.len(), self.model.tags.len());
+        for (&tag, expected) in &self.model.tags {
+            let tag = fs.get_tnb_by_listed_name(tag.as_ref(), UID).expect(tag);
+            let members = fs.tag_content[&tag]
+                .iter()
+                .map(|&file| fs.files.get_by_left(&file).unwrap().to_str().unwrap())
+                .collect::<BTreeSet<_>>();
+            assert!(members.iter().copied().eq(expected.iter().copied()));
+        }
+
+        // Nothing leaks handles on the source
+        assert_eq!(self.source.open_handles(), 0, "leaked source handles");
+    }
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(RUNS))]
+
+    #[test]
+    fn random_operation_sequences(ops in prop::collection::vec(op(), 1..=STEPS)) {
+        let mut sim = Sim::new();
+        for op in ops.iter() {
+            sim.apply(op);
+            sim.check();
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use std::error::Error;
+use std::time::SystemTime;
+
+use crate::fs::backing::BackingFS;
+use crate::fs::error::TagError;
+use crate::fs::profile::state_file;
+use crate::fs::tag::{read_state, TagFS};
+
+/// Snapshots are savefiles next to the live one, named after this prefix and the snapshot.
+pub const SNAPSHOT_PREFIX: &str = ".tagfs-snapshot-";
+
+/// The path of the savefile of snapshot `name`.
+fn snapshot_path(name: &str) -> Result<String, TagError> {
+    if name.is_empty() || name.contains('/') {
+        return Err(TagError::SnapshotName(name.to_string()));
+    }
+
+    Ok(state_file(&format!("{SNAPSHOT_PREFIX}{name}")))
+}
+
+impl<B> TagFS<B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    /// Serve snapshot `name` instead of the live tags. Nothing is saved, so the mount should be
+    /// configured read-only.
+    pub fn new_from_snapshot(backing: B, name: &str) -> anyhow::Result<TagFS<B>> {
+        Self::new_from_file(backing, &snapshot_path(name)?)
+    }
+
+    /// Save the tags as they are now as snapshot `name`, replacing any snapshot by that name.
+    pub fn create_snapshot(&mut self, name: &str) -> Result<String, TagError> {
+        let path = snapshot_path(name)?;
+
+        let previous = self.snapshots.insert(name.to_string(), SystemTime::now());
+        if let Err(error) = self.write_state(&path) {
+            match previous {
+                Some(at) => self.snapshots.insert(name.to_string(), at),
+                None => self.snapshots.remove(name),
+            };
+            return Err(TagError::Source(error.to_string()));
+        }
+
+        match previous {
+            Some(_) => Ok(format!("replaced snapshot '{name}'")),
+            None => Ok(format!("created snapshot '{name}'")),
+        }
+    }
+
+    /// The snapshots with the time they were taken, one per line.
+    pub fn list_snapshots(&self) -> String {
+        self.snapshots
+            .iter()
+            .map(|(name, &at)| format!("{name}\t{}\n", humantime::format_rfc3339_seconds(at)))
+            .collect()
+    }
+
+    /// Go back to the tags of snapshot `name`. Files indexed since it was taken stay, without
+    /// tags, and files that are gone since don't come back.
+    pub fn restore_snapshot(&mut self, name: &str) -> Result<String, TagError> {
+        if !self.snapshots.contains_key(name) {
+            return Err(TagError::NoSuchSnapshot(name.to_string()));
+        }
+        let state = read_state(&self.backing, &snapshot_path(name)?)
+            .map_err(|error| TagError::Source(error.to_string()))?;
+
+        let mut tag_content = state.tag_content;
+        for files in tag_content.values_mut() {
+            files.retain(|file| self.files.contains_left(file));
+        }
+
+        self.note_all_tags(state.tags.left_values().copied());
+        let tag_content = std::mem::replace(&mut self.tag_content, tag_content);
+        let tags = std::mem::replace(&mut self.tags, state.tags);
+        self.tag_meta = state.tag_meta;
+        self.stamp_replaced(&tag_content, &tags);
+        self.restore_tree(state.tree);
+
+        Ok(format!("restored snapshot '{name}'"))
+    }
+}
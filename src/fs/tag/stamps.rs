@@ -0,0 +1,211 @@
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+
+use bimap::BiMap;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+
+use crate::file::{FileNumber, TagNumber};
+use crate::fs::tag::{FileName, TagFS};
+
+/// When an element of an add/remove set was last added and removed. Merging keeps the latest of
+/// each, so copies that were changed independently end up the same whatever order they merge in.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct Stamp {
+    pub added: Option<SystemTime>,
+    pub removed: Option<SystemTime>,
+}
+
+impl Stamp {
+    /// The stamp of elements that were there before stamps were kept.
+    pub const LEGACY: Stamp = Stamp {
+        added: Some(SystemTime::UNIX_EPOCH),
+        removed: None,
+    };
+
+    /// Whether the element is in the set. An add wins over a remove at the same time.
+    pub fn is_present(&self) -> bool {
+        self.added.is_some() && self.added >= self.removed
+    }
+
+    pub fn merge(self, other: Stamp) -> Stamp {
+        Stamp {
+            added: self.added.max(other.added),
+            removed: self.removed.max(other.removed),
+        }
+    }
+
+    fn add(&mut self) {
+        self.added = Some(SystemTime::now());
+    }
+
+    fn remove(&mut self) {
+        self.removed = Some(SystemTime::now());
+    }
+}
+
+/// The add/remove sets behind the tags and their memberships. Tags are stamped by name, so a
+/// removed tag keeps its stamp. Memberships of a removed tag are dropped instead: removing the tag
+/// removes all memberships added before it.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct Stamps {
+    pub tags: HashMap<FileName, Stamp>,
+    pub memberships: HashMap<(TagNumber, FileNumber), Stamp>,
+}
+
+impl<B> TagFS<B> {
+    pub(super) fn stamp_tag_added(&mut self, name: &FileName) {
+        self.stamps.tags.entry(name.clone()).or_default().add();
+    }
+
+    pub(super) fn stamp_tag_removed(&mut self, tag: TagNumber, name: &FileName) {
+        self.stamps.tags.entry(name.clone()).or_default().remove();
+        self.stamps.memberships.retain(|&(t, _), _| t != tag);
+    }
+
+    /// A renamed tag keeps its memberships, only its names are stamped.
+    pub(super) fn stamp_tag_renamed(&mut self, old: &FileName, new: &FileName) {
+        self.stamps.tags.entry(old.clone()).or_default().remove();
+        self.stamp_tag_added(new);
+    }
+
+    pub(super) fn stamp_added(&mut self, tag: TagNumber, file: FileNumber) {
+        self.stamps.memberships.entry((tag, file)).or_default().add();
+    }
+
+    pub(super) fn stamp_removed(&mut self, tag: TagNumber, file: FileNumber) {
+        self.stamps.memberships.entry((tag, file)).or_default().remove();
+    }
+
+    /// Forget the stamps of a file that's no longer indexed.
+    pub(super) fn unstamp_file(&mut self, file: FileNumber) {
+        self.stamps.memberships.retain(|&(_, f), _| f != file);
+    }
+
+    /// The stamp of a membership, whether or not it's present.
+    pub(super) fn membership_stamp(&self, tag: TagNumber, file: FileNumber) -> Stamp {
+        let present = self.tag_content.get(&tag).map(|set| set.contains(&file));
+        match self.stamps.memberships.get(&(tag, file)) {
+            Some(&stamp) => stamp,
+            None if present == Some(true) => Stamp::LEGACY,
+            None => Stamp::default(),
+        }
+    }
+
+    /// The stamp of a tag name, whether or not there's such a tag.
+    pub(super) fn tag_stamp(&self, name: &FileName) -> Stamp {
+        match self.stamps.tags.get(name) {
+            Some(&stamp) => stamp,
+            None if self.tags.contains_right(name) => Stamp::LEGACY,
+            None => Stamp::default(),
+        }
+    }
+
+    /// Stamp what changed since `tag_content` and `tags`, for changes that replace all tags like
+    /// restoring a snapshot.
+    pub(super) fn stamp_replaced(
+        &mut self,
+        tag_content: &IndexMap<TagNumber, HashSet<FileNumber>>,
+        tags: &BiMap<TagNumber, FileName>,
+    ) {
+        let all = tag_content.keys().chain(self.tag_content.keys());
+        let members = all.map(|&tag| (tag, tag_content.get(&tag))).collect();
+        let all = tags.left_values().chain(self.tags.left_values());
+        let names = all.map(|&tag| (tag, tags.get_by_left(&tag))).collect();
+
+        self.stamp_changes(&members, &names);
+    }
+
+    /// Stamp what changed in the tags of `members` and `names`, which had those members and
+    /// names before, `None` where the tag didn't exist. For changes that replace tags wholesale
+    /// like undo.
+    pub(super) fn stamp_changes(
+        &mut self,
+        members: &HashMap<TagNumber, Option<&HashSet<FileNumber>>>,
+        names: &HashMap<TagNumber, Option<&FileName>>,
+    ) {
+        let mut added = vec![];
+        for (&tag, &old) in names.iter() {
+            match (old, self.tags.get_by_left(&tag).cloned()) {
+                (Some(old), Some(now)) if *old == now => {}
+                (Some(old), Some(now)) => {
+                    self.stamps.tags.entry(old.clone()).or_default().remove();
+                    added.push(now);
+                }
+                (Some(old), None) => self.stamp_tag_removed(tag, old),
+                (None, Some(now)) => added.push(now),
+                (None, None) => {}
+            }
+        }
+        for name in added {
+            self.stamp_tag_added(&name);
+        }
+
+        let empty = HashSet::new();
+        let mut changes = vec![];
+        for (&tag, &before) in members.iter() {
+            let Some(files) = self.tag_content.get(&tag) else { continue };
+            let before = before.unwrap_or(&empty);
+            changes.extend(files.difference(before).map(|&file| (tag, file, true)));
+            changes.extend(before.difference(files).map(|&file| (tag, file, false)));
+        }
+        for (tag, file, added) in changes {
+            match added {
+                true => self.stamp_added(tag, file),
+                false => self.stamp_removed(tag, file),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::fs::backing::ExternalFS;
+
+    fn at(secs: u64) -> Option<SystemTime> {
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn merges_stamps_in_any_order() {
+        let added = Stamp { added: at(2), removed: at(1) };
+        let removed = Stamp { added: at(1), removed: at(3) };
+        let readded = Stamp { added: at(4), removed: None };
+
+        // The last of the adds and removes wins, whichever copy it came from
+        assert!(added.is_present() && !removed.is_present());
+        assert_eq!(added.merge(removed), removed.merge(added));
+        assert!(!added.merge(removed).is_present());
+        let all = added.merge(removed).merge(readded);
+        assert_eq!(all, readded.merge(removed).merge(added));
+        assert!(all.is_present());
+
+        // An add wins over a remove at the same time, and nothing was never added
+        assert!(Stamp { added: at(5), removed: at(5) }.is_present());
+        assert!(!Stamp::default().is_present());
+        assert!(!Stamp { added: None, removed: at(1) }.is_present());
+    }
+
+    #[test]
+    fn stamps_changes_to_tags_and_memberships() {
+        let mut fs = TagFS::new(ExternalFS::new("/nonexistent"));
+        fs.repopulate([FileName::from("a.flac")]);
+        let file = fs.get_fnb_by_name("a.flac").unwrap();
+        let music = fs.create_tag("music".into());
+        fs.add_file_to(file, music);
+        assert!(fs.membership_stamp(music, file).is_present());
+
+        fs.remove_file_from(file, music);
+        let stamp = fs.membership_stamp(music, file);
+        assert!(!stamp.is_present() && stamp.added.is_some());
+
+        // Removing a tag stamps its name and drops the stamps of its memberships
+        fs.add_file_to(file, music);
+        fs.remove_tag(music, None, true).unwrap();
+        assert!(!fs.tag_stamp(&"music".into()).is_present());
+        assert!(fs.stamps.memberships.is_empty());
+    }
+}
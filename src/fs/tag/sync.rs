@@ -0,0 +1,282 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::Path;
+
+use crate::file::FileNumber;
+use crate::fs::backing::BackingFS;
+use crate::fs::error::TagError;
+use crate::fs::meta::TagMeta;
+use crate::fs::tag::stamps::Stamp;
+use crate::fs::tag::{decode_state, FileName, PersistentState, TagFS};
+
+/// What a merge changed, for its report.
+#[derive(Default)]
+struct Merged {
+    added: usize,
+    removed: usize,
+    created: usize,
+    dropped: usize,
+}
+
+impl<B: BackingFS> TagFS<B> {
+    /// Merge the tags of another copy of the library, given its savefile. Every tag and
+    /// membership keeps the latest of its add and remove stamps from either copy, and removing a
+    /// tag removes the memberships added before it.
+    ///
+    /// Files are matched by name, or by size and modification time when they were renamed.
+    /// Only this copy is changed, syncing the other way brings the other copy up to date.
+    pub fn sync(&mut self, remote: &Path) -> Result<String, TagError> {
+        let state = decode_state(&std::fs::read(remote)?)
+            .map_err(|error| TagError::SyncState(error.to_string()))?;
+
+        self.merge_state(&state)
+    }
+
+    /// Merge the tags of `state` into these, see [TagFS::sync].
+    pub(super) fn merge_state(&mut self, state: &PersistentState) -> Result<String, TagError> {
+        let names = self.match_files(state);
+
+        let mut tags = self.tag_stamps();
+        for (name, stamp) in remote_tag_stamps(state) {
+            let merged = tags.get(&name).copied().unwrap_or_default().merge(stamp);
+            tags.insert(name, merged);
+        }
+        let mut memberships = self.membership_stamps();
+        for (key, stamp) in remote_membership_stamps(state, &names) {
+            let merged = memberships.get(&key).copied().unwrap_or_default().merge(stamp);
+            memberships.insert(key, merged);
+        }
+
+        // Memberships added before their tag was last removed went with it
+        for ((tag, _), stamp) in memberships.iter_mut() {
+            let removed = tags.get(tag).and_then(|stamp| stamp.removed);
+            if stamp.added < removed {
+                stamp.removed = stamp.removed.max(removed);
+            }
+        }
+        let revived = memberships
+            .iter()
+            .filter(|(_, stamp)| stamp.is_present())
+            .map(|((tag, _), _)| tag.clone())
+            .collect::<BTreeSet<_>>();
+
+        let mut merged = Merged::default();
+        for (name, stamp) in tags.iter() {
+            let present = stamp.is_present() || revived.contains(name);
+            match (self.get_tnb_by_name(name), present) {
+                (None, true) => {
+                    let tag = self.create_tag(name.clone());
+                    if let Some(meta) = remote_meta(state, name) {
+                        *self.tag_meta_mut(tag) = meta;
+                    }
+                    merged.created += 1;
+                }
+                (Some(tag), false) => {
+                    self.remove_tag(tag, None, true)?;
+                    merged.dropped += 1;
+                }
+                _ => {}
+            }
+        }
+        for ((tag, file), stamp) in memberships.iter() {
+            let (Some(tag), Some(file)) = (self.get_tnb_by_name(tag), self.get_fnb_by_name(file))
+            else {
+                continue;
+            };
+            let member = self.tag_content[&tag].contains(&file);
+            match (member, stamp.is_present()) {
+                (false, true) => {
+                    self.add_file_to(file, tag);
+                    merged.added += 1;
+                }
+                (true, false) => {
+                    self.remove_file_from(file, tag);
+                    merged.removed += 1;
+                }
+                _ => {}
+            }
+        }
+
+        // Take over the merged stamps, except where applying them changed something else, like
+        // taking a file out of the rest of a group
+        for (name, stamp) in tags {
+            if self.tags.contains_right(&name) == stamp.is_present() {
+                self.stamps.tags.insert(name, stamp);
+            }
+        }
+        for ((tag, file), stamp) in memberships {
+            let (Some(tag), Some(file)) = (self.get_tnb_by_name(&tag), self.get_fnb_by_name(&file))
+            else {
+                continue;
+            };
+            if self.tag_content[&tag].contains(&file) == stamp.is_present() {
+                self.stamps.memberships.insert((tag, file), stamp);
+            }
+        }
+
+        Ok(format!(
+            "added {} and removed {} membership(s), created {} and removed {} tag(s)",
+            merged.added, merged.removed, merged.created, merged.dropped
+        ))
+    }
+
+    fn tag_stamps(&self) -> BTreeMap<FileName, Stamp> {
+        let names = self.tags.right_values().chain(self.stamps.tags.keys());
+
+        names.map(|name| (name.clone(), self.tag_stamp(name))).collect()
+    }
+
+    /// The stamps of the memberships by tag and file name.
+    fn membership_stamps(&self) -> BTreeMap<(FileName, FileName), Stamp> {
+        let members = self
+            .tag_content
+            .iter()
+            .flat_map(|(&tag, files)| files.iter().map(move |&file| (tag, file)));
+        let keys = members.chain(self.stamps.memberships.keys().copied());
+
+        keys.filter_map(|(tag, file)| {
+            let tag_name = self.tags.get_by_left(&tag)?.clone();
+            let file_name = self.get_fnm_by_number(file)?.clone();
+
+            Some(((tag_name, file_name), self.membership_stamp(tag, file)))
+        })
+        .collect()
+    }
+
+    /// The local name of every file of `state` that's also here, by its number in `state`.
+    fn match_files(&self, state: &PersistentState) -> HashMap<FileNumber, FileName> {
+        let mut matched = state
+            .files
+            .iter()
+            .filter(|(_, name)| self.files.contains_right(*name))
+            .map(|(&file, name)| (file, name.clone()))
+            .collect::<HashMap<_, _>>();
+
+        // Renamed files, as long as there's only one candidate for them
+        let mut by_snapshot = HashMap::<_, Vec<_>>::new();
+        for (file, name) in self.files.iter() {
+            if state.files.contains_right(name) {
+                continue;
+            }
+            if let Some(snapshot) = self.file_meta(*file).and_then(|meta| meta.snapshot) {
+                by_snapshot.entry((snapshot.size, snapshot.mtime)).or_default().push(name);
+            }
+        }
+        for (file, name) in state.files.iter() {
+            let snapshot = state.file_meta.get(file).and_then(|meta| meta.snapshot);
+            if matched.contains_key(file) || self.files.contains_right(name) {
+                continue;
+            }
+            let Some(snapshot) = snapshot else { continue };
+            let candidates = by_snapshot.get(&(snapshot.size, snapshot.mtime));
+            if let Some([name]) = candidates.map(Vec::as_slice) {
+                matched.insert(*file, (*name).clone());
+            }
+        }
+
+        matched
+    }
+}
+
+/// Like [TagFS::tag_stamps], for the tags of `state`.
+fn remote_tag_stamps(state: &PersistentState) -> BTreeMap<FileName, Stamp> {
+    let legacy = state.tags.right_values().map(|name| (name.clone(), Stamp::LEGACY));
+
+    // Recorded stamps come last, so they replace the legacy ones
+    legacy.chain(state.stamps.tags.clone()).collect()
+}
+
+/// Like [TagFS::membership_stamps], for the memberships of `state` with its files under their
+/// local names. Files that aren't here are left out.
+fn remote_membership_stamps(
+    state: &PersistentState,
+    names: &HashMap<FileNumber, FileName>,
+) -> BTreeMap<(FileName, FileName), Stamp> {
+    let members = state
+        .tag_content
+        .iter()
+        .flat_map(|(&tag, files)| files.iter().map(move |&file| ((tag, file), Stamp::LEGACY)));
+    let stamped = state.stamps.memberships.iter().map(|(&key, &stamp)| (key, stamp));
+
+    members
+        .chain(stamped)
+        .filter_map(|((tag, file), stamp)| {
+            let tag = state.tags.get_by_left(&tag)?.clone();
+
+            Some(((tag, names.get(&file)?.clone()), stamp))
+        })
+        .collect()
+}
+
+/// The metadata of the tag called `name` in `state`, for when it's created here. The manual
+/// order refers to files by their numbers in `state`, so it's dropped.
+fn remote_meta(state: &PersistentState, name: &FileName) -> Option<TagMeta> {
+    let tag = state.tags.get_by_right(name)?;
+    let mut meta = state.tag_meta.get(tag)?.clone();
+    meta.order.clear();
+
+    Some(meta)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::ControlCommand;
+    use crate::fs::backing::ExternalFS;
+
+    fn mount() -> TagFS<ExternalFS> {
+        let mut fs = TagFS::new(ExternalFS::new("/nonexistent"));
+        fs.repopulate(["a.flac", "b.flac", "c.flac"].map(FileName::from));
+        fs
+    }
+
+    /// Another copy of the library of `fs`, as it is now.
+    fn copy(fs: &TagFS<ExternalFS>) -> TagFS<ExternalFS> {
+        let mut copy = mount();
+        copy.restore_state(decode_state(&fs.encode_state().unwrap()).unwrap());
+        copy
+    }
+
+    fn run(fs: &mut TagFS<ExternalFS>, line: &str) {
+        fs.execute(ControlCommand::parse_line(line).unwrap()).unwrap();
+    }
+
+    /// The names of the tags of `file`, sorted.
+    fn tags_of(fs: &TagFS<ExternalFS>, file: &str) -> Vec<String> {
+        let file = fs.get_fnb_by_name(file).unwrap();
+        let tags = fs.tags_of(file).into_iter().map(|tag| fs.tags().get_by_left(&tag).unwrap());
+        let mut names = tags.map(|name| name.to_string_lossy().into_owned()).collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn merges_changes_made_on_both_copies() {
+        let mut laptop = mount();
+        run(&mut laptop, "tag a.flac music");
+        run(&mut laptop, "tag b.flac music");
+        let mut nas = copy(&laptop);
+
+        run(&mut laptop, "tag c.flac live");
+        run(&mut nas, "untag b.flac music");
+        run(&mut nas, "tag a.flac live");
+        let report = laptop.merge_state(&decode_state(&nas.encode_state().unwrap()).unwrap());
+        assert!(report.unwrap().starts_with("added 1 and removed 1 membership(s)"));
+        assert_eq!(tags_of(&laptop, "a.flac"), ["live", "music"]);
+        assert!(tags_of(&laptop, "b.flac").is_empty());
+        assert_eq!(tags_of(&laptop, "c.flac"), ["live"]);
+
+        // Syncing the other way brings the copies together, after which there's nothing to merge
+        nas.merge_state(&decode_state(&laptop.encode_state().unwrap()).unwrap()).unwrap();
+        for file in ["a.flac", "b.flac", "c.flac"] {
+            assert_eq!(tags_of(&nas, file), tags_of(&laptop, file));
+        }
+        let again = laptop.merge_state(&decode_state(&nas.encode_state().unwrap()).unwrap());
+        assert!(again.unwrap().starts_with("added 0 and removed 0 membership(s), created 0"));
+
+        // A tag removed on one copy takes the memberships the other copy knew of with it
+        run(&mut nas, "rm-tag live --force");
+        laptop.merge_state(&decode_state(&nas.encode_state().unwrap()).unwrap()).unwrap();
+        assert!(laptop.get_tnb_by_name("live").is_none());
+        assert_eq!(tags_of(&laptop, "a.flac"), ["music"]);
+    }
+}
@@ -0,0 +1,102 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fmt::{Display, Write as _};
+use std::time::{Duration, Instant};
+
+use libc::c_int;
+use log::Level;
+
+use crate::fs::tag::TagFS;
+use crate::logging::log_with;
+
+/// Requests that change the tags, which are logged at debug level even when not tracing.
+const CHANGES: [&str; 5] = ["mkdir", "create", "unlink", "rmdir", "rename"];
+
+/// How often a kind of request was made while tracing, how often it failed and how long it took.
+#[derive(Debug, Default, Copy, Clone)]
+pub(super) struct OpStats {
+    count: u64,
+    failed: u64,
+    total: Duration,
+    slowest: Duration,
+}
+
+impl<B> TagFS<B> {
+    /// Start tracing requests, or stop when `off`. While tracing, every request is logged at
+    /// info level with how long it took and its errno, and counted for the report.
+    pub fn set_tracing(&mut self, off: bool) -> String {
+        if off {
+            return match self.traced.take() {
+                Some(traced) => format!("stopped tracing requests\n{}", trace_report(&traced)),
+                None => "requests weren't being traced".to_string(),
+            };
+        }
+
+        match &self.traced {
+            Some(traced) => trace_report(traced),
+            None => {
+                self.traced = Some(BTreeMap::new());
+                "tracing requests, run `trace` again for a report or `trace --off` to stop"
+                    .to_string()
+            }
+        }
+    }
+
+    /// Log a request with its fields apart for the structured log formats, and count it while
+    /// tracing. Other than those changing the tags, requests are only logged while tracing.
+    pub(super) fn log_op<T>(
+        &mut self,
+        op: &'static str,
+        ino: u64,
+        name: &OsStr,
+        started: Instant,
+        result: &Result<T, c_int>,
+    ) {
+        let elapsed = started.elapsed();
+        let level = match self.traced.as_mut() {
+            Some(traced) => {
+                let stats = traced.entry(op).or_default();
+                stats.count += 1;
+                stats.failed += result.is_err() as u64;
+                stats.total += elapsed;
+                stats.slowest = stats.slowest.max(elapsed);
+                Level::Info
+            }
+            None if CHANGES.contains(&op) => Level::Debug,
+            None => return,
+        };
+
+        let duration = elapsed.as_secs_f64();
+        let errno = result.as_ref().err().copied().unwrap_or(0);
+        let name = name.to_string_lossy();
+        let fields: [(&'static str, &dyn Display); 5] = [
+            ("op", &op),
+            ("ino", &ino),
+            ("name", &name),
+            ("duration", &duration),
+            ("errno", &errno),
+        ];
+        let message = match name.is_empty() {
+            true => format!("{op} {ino}: errno {errno} after {duration:.6}s"),
+            false => format!("{op} '{name}' in {ino}: errno {errno} after {duration:.6}s"),
+        };
+
+        log_with(level, &fields, format_args!("{message}"));
+    }
+}
+
+/// The traced requests per kind, one per line: how often they were made and failed, and their
+/// mean and slowest duration.
+fn trace_report(traced: &BTreeMap<&'static str, OpStats>) -> String {
+    let mut report = "op\tcount\tfailed\tmean\tslowest\n".to_string();
+    for (op, stats) in traced {
+        let mean = stats.total / stats.count.max(1) as u32;
+        let _ = writeln!(
+            report,
+            "{op}\t{}\t{}\t{mean:?}\t{:?}",
+            stats.count, stats.failed, stats.slowest
+        );
+    }
+
+    report
+}
@@ -0,0 +1,90 @@
+use std::ffi::OsStr;
+
+use crate::file::{nfc, TagNumber};
+use crate::fs::error::TagError;
+use crate::fs::tag::{TagFS, STARRED, USER_PREFIX};
+
+impl<B> TagFS<B> {
+    /// Whether tags called `name` may be created and applied. Without a vocabulary any tag may,
+    /// with one only those it declares, the tags tagfs applies itself (`@starred`, the inbox and
+    /// those of `--tag` rules) and private `user:` tags, which other users never see. Names are
+    /// compared like tags are looked up.
+    pub fn is_declared(&self, name: &OsStr) -> bool {
+        let Some(vocabulary) = &self.config.vocabulary else { return true };
+        let name = nfc(name);
+        if name.as_encoded_bytes().starts_with(USER_PREFIX.as_bytes()) {
+            return true;
+        }
+
+        let rules = self.config.tag_rules.iter().map(|rule| OsStr::new(rule.tag.as_str()));
+        vocabulary
+            .iter()
+            .map(|declared| declared.as_os_str())
+            .chain(self.config.inbox.as_deref())
+            .chain(rules)
+            .chain([OsStr::new(STARRED)])
+            .any(|declared| self.config.tag_case.matches(&name, &nfc(declared)))
+    }
+
+    /// Whether `tag` may be applied, see [TagFS::is_declared]. Ephemeral tags always may, since
+    /// they're never saved.
+    pub(super) fn may_apply(&self, tag: TagNumber) -> bool {
+        let declared = self.tags.get_by_left(&tag).is_some_and(|name| self.is_declared(name));
+
+        declared || self.is_ephemeral(tag)
+    }
+
+    /// Fail for a tag called `name` that may not be applied, see [TagFS::may_apply].
+    pub(crate) fn require_declared(&self, name: &OsStr) -> Result<(), TagError> {
+        let ephemeral = self.get_tnb_by_name(name).is_some_and(|tag| self.is_ephemeral(tag));
+        if !ephemeral && !self.is_declared(name) {
+            return Err(TagError::Undeclared(name.into()));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libc::EPERM;
+
+    use super::*;
+    use crate::control::ControlCommand;
+    use crate::file::Ino;
+    use crate::fs::audit::Actor;
+    use crate::fs::backing::ExternalFS;
+    use crate::fs::config::{Config, TagCase};
+    use crate::fs::tag::FileName;
+
+    const USER: Actor = Actor { uid: 1000, pid: 1 };
+
+    fn run(fs: &mut TagFS<ExternalFS>, line: &str) -> Result<String, TagError> {
+        fs.execute(ControlCommand::parse_line(line).unwrap())
+    }
+
+    #[test]
+    fn only_applies_declared_tags() {
+        let mut fs = TagFS::new(ExternalFS::new("/nonexistent"));
+        fs.repopulate([FileName::from("a.flac")]);
+        let vocabulary = || Some(vec!["rock".into(), "Live".into()]);
+        fs.set_config(Config { vocabulary: vocabulary(), ..Default::default() });
+
+        assert!(fs.make_tag(Ino::ROOT, "rock".as_ref(), USER).is_ok());
+        assert_eq!(fs.make_tag(Ino::ROOT, "jazz".as_ref(), USER).err(), Some(EPERM));
+        assert!(matches!(run(&mut fs, "tag a.flac jazz"), Err(TagError::Undeclared(_))));
+        assert!(fs.get_tnb_by_name("jazz").is_none());
+
+        // Except for the tags of tagfs itself, private and ephemeral tags
+        assert!(run(&mut fs, "star a.flac").is_ok());
+        assert!(fs.make_tag(Ino::ROOT, "user:mine".as_ref(), USER).is_ok());
+        assert!(fs.make_tag(Ino::ROOT, ".tmp-jazz".as_ref(), USER).is_ok());
+        assert!(run(&mut fs, "tag a.flac .tmp-jazz").is_ok());
+
+        // Declared names are compared like tags are looked up
+        assert!(!fs.is_declared("live".as_ref()));
+        let tag_case = TagCase::Insensitive;
+        fs.set_config(Config { vocabulary: vocabulary(), tag_case, ..Default::default() });
+        assert!(fs.is_declared("live".as_ref()));
+    }
+}
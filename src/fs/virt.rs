@@ -0,0 +1,284 @@
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+
+use crate::file::{FileNumber, Ino, Split, TagNumber};
+use crate::fs::range::ValueRange;
+
+/// Tag numbers from here on are pseudo tags, see [PseudoTag]. Real tags are numbered within the
+/// tag part of an inode, which is at most 48 bits wide, so these never collide with them.
+pub const PSEUDO_TAGS: TagNumber = 1 << 48;
+
+/// Handles of open virtual files have this bit set, so they never collide with backing handles.
+pub const VIRTUAL_HANDLE: u64 = 1 << 63;
+
+/// Directories generated by tagfs itself, numbered in the reserved part of the tag space.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum VirtualDir {
+    /// `/.tagfs`, holding reports about the filesystem
+    Control,
+    /// `/@all`, every indexed file without any tag directories
+    All,
+    /// `/@recent`, files whose tags changed recently
+    Recent,
+    /// `/@new`, files discovered by the most recent scans of the source
+    New,
+    /// `/.bytag`, a directory per file listing the tags it carries
+    ByTag,
+    /// `/by-ext`, a directory per file extension
+    ByExt,
+    /// `/@duplicates`, a directory per set of files with identical content
+    Duplicates,
+    /// `/@missing`, indexed files that are absent from the source
+    Missing,
+    /// `/.tagfs/complete`, a directory per prefix listing the tags that start with it
+    Complete,
+    /// `/@search`, a directory per search listing the files whose contents have all its words
+    Search,
+    /// `/.tagfs/archives`, a tar archive per tag of the files in it
+    Archives,
+}
+
+impl VirtualDir {
+    pub const ALL: [VirtualDir; 11] = [
+        VirtualDir::Control,
+        VirtualDir::All,
+        VirtualDir::Recent,
+        VirtualDir::New,
+        VirtualDir::ByTag,
+        VirtualDir::ByExt,
+        VirtualDir::Duplicates,
+        VirtualDir::Missing,
+        VirtualDir::Complete,
+        VirtualDir::Search,
+        VirtualDir::Archives,
+    ];
+
+    pub fn ino_part(self, split: Split) -> u64 {
+        split.virtual_dirs() + self as u64
+    }
+
+    pub fn from_ino_part(ino_part: u64, split: Split) -> Option<Self> {
+        Self::ALL.into_iter().find(|vd| vd.ino_part(split) == ino_part)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            VirtualDir::Control => ".tagfs",
+            VirtualDir::All => "@all",
+            VirtualDir::Recent => "@recent",
+            VirtualDir::New => "@new",
+            VirtualDir::ByTag => ".bytag",
+            VirtualDir::ByExt => "by-ext",
+            VirtualDir::Duplicates => "@duplicates",
+            VirtualDir::Missing => "@missing",
+            VirtualDir::Complete => "complete",
+            VirtualDir::Search => "@search",
+            VirtualDir::Archives => "archives",
+        }
+    }
+
+    /// The virtual directory called `name` inside the directory with inode tag part `parent`.
+    pub fn find(parent: u64, name: &OsStr, split: Split) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|vd| vd.parent(split) == parent && name == vd.name())
+    }
+
+    /// The inode tag part of the directory containing this one.
+    pub fn parent(self, split: Split) -> u64 {
+        match self.container() {
+            Some(vd) => vd.ino_part(split),
+            None => Ino::ROOT.0,
+        }
+    }
+
+    /// The virtual directory containing this one, unless it's in the root.
+    fn container(self) -> Option<VirtualDir> {
+        match self {
+            VirtualDir::Control
+            | VirtualDir::All
+            | VirtualDir::Recent
+            | VirtualDir::New
+            | VirtualDir::ByTag
+            | VirtualDir::ByExt
+            | VirtualDir::Duplicates
+            | VirtualDir::Missing
+            | VirtualDir::Search => None,
+            VirtualDir::Complete | VirtualDir::Archives => Some(VirtualDir::Control),
+        }
+    }
+
+    /// Whether `name` is taken by a virtual directory in the root, and so can't be used for a tag.
+    pub fn is_reserved(name: &OsStr) -> bool {
+        Self::ALL
+            .into_iter()
+            .any(|vd| vd.container().is_none() && name == vd.name())
+    }
+}
+
+/// A filter computed from the files themselves rather than stored memberships. Pseudo tags get
+/// tag numbers at runtime, so they can be part of tag tree paths and intersected with real tags.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum PseudoTag {
+    /// Files with this (lowercase) extension
+    Extension(OsString),
+    /// Files whose contents have all these words, see `TagFS::index_content`
+    Content(String),
+    /// Files with a value tag in this range, like `year>=2015`
+    Range(ValueRange),
+}
+
+impl PseudoTag {
+    pub fn matches(&self, name: &OsStr) -> bool {
+        match self {
+            PseudoTag::Extension(ext) => extension(name).as_ref() == Some(ext),
+            // Matched against the content index instead, see `TagFS::has_tag`
+            PseudoTag::Content(_) => false,
+            // Matched against the value tags instead, see `TagFS::has_tag`
+            PseudoTag::Range(_) => false,
+        }
+    }
+
+    /// Whether the pseudo tag is made up from a name that was looked up, like a search, rather
+    /// than from the files, so only the most recently used ones are kept.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, PseudoTag::Content(_) | PseudoTag::Range(_))
+    }
+}
+
+/// The lowercase extension of a file name, if it has one.
+pub fn extension(name: &OsStr) -> Option<OsString> {
+    Path::new(name)
+        .extension()
+        .map(|ext| ext.to_ascii_lowercase())
+}
+
+/// Directories generated per file or tag at runtime. Their inode tag parts are handed out by
+/// the tag tree, so they never collide with tag directories.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum VirtualNode {
+    /// `/.bytag/<file>`, holding a symlink to the directory of every tag of the file
+    FileTags(FileNumber),
+    /// `<tag dir>/.shuffle-<count>`, a random sample of the files in the tag directory
+    Shuffle { dir: u64, count: usize },
+    /// `/@duplicates/<file>`, the files with the same content as `<file>`, indexing
+    /// `TagFS::duplicates`
+    Duplicates(usize),
+    /// `/.tagfs/complete/<prefix>`, holding a symlink to the directory of every tag starting with
+    /// `<prefix>`, by the number `TagFS::completions` handed out for the prefix
+    Complete(u64),
+}
+
+impl VirtualNode {
+    /// The inode tag part of the directory containing this one.
+    pub fn parent(self, split: Split) -> u64 {
+        match self {
+            VirtualNode::FileTags(_) => VirtualDir::ByTag.ino_part(split),
+            VirtualNode::Shuffle { dir, .. } => dir,
+            VirtualNode::Duplicates(_) => VirtualDir::Duplicates.ino_part(split),
+            VirtualNode::Complete(_) => VirtualDir::Complete.ino_part(split),
+        }
+    }
+
+    /// Whether the node is made up from a name that was looked up, which any name can be, so only
+    /// the most recently used ones are kept.
+    pub fn is_transient(self) -> bool {
+        matches!(self, VirtualNode::Shuffle { .. } | VirtualNode::Complete(_))
+    }
+
+    /// Parse the name of a shuffle directory, e.g. `.shuffle-20`, into the sample size.
+    pub fn shuffle_count(name: &OsStr) -> Option<usize> {
+        let count = name.to_str()?.strip_prefix(".shuffle-")?.parse().ok()?;
+
+        (count > 0).then_some(count)
+    }
+}
+
+/// Files generated by tagfs itself, numbered in the reserved part of the file space.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum VirtualFile {
+    /// The description of the tag a directory represents
+    Description,
+    /// Per-tag statistics, see `TagFS::tag_stats`
+    TagStats,
+    /// Runs the control commands written to it, reading it shows their reports
+    Control,
+    /// A short summary of the index, see `TagFS::stats`
+    Stats,
+    /// The control commands that failed recently
+    Errors,
+    /// The version of tagfs serving the mount
+    Version,
+    /// What the last scan of the source changed, see `TagFS::last_scan_report`
+    LastScan,
+    /// The size of the members of every tag, see `TagFS::disk_usage`
+    DiskUsage,
+    /// Quick checks of the mount for monitoring, see `TagFS::health`
+    Health,
+}
+
+impl VirtualFile {
+    pub const ALL: [VirtualFile; 9] = [
+        VirtualFile::Description,
+        VirtualFile::TagStats,
+        VirtualFile::Control,
+        VirtualFile::Stats,
+        VirtualFile::Errors,
+        VirtualFile::Version,
+        VirtualFile::LastScan,
+        VirtualFile::DiskUsage,
+        VirtualFile::Health,
+    ];
+
+    pub fn number(self, split: Split) -> FileNumber {
+        split.virtual_files() + self as u64
+    }
+
+    pub fn from_number(number: FileNumber, split: Split) -> Option<Self> {
+        Self::ALL.into_iter().find(|vf| vf.number(split) == number)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            VirtualFile::Description => ".description",
+            VirtualFile::TagStats => "tag-stats",
+            VirtualFile::Control => "control",
+            VirtualFile::Stats => "stats",
+            VirtualFile::Errors => "errors",
+            VirtualFile::Version => "version",
+            VirtualFile::LastScan => "last-scan",
+            VirtualFile::DiskUsage => "du",
+            VirtualFile::Health => "health",
+        }
+    }
+
+    pub fn from_name(name: &OsStr) -> Option<Self> {
+        Self::ALL.into_iter().find(|vf| name == vf.name())
+    }
+
+    /// The virtual directory this file lives in, or `None` if it lives in tag directories.
+    pub fn dir(self) -> Option<VirtualDir> {
+        match self {
+            VirtualFile::Description => None,
+            VirtualFile::TagStats
+            | VirtualFile::Control
+            | VirtualFile::Stats
+            | VirtualFile::Errors
+            | VirtualFile::Version
+            | VirtualFile::LastScan
+            | VirtualFile::DiskUsage
+            | VirtualFile::Health => Some(VirtualDir::Control),
+        }
+    }
+
+    /// Whether the file accepts writes, the others are read-only.
+    pub fn is_writable(self) -> bool {
+        self == VirtualFile::Control
+    }
+
+    /// Whether the file is a report too costly to generate just for its size, so its size is 0.
+    /// It's generated when opened, and read with direct I/O like the archives.
+    pub fn is_unsized(self) -> bool {
+        matches!(self, VirtualFile::TagStats | VirtualFile::DiskUsage | VirtualFile::Health)
+    }
+}
@@ -0,0 +1,307 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::{Arguments, Display, Write as _};
+use std::io;
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use pretty_env_logger::env_logger::Builder;
+
+/// How log records are written.
+#[derive(ValueEnum, Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum LogFormat {
+    /// Human readable lines on stderr
+    #[default]
+    Text,
+    /// One JSON object per line on stderr
+    Json,
+    /// Straight to the systemd journal, with the fields of a record as journal fields
+    Journald,
+}
+
+/// Where journald takes entries in its native protocol.
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Identical warnings and errors logged within this long of the first are only counted.
+pub(crate) const REPEAT_WINDOW: Duration = Duration::from_secs(60);
+
+/// The most verbose level the logger passes on, [set_level] can't go past it.
+const MAX_LEVEL: LevelFilter = LevelFilter::Debug;
+
+thread_local! {
+    /// The fields of the record being logged, see [log_with].
+    static FIELDS: RefCell<Vec<(&'static str, String)>> = const { RefCell::new(vec![]) };
+}
+
+/// Log `message` at `level` with `fields`, like the operation and inode of a request. The json
+/// and journald formats output them as fields of their own, the message should mention them too.
+pub fn log_with(level: Level, fields: &[(&'static str, &dyn Display)], message: Arguments) {
+    let fields = fields.iter().map(|(key, value)| (*key, value.to_string())).collect();
+    FIELDS.with(|current| *current.borrow_mut() = fields);
+    log::log!(level, "{message}");
+    FIELDS.with(|current| current.borrow_mut().clear());
+}
+
+fn fields() -> Vec<(&'static str, String)> {
+    FIELDS.with(|current| current.borrow().clone())
+}
+
+/// Install the logger for `format`. Falls back to text when the journal can't be reached.
+pub fn setup(format: LogFormat) {
+    let mut builder = Builder::new();
+    builder.filter_level(MAX_LEVEL);
+    builder.format_timestamp_secs();
+    if format == LogFormat::Json {
+        builder.format(|buf, record| writeln!(buf, "{}", json_line(record)));
+    }
+    let text = builder.build();
+    let max = text.filter();
+
+    let mut unreachable = None;
+    let logger: Box<dyn Log> = match format {
+        LogFormat::Journald => match Journald::connect(max) {
+            Ok(journald) => Box::new(journald),
+            Err(e) => {
+                unreachable = Some(e);
+                Box::new(text)
+            }
+        },
+        LogFormat::Text | LogFormat::Json => Box::new(text),
+    };
+
+    let limited = RateLimited {
+        inner: logger,
+        repeats: Default::default(),
+    };
+    if log::set_boxed_logger(Box::new(limited)).is_ok() {
+        log::set_max_level(max);
+    }
+    if let Some(e) = unreachable {
+        log::warn!("can't log to the journal, logging to stderr instead: {e}");
+    }
+}
+
+/// Log up to `level` from now on, e.g. to quiet a mount that's running. Returns the level it
+/// logs at, which is no more verbose than [MAX_LEVEL].
+pub fn set_level(level: LevelFilter) -> LevelFilter {
+    let level = level.min(MAX_LEVEL);
+    log::set_max_level(level);
+
+    level
+}
+
+/// A record as a JSON object, with its fields as string members.
+fn json_line(record: &Record) -> String {
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let mut line = format!(
+        "{{\"time\":{:.3},\"level\":{},\"target\":{},\"message\":{}",
+        time.as_secs_f64(),
+        json_string(record.level().as_str()),
+        json_string(record.target()),
+        json_string(&record.args().to_string())
+    );
+    for (key, value) in fields() {
+        let _ = write!(line, ",{}:{}", json_string(key), json_string(&value));
+    }
+    line.push('}');
+
+    line
+}
+
+pub(crate) fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+
+    quoted
+}
+
+/// Writes records to the journal in its native protocol.
+struct Journald {
+    socket: UnixDatagram,
+    max: LevelFilter,
+}
+
+impl Journald {
+    fn connect(max: LevelFilter) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNALD_SOCKET)?;
+
+        Ok(Self { socket, max })
+    }
+}
+
+impl Log for Journald {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.max
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let priority = match record.level() {
+            Level::Error => "3",
+            Level::Warn => "4",
+            Level::Info => "6",
+            Level::Debug | Level::Trace => "7",
+        };
+        let mut entry = vec![];
+        journal_field(&mut entry, "PRIORITY", priority);
+        journal_field(&mut entry, "MESSAGE", &record.args().to_string());
+        journal_field(&mut entry, "SYSLOG_IDENTIFIER", "tagfs");
+        journal_field(&mut entry, "TARGET", record.target());
+        for (key, value) in fields() {
+            journal_field(&mut entry, &key.to_uppercase(), &value);
+        }
+
+        // Nowhere left to report it
+        let _ = self.socket.send(&entry);
+    }
+
+    fn flush(&self) {}
+}
+
+/// Append a field to a journal entry. Values spanning lines are prefixed with their length.
+fn journal_field(entry: &mut Vec<u8>, key: &str, value: &str) {
+    entry.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// Passes records on, except warnings and errors identical to one passed on within
+/// [REPEAT_WINDOW], e.g. a failing read of a file that a file manager keeps retrying: those are
+/// counted, and once the window is over a single record says how often the message repeated.
+struct RateLimited {
+    inner: Box<dyn Log>,
+    repeats: Mutex<Repeats>,
+}
+
+#[derive(Default)]
+struct Repeats {
+    /// The warnings and errors passed on within the window, by message
+    seen: HashMap<String, Repeated>,
+    /// When the window of the first of them is over
+    due: Option<Instant>,
+}
+
+struct Repeated {
+    /// When the message was passed on
+    at: Instant,
+    level: Level,
+    target: String,
+    /// How often it was held back since
+    held: u64,
+}
+
+impl Repeats {
+    /// Take the messages whose window is over by `now`, or all of them if `all`, keeping those
+    /// that were held back.
+    fn take_due(&mut self, now: Instant, all: bool) -> Vec<(String, Repeated)> {
+        if !all && self.due.is_none_or(|due| now < due) {
+            return vec![];
+        }
+
+        let (due, seen): (HashMap<_, _>, HashMap<_, _>) = std::mem::take(&mut self.seen)
+            .into_iter()
+            .partition(|(_, repeated)| all || now.duration_since(repeated.at) >= REPEAT_WINDOW);
+        self.due = seen.values().map(|repeated| repeated.at + REPEAT_WINDOW).min();
+        self.seen = seen;
+
+        due.into_iter().filter(|(_, repeated)| repeated.held > 0).collect()
+    }
+}
+
+impl RateLimited {
+    fn report_repeats(&self, repeats: Vec<(String, Repeated)>) {
+        if repeats.is_empty() {
+            return;
+        }
+
+        // The fields of the record being logged aren't those of the repeated ones
+        let fields = FIELDS.with(RefCell::take);
+        for (message, repeated) in repeats {
+            let held = repeated.held;
+            self.inner.log(
+                &Record::builder()
+                    .args(format_args!("{message} (repeated {held} more time(s))"))
+                    .level(repeated.level)
+                    .target(&repeated.target)
+                    .build(),
+            );
+        }
+        FIELDS.with(|current| *current.borrow_mut() = fields);
+    }
+}
+
+impl Log for RateLimited {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        let now = Instant::now();
+        let mut repeats = self.repeats.lock().unwrap_or_else(PoisonError::into_inner);
+        let due = repeats.take_due(now, false);
+        if record.level() <= Level::Warn {
+            let message = record.args().to_string();
+            if let Some(repeated) = repeats.seen.get_mut(&message) {
+                repeated.held += 1;
+                drop(repeats);
+                return self.report_repeats(due);
+            }
+
+            // Past that many, something is logging distinct messages and they all go through
+            if repeats.seen.len() < 1024 {
+                repeats.due.get_or_insert(now + REPEAT_WINDOW);
+                let repeated = Repeated {
+                    at: now,
+                    level: record.level(),
+                    target: record.target().to_string(),
+                    held: 0,
+                };
+                repeats.seen.insert(message, repeated);
+            }
+        }
+        drop(repeats);
+
+        self.report_repeats(due);
+        self.inner.log(record);
+    }
+
+    fn flush(&self) {
+        let mut repeats = self.repeats.lock().unwrap_or_else(PoisonError::into_inner);
+        let due = repeats.take_due(Instant::now(), true);
+        drop(repeats);
+
+        self.report_repeats(due);
+        self.inner.flush()
+    }
+}
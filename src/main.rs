@@ -1,14 +1,25 @@
 #![feature(cell_update)]
 
-use clap::Parser;
+use std::error::Error;
+use std::ffi::OsString;
+use std::io::ErrorKind;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use clap::error::ErrorKind as ClapErrorKind;
+use clap::{CommandFactory, Parser};
 use fuser::MountOption;
-use log::{error, LevelFilter};
-use pretty_env_logger::env_logger::Builder;
+use log::info;
 
-use cli::Args;
+use cli::{Args, Command};
 
-use crate::fs::backing::ExternalFS;
-use crate::fs::tag::TagFS;
+use crate::control::ControlCommand;
+use crate::fs::audit::{command_detail, Actor};
+use crate::fs::backing::{BackingFS, ExternalFS, Stat};
+use crate::fs::config::Config;
+use crate::fs::profile::set_profile;
+use crate::fs::retry::{RetryPolicy, RetryingFS};
+use crate::fs::tag::{group_duplicates, spawn_verifier, TagFS};
 
 mod file;
 
@@ -16,47 +27,215 @@ mod fs;
 
 mod cli;
 
-fn main() -> std::io::Result<()> {
-    setup_logger();
+mod control;
 
-    let args = Args::parse();
+mod logging;
+
+mod tui;
+
+mod watch;
+
+/// How many files a background scan hands over at once.
+const SCAN_BATCH: usize = 1024;
+
+fn main() -> anyhow::Result<()> {
+    let mut args = Args::parse();
+    logging::setup(args.log_format);
 
     let source_path = args.source_path.as_str();
+    if let Some(profile) = &args.profile {
+        set_profile(profile)?;
+    }
+
+    match args.command.take() {
+        // Only reads the savefile, while the others write it
+        Some(Command::Watch(watch)) => return watch::run(source_path, &watch),
+        Some(Command::Tui(tui::Tui { mount: Some(mount) })) => {
+            return tui::run_mounted(source_path, &mount)
+        }
+        Some(command) => return run_command(command, source_path, args.index_config()),
+        None => {}
+    }
+
+    let config = args.config();
+    let nested = config.nested;
+    let groups = args.groups;
+    let find_duplicates = args.find_duplicates;
+    let index_content = args.index_content;
+    let allow_other = args.allow_other;
+    let timeout = args.backing_timeout;
+    let max_open = args.max_open_files;
+    let ino_split = args.ino_split;
+    let snapshot = args.snapshot;
+    let retry = RetryPolicy {
+        retries: args.retries,
+        ..Default::default()
+    };
+    let Some(mount_path) = args.mount_path else {
+        Args::command()
+            .error(
+                ClapErrorKind::MissingRequiredArgument,
+                "--mount-path is required when no subcommand is given",
+            )
+            .exit()
+    };
 
-    let mut fs = match TagFS::new_from_save(ExternalFS::new(source_path)) {
-        Ok(fs) => fs,
-        Err(e) => {
-            error!("Couldn't recover FS from savefile: {e}, creating empty FS");
-            TagFS::new(ExternalFS::new(source_path))
+    let backing = || {
+        let external = ExternalFS::new(source_path)
+            .with_timeout(timeout)
+            .with_max_open(max_open)
+            .with_nested(nested);
+
+        RetryingFS::new(external, retry)
+    };
+    let mut fs = match snapshot {
+        Some(name) => TagFS::new_from_snapshot(backing(), &name)?,
+        None => match TagFS::new_from_save(backing()) {
+            Ok(fs) => fs,
+            Err(e) if is_not_found(&e) => {
+                info!("No savefile yet, creating empty FS");
+                TagFS::new(backing())
+            }
+            // Starting empty would overwrite the savefile with the next save
+            Err(e) => return Err(e.context("the savefile exists but can't be read, not mounting")),
+        },
+    };
+
+    let files = fs.backing().list("")?;
+
+    if let Some(split) = ino_split {
+        fs.set_ino_split(split)?;
+    }
+    fs.set_config(config);
+    fs.starred_tag();
+    for group in groups {
+        let tags = group
+            .tags
+            .into_iter()
+            .map(|tag| fs.get_or_create_tag(tag.into()))
+            .collect::<Vec<_>>();
+        fs.declare_group(&group.name, &tags);
+    }
+    // Reads every file, so it'd wait for the scan anyway
+    if index_content {
+        fs.repopulate(files.map(Into::into));
+    } else {
+        fs.scan_in_background(spawn_scan(files, backing()));
+    }
+    if find_duplicates {
+        fs.find_duplicates_in_background(spawn_duplicates(backing())?);
+    }
+    fs.set_verifier(spawn_verifier(backing()));
+    if index_content {
+        if let Err(e) = fs.index_content() {
+            fs.record_error("index the contents of the source", &e);
         }
+    }
+
+    // Other users include root
+    let access = match allow_other {
+        true => MountOption::AllowOther,
+        false => MountOption::AllowRoot,
     };
+    fuser::mount2(fs, mount_path, &[MountOption::AutoUnmount, access])?;
+    // Report the counts of repeated warnings still held back
+    log::logger().flush();
 
-    let files = std::fs::read_dir(source_path)?
-        .filter_map(|e| {
-            e.ok()
-                .filter(|e| e.file_type().map(|ft| ft.is_file()).unwrap_or(false))
-        })
-        .map(|e| e.file_name());
+    Ok(())
+}
+
+/// List `files` and stat them in `backing` on a thread of its own, handing them over in batches.
+fn spawn_scan<B>(
+    files: impl Iterator<Item = OsString> + Send + 'static,
+    backing: B,
+) -> Receiver<Vec<(OsString, Option<Stat>)>>
+where
+    B: BackingFS + Send + 'static,
+{
+    let (sender, batches) = mpsc::channel();
+    thread::spawn(move || {
+        let mut files = files.peekable();
+        while files.peek().is_some() {
+            let names = files.by_ref().take(SCAN_BATCH).collect::<Vec<_>>();
+            let stats = backing.stat_all(&names).into_iter().map(Result::ok);
+            if sender.send(names.into_iter().zip(stats).collect()).is_err() {
+                return;
+            }
+        }
+    });
 
-    fs.repopulate(files);
+    batches
+}
 
-    fuser::mount2(
-        fs,
-        args.mount_path,
-        &[MountOption::AutoUnmount, MountOption::AllowRoot],
-    )
+/// Look for identical files in `backing` on a thread of its own, see [group_duplicates].
+fn spawn_duplicates<B>(backing: B) -> anyhow::Result<Receiver<Vec<Vec<OsString>>>>
+where
+    B: BackingFS + Send + 'static,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    let files = backing.list("")?;
+    let (sender, groups) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(group_duplicates(&backing, files));
+    });
+
+    Ok(groups)
 }
 
-fn setup_logger() {
-    // Create a new `env_logger::Builder`
-    let mut builder = Builder::new();
+/// Run a subcommand directly against the savefile in `source_path`.
+fn run_command(command: Command, source_path: &str, config: Config) -> anyhow::Result<()> {
+    // A backup replaces a broken savefile
+    let restores = matches!(command, Command::Control(ControlCommand::RestoreBackup { .. }));
+    let backing = || ExternalFS::new(source_path).with_nested(config.nested);
+    let mut fs = match TagFS::new_from_save(backing()) {
+        Ok(fs) => fs,
+        // Only start from scratch if there's no savefile yet, never overwrite a broken one
+        Err(e) if is_not_found(&e) || restores => TagFS::new(backing()),
+        Err(e) => return Err(e),
+    };
+    fs.set_config(config);
+    // Read-only commands look at the index as saved and leave the savefile alone
+    let read_only = matches!(&command, Command::Control(command) if command.is_read_only());
+    let scanned = match read_only {
+        true => None,
+        false => {
+            let files = fs.backing().list("")?;
+            let scanned = fs.repopulate(files.map(Into::into));
+            fs.expire();
+            Some(scanned)
+        }
+    };
+
+    match command {
+        Command::Control(command) => {
+            let what = format!("{command:?}");
+            let report = fs.execute(command)?;
+            println!("{}", report.trim_end());
+            if read_only {
+                return Ok(());
+            }
 
-    // Set the minimum log level to `Debug`
-    builder.filter_level(LevelFilter::Debug);
+            fs.audit(Actor::current(), "command", &command_detail(&what, &report));
+        }
+        Command::Tui(_) => tui::run(&mut fs)?,
+        Command::Rescan => print!("{}", scanned.map(|s| s.report()).unwrap_or_default()),
+        Command::Doctor => {
+            let health = fs.health();
+            print!("{}", health.report());
+            if health.failed() {
+                anyhow::bail!("some checks failed");
+            }
+            return Ok(());
+        }
+        Command::Watch(_) => unreachable!("watching is run without loading the savefile to edit"),
+    }
 
-    // Configure the log format
-    builder.format_timestamp_secs();
+    fs.save()
+}
 
-    // Initialize the logger
-    builder.init();
+fn is_not_found(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<std::io::Error>()
+        .map(|e| e.kind() == ErrorKind::NotFound)
+        .unwrap_or(false)
 }
@@ -0,0 +1,507 @@
+use std::error::Error;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::bail;
+use clap::Args;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Style, Stylize};
+use ratatui::widgets::{Block, Clear, List, ListState, Paragraph};
+use ratatui::Frame;
+
+use crate::control::{quote_word, ControlCommand};
+use crate::file::{FileNumber, TagNumber};
+use crate::fs::audit::{command_detail, Actor};
+use crate::fs::backing::{BackingFS, ExternalFS};
+use crate::fs::profile::{state_file, SAVEFILE};
+use crate::fs::tag::TagFS;
+use crate::fs::virt::{VirtualDir, VirtualFile};
+
+/// How far page up and down move.
+const PAGE: usize = 10;
+
+/// How often a running mount is checked for changes made elsewhere.
+const REFRESH: Duration = Duration::from_secs(1);
+
+const HELP: &str = "tab: switch pane  space: toggle tag  enter: only files with the tag  \
+                    t: tag  n: new tag  u: undo  /: find  :: command  q: quit";
+
+/// Browse the files and tags in the terminal, see [run] and [run_mounted].
+#[derive(Args, Debug)]
+pub(crate) struct Tui {
+    /// Where the source is mounted, to make changes through the running mount. Without it the
+    /// savefile is edited, so the source shouldn't be mounted meanwhile
+    #[arg(short, long, value_name = "PATH")]
+    pub mount: Option<PathBuf>,
+}
+
+/// Browse the files and tags in the terminal and change them, saving after every change. It
+/// works on the savefile, so the source shouldn't be mounted meanwhile.
+pub fn run<B>(fs: &mut TagFS<B>) -> anyhow::Result<()>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    browse(Savefile(fs))
+}
+
+/// Browse the files and tags of the running mount of `source_path` at `mount`, changing them
+/// through its control file. What the mount or others change shows up as the mount saves.
+pub fn run_mounted(source_path: &str, mount: &Path) -> anyhow::Result<()> {
+    browse(Mounted::new(source_path, mount)?)
+}
+
+fn browse(mut store: impl Store) -> anyhow::Result<()> {
+    // Anything logged shows up in the middle of the screen
+    log::set_max_level(log::LevelFilter::Error);
+    let mut terminal = ratatui::try_init()?;
+    let mut screen = Screen::default();
+    let browsed = loop {
+        if let Err(e) = terminal.draw(|frame| screen.draw(frame, store.fs())) {
+            break Err(e);
+        }
+        match event::poll(REFRESH) {
+            Ok(true) => {}
+            Ok(false) => {
+                store.refresh();
+                continue;
+            }
+            Err(e) => break Err(e),
+        }
+
+        let key = match event::read() {
+            Ok(Event::Key(key)) => Key::of(key),
+            Ok(_) => None,
+            Err(e) => break Err(e),
+        };
+        if let Some(key) = key {
+            if !screen.handle(&mut store, key) {
+                break Ok(());
+            }
+        }
+    };
+    ratatui::restore();
+
+    Ok(browsed?)
+}
+
+/// Where the tags come from and changes go to.
+trait Store {
+    type Backing;
+
+    fn fs(&self) -> &TagFS<Self::Backing>;
+
+    /// Run a command written like to `/.tagfs/control`, returning its report.
+    fn run(&mut self, line: &str) -> Result<String, String>;
+
+    /// Pick up changes made elsewhere.
+    fn refresh(&mut self) {}
+}
+
+/// The savefile, edited in place like the `tagfs` subcommands do.
+struct Savefile<'a, B>(&'a mut TagFS<B>);
+
+impl<B> Store for Savefile<'_, B>
+where
+    B: BackingFS,
+    <B as BackingFS>::Error: Error + Send + Sync + 'static,
+{
+    type Backing = B;
+
+    fn fs(&self) -> &TagFS<B> {
+        self.0
+    }
+
+    fn run(&mut self, line: &str) -> Result<String, String> {
+        let command = ControlCommand::parse_line(line).map_err(|e| e.to_string())?;
+        let read_only = command.is_read_only();
+        let what = format!("{command:?}");
+        let report = self.0.execute(command).map_err(|e| e.to_string())?;
+        if !read_only {
+            let detail = command_detail(&what, &report);
+            self.0.audit(Actor::current(), "command", &detail);
+            self.0.save().map_err(|e| format!("can't save: {e}"))?;
+        }
+
+        Ok(report)
+    }
+}
+
+/// A running mount: changes are written to its control file, so they go through the mount like
+/// any other, and its savefile is read again whenever it saved.
+struct Mounted {
+    source_path: String,
+    control: PathBuf,
+    fs: TagFS<ExternalFS>,
+    /// When the savefile that was read last was written
+    saved: Option<SystemTime>,
+}
+
+impl Mounted {
+    fn new(source_path: &str, mount: &Path) -> anyhow::Result<Self> {
+        let control = mount.join(VirtualDir::Control.name()).join(VirtualFile::Control.name());
+        if !control.is_file() {
+            bail!("'{}' isn't a running tagfs mount", mount.display());
+        }
+
+        let fs = TagFS::new_from_save(ExternalFS::new(source_path))?;
+        let saved = Self::saved_at(&fs);
+        Ok(Mounted {
+            source_path: source_path.to_string(),
+            control,
+            fs,
+            saved,
+        })
+    }
+
+    fn saved_at(fs: &TagFS<ExternalFS>) -> Option<SystemTime> {
+        fs.backing().get_metadata(state_file(SAVEFILE)).map(|attr| attr.mtime).ok()
+    }
+}
+
+impl Store for Mounted {
+    type Backing = ExternalFS;
+
+    fn fs(&self) -> &TagFS<ExternalFS> {
+        &self.fs
+    }
+
+    fn run(&mut self, line: &str) -> Result<String, String> {
+        let written = OpenOptions::new()
+            .write(true)
+            .open(&self.control)
+            .and_then(|mut control| control.write_all(format!("{line}\n").as_bytes()));
+        // The mount saves before a command succeeds
+        self.refresh();
+        if let Err(e) = written {
+            return Err(format!("{e}, the mount lists why in /.tagfs/errors"));
+        }
+
+        // Reading it back shows what the command did
+        std::fs::read_to_string(&self.control).map_err(|e| e.to_string())
+    }
+
+    fn refresh(&mut self) {
+        let saved = Self::saved_at(&self.fs);
+        if saved.is_none() || saved == self.saved {
+            return;
+        }
+
+        // A save may be in the middle of being written, it's read again on the next refresh
+        if let Ok(fs) = TagFS::new_from_save(ExternalFS::new(&self.source_path)) {
+            self.fs = fs;
+            self.saved = saved;
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Key {
+    Up,
+    Down,
+    Left,
+    Right,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Tab,
+    Enter,
+    Escape,
+    Backspace,
+    Interrupt,
+    Text(String),
+}
+
+impl Key {
+    /// The key pressed in `event`, if it's one the tui uses.
+    fn of(event: KeyEvent) -> Option<Key> {
+        if event.kind != KeyEventKind::Press {
+            return None;
+        }
+
+        let key = match event.code {
+            KeyCode::Up => Key::Up,
+            KeyCode::Down => Key::Down,
+            KeyCode::Left => Key::Left,
+            KeyCode::Right => Key::Right,
+            KeyCode::PageUp => Key::PageUp,
+            KeyCode::PageDown => Key::PageDown,
+            KeyCode::Home => Key::Home,
+            KeyCode::End => Key::End,
+            KeyCode::Tab | KeyCode::BackTab => Key::Tab,
+            KeyCode::Enter => Key::Enter,
+            KeyCode::Esc => Key::Escape,
+            KeyCode::Backspace => Key::Backspace,
+            KeyCode::Char('c' | 'd') if event.modifiers.contains(KeyModifiers::CONTROL) => {
+                Key::Interrupt
+            }
+            KeyCode::Char(c) => Key::Text(c.to_string()),
+            _ => return None,
+        };
+
+        Some(key)
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+enum Pane {
+    #[default]
+    Files,
+    Tags,
+}
+
+/// What the bottom line is asking for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Prompt {
+    Tag,
+    NewTag,
+    Find,
+    Command,
+}
+
+impl Prompt {
+    fn label(&self) -> &'static str {
+        match self {
+            Prompt::Tag => "tag with: ",
+            Prompt::NewTag => "new tag: ",
+            Prompt::Find => "find: ",
+            Prompt::Command => ":",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Screen {
+    pane: Pane,
+    /// The selected file and tag
+    file_at: usize,
+    tag_at: usize,
+    /// The scroll positions of the panes
+    file_list: ListState,
+    tag_list: ListState,
+    /// Only list the files with this tag
+    shown_tag: Option<TagNumber>,
+    /// Only list the files whose name contains this
+    find: String,
+    prompt: Option<(Prompt, String)>,
+    status: String,
+    /// The report of a command, shown over the panes until a key is pressed
+    output: Vec<String>,
+}
+
+impl Screen {
+    /// The listed files, by name.
+    fn files<B>(&self, fs: &TagFS<B>) -> Vec<(FileNumber, String)> {
+        let find = self.find.to_lowercase();
+        let mut files = fs
+            .files()
+            .iter()
+            .filter(|&(&file, _)| self.shown_tag.map(|tag| fs.has_tag(file, tag)).unwrap_or(true))
+            .map(|(&file, name)| (file, name.to_string_lossy().into_owned()))
+            .filter(|(_, name)| name.to_lowercase().contains(&find))
+            .collect::<Vec<_>>();
+        files.sort_by(|(_, a), (_, b)| a.cmp(b));
+
+        files
+    }
+
+    /// Whether to keep going after `key`.
+    fn handle(&mut self, store: &mut impl Store, key: Key) -> bool {
+        if !self.output.is_empty() {
+            self.output.clear();
+            return true;
+        }
+        if let Some((prompt, input)) = self.prompt.take() {
+            self.answer(store, prompt, input, key);
+            return true;
+        }
+
+        let files = self.files(store.fs());
+        let tags = tags(store.fs());
+        let file = files.get(self.file_at).map(|(file, name)| (*file, name.clone()));
+        let tag = tags.get(self.tag_at).map(|(tag, name)| (*tag, name.clone()));
+        let (at, len) = match self.pane {
+            Pane::Files => (&mut self.file_at, files.len()),
+            Pane::Tags => (&mut self.tag_at, tags.len()),
+        };
+        let last = len.saturating_sub(1);
+
+        match (key, self.pane) {
+            (Key::Interrupt, _) => return false,
+            (Key::Text(text), _) if text == "q" => return false,
+            (Key::Tab | Key::Left | Key::Right, Pane::Files) => self.pane = Pane::Tags,
+            (Key::Tab | Key::Left | Key::Right, Pane::Tags) => self.pane = Pane::Files,
+            (Key::Up, _) => *at = at.saturating_sub(1),
+            (Key::Down, _) => *at = (*at + 1).min(last),
+            (Key::PageUp, _) => *at = at.saturating_sub(PAGE),
+            (Key::PageDown, _) => *at = (*at + PAGE).min(last),
+            (Key::Home, _) => *at = 0,
+            (Key::End, _) => *at = last,
+            (Key::Text(text), _) if text == "k" => *at = at.saturating_sub(1),
+            (Key::Text(text), _) if text == "j" => *at = (*at + 1).min(last),
+            (Key::Escape, _) => {
+                self.shown_tag = None;
+                self.find.clear();
+            }
+            (Key::Enter, Pane::Tags) => {
+                let tag = tag.map(|(tag, _)| tag);
+                self.shown_tag = if self.shown_tag == tag { None } else { tag };
+                self.file_at = 0;
+            }
+            (Key::Text(text), Pane::Tags) if text == " " => {
+                if let (Some((file, file_name)), Some((tag, tag_name))) = (file, tag) {
+                    let action = match store.fs().has_tag(file, tag) {
+                        true => "untag",
+                        false => "tag",
+                    };
+                    let (file, tag) = (quote_word(&file_name), quote_word(&tag_name));
+                    self.apply(store, &format!("{action} {file} {tag}"));
+                }
+            }
+            (Key::Text(text), _) => match text.as_str() {
+                "t" if file.is_some() => self.prompt = Some((Prompt::Tag, String::new())),
+                "n" => self.prompt = Some((Prompt::NewTag, String::new())),
+                "/" => self.prompt = Some((Prompt::Find, self.find.clone())),
+                ":" => self.prompt = Some((Prompt::Command, String::new())),
+                "u" => self.apply(store, "undo"),
+                _ => {}
+            },
+            _ => {}
+        }
+
+        true
+    }
+
+    /// Edit the answer to `prompt` with `key`, acting on it once it's entered.
+    fn answer(&mut self, store: &mut impl Store, prompt: Prompt, mut input: String, key: Key) {
+        match key {
+            Key::Enter => {}
+            Key::Escape | Key::Interrupt => {
+                if prompt == Prompt::Find {
+                    self.find.clear();
+                }
+                return;
+            }
+            Key::Backspace => {
+                input.pop();
+            }
+            Key::Text(ref text) => input.push_str(text),
+            _ => {}
+        }
+        if prompt == Prompt::Find {
+            self.find = input.clone();
+            self.file_at = 0;
+        }
+        if key != Key::Enter {
+            self.prompt = Some((prompt, input));
+            return;
+        }
+        if input.is_empty() {
+            return;
+        }
+
+        match prompt {
+            Prompt::Tag => {
+                let Some((_, name)) = self.files(store.fs()).into_iter().nth(self.file_at) else {
+                    return;
+                };
+                let line = format!("tag {} {}", quote_word(&name), quote_word(&input));
+                self.apply(store, &line);
+            }
+            Prompt::NewTag => self.apply(store, &format!("create-tags {}", quote_word(&input))),
+            Prompt::Find => {}
+            Prompt::Command => self.apply(store, &input),
+        }
+    }
+
+    /// Run the command `line`, showing its report.
+    fn apply(&mut self, store: &mut impl Store, line: &str) {
+        let report = match store.run(line) {
+            Ok(report) => report,
+            Err(e) => {
+                self.status = e;
+                return;
+            }
+        };
+
+        let lines = report.trim_end().lines().map(str::to_string).collect::<Vec<_>>();
+        match lines.len() {
+            0 | 1 => self.status = lines.concat(),
+            _ => self.output = lines,
+        }
+    }
+
+    fn draw<B>(&mut self, frame: &mut Frame, fs: &TagFS<B>) {
+        let files = self.files(fs);
+        let tags = tags(fs);
+        self.file_at = self.file_at.min(files.len().saturating_sub(1));
+        self.tag_at = self.tag_at.min(tags.len().saturating_sub(1));
+        self.file_list.select((!files.is_empty()).then_some(self.file_at));
+        self.tag_list.select((!tags.is_empty()).then_some(self.tag_at));
+        let file = files.get(self.file_at).map(|&(file, _)| file);
+
+        let mut title = format!(" tagfs: {} file(s)", files.len());
+        if let Some(name) = self.shown_tag.and_then(|tag| fs.tags().get_by_left(&tag)) {
+            title.push_str(&format!(" tagged '{}'", name.to_string_lossy()));
+        }
+        if !self.find.is_empty() {
+            title.push_str(&format!(" matching '{}'", self.find));
+        }
+
+        let rows = [Constraint::Length(1), Constraint::Min(0), Constraint::Length(1)];
+        let [top, body, bottom] = Layout::vertical(rows).areas(frame.area());
+        frame.render_widget(Paragraph::new(title).reversed(), top);
+
+        // The selection of the pane with the focus is reversed, that of the other one bold
+        let highlight = |pane| match self.pane == pane {
+            true => Style::new().reversed(),
+            false => Style::new().bold(),
+        };
+        let [left, right] = Layout::horizontal([Constraint::Fill(1); 2]).areas(body);
+        let names = files.iter().map(|(_, name)| name.as_str());
+        let list = List::new(names)
+            .block(Block::bordered().title(" files "))
+            .highlight_style(highlight(Pane::Files));
+        frame.render_stateful_widget(list, left, &mut self.file_list);
+
+        let labels = tags.iter().map(|(tag, name)| {
+            let tagged = file.map(|file| fs.has_tag(file, *tag)).unwrap_or(false);
+            let count = fs.members(*tag).map(|members| members.len()).unwrap_or(0);
+            format!("[{}] {name} ({count})", if tagged { 'x' } else { ' ' })
+        });
+        let list = List::new(labels)
+            .block(Block::bordered().title(" tags "))
+            .highlight_style(highlight(Pane::Tags));
+        frame.render_stateful_widget(list, right, &mut self.tag_list);
+
+        if !self.output.is_empty() {
+            frame.render_widget(Clear, body);
+            let output = Paragraph::new(self.output.join("\n")).block(Block::bordered());
+            frame.render_widget(output, body);
+        }
+
+        let line = match &self.prompt {
+            Some((prompt, input)) => format!("{}{input}", prompt.label()),
+            None if !self.output.is_empty() => "press any key".to_string(),
+            None if !self.status.is_empty() => self.status.clone(),
+            None => HELP.to_string(),
+        };
+        frame.render_widget(Paragraph::new(line), bottom);
+    }
+}
+
+/// The tags, by name.
+fn tags<B>(fs: &TagFS<B>) -> Vec<(TagNumber, String)> {
+    let mut tags = fs
+        .tags()
+        .iter()
+        .map(|(&tag, name)| (tag, name.to_string_lossy().into_owned()))
+        .collect::<Vec<_>>();
+    tags.sort_by(|(_, a), (_, b)| a.cmp(b));
+
+    tags
+}
@@ -0,0 +1,98 @@
+use std::collections::HashSet;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use clap::Args;
+use log::warn;
+
+use crate::file::FileNumber;
+use crate::fs::backing::{BackingFS, ExternalFS};
+use crate::fs::profile::{state_file, SAVEFILE};
+use crate::fs::tag::TagFS;
+
+/// Follow which files match a query as the tags change, see [run].
+#[derive(Args, Debug)]
+pub(crate) struct Watch {
+    /// Tags the files have to carry, as a path in the mount, e.g. `music/to-convert`
+    query: String,
+
+    /// How often to look for changes to the savefile. Where the source can be watched, changes
+    /// are seen right away
+    #[arg(short, long, default_value = "1s", value_parser = humantime::parse_duration)]
+    interval: Duration,
+
+    /// Don't report the files that match when starting, only those entering and leaving later
+    #[arg(long)]
+    changes_only: bool,
+}
+
+/// Print `entered` or `left`, a tab and the file for every file entering or leaving the result
+/// of the query, whenever the savefile of `source_path` changes, e.g. because the mount saved.
+/// Only reads the savefile, so it can run next to a mount. Runs until stdout is closed.
+pub(crate) fn run(source_path: &str, watch: &Watch) -> anyhow::Result<()> {
+    let backing = ExternalFS::new(source_path);
+    let savefile = state_file(SAVEFILE);
+    let changes = backing.capabilities().watchable.then(|| backing.watch("")).flatten();
+    let tags = watch.query.split('/').filter(|tag| !tag.is_empty()).collect::<Vec<_>>();
+
+    let mut seen: Option<SystemTime> = None;
+    let mut matching: Option<HashSet<FileNumber>> = None;
+    let mut stdout = io::stdout().lock();
+    loop {
+        let modified = backing.get_metadata(&savefile).map(|attr| attr.mtime).ok();
+        if modified.is_some() && modified != seen {
+            // A save may be in the middle of being written, it's read again on the next round
+            match TagFS::new_from_save(ExternalFS::new(source_path)) {
+                Ok(fs) => {
+                    let now = query(&fs, &tags);
+                    let before = match matching.take() {
+                        Some(before) => before,
+                        None if watch.changes_only => now.clone(),
+                        None => HashSet::new(),
+                    };
+                    report(&mut stdout, &fs, "entered", now.difference(&before))?;
+                    report(&mut stdout, &fs, "left", before.difference(&now))?;
+                    stdout.flush()?;
+
+                    matching = Some(now);
+                    seen = modified;
+                }
+                Err(e) => warn!("the savefile can't be read yet: {e:#}"),
+            }
+        }
+
+        // Any change to the source wakes it up early, the mtime tells whether it was a save
+        match changes.as_ref().map(|changes| changes.recv_timeout(watch.interval)) {
+            Some(Ok(_) | Err(RecvTimeoutError::Timeout)) => {}
+            // The source can't be watched, or not anymore
+            Some(Err(RecvTimeoutError::Disconnected)) | None => thread::sleep(watch.interval),
+        }
+    }
+}
+
+/// The files carrying all of `tags`, none while any of them doesn't exist.
+fn query(fs: &TagFS<ExternalFS>, tags: &[&str]) -> HashSet<FileNumber> {
+    let path = tags.iter().map(|&tag| fs.get_tnb_by_name(tag)).collect::<Option<Vec<_>>>();
+
+    path.map(|path| fs.calculate_intersection(&path)).unwrap_or_default()
+}
+
+fn report<'a>(
+    out: &mut impl Write,
+    fs: &TagFS<ExternalFS>,
+    what: &str,
+    files: impl Iterator<Item = &'a FileNumber>,
+) -> io::Result<()> {
+    let mut names = files.filter_map(|&file| fs.get_fnm_by_number(file)).collect::<Vec<_>>();
+    names.sort();
+    for name in names {
+        out.write_all(format!("{what}\t").as_bytes())?;
+        out.write_all(name.as_bytes())?;
+        out.write_all(b"\n")?;
+    }
+
+    Ok(())
+}